@@ -0,0 +1,126 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::profile::Profile;
+use crate::util::token::{self, Limits, Permission, Scope};
+use crate::util::{audit, fs};
+use chrono::Utc;
+use clap::{ArgGroup, Args};
+use std::io::Write;
+use tracing::{Level, event, info};
+
+/// Manages scoped bearer tokens of the `serve` API. New tokens are printed once to
+/// stdout; only their hashes are stored.
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("action").required(true).args(["create", "list", "revoke"])))]
+pub struct ApiTokenArgs {
+    /// Create a token with this descriptive name
+    #[arg(long, value_name = "NAME")]
+    pub create: Option<String>,
+
+    /// List all tokens
+    #[arg(long)]
+    pub list: bool,
+
+    /// Revoke the token with this id
+    #[arg(long, value_name = "ID")]
+    pub revoke: Option<String>,
+
+    /// Permission of the new token (repeatable)
+    #[arg(long, value_enum, default_value = "sign", conflicts_with_all = ["list", "revoke"])]
+    pub permission: Vec<Permission>,
+
+    /// Name or `*.suffix` pattern the CN and SANs must match (repeatable; any when omitted)
+    #[arg(long, conflicts_with_all = ["list", "revoke"])]
+    pub allow_name: Vec<String>,
+
+    /// Certificate profile the token may request (repeatable; any when omitted)
+    #[arg(long, value_enum, conflicts_with_all = ["list", "revoke"])]
+    pub allow_profile: Vec<Profile>,
+
+    /// Longest certificate lifetime in days the token may request
+    #[arg(long, conflicts_with_all = ["list", "revoke"])]
+    pub max_days: Option<u32>,
+
+    /// API requests per minute, overriding the server's `--rate-limit-identity`
+    #[arg(long, conflicts_with_all = ["list", "revoke"])]
+    pub rate_limit: Option<u32>,
+
+    /// Certificates per quota window, overriding the server's `--quota-identity`
+    #[arg(long, conflicts_with_all = ["list", "revoke"])]
+    pub quota: Option<u32>,
+
+    /// Days until the token expires
+    #[arg(long, default_value = "90", conflicts_with_all = ["list", "revoke"])]
+    pub valid_days: u32,
+}
+
+impl Runnable for ApiTokenArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let mut out = std::io::stdout().lock();
+        if let Some(name) = &self.create {
+            let scope = Scope {
+                names: self.allow_name,
+                profiles: self.allow_profile,
+                max_days: self.max_days,
+            };
+            let limits = Limits {
+                rate_limit: self.rate_limit,
+                quota: self.quota,
+            };
+            let (secret, token) =
+                token::create(name, self.permission, scope, limits, self.valid_days)?;
+            writeln!(out, "{}", secret.as_str())
+                .and_then(|()| out.flush())
+                .map_err(Error::from)?;
+            info!(id = %token.id, %name, expires = %token.expires, "API token created");
+            audit::emit("api-token", &format!("{} created ({name})", token.id), json)?;
+        } else if let Some(id) = &self.revoke {
+            let token = token::revoke(id)?;
+            info!(%id, name = %token.name, "API token revoked");
+            audit::emit("api-token", &format!("{id} revoked"), json)?;
+        } else {
+            let now = Utc::now();
+            for t in fs::read_api_tokens()? {
+                let status = match t.revoked {
+                    Some(at) => format!("revoked {}", at.to_rfc3339()),
+                    None if !t.is_active(now) => format!("expired {}", t.expires.to_rfc3339()),
+                    None => format!("expires {}", t.expires.to_rfc3339()),
+                };
+                let permissions: Vec<&str> = t.permissions.iter().map(|p| p.name()).collect();
+                let mut scope = Vec::new();
+                if !t.scope.names.is_empty() {
+                    scope.push(format!("names={}", t.scope.names.join(",")));
+                }
+                if !t.scope.profiles.is_empty() {
+                    let profiles: Vec<&str> = t.scope.profiles.iter().map(|p| p.name()).collect();
+                    scope.push(format!("profiles={}", profiles.join(",")));
+                }
+                if let Some(max) = t.scope.max_days {
+                    scope.push(format!("max-days={max}"));
+                }
+                if let Some(rate) = t.limits.rate_limit {
+                    scope.push(format!("rate-limit={rate}/min"));
+                }
+                if let Some(quota) = t.limits.quota {
+                    scope.push(format!("quota={quota}"));
+                }
+                if scope.is_empty() {
+                    scope.push("unrestricted".into());
+                }
+                writeln!(
+                    out,
+                    "{}  {}  {}  {}  {}",
+                    t.id,
+                    t.name,
+                    permissions.join(","),
+                    scope.join(" "),
+                    status
+                )
+                .map_err(Error::from)?;
+            }
+            out.flush().map_err(Error::from)?;
+        }
+        event!(Level::INFO, "API tokens handled");
+        Ok(())
+    }
+}
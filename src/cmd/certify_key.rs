@@ -0,0 +1,101 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::issued::IssuedCert;
+use crate::util::pqcert::CertifiedKey;
+use crate::util::x509::{self, CaSigner};
+use crate::util::{audit, fs, pqcert, revocation};
+use clap::{ArgGroup, Args};
+use tracing::{Level, event, info};
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("key").required(true).args(["name", "pubkey"])))]
+pub struct CertifyKeyArgs {
+    /// Keystore key to certify
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Public key (`.pub` written by `keygen`) to certify
+    #[arg(long)]
+    pub pubkey: Option<String>,
+
+    /// Common-Name of the signer; defaults to the key name
+    #[arg(long)]
+    pub cn: Option<String>,
+
+    /// Validity period in days
+    #[arg(long, default_value = "365")]
+    pub days: u32,
+
+    /// Certify the key's Kyber encryption key instead of its signing key
+    #[arg(long)]
+    pub encryption: bool,
+}
+
+impl Runnable for CertifyKeyArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let identity = match (&self.name, &self.pubkey) {
+            (Some(name), _) => fs::read_identity(name)?.public,
+            (None, Some(path)) => fs::read_public_identity(path)?,
+            (None, None) => return Err(Error::Other("no key given".into())),
+        };
+        let (key, public_key, key_id) = if self.encryption {
+            let kem = identity
+                .kem
+                .as_ref()
+                .ok_or_else(|| Error::Other(format!("{} has no encryption key", identity.name)))?;
+            if kem.x25519.is_some() {
+                return Err(Error::Other(format!(
+                    "{} has a {} hybrid encryption key; its certificate could only cover \
+                     the {} half, so distribute the .pub file instead",
+                    identity.name,
+                    kem.algorithm_name(),
+                    kem.algorithm.name()
+                )));
+            }
+            (
+                CertifiedKey::Encryption(kem.algorithm),
+                kem.public_key()?,
+                &kem.key_id,
+            )
+        } else {
+            if identity.classical.is_some() {
+                return Err(Error::Other(format!(
+                    "{} is a {} hybrid key; its certificate could only cover the {} half, \
+                     so distribute the .pub file instead",
+                    identity.name,
+                    identity.algorithm_name(),
+                    identity.algorithm.name()
+                )));
+            }
+            (
+                CertifiedKey::Signing(identity.algorithm),
+                identity.public_key()?,
+                &identity.key_id,
+            )
+        };
+        revocation::ensure_key_allowed(&pqcert::spki(key, &public_key))?;
+
+        let cn = self.cn.clone().unwrap_or_else(|| identity.name.clone());
+        // encryption certificates are stored beside the signing certificate as `<name>.kem`
+        let suffix = if self.encryption { ".kem" } else { "" };
+        fs::check_cert_name(&cn)?;
+        let signer = CaSigner::load()?;
+        let der = pqcert::issue(&signer, &cn, key, &public_key, self.days)?;
+        let cert_pem = x509::der_to_pem(&der, "CERTIFICATE");
+
+        let entry = IssuedCert::from_der(&der)?;
+        fs::write_cert(&format!("{cn}{suffix}"), &cert_pem, None)?;
+        fs::record_issued(&entry, &cert_pem)?;
+        if let Some(name) = &self.name {
+            fs::write_identity_cert(&format!("{name}{suffix}"), &cert_pem)?;
+        }
+        info!(%cn, serial = %entry.serial, %key_id, algorithm = %key.name(), "key certified");
+        audit::emit(
+            "certify-key",
+            &format!("{cn} {key_id} {}", entry.serial),
+            json,
+        )?;
+        event!(Level::INFO, "key certificate issued");
+        Ok(())
+    }
+}
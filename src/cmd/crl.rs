@@ -0,0 +1,66 @@
+use crate::error::{Error, Result};
+use crate::util::crl::{self, CrlInfo};
+use crate::util::x509::{self, CaSigner};
+use crate::util::{audit, fs, revocation};
+use chrono::{Duration, Utc};
+use clap::Args;
+use tracing::{Level, debug, event, info};
+
+#[derive(Args, Debug)]
+pub struct CrlArgs {
+    /// Days until nextUpdate
+    #[arg(long, default_value = "7")]
+    pub days: u32,
+
+    /// Issue a delta CRL against the last complete CRL
+    #[arg(long)]
+    pub delta: bool,
+}
+
+impl crate::cmd::Runnable for CrlArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let signer = CaSigner::load()?;
+        // held until the state is saved, so no two CRLs get the same number
+        let _lock = fs::lock_revocations()?;
+        let mut state = fs::read_crl_state()?;
+
+        let now = Utc::now();
+        let number = state.last_number + 1;
+        let (entries, delta_base) = if self.delta {
+            let (Some(base), Some(issued)) = (state.base_number, state.base_issued) else {
+                return Err(Error::Other(
+                    "no complete CRL issued yet; run `crl` without --delta first".into(),
+                ));
+            };
+            let mut changes = fs::read_revocations()?;
+            changes.retain(|e| e.revoked_at > issued);
+            (revocation::latest(changes), Some(base))
+        } else {
+            (revocation::current()?, None)
+        };
+
+        let info = CrlInfo {
+            number,
+            this_update: now,
+            next_update: now + Duration::days(self.days.into()),
+            delta_base,
+        };
+        debug!(number, entries = entries.len(), "building CRL");
+        let der = crl::build(&signer, &info, &entries)?;
+        let pem = x509::der_to_pem(&der, "X509 CRL");
+
+        let name = if self.delta { "delta" } else { "crl" };
+        fs::write_crl(name, &der, &pem)?;
+        state.last_number = number;
+        if !self.delta {
+            state.base_number = Some(number);
+            state.base_issued = Some(now);
+        }
+        fs::write_crl_state(&state)?;
+
+        info!(number, "{} CRL written", name);
+        audit::emit("crl", &format!("{name} #{number}"), json)?;
+        event!(Level::INFO, "CRL issued");
+        Ok(())
+    }
+}
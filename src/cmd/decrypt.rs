@@ -0,0 +1,67 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::encryption;
+use crate::util::{audit, fs, keystore};
+use clap::Args;
+use tracing::{Level, event, info};
+
+#[derive(Args, Debug)]
+pub struct DecryptArgs {
+    /// File written by `encrypt`
+    #[arg(long)]
+    pub file: String,
+
+    /// Keystore key the file was encrypted to
+    #[arg(long)]
+    pub key: String,
+
+    /// Output file (defaults to --file without its `.enc` suffix)
+    #[arg(long)]
+    pub out: Option<String>,
+
+    /// File holding the keystore passphrase (defaults to $HYPATIA_PASSPHRASE or a prompt)
+    #[arg(long)]
+    pub passphrase_file: Option<String>,
+
+    /// Overwrite an existing output file
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl Runnable for DecryptArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let out = match (&self.out, self.file.strip_suffix(".enc")) {
+            (Some(out), _) => out.clone(),
+            (None, Some(stem)) if !stem.is_empty() => stem.to_owned(),
+            (None, _) => {
+                return Err(Error::Other(format!(
+                    "{} has no .enc suffix; pass --out",
+                    self.file
+                )));
+            }
+        };
+        let pass = keystore::passphrase(self.passphrase_file.as_deref())?;
+        let (identity, sk) = keystore::unlock(&self.key, &pass)?;
+        let (Some(key), Some(secret)) = (&identity.kem, &sk.kem) else {
+            return Err(Error::Other(format!(
+                "key {} has no encryption key",
+                self.key
+            )));
+        };
+
+        let input = std::fs::File::open(&self.file).map_err(Error::from)?;
+        // plaintext reaches --out only after the last chunk has authenticated
+        let bytes = fs::write_output(&out, 0o600, self.force, |w| {
+            encryption::decrypt(std::io::BufReader::new(input), w, key, secret)
+        })?;
+
+        info!(file = %self.file, %out, bytes, key_id = %key.key_id, "file decrypted");
+        audit::emit(
+            "decrypt",
+            &format!("{} {} {}", self.file, self.key, key.key_id),
+            json,
+        )?;
+        event!(Level::INFO, "decryption complete");
+        Ok(())
+    }
+}
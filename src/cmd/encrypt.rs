@@ -0,0 +1,102 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::encryption::{self, Recipient};
+use crate::util::{audit, fs, pqcert, x509};
+use chrono::Utc;
+use clap::Args;
+use tracing::{Level, debug, event, info};
+
+#[derive(Args, Debug)]
+pub struct EncryptArgs {
+    /// File to encrypt
+    #[arg(long)]
+    pub file: String,
+
+    /// Recipient: a `.pub` file with an encryption key or an encryption certificate (repeatable)
+    #[arg(long, required = true)]
+    pub to: Vec<String>,
+
+    /// Output file (defaults to `<file>.enc`)
+    #[arg(long)]
+    pub out: Option<String>,
+
+    /// Root certificate recipient certificates must chain to (defaults to this CA's root)
+    #[arg(long)]
+    pub ca: Option<String>,
+
+    /// CRL (PEM or DER) recipient certificates are checked against
+    #[arg(long)]
+    pub crl: Option<String>,
+
+    /// Overwrite an existing output file
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl EncryptArgs {
+    fn recipient(&self, path: &str) -> Result<Recipient> {
+        let data = std::fs::read_to_string(path).map_err(Error::from)?;
+        if data.contains("-----BEGIN CERTIFICATE-----") {
+            let root = match &self.ca {
+                Some(ca) => std::fs::read_to_string(ca).map_err(Error::from)?,
+                None => fs::read_root_cert()?,
+            };
+            let crl = match &self.crl {
+                Some(crl) => Some(x509::read_pem_or_der(crl, "X509 CRL")?),
+                None => None,
+            };
+            let (name, key) = pqcert::validate_recipient(
+                &x509::pem_to_der(&data, "CERTIFICATE")?,
+                &x509::pem_to_der(&root, "CERTIFICATE")?,
+                crl.as_deref(),
+                Utc::now(),
+            )
+            .map_err(|e| Error::Other(format!("{path}: {}", e.plain())))?;
+            return Ok(Recipient { name, key });
+        }
+        let identity = fs::read_public_identity(path)?;
+        let key = identity.kem.ok_or_else(|| {
+            Error::Other(format!(
+                "{path} has no encryption key; create one with `keygen --kem`"
+            ))
+        })?;
+        Ok(Recipient {
+            name: identity.name,
+            key,
+        })
+    }
+}
+
+impl Runnable for EncryptArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let recipients = self
+            .to
+            .iter()
+            .map(|path| self.recipient(path))
+            .collect::<Result<Vec<_>>>()?;
+        for r in &recipients {
+            debug!(name = %r.name, key_id = %r.key.key_id, algorithm = %r.key.algorithm_name(), "recipient");
+        }
+        let out = self
+            .out
+            .clone()
+            .unwrap_or_else(|| format!("{}.enc", self.file));
+        let input = std::fs::File::open(&self.file).map_err(Error::from)?;
+        let bytes = fs::write_output(&out, 0o644, self.force, |w| {
+            encryption::encrypt(std::io::BufReader::new(input), w, &recipients)
+        })?;
+
+        let names: Vec<String> = recipients
+            .iter()
+            .map(|r| format!("{} {}", r.name, r.key.key_id))
+            .collect();
+        info!(file = %self.file, %out, bytes, recipients = %names.join(", "), "file encrypted");
+        audit::emit(
+            "encrypt",
+            &format!("{} {}", self.file, names.join(", ")),
+            json,
+        )?;
+        event!(Level::INFO, "encryption complete");
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::{audit, fs, password};
+use clap::Args;
+use std::io::{BufRead, Write};
+use tracing::{Level, event, info};
+use zeroize::Zeroizing;
+
+#[derive(Args, Debug)]
+pub struct EstUserArgs {
+    /// User name devices send in HTTP basic auth
+    #[arg(long)]
+    pub name: String,
+
+    /// File holding the password (defaults to a prompt)
+    #[arg(long, conflicts_with = "remove")]
+    pub password_file: Option<String>,
+
+    /// Remove the user instead of setting its password
+    #[arg(long)]
+    pub remove: bool,
+}
+
+impl EstUserArgs {
+    fn password(&self) -> Result<Zeroizing<String>> {
+        let mut pass = match &self.password_file {
+            Some(path) => Zeroizing::new(std::fs::read_to_string(path).map_err(Error::from)?),
+            None => {
+                eprint!("password for {}: ", self.name);
+                std::io::stderr().flush().map_err(Error::from)?;
+                let mut line = Zeroizing::new(String::new());
+                std::io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(Error::from)?;
+                line
+            }
+        };
+        let len = pass.trim_end_matches(['\r', '\n']).len();
+        pass.truncate(len);
+        if pass.is_empty() {
+            return Err(Error::Other("empty password".into()));
+        }
+        Ok(pass)
+    }
+}
+
+impl Runnable for EstUserArgs {
+    fn run(self, json: bool) -> Result<()> {
+        if self.name.is_empty()
+            || self.name.contains(':')
+            || self.name.contains(char::is_whitespace)
+        {
+            return Err(Error::Other(format!(
+                "invalid EST user name {:?}",
+                self.name
+            )));
+        }
+        let mut users = fs::read_est_users()?;
+        let existing = users.iter().position(|(name, _)| *name == self.name);
+        let action = if self.remove {
+            let index =
+                existing.ok_or_else(|| Error::Other(format!("no EST user {}", self.name)))?;
+            users.remove(index);
+            "removed"
+        } else {
+            let hash = password::hash(&self.password()?);
+            match existing {
+                Some(index) => users[index].1 = hash,
+                None => users.push((self.name.clone(), hash)),
+            }
+            if existing.is_some() {
+                "updated"
+            } else {
+                "added"
+            }
+        };
+        fs::write_est_users(&users)?;
+
+        info!(name = %self.name, action, "EST user saved");
+        audit::emit("est-user", &format!("{} {action}", self.name), json)?;
+        event!(Level::INFO, "EST users updated");
+        Ok(())
+    }
+}
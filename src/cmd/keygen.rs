@@ -0,0 +1,82 @@
+use crate::cmd::Runnable;
+use crate::error::Result;
+use crate::util::keystore::{self, Classical};
+use crate::util::pq::{Algorithm, Kem};
+use crate::util::{audit, fs};
+use clap::Args;
+use tracing::{Level, event, info};
+
+#[derive(Args, Debug)]
+pub struct KeygenArgs {
+    /// Name of the key in the keystore
+    #[arg(long)]
+    pub name: String,
+
+    /// Signature algorithm
+    #[arg(long, value_enum, default_value = "falcon512")]
+    pub algorithm: Algorithm,
+
+    /// Also create a classical key; signatures then verify only if both keys agree
+    #[arg(long, value_enum)]
+    pub hybrid: Option<Classical>,
+
+    /// Also create a Kyber encryption key so files can be encrypted to this identity
+    #[arg(long, value_enum)]
+    pub kem: Option<Kem>,
+
+    /// Pair the encryption key with an X25519 key; decryption then needs both
+    #[arg(long, requires = "kem")]
+    pub x25519: bool,
+
+    /// File holding the passphrase (defaults to $HYPATIA_PASSPHRASE or a prompt)
+    #[arg(long)]
+    pub passphrase_file: Option<String>,
+
+    /// Overwrite an existing key with the same name
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl Runnable for KeygenArgs {
+    fn run(self, json: bool) -> Result<()> {
+        keystore::check_name(&self.name)?;
+        let pass = keystore::passphrase(self.passphrase_file.as_deref())?;
+        let identity = keystore::generate(
+            &self.name,
+            self.algorithm,
+            self.hybrid,
+            self.kem,
+            self.x25519,
+            &pass,
+        )?;
+        fs::write_identity(&identity, self.force)?;
+
+        let key_id = &identity.public.key_id;
+        info!(
+            name = %self.name,
+            %key_id,
+            algorithm = %identity.public.algorithm_name(),
+            public = ?fs::public_identity_path(&self.name),
+            "signing key generated"
+        );
+        if let Some(kem) = &identity.public.kem {
+            info!(
+                name = %self.name,
+                key_id = %kem.key_id,
+                algorithm = %kem.algorithm_name(),
+                "encryption key generated"
+            );
+        }
+        audit::emit(
+            "keygen",
+            &format!(
+                "{} {} {key_id}",
+                self.name,
+                identity.public.algorithm_name()
+            ),
+            json,
+        )?;
+        event!(Level::INFO, "key stored");
+        Ok(())
+    }
+}
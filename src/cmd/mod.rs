@@ -1,8 +1,19 @@
+pub mod api_token;
+pub mod certify_key;
+pub mod crl;
+pub mod decrypt;
+pub mod encrypt;
+pub mod est_user;
 pub mod init_root;
+pub mod keygen;
 pub mod revoke;
+pub mod scep_challenge;
 pub mod serve;
+pub mod sign;
 pub mod sign_cert;
 pub mod signature;
+pub mod timestamp;
+pub mod unhold;
 
 use crate::error::Result;
 
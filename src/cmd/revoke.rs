@@ -1,21 +1,159 @@
-use crate::error::Result;
-use crate::util::{audit, fs};
-use clap::Args;
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::crl::Reason;
+use crate::util::issued::IssuedCert;
+use crate::util::{audit, fs, revocation, x509};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args};
+use rcgen::{KeyPair, PublicKeyData};
+use std::path::Path;
 use tracing::{Level, event, info};
+use zeroize::Zeroizing;
 
 #[derive(Args, Debug)]
+#[command(group(
+    ArgGroup::new("target")
+        .required(true)
+        .args(["serial", "cert", "cn", "spki", "private_key"])
+))]
 pub struct RevokeArgs {
     /// Serial number of certificate to revoke
     #[arg(long)]
-    pub serial: String,
+    pub serial: Option<String>,
+
+    /// PEM certificate file to revoke
+    #[arg(long)]
+    pub cert: Option<String>,
+
+    /// Revoke every unexpired certificate issued for this Common-Name
+    #[arg(long)]
+    pub cn: Option<String>,
+
+    /// Public key (SHA-256 of its SPKI, or a PEM public key or certificate) whose
+    /// certificates are all revoked
+    #[arg(long, requires = "key_compromise")]
+    pub spki: Option<String>,
+
+    /// Private key submitted as proof of compromise
+    #[arg(long, requires = "key_compromise")]
+    pub private_key: Option<String>,
+
+    /// Revoke every certificate sharing the given key and block it from future CSRs
+    #[arg(long)]
+    pub key_compromise: bool,
+
+    /// RFC 5280 revocation reason
+    #[arg(long, value_enum, default_value = "unspecified")]
+    pub reason: Reason,
+
+    /// When the key was compromised or the certificate became invalid (RFC 3339)
+    #[arg(long)]
+    pub invalidity_date: Option<DateTime<Utc>>,
+
+    /// Days until nextUpdate of the regenerated CRL
+    #[arg(long, default_value = "7")]
+    pub crl_days: u32,
 }
 
-impl crate::cmd::Runnable for RevokeArgs {
+impl RevokeArgs {
+    /// SPKI hash of the compromised key given by `--spki` or `--private-key`.
+    fn compromised_key(&self) -> Result<Option<String>> {
+        if let Some(path) = &self.private_key {
+            let pem = Zeroizing::new(std::fs::read_to_string(path).map_err(Error::from)?);
+            let key = KeyPair::from_pem(&pem).map_err(Error::from)?;
+            return Ok(Some(x509::spki_hash(&key.subject_public_key_info())));
+        }
+        let Some(spki) = &self.spki else {
+            return Ok(None);
+        };
+        if !Path::new(spki).exists() {
+            let hash: String = spki.chars().filter(|c| *c != ':').collect();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::Other(format!(
+                    "{spki} is neither a file nor a SHA-256 hash"
+                )));
+            }
+            return Ok(Some(hash.to_lowercase()));
+        }
+        let pem = std::fs::read_to_string(spki).map_err(Error::from)?;
+        match x509::pem_to_der(&pem, "PUBLIC KEY") {
+            Ok(der) => Ok(Some(x509::spki_hash(&der))),
+            Err(_) => {
+                let cert = IssuedCert::from_der(&x509::pem_to_der(&pem, "CERTIFICATE")?)?;
+                Ok(Some(cert.spki_sha256))
+            }
+        }
+    }
+
+    fn serials(&self) -> Result<Vec<String>> {
+        if let Some(serial) = &self.serial {
+            return Ok(vec![serial.clone()]);
+        }
+        if let Some(path) = &self.cert {
+            let pem = std::fs::read_to_string(path).map_err(Error::from)?;
+            let cert = IssuedCert::from_der(&x509::pem_to_der(&pem, "CERTIFICATE")?)?;
+            return Ok(vec![cert.serial]);
+        }
+        let cn = self.cn.as_deref().unwrap_or_default();
+        let revoked: Vec<String> = revocation::current()?
+            .into_iter()
+            .map(|e| e.serial)
+            .collect();
+        let now = Utc::now();
+        let serials: Vec<String> = fs::read_issued()?
+            .into_iter()
+            .filter(|c| c.cn == cn && c.not_after > now && !revoked.contains(&c.serial))
+            .map(|c| c.serial)
+            .collect();
+        if serials.is_empty() {
+            return Err(Error::Other(format!(
+                "no unrevoked certificate issued for {cn}"
+            )));
+        }
+        Ok(serials)
+    }
+}
+
+impl Runnable for RevokeArgs {
     fn run(self, json: bool) -> Result<()> {
-        fs::append_revocation(&self.serial)?;
-        info!(serial = %self.serial, "certificate revoked");
-        audit::emit("revoke", &self.serial, json)?;
+        if let Some(hash) = self.compromised_key()? {
+            let revoked = revocation::revoke_key(&hash, self.invalidity_date)?;
+            info!(%hash, count = revoked.len(), "key compromise handled");
+            for entry in &revoked {
+                audit::emit(
+                    "revoke",
+                    &serde_json::to_string(entry).map_err(Error::from)?,
+                    json,
+                )?;
+            }
+            audit::emit("block-key", &hash, json)?;
+            return crate::cmd::crl::CrlArgs {
+                days: self.crl_days,
+                delta: false,
+            }
+            .run(json);
+        }
+
+        let reason = if self.key_compromise {
+            Reason::KeyCompromise
+        } else {
+            self.reason
+        };
+        for serial in self.serials()? {
+            let entry = revocation::revoke(&serial, reason, self.invalidity_date)?;
+            info!(serial = %entry.serial, ?reason, "certificate revoked");
+            audit::emit(
+                "revoke",
+                &serde_json::to_string(&entry).map_err(Error::from)?,
+                json,
+            )?;
+        }
         event!(Level::INFO, "revocation written");
-        Ok(())
+
+        crate::cmd::crl::CrlArgs {
+            days: self.crl_days,
+            delta: false,
+        }
+        .run(json)
     }
 }
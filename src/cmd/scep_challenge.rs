@@ -0,0 +1,42 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::{audit, scep};
+use clap::Args;
+use std::io::Write;
+use tracing::{Level, event, info};
+
+/// Issues a one-time challenge password for SCEP enrollment; the password goes to stdout
+/// and only its hash is kept.
+#[derive(Args, Debug)]
+pub struct ScepChallengeArgs {
+    /// Common-Name the enrolling device has to request; the challenge is good for no other
+    #[arg(long)]
+    pub cn: String,
+
+    /// Hours until the challenge expires
+    #[arg(long, default_value = "24")]
+    pub hours: u32,
+}
+
+impl Runnable for ScepChallengeArgs {
+    fn run(self, json: bool) -> Result<()> {
+        if self.hours == 0 {
+            return Err(Error::Other("--hours must be at least 1".into()));
+        }
+        let (password, expiry) =
+            scep::new_challenge(&self.cn, chrono::Duration::hours(self.hours.into()))?;
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{}", password.as_str())
+            .and_then(|()| out.flush())
+            .map_err(Error::from)?;
+
+        info!(cn = %self.cn, %expiry, "SCEP challenge issued");
+        audit::emit(
+            "scep-challenge",
+            &format!("{} expires {}", self.cn, expiry.to_rfc3339()),
+            json,
+        )?;
+        event!(Level::INFO, "SCEP challenge issued");
+        Ok(())
+    }
+}
@@ -1,24 +1,55 @@
 use crate::cmd::Runnable;
+use crate::cmd::sign_cert::{self, Issued, KeySource};
 use crate::error::{Error, Result};
-use crate::util::{audit, fs};
+use crate::util::crl::{Reason, Revocation};
+use crate::util::ocsp::{self, CertStatus, Responder};
+use crate::util::profile::{KeyAlgorithm, Profile};
+use crate::util::ratelimit::{self, Limiter, RetryAfter};
+use crate::util::scep::Responder as ScepResponder;
+use crate::util::token::{self, Limits, Permission, Scope};
+use crate::util::tsp::{self, Authority};
+use crate::util::{audit, fs, revocation};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use bytes::Bytes;
-use clap::Args;
-use http_body_util::{BodyExt, Full};
-use hyper::body::Incoming as IncomingBody;
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use conn::{InFlight, TimedIo};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Incoming};
 use hyper::http::StatusCode;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response};
-use hyper_util::rt::TokioIo;
-use rustls::ServerConfig;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use mtls::{ClientAuth, ClientIdentity, Mtls};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls_pemfile::{certs, private_key};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tracing::{error, info};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+mod acme;
+mod conn;
+mod est;
+mod mtls;
+mod policy;
+mod rest;
+mod scep;
+mod tokens;
+
+/// How often the delegated signers are checked for an approaching expiry; they are
+/// renewed a day ahead, so hourly is plenty.
+const SIGNER_CHECK: Duration = Duration::from_secs(3600);
 
 #[derive(Args, Debug)]
 pub struct ServeArgs {
@@ -34,15 +65,364 @@ pub struct ServeArgs {
     #[arg(long)]
     pub tls_key: String,
 
-    /// Bearer token for authentication
+    /// Bearer token with every permission; it shows up in `ps`, so prefer tokens from
+    /// `api-token`
+    #[arg(long)]
+    pub token: Option<String>,
+
+    /// Whether TLS clients may, must or cannot present a certificate
+    #[arg(long, value_enum, default_value = "optional")]
+    pub client_auth: ClientAuth,
+
+    /// PEM bundle of CAs trusted for client certificates (defaults to this CA)
+    #[arg(long)]
+    pub client_ca: Option<String>,
+
+    /// API permissions for the client certificate whose CN or SAN is NAME and whose key
+    /// has the hex SHA-256 SubjectPublicKeyInfo hash SPKI, as NAME@SPKI=sign,revoke,admin
+    /// (repeatable)
+    #[arg(long, value_name = "NAME@SPKI=PERMISSIONS")]
+    pub client_identity: Vec<ClientIdentity>,
+
+    /// URL path of the OCSP responder
+    #[arg(long, default_value = "/ocsp")]
+    pub ocsp_path: String,
+
+    /// Validity of OCSP responses in hours
+    #[arg(long, default_value = "24")]
+    pub ocsp_validity: u32,
+
+    /// Lifetime of the delegated OCSP signing certificate in days
+    #[arg(long, default_value = "30")]
+    pub ocsp_signer_days: u32,
+
+    /// URL path of the RFC 3161 time-stamping authority
+    #[arg(long, default_value = "/tsa")]
+    pub tsa_path: String,
+
+    /// Lifetime of the TSA signing certificate in days
+    #[arg(long, default_value = "365")]
+    pub tsa_signer_days: u32,
+
+    /// Policy OID stamped into time-stamp tokens
+    #[arg(long, default_value = tsp::DEFAULT_POLICY)]
+    pub tsa_policy: String,
+
+    /// Days until nextUpdate of CRLs regenerated after API revocations
+    #[arg(long, default_value = "7")]
+    pub crl_days: u32,
+
+    /// URL path of the ACME (RFC 8555) directory
+    #[arg(long, default_value = "/acme")]
+    pub acme_path: String,
+
+    /// Validity of certificates issued through ACME in days
+    #[arg(long, default_value = "90")]
+    pub acme_days: u32,
+
+    /// Domain suffix ACME may issue for (repeatable; any domain when omitted)
+    #[arg(long)]
+    pub acme_domain: Vec<String>,
+
+    /// host:port http-01 validation connects to instead of port 80 of the domain, e.g. a
+    /// local stand-in web server
+    #[arg(long)]
+    pub acme_http_addr: Option<String>,
+
+    /// DNS resolver for dns-01 validation (defaults to the first nameserver in /etc/resolv.conf)
     #[arg(long)]
-    pub token: String,
+    pub acme_dns_server: Option<SocketAddr>,
+
+    /// Validity of certificates enrolled over EST in days
+    #[arg(long, default_value = "365")]
+    pub est_days: u32,
+
+    /// Key usages of certificates enrolled over EST
+    #[arg(long, value_enum, default_value = "default")]
+    pub est_profile: Profile,
+
+    /// Path of the SCEP endpoint
+    #[arg(long, default_value = "/scep")]
+    pub scep_path: String,
+
+    /// Validity of certificates enrolled over SCEP in days
+    #[arg(long, default_value = "365")]
+    pub scep_days: u32,
+
+    /// Validity in days of the SCEP registration authority certificate
+    #[arg(long, default_value = "365")]
+    pub scep_signer_days: u32,
+
+    /// Requests per minute from one client IP address (0 disables the limit)
+    #[arg(long, default_value = "300")]
+    pub rate_limit_ip: u32,
+
+    /// API requests per minute of one token or client certificate, unless the token sets
+    /// its own (0 disables the limit)
+    #[arg(long, default_value = "60")]
+    pub rate_limit_identity: u32,
+
+    /// Certificates one token or client certificate may obtain per quota window, unless
+    /// the token sets its own
+    #[arg(long)]
+    pub quota_identity: Option<u32>,
+
+    /// Certificates per registered domain (the last two labels of a name) per quota window
+    #[arg(long)]
+    pub quota_domain: Option<u32>,
+
+    /// Length of the quota window in hours
+    #[arg(long, default_value = "24")]
+    pub quota_window_hours: u32,
+
+    /// JSON file overriding --client-identity and the rate limit and quota flags,
+    /// re-read on SIGHUP
+    #[arg(long, value_name = "PATH")]
+    pub policy: Option<String>,
+
+    /// Seconds to let in-flight requests finish after SIGTERM or SIGINT
+    #[arg(long, default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    /// Connections served at once; further ones are closed right after accepting
+    #[arg(long, default_value = "1024")]
+    pub max_connections: usize,
+
+    /// Seconds a client may take to complete the TLS handshake
+    #[arg(long, default_value = "10")]
+    pub handshake_timeout: u64,
+
+    /// Seconds a client may take to send request headers, and again the request body
+    #[arg(long, default_value = "10")]
+    pub header_timeout: u64,
+
+    /// Seconds an open connection may stay silent between requests
+    #[arg(long, default_value = "60")]
+    pub idle_timeout: u64,
+
+    /// Largest request body in bytes
+    #[arg(long, default_value = "1048576")]
+    pub max_body: usize,
+
+    /// Offer HTTP/2 through ALPN besides HTTP/1.1
+    #[arg(long)]
+    pub http2: bool,
+}
+
+struct AppState {
+    token_hash: Option<[u8; 32]>,
+    /// Replaced as a whole on SIGHUP
+    mtls: RwLock<Arc<Mtls>>,
+    limiter: Arc<Limiter>,
+    max_body: usize,
+    body_timeout: Duration,
+    crl_days: u32,
+    ocsp_path: String,
+    /// Replaced when its certificate is about to expire
+    ocsp: RwLock<Arc<Responder>>,
+    tsa_path: String,
+    /// Replaced when its certificate is about to expire
+    tsa: RwLock<Arc<Authority>>,
+    acme_path: String,
+    acme: Arc<acme::Server>,
+    est: est::Service,
+    scep_path: String,
+    /// Replaced when its certificate is about to expire
+    scep: RwLock<Arc<ScepResponder>>,
+    scep_days: u32,
+}
+
+impl AppState {
+    fn mtls(&self) -> Arc<Mtls> {
+        current(&self.mtls)
+    }
+
+    fn ocsp(&self) -> Arc<Responder> {
+        current(&self.ocsp)
+    }
+
+    fn tsa(&self) -> Arc<Authority> {
+        current(&self.tsa)
+    }
+
+    fn scep(&self) -> Arc<ScepResponder> {
+        current(&self.scep)
+    }
+}
+
+fn current<T>(lock: &RwLock<Arc<T>>) -> Arc<T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner).clone()
 }
 
+/// Swaps in `renewed` if it holds a new signer; `what` names it in the log.
+fn replace<T>(lock: &RwLock<Arc<T>>, renewed: Result<Option<T>>, what: &str) {
+    match renewed {
+        Ok(Some(signer)) => {
+            *lock.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(signer);
+            info!("renewed the {} certificate", what);
+        }
+        Ok(None) => {}
+        Err(e) => error!("renewing the {} certificate failed: {}", what, e),
+    }
+}
+
+/// Who an API request was authenticated as, and what it may do.
+struct Identity {
+    name: String,
+    permissions: Vec<Permission>,
+    scope: Scope,
+    limits: Limits,
+}
+
+impl Identity {
+    fn may(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
+
+/// Body of `POST /sign`. Without a CSR the server generates the key pair and returns it;
+/// with one, `cn` and `san` default to the names the CSR carries.
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct CertRequest {
-    cn: String,
-    days: Option<u32>,
+    cn: Option<String>,
+    #[serde(default)]
+    san: Vec<String>,
+    days: u32,
+    #[serde(default)]
+    profile: Profile,
+    key_algorithm: Option<KeyAlgorithm>,
+    /// PEM or base64 DER PKCS#10 request
+    csr: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CertResponse<'a> {
+    serial: &'a str,
+    certificate: &'a str,
+    /// Issuing certificates up to the root, leaf excluded
+    chain: Vec<String>,
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key: Option<&'a str>,
+}
+
+/// A JSON error of the certificate and token API. `code` is stable for clients to match
+/// on; `message` is for humans.
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    retry_after: Option<RetryAfter>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// A 429 telling the client when to retry.
+    fn limited(code: &'static str, message: impl Into<String>, retry_after: RetryAfter) -> Self {
+        ApiError {
+            retry_after: Some(retry_after),
+            ..ApiError::new(StatusCode::TOO_MANY_REQUESTS, code, message)
+        }
+    }
+
+    fn malformed(e: serde_json::Error) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "malformed_request", e.to_string())
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+
+    fn internal() -> Self {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "internal error",
+        )
+    }
+
+    fn response(&self) -> Response<Full<Bytes>> {
+        let mut resp = json(
+            self.status,
+            &serde_json::json!({ "error": { "code": self.code, "message": self.message } }),
+        );
+        if let Some(RetryAfter(secs)) = self.retry_after {
+            resp.headers_mut()
+                .insert(hyper::header::RETRY_AFTER, secs.into());
+        }
+        resp
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeRequest {
+    serial: String,
+    reason: Option<Reason>,
+    invalidity_date: Option<DateTime<Utc>>,
+    /// RFC 3339 time the holder signed the request
+    timestamp: Option<String>,
+    /// Base64 signature over the self-service revocation message
+    signature: Option<String>,
+}
+
+impl ServeArgs {
+    /// Loads the TLS certificate, client CA bundle and policy file; run at startup and on
+    /// every SIGHUP.
+    fn load_settings(&self) -> Result<(Mtls, ratelimit::Config)> {
+        let settings = policy::load(self)?;
+        let mtls = Mtls::new(
+            load_certs(&self.tls_cert)?,
+            load_private_key(&self.tls_key)?,
+            self.client_auth,
+            self.client_ca.as_deref(),
+            settings.identities,
+            self.http2,
+        )?;
+        Ok((mtls, settings.limits))
+    }
+
+    /// Replaces delegated signers whose certificates expire within a day.
+    fn renew_signers(&self, state: &AppState) {
+        let renewed = state
+            .ocsp()
+            .renew(self.ocsp_validity, self.ocsp_signer_days);
+        replace(&state.ocsp, renewed, "OCSP signing");
+        let renewed = state.tsa().renew(self.tsa_signer_days);
+        replace(&state.tsa, renewed, "TSA");
+        let renewed = state.scep().renew(self.scep_signer_days);
+        replace(&state.scep, renewed, "SCEP RA");
+    }
+
+    /// Swaps in freshly loaded settings, keeping the previous ones if anything fails.
+    fn reload(&self, state: &AppState, json: bool) {
+        self.renew_signers(state);
+        let result = self.load_settings().and_then(|(mtls, limits)| {
+            // tokens are looked up per request; this only checks the store still parses
+            let tokens = fs::read_api_tokens()?.len();
+            *state.mtls.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(mtls);
+            state.limiter.reconfigure(limits);
+            Ok(tokens)
+        });
+        match result {
+            Ok(tokens) => {
+                info!(tokens, "reloaded TLS certificate, policy and API tokens");
+                if let Err(e) = audit::emit("serve-reload", &self.addr, json) {
+                    error!("audit failed: {}", e);
+                }
+            }
+            Err(e) => error!("reload failed, keeping the previous settings: {}", e),
+        }
+    }
 }
 
 impl Runnable for ServeArgs {
@@ -52,106 +432,652 @@ impl Runnable for ServeArgs {
             .parse::<SocketAddr>()
             .map_err(|e| Error::Other(e.to_string()))?;
         info!("starting API on {}", addr);
-      
-        let certs = load_certs(&self.tls_cert)?;
-        let key = load_private_key(&self.tls_key)?;
-        let tls_cfg = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| Error::Other(e.to_string()))?;
-        let tls_cfg = Arc::new(tls_cfg);
 
-        let token = Arc::new(self.token);
         fs::ensure_dirs()?;
+        let (mtls, limits) = self.load_settings()?;
+        let ocsp = Responder::load(self.ocsp_validity, self.ocsp_signer_days)?;
+        let tsa = Authority::load(self.tsa_signer_days, &self.tsa_policy)?;
+        let scep = ScepResponder::load(self.scep_signer_days)?;
+        let limiter = Arc::new(Limiter::load(limits)?);
+        let acme = acme::Server::load(
+            acme::Config {
+                days: self.acme_days,
+                domains: self.acme_domain.clone(),
+                http_addr: self.acme_http_addr.clone(),
+                dns_server: self.acme_dns_server,
+                crl_days: self.crl_days,
+            },
+            limiter.clone(),
+        )?;
+        let state = Arc::new(AppState {
+            token_hash: self
+                .token
+                .as_ref()
+                .map(|t| Sha256::digest(t.as_bytes()).into()),
+            mtls: RwLock::new(Arc::new(mtls)),
+            limiter: limiter.clone(),
+            max_body: self.max_body,
+            body_timeout: Duration::from_secs(self.header_timeout),
+            crl_days: self.crl_days,
+            ocsp_path: self.ocsp_path.trim_end_matches('/').to_owned(),
+            ocsp: RwLock::new(Arc::new(ocsp)),
+            tsa_path: self.tsa_path.trim_end_matches('/').to_owned(),
+            tsa: RwLock::new(Arc::new(tsa)),
+            acme_path: self.acme_path.trim_end_matches('/').to_owned(),
+            acme: Arc::new(acme),
+            est: est::Service::new(self.est_days, self.est_profile, limiter.clone()),
+            scep_path: self.scep_path.trim_end_matches('/').to_owned(),
+            scep: RwLock::new(Arc::new(scep)),
+            scep_days: self.scep_days,
+        });
         let rt = tokio::runtime::Runtime::new().map_err(|e| Error::Other(e.to_string()))?;
-        rt.block_on(async move {
+        let stopped = rt.block_on(async {
             let listener = tokio::net::TcpListener::bind(addr)
                 .await
                 .map_err(|e| Error::Other(e.to_string()))?;
-            loop {
-                let (stream, _) = listener
-                    .accept()
-                    .await
-                    .map_err(|e| Error::Other(e.to_string()))?;
-                let acceptor = tokio_rustls::TlsAcceptor::from(tls_cfg.clone());
-                let service = service_fn({
-                    let token = token.clone();
-                    move |req| handle(req, token.clone())
-                });
+            let mut terminate = signal(SignalKind::terminate()).map_err(Error::from)?;
+            let mut interrupt = signal(SignalKind::interrupt()).map_err(Error::from)?;
+            let mut hangup = signal(SignalKind::hangup()).map_err(Error::from)?;
+            let saver = state.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(30));
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = saver.limiter.save() {
+                        error!("saving rate limits failed: {}", e);
+                    }
+                }
+            });
+            audit::emit("serve-start", &self.addr, json)?;
+
+            let graceful = GracefulShutdown::new();
+            let connections = Arc::new(Semaphore::new(self.max_connections));
+            let handshake = Duration::from_secs(self.handshake_timeout);
+            let header = Duration::from_secs(self.header_timeout);
+            let idle = Duration::from_secs(self.idle_timeout);
+            let http2 = self.http2;
+            let mut renewal = tokio::time::interval(SIGNER_CHECK);
+            let signal = loop {
+                let (stream, remote) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            error!("accept failed: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = hangup.recv() => {
+                        self.reload(&state, json);
+                        continue;
+                    }
+                    _ = renewal.tick() => {
+                        self.renew_signers(&state);
+                        continue;
+                    }
+                    _ = terminate.recv() => break "SIGTERM",
+                    _ = interrupt.recv() => break "SIGINT",
+                };
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!(%remote, "connection limit reached, closing connection");
+                    continue;
+                };
+                let tls_cfg = match state.mtls().config() {
+                    Ok(cfg) => cfg,
+                    Err(e) => {
+                        error!("TLS configuration failed: {}", e);
+                        continue;
+                    }
+                };
+                let acceptor = tokio_rustls::TlsAcceptor::from(tls_cfg);
+                let state = state.clone();
+                let watcher = graceful.watcher();
                 tokio::spawn(async move {
-                    match acceptor.accept(stream).await {
-                        Ok(tls) => {
-                            let io = TokioIo::new(tls);
-                            if let Err(e) =
-                                http1::Builder::new().serve_connection(io, service).await
-                            {
-                                error!("server error: {}", e);
-                            }
+                    let _permit = permit;
+                    let tls = match tokio::time::timeout(handshake, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => tls,
+                        Ok(Err(e)) => {
+                            error!("tls error: {}", e);
+                            return;
                         }
-                        Err(e) => error!("tls error: {}", e),
+                        Err(_) => {
+                            warn!(%remote, "TLS handshake timed out");
+                            return;
+                        }
+                    };
+                    let peer = tls
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|chain| chain.first())
+                        .map(|cert| Arc::new(cert.clone().into_owned()));
+                    let in_flight = Arc::new(InFlight::default());
+                    let io = TokioIo::new(TimedIo::new(tls, in_flight.clone(), header, idle));
+                    let service = service_fn(move |req| {
+                        let request = in_flight.start();
+                        let response = handle(req, state.clone(), remote.ip(), peer.clone());
+                        async move {
+                            let _request = request;
+                            response.await
+                        }
+                    });
+                    let mut builder = auto::Builder::new(TokioExecutor::new());
+                    // TimedIo enforces header and idle timeouts for both protocols
+                    builder.http1().header_read_timeout(None);
+                    if !http2 {
+                        builder = builder.http1_only();
+                    }
+                    let conn = builder.serve_connection(io, service);
+                    if let Err(e) = watcher.watch(conn).await {
+                        info!(%remote, "connection closed: {}", e);
                     }
                 });
+            };
+
+            // stop accepting, then let open connections finish their current request
+            drop(listener);
+            info!(%signal, connections = graceful.count(), "shutting down");
+            let timeout = Duration::from_secs(self.shutdown_timeout);
+            if tokio::time::timeout(timeout, graceful.shutdown())
+                .await
+                .is_err()
+            {
+                warn!(
+                    "requests still in flight after {}s, closing",
+                    self.shutdown_timeout
+                );
+                return Ok(format!("{signal}, drain timed out"));
             }
-            #[allow(unreachable_code)]
-            Ok::<(), Error>(())
+            Ok::<_, Error>(signal.to_owned())
         })?;
-        audit::emit("serve", &self.addr, json)?;
+        state.limiter.save()?;
+        info!("API on {} stopped", addr);
+        audit::emit("serve-stop", &format!("{} ({stopped})", self.addr), json)?;
         Ok(())
     }
 }
 
 async fn handle(
-    req: Request<IncomingBody>,
-    token: Arc<String>,
+    req: Request<Incoming>,
+    state: Arc<AppState>,
+    remote: IpAddr,
+    peer: Option<Arc<CertificateDer<'static>>>,
 ) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
-    if req.method() == Method::POST && req.uri().path() == "/sign" {
-        match req
-            .headers()
-            .get("authorization")
-            .and_then(|h| h.to_str().ok())
-        {
-            Some(h) if h == format!("Bearer {}", token.as_str()) => {}
-            _ => {
-                let mut r = Response::new(Full::new(Bytes::from("unauthorized")));
-                *r.status_mut() = StatusCode::UNAUTHORIZED;
-                return Ok(r);
-            }
+    if let Err(retry) = state.limiter.check_ip(remote) {
+        warn!(%remote, "rate limit exceeded");
+        return Ok(ApiError::limited("rate_limited", "too many requests", retry).response());
+    }
+    let (parts, body) = req.into_parts();
+    let body = match read_body(body, state.max_body, state.body_timeout).await? {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(%remote, "{}", e.message);
+            return Ok(e.response());
         }
+    };
+    let req = Request::from_parts(parts, body);
+    let path = req.uri().path();
+    if let Some(operation) = path.strip_prefix("/.well-known/est/") {
+        let operation = operation.to_owned();
+        // EST only trusts certificates it could have issued itself
+        let peer = peer.as_deref().filter(|p| state.mtls().issued_here(p));
+        return est::handle(req, &operation, peer, &state.est).await;
+    }
+    if path == state.ocsp_path || path.starts_with(&format!("{}/", state.ocsp_path)) {
+        return handle_ocsp(req, state).await;
+    }
+    if path == state.tsa_path {
+        return handle_tsa(req, state).await;
+    }
+    if path == state.scep_path {
+        return scep::handle(req, &state.scep(), state.scep_days, &state.limiter).await;
+    }
+    if path.starts_with(&format!("{}/", state.acme_path)) {
+        return acme::handle(req, state.acme.clone(), &state.acme_path).await;
+    }
+    rest::dispatch(req, state, peer).await
+}
+
+/// Collects a request body of at most `max` bytes that arrives within `timeout`. Errors of
+/// the connection itself are passed on.
+async fn read_body<B>(
+    body: B,
+    max: usize,
+    timeout: Duration,
+) -> std::result::Result<std::result::Result<Bytes, ApiError>, B::Error>
+where
+    B: Body,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    match tokio::time::timeout(timeout, Limited::new(body, max).collect()).await {
+        Ok(Ok(body)) => Ok(Ok(body.to_bytes())),
+        Ok(Err(e)) => match e.downcast::<B::Error>() {
+            Ok(e) => Err(*e),
+            Err(_) => Ok(Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                format!("request body exceeds {max} bytes"),
+            ))),
+        },
+        Err(_) => Ok(Err(ApiError::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "request_timeout",
+            "request body not received in time",
+        ))),
+    }
+}
+
+fn reply(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(body.into()));
+    *resp.status_mut() = status;
+    resp
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Full<Bytes>> {
+    let mut resp = reply(status, serde_json::to_vec(value).unwrap_or_default());
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    resp
+}
 
-        let body = req.collect().await?.to_bytes();
-        let data: CertRequest = match serde_json::from_slice(&body) {
-            Ok(d) => d,
-            Err(_) => {
-                let mut resp = Response::new(Full::new(Bytes::from("bad request")));
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
+/// Maps a bearer token or a verified client certificate to an API identity. The
+/// `--token` secret stands for an unrestricted identity with every permission.
+fn identify(
+    req: &Request<Bytes>,
+    state: &AppState,
+    peer: Option<&CertificateDer<'static>>,
+) -> Option<Identity> {
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if let Some(bearer) = bearer {
+        if let Some(hash) = &state.token_hash
+            && token::constant_time_eq(&Sha256::digest(bearer.as_bytes()), hash)
+        {
+            return Some(Identity {
+                name: "token".into(),
+                permissions: Permission::value_variants().to_vec(),
+                scope: Scope::default(),
+                limits: Limits::default(),
+            });
+        }
+        let tokens = match fs::read_api_tokens() {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                error!("API token lookup failed: {}", e);
+                return None;
             }
         };
-        let days = match data.days {
-            Some(d) => d,
+        return match token::authenticate(bearer, &tokens, Utc::now()) {
+            Some(t) => Some(Identity {
+                name: format!("token {} ({})", t.id, t.name),
+                permissions: t.permissions.clone(),
+                scope: t.scope.clone(),
+                limits: t.limits,
+            }),
             None => {
-                let mut resp = Response::new(Full::new(Bytes::from("missing days")));
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
+                warn!("unknown, expired or revoked API token");
+                None
             }
         };
-        let args = crate::cmd::sign_cert::SignCertArgs {
-            cn: data.cn,
-            days,
-            san: vec![],
-        };
-        if let Err(e) = args.run(false) {
+    }
+    match state.mtls().identify(peer?) {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("client certificate not mapped: {}", e);
+            None
+        }
+    }
+}
+
+/// Rejects requests without an identity (401) or without `permission` (403).
+fn authorize(
+    identity: Option<&Identity>,
+    permission: Permission,
+) -> std::result::Result<&Identity, ApiError> {
+    match identity {
+        None => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "a valid API token or client certificate is required",
+        )),
+        Some(id) if !id.may(permission) => {
+            warn!(identity = %id.name, ?permission, "permission denied");
+            Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                format!("the {} permission is required", permission.name()),
+            ))
+        }
+        Some(id) => Ok(id),
+    }
+}
+
+/// Rejects empty subject names, names with whitespace or control characters, and common
+/// names that cannot name a file in the certificate directory.
+fn check_names(cn: &str, san: &[String]) -> Result<()> {
+    fs::check_cert_name(cn)?;
+    match std::iter::once(cn)
+        .chain(san.iter().map(String::as_str))
+        .find(|n| n.is_empty() || n.contains(|c: char| c.is_whitespace() || c.is_control()))
+    {
+        Some(name) => Err(Error::Other(format!("invalid name {name:?}"))),
+        None => Ok(()),
+    }
+}
+
+/// Whose issuance quota a certificate counts against.
+struct Requester<'a> {
+    name: &'a str,
+    /// Replaces `--quota-identity`, as a token's own quota does
+    quota: Option<u32>,
+}
+
+/// Issues a certificate for a network client; REST, ACME, EST and SCEP all go through here.
+/// The names count against the quotas of `requester` and of their registered domains
+/// first, and a failed issuance gives the reservation back.
+fn issue(
+    limiter: &Limiter,
+    requester: Requester<'_>,
+    cn: &str,
+    san: Vec<String>,
+    days: u32,
+    profile: Profile,
+    key: KeySource<'_>,
+) -> Result<std::result::Result<Issued, RetryAfter>> {
+    let names: Vec<&str> = std::iter::once(cn)
+        .chain(san.iter().map(String::as_str))
+        .collect();
+    let reservation = match limiter.reserve(requester.name, requester.quota, &names)? {
+        Ok(reservation) => reservation,
+        Err(retry) => {
+            warn!(requester = %requester.name, %cn, "issuance quota exceeded");
+            return Ok(Err(retry));
+        }
+    };
+    match sign_cert::issue(cn, san, days, profile, key) {
+        Ok(issued) => Ok(Ok(issued)),
+        Err(e) => {
+            if let Err(e) = limiter.release(reservation) {
+                error!("releasing the quota reservation failed: {}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Issues the certificate of a [`CertRequest`] within the identity's scope.
+fn sign(
+    body: &[u8],
+    identity: &Identity,
+    state: &AppState,
+) -> std::result::Result<Response<Full<Bytes>>, ApiError> {
+    let data: CertRequest = serde_json::from_slice(body).map_err(ApiError::malformed)?;
+    if data.days == 0 {
+        return Err(ApiError::invalid("days must be at least 1"));
+    }
+    let max_days = sign_cert::max_days().map_err(|e| {
+        error!("reading the root certificate failed: {}", e);
+        ApiError::internal()
+    })?;
+    if data.days > max_days {
+        return Err(ApiError::invalid(format!(
+            "days must be at most {max_days}, when the root CA expires"
+        )));
+    }
+    let csr = match &data.csr {
+        Some(_) if data.key_algorithm.is_some() => {
+            return Err(ApiError::invalid(
+                "key_algorithm cannot be combined with a csr",
+            ));
+        }
+        Some(csr) => Some(
+            est::parse_csr(csr.as_bytes())
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_csr", e.plain()))?,
+        ),
+        None => None,
+    };
+    let (cn, san) = match &csr {
+        Some((_, _, csr_cn, csr_names)) => (
+            data.cn.unwrap_or_else(|| csr_cn.clone()),
+            if data.san.is_empty() {
+                csr_names.clone()
+            } else {
+                data.san
+            },
+        ),
+        None => (
+            data.cn
+                .ok_or_else(|| ApiError::invalid("cn is required without a csr"))?,
+            data.san,
+        ),
+    };
+    check_names(&cn, &san).map_err(|e| ApiError::invalid(e.plain()))?;
+    let names: Vec<&str> = std::iter::once(cn.as_str())
+        .chain(san.iter().map(String::as_str))
+        .collect();
+    if let Err(e) = identity.scope.check(&names, data.profile, data.days) {
+        warn!(identity = %identity.name, "{}", e);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "out_of_scope",
+            e.plain(),
+        ));
+    }
+    let key = match &csr {
+        Some((spki, csr, _, _)) => {
+            revocation::ensure_key_allowed(spki)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "key_blocked", e.plain()))?;
+            KeySource::Csr(csr)
+        }
+        None => KeySource::Generate(data.key_algorithm.unwrap_or_default()),
+    };
+
+    info!(identity = %identity.name, %cn, "certificate requested via API");
+    let requester = Requester {
+        name: &identity.name,
+        quota: identity.limits.quota,
+    };
+    let (issued, pem, key_pem) = match issue(
+        &state.limiter,
+        requester,
+        &cn,
+        san,
+        data.days,
+        data.profile,
+        key,
+    ) {
+        Ok(Ok(issued)) => issued,
+        Ok(Err(retry)) => {
+            return Err(ApiError::limited(
+                "quota_exceeded",
+                "certificate quota exceeded",
+                retry,
+            ));
+        }
+        Err(e) => {
             error!("cert signing failed: {}", e);
-            let mut resp = Response::new(Full::new(Bytes::from("error")));
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return Ok(resp);
+            return Err(ApiError::internal());
         }
-        Ok(Response::new(Full::new(Bytes::from("ok"))))
+    };
+    let root = fs::read_root_cert().map_err(|e| {
+        error!("reading the root certificate failed: {}", e);
+        ApiError::internal()
+    })?;
+    if let Err(e) = audit::emit(
+        "sign-cert",
+        &format!("{cn} ({}) by {}", issued.serial, identity.name),
+        false,
+    ) {
+        error!("audit failed: {}", e);
+    }
+    Ok(json(
+        StatusCode::CREATED,
+        &CertResponse {
+            serial: &issued.serial,
+            certificate: &pem,
+            chain: vec![root],
+            not_before: issued.not_before,
+            not_after: issued.not_after,
+            private_key: key_pem.as_deref().map(|k| k.as_str()),
+        },
+    ))
+}
+
+/// Revokes a certificate either for an identity with the revoke permission or for a
+/// holder who signed the request with the certificate's private key.
+fn revoke(
+    body: &[u8],
+    identity: Option<&Identity>,
+    state: &AppState,
+) -> std::result::Result<Response<Full<Bytes>>, ApiError> {
+    let admin = identity.filter(|id| id.may(Permission::Revoke));
+    let data: RevokeRequest = serde_json::from_slice(body).map_err(ApiError::malformed)?;
+    let reason = data.reason.unwrap_or(Reason::Unspecified);
+    let result = if admin.is_some() {
+        revocation::revoke(&data.serial, reason, data.invalidity_date)
     } else {
-        let mut resp = Response::new(Full::new(Bytes::from("not found")));
-        *resp.status_mut() = StatusCode::NOT_FOUND;
-        Ok(resp)
+        let (Some(timestamp), Some(signature)) = (&data.timestamp, &data.signature) else {
+            return Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "revocation needs the revoke permission or a holder signature",
+            ));
+        };
+        let signature = BASE64
+            .decode(signature)
+            .map_err(|_| ApiError::invalid("signature is not base64"))?;
+        revocation::revoke_self_service(&data.serial, reason, timestamp, &signature)
+    };
+    let entry = result.map_err(|e| {
+        error!("revocation refused: {}", e);
+        ApiError::new(StatusCode::BAD_REQUEST, "revocation_refused", e.plain())
+    })?;
+    Ok(revoked(
+        &entry,
+        admin.map_or("holder", |id| &id.name),
+        state,
+    ))
+}
+
+/// Records a revocation made through the API and regenerates the CRL.
+fn revoked(entry: &Revocation, by: &str, state: &AppState) -> Response<Full<Bytes>> {
+    info!(serial = %entry.serial, %by, "certificate revoked via API");
+    let record = serde_json::to_string(&entry).unwrap_or_default();
+    if let Err(e) = audit::emit("revoke", &record, false) {
+        error!("audit failed: {}", e);
+    }
+    let crl = crate::cmd::crl::CrlArgs {
+        days: state.crl_days,
+        delta: false,
+    };
+    if let Err(e) = crl.run(false) {
+        error!("CRL regeneration failed: {}", e);
     }
+    json(StatusCode::OK, entry)
+}
+
+async fn handle_ocsp(
+    req: Request<Bytes>,
+    state: Arc<AppState>,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    let der = match *req.method() {
+        Method::POST => Some(req.into_body().to_vec()),
+        Method::GET => req
+            .uri()
+            .path()
+            .strip_prefix(&format!("{}/", state.ocsp_path))
+            .map(percent_decode)
+            .and_then(|b64| BASE64.decode(b64).ok()),
+        _ => None,
+    };
+    let body = match der.map(|d| ocsp::parse_request(&d)) {
+        Some(Ok(request)) => match lookup_statuses() {
+            Ok(statuses) => state
+                .ocsp()
+                .respond(&request, |serial| match statuses.get(serial) {
+                    Some(status) => status.clone(),
+                    None => CertStatus::Unknown,
+                })
+                .unwrap_or_else(|e| {
+                    error!("OCSP signing failed: {}", e);
+                    ocsp::error_response(ocsp::INTERNAL_ERROR)
+                }),
+            Err(e) => {
+                error!("OCSP lookup failed: {}", e);
+                ocsp::error_response(ocsp::INTERNAL_ERROR)
+            }
+        },
+        Some(Err(e)) => {
+            error!("bad OCSP request: {}", e);
+            ocsp::error_response(ocsp::MALFORMED_REQUEST)
+        }
+        None => ocsp::error_response(ocsp::MALFORMED_REQUEST),
+    };
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/ocsp-response"),
+    );
+    Ok(resp)
+}
+
+/// Answers RFC 3161 time-stamp queries posted as `application/timestamp-query`.
+async fn handle_tsa(
+    req: Request<Bytes>,
+    state: Arc<AppState>,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    if req.method() != Method::POST {
+        return Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+    }
+    let body = req.into_body();
+    let mut resp = Response::new(Full::new(Bytes::from(state.tsa().respond(&body))));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/timestamp-reply"),
+    );
+    Ok(resp)
+}
+
+/// Status of every issued certificate, keyed by canonical serial.
+fn lookup_statuses() -> Result<HashMap<String, CertStatus>> {
+    let mut statuses: HashMap<String, CertStatus> = fs::read_issued()?
+        .into_iter()
+        .map(|c| (c.serial, CertStatus::Good))
+        .collect();
+    for entry in revocation::current()? {
+        statuses.insert(
+            entry.serial,
+            CertStatus::Revoked {
+                at: entry.revoked_at,
+                reason: entry.reason,
+            },
+        );
+    }
+    Ok(statuses)
+}
+
+/// Decodes the `%XX` escapes clients use for base64 in OCSP GET URLs.
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(&[hi, lo]) = bytes.get(i + 1..i + 3)
+            && let (Some(hi), Some(lo)) = ((hi as char).to_digit(16), (lo as char).to_digit(16))
+        {
+            out.push((hi * 16 + lo) as u8);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
@@ -172,3 +1098,228 @@ fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
         None => Err(Error::Other("no key found".into())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::sync::Once;
+
+    /// Creates the root CA of the scratch data directory that handler tests issue from.
+    pub(super) fn ca() {
+        static ROOT: Once = Once::new();
+        ROOT.call_once(|| {
+            if fs::read_root_ca().is_ok() {
+                return;
+            }
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "Hypatia Test Root");
+            let cert = params.self_signed(&key).unwrap();
+            fs::write_root_ca(&cert.pem(), &key.serialize_pem(), true).unwrap();
+        });
+    }
+
+    /// A limiter with only an identity quota, for identities no other test uses.
+    pub(super) fn limiter(identity_quota: Option<u32>) -> Arc<Limiter> {
+        let config = ratelimit::Config {
+            per_ip: 0,
+            per_identity: 0,
+            identity_quota,
+            domain_quota: None,
+            window: chrono::Duration::hours(1),
+        };
+        Arc::new(Limiter::load(config).unwrap())
+    }
+
+    #[test]
+    fn issuance_reserves_quota_and_releases_it_on_failure() {
+        ca();
+        let limiter = limiter(Some(1));
+        let cn = format!("rest-{:016x}.test", rand::random::<u64>());
+        let requester = || Requester {
+            name: &cn,
+            quota: None,
+        };
+        let key = || KeySource::Generate(KeyAlgorithm::EcdsaP256);
+
+        // refused by the certificate name policy after the quota was reserved
+        let failed = issue(
+            &limiter,
+            requester(),
+            "a/b",
+            vec![],
+            30,
+            Profile::Default,
+            key(),
+        );
+        assert!(failed.is_err());
+        let issued = issue(
+            &limiter,
+            requester(),
+            &cn,
+            vec![],
+            30,
+            Profile::Default,
+            key(),
+        );
+        assert!(matches!(issued, Ok(Ok(_))));
+        let again = issue(
+            &limiter,
+            requester(),
+            &cn,
+            vec![],
+            30,
+            Profile::Default,
+            key(),
+        );
+        assert!(matches!(again, Ok(Err(RetryAfter(_)))));
+
+        let quota = Requester {
+            name: &cn,
+            quota: Some(2),
+        };
+        let own_quota = issue(&limiter, quota, &cn, vec![], 30, Profile::Default, key());
+        assert!(matches!(own_quota, Ok(Ok(_))));
+    }
+
+    /// A body whose client never sends anything.
+    struct Silent;
+
+    impl Body for Silent {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<std::result::Result<hyper::body::Frame<Bytes>, Self::Error>>>
+        {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[test]
+    fn bodies_are_limited_in_size_and_time() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let second = Duration::from_secs(1);
+        let body = Full::new(Bytes::from_static(b"0123456789"));
+        let read = rt.block_on(read_body(body, 10, second)).unwrap();
+        assert_eq!(read.ok().unwrap(), &b"0123456789"[..]);
+
+        let body = Full::new(Bytes::from_static(b"0123456789x"));
+        let read = rt.block_on(read_body(body, 10, second)).unwrap();
+        assert_eq!(read.err().unwrap().status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let read = rt
+            .block_on(read_body(Silent, 10, Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(read.err().unwrap().status, StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn percent_decode_tolerates_bad_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%é%4"), "%é%4");
+        assert_eq!(percent_decode("%zz%"), "%zz%");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn certificates_end_within_the_ca() {
+        ca();
+        let limiter = limiter(None);
+        let cn = format!("days-{:016x}.test", rand::random::<u64>());
+        let issue = |days| {
+            issue(
+                &limiter,
+                Requester {
+                    name: &cn,
+                    quota: None,
+                },
+                &cn,
+                vec![],
+                days,
+                Profile::Default,
+                KeySource::Generate(KeyAlgorithm::EcdsaP256),
+            )
+        };
+        let max = sign_cert::max_days().unwrap();
+        assert!(issue(u32::MAX).is_err());
+        assert!(issue(max + 1).is_err());
+        assert!(matches!(issue(max), Ok(Ok(_))));
+    }
+
+    #[test]
+    fn concurrent_revocations_and_crls_do_not_interleave() {
+        use crate::cmd::Runnable;
+        ca();
+        let cn = format!("revoke-{:016x}.test", rand::random::<u64>());
+        let key = KeySource::Generate(KeyAlgorithm::EcdsaP256);
+        let (issued, _, _) = sign_cert::issue(&cn, vec![], 30, Profile::Default, key).unwrap();
+        let before = fs::read_crl_state().unwrap().last_number;
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let serial = issued.serial.clone();
+                std::thread::spawn(move || {
+                    let revoked = revocation::revoke(&serial, Reason::Superseded, None);
+                    let crl = crate::cmd::crl::CrlArgs {
+                        days: 1,
+                        delta: false,
+                    };
+                    crl.run(false).unwrap();
+                    revoked.is_ok()
+                })
+            })
+            .collect();
+        let revoked = threads.into_iter().map(|t| t.join().unwrap());
+        let revoked = revoked.filter(|ok| *ok).count();
+        assert_eq!(revoked, 1);
+        let entries = fs::read_revocations().unwrap();
+        assert_eq!(
+            entries.iter().filter(|e| e.serial == issued.serial).count(),
+            1
+        );
+        // other tests may issue CRLs too, but none of these eight may reuse a number
+        assert!(fs::read_crl_state().unwrap().last_number >= before + 8);
+    }
+
+    /// A self-signed certificate and key that expire within the hour.
+    fn expiring_signer() -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.not_after = time::OffsetDateTime::now_utc() + time::Duration::hours(1);
+        (params.self_signed(&key).unwrap().pem(), key.serialize_pem())
+    }
+
+    #[test]
+    fn signers_are_renewed_before_they_expire() {
+        ca();
+        let (cert, key) = expiring_signer();
+        fs::write_ocsp_signer(&cert, &key).unwrap();
+        // replaces the expiring signer on disk with one that expires right away
+        let ocsp = Responder::load(1, 0).unwrap();
+        let renewed = ocsp.renew(1, 30).unwrap().unwrap();
+        assert!(renewed.renew(1, 30).unwrap().is_none());
+
+        let (cert, key) = expiring_signer();
+        fs::write_tsa_signer(&cert, &key).unwrap();
+        let tsa = Authority::load(0, tsp::DEFAULT_POLICY).unwrap();
+        let renewed = tsa.renew(30).unwrap().unwrap();
+        assert!(renewed.renew(30).unwrap().is_none());
+    }
+
+    #[test]
+    fn requested_names_must_be_safe() {
+        let san = vec!["www.example.com".to_owned()];
+        assert!(check_names("example.com", &san).is_ok());
+        for cn in ["", "../etc/passwd", "a/b", "a\\b", "bad name", "nul\0"] {
+            assert!(check_names(cn, &san).is_err(), "{cn:?}");
+        }
+        assert!(check_names("example.com", &["two words".to_owned()]).is_err());
+    }
+}
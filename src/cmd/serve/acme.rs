@@ -0,0 +1,1575 @@
+use crate::cmd::Runnable;
+use crate::cmd::sign_cert::KeySource;
+use crate::error::{Error, Result};
+use crate::util::crl::Reason;
+use crate::util::jose::{self, Jwk, Jws, KeyRef};
+use crate::util::profile::Profile;
+use crate::util::ratelimit::{Limiter, RetryAfter};
+use crate::util::{audit, dns, fs, revocation, x509};
+use bytes::Bytes;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::header::{self, HeaderValue};
+use hyper::http::StatusCode;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use rcgen::{CertificateSigningRequestParams, PublicKeyData};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tracing::{debug, error, info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{Requester, issue};
+
+const ERROR_NS: &str = "urn:ietf:params:acme:error:";
+/// Outstanding nonces kept before the oldest are forgotten.
+const MAX_NONCES: usize = 10_000;
+/// Days pending orders and authorizations stay usable.
+const LIFETIME_DAYS: i64 = 7;
+const VALIDATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_CHALLENGE_RESPONSE: usize = 8 * 1024;
+
+pub struct Config {
+    /// Validity of issued certificates in days
+    pub days: u32,
+    /// Domain suffixes certificates may be issued for; any when empty
+    pub domains: Vec<String>,
+    /// host:port http-01 validation connects to instead of port 80 of the identifier
+    pub http_addr: Option<String>,
+    /// Resolver for dns-01 validation; the system resolver when unset
+    pub dns_server: Option<SocketAddr>,
+    /// Days until nextUpdate of CRLs regenerated after revocations
+    pub crl_days: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Deactivated,
+    Expired,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Identifier {
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Account {
+    status: Status,
+    contact: Vec<String>,
+    jwk: Value,
+    thumbprint: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Order {
+    account: String,
+    status: Status,
+    expires: DateTime<Utc>,
+    identifiers: Vec<Identifier>,
+    authorizations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Authorization {
+    account: String,
+    identifier: Identifier,
+    wildcard: bool,
+    status: Status,
+    expires: DateTime<Utc>,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    token: String,
+    status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    validated: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+/// Accounts, orders and authorizations, persisted as one JSON document.
+#[derive(Default, Serialize, Deserialize)]
+struct Store {
+    accounts: BTreeMap<String, Account>,
+    orders: BTreeMap<String, Order>,
+    authorizations: BTreeMap<String, Authorization>,
+}
+
+impl Store {
+    fn save(&self) -> std::result::Result<(), Problem> {
+        fs::write_acme_state(self).map_err(Problem::internal)
+    }
+
+    /// Order status after taking expiry and the state of its authorizations into account.
+    fn refresh_order(&mut self, id: &str) {
+        let Some(order) = self.orders.get(id) else {
+            return;
+        };
+        if order.status != Status::Pending {
+            return;
+        }
+        let now = Utc::now();
+        let statuses: Vec<Status> = order
+            .authorizations
+            .iter()
+            .map(|a| {
+                self.authorizations
+                    .get(a)
+                    .map_or(Status::Invalid, |a| authz_status(a, now))
+            })
+            .collect();
+        let status = if order.expires < now
+            || statuses
+                .iter()
+                .any(|s| *s != Status::Pending && *s != Status::Valid)
+        {
+            Status::Invalid
+        } else if statuses.iter().all(|s| *s == Status::Valid) {
+            Status::Ready
+        } else {
+            Status::Pending
+        };
+        if let Some(order) = self.orders.get_mut(id) {
+            order.status = status;
+        }
+    }
+
+    fn owns_certificate(&self, account: &str, serial: &str) -> bool {
+        self.orders
+            .values()
+            .any(|o| o.account == account && o.certificate.as_deref() == Some(serial))
+    }
+}
+
+fn authz_status(authz: &Authorization, now: DateTime<Utc>) -> Status {
+    if authz.status == Status::Pending && authz.expires < now {
+        Status::Expired
+    } else {
+        authz.status
+    }
+}
+
+/// ACME error document (RFC 7807 problem details with the ACME error namespace).
+#[derive(Debug)]
+struct Problem {
+    kind: &'static str,
+    status: StatusCode,
+    detail: String,
+    location: Option<String>,
+    retry_after: Option<RetryAfter>,
+}
+
+impl Problem {
+    fn new(kind: &'static str, status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            status,
+            detail: detail.into(),
+            location: None,
+            retry_after: None,
+        }
+    }
+
+    fn malformed(detail: impl Into<String>) -> Self {
+        Self::new("malformed", StatusCode::BAD_REQUEST, detail)
+    }
+
+    fn unauthorized(detail: impl Into<String>) -> Self {
+        Self::new("unauthorized", StatusCode::FORBIDDEN, detail)
+    }
+
+    fn not_found() -> Self {
+        Self::new("malformed", StatusCode::NOT_FOUND, "no such resource")
+    }
+
+    fn internal(e: Error) -> Self {
+        error!("ACME request failed: {}", e);
+        Self::new(
+            "serverInternal",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error",
+        )
+    }
+
+    fn to_json(&self) -> Value {
+        let mut doc = json!({
+            "type": format!("{ERROR_NS}{}", self.kind),
+            "detail": self.detail,
+            "status": self.status.as_u16(),
+        });
+        if self.kind == "badSignatureAlgorithm" {
+            doc["algorithms"] = json!(jose::ALGORITHMS);
+        }
+        doc
+    }
+
+    fn into_reply(self) -> Reply {
+        let mut reply = Reply::json(self.status, &self.to_json());
+        reply.content_type = "application/problem+json";
+        reply.location = self.location;
+        reply.retry_after = self.retry_after;
+        reply
+    }
+}
+
+type Outcome = std::result::Result<Reply, Problem>;
+
+struct Reply {
+    status: StatusCode,
+    content_type: &'static str,
+    location: Option<String>,
+    link: Option<String>,
+    retry_after: Option<RetryAfter>,
+    body: Bytes,
+}
+
+impl Reply {
+    fn empty(status: StatusCode) -> Self {
+        Self {
+            status,
+            content_type: "",
+            location: None,
+            link: None,
+            retry_after: None,
+            body: Bytes::new(),
+        }
+    }
+
+    fn json(status: StatusCode, value: &Value) -> Self {
+        Self {
+            content_type: "application/json",
+            body: Bytes::from(value.to_string()),
+            ..Self::empty(status)
+        }
+    }
+
+    fn at(mut self, location: String) -> Self {
+        self.location = Some(location);
+        self
+    }
+}
+
+/// A verified request: the JWS and the key it was signed with, plus the account for `kid`.
+struct Signed {
+    jws: Jws,
+    key: Jwk,
+    account: Option<(String, Account)>,
+}
+
+impl Signed {
+    fn account_id(&self) -> std::result::Result<&str, Problem> {
+        self.account
+            .as_ref()
+            .map(|(id, _)| id.as_str())
+            .ok_or_else(|| Problem::malformed("request must be signed by an account key (kid)"))
+    }
+
+    fn payload<T: for<'de> Deserialize<'de>>(&self) -> std::result::Result<T, Problem> {
+        self.jws.json().map_err(|e| Problem::malformed(e.plain()))
+    }
+}
+
+#[derive(Default)]
+struct Nonces {
+    issued: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+/// ACME (RFC 8555) server state: the persistent store plus outstanding anti-replay nonces.
+pub struct Server {
+    config: Config,
+    /// Issuance quotas, shared with the other enrollment protocols
+    limiter: Arc<Limiter>,
+    store: Mutex<Store>,
+    nonces: Mutex<Nonces>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewAccount {
+    #[serde(default)]
+    contact: Vec<String>,
+    #[serde(default)]
+    only_return_existing: bool,
+}
+
+#[derive(Deserialize)]
+struct AccountUpdate {
+    contact: Option<Vec<String>>,
+    status: Option<Status>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewOrder {
+    identifiers: Vec<Identifier>,
+    not_before: Option<String>,
+    not_after: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusUpdate {
+    status: Option<Status>,
+}
+
+#[derive(Deserialize)]
+struct Finalize {
+    csr: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyChange {
+    account: String,
+    old_key: Value,
+}
+
+#[derive(Deserialize)]
+struct RevokeCert {
+    certificate: String,
+    reason: Option<u8>,
+}
+
+impl Server {
+    pub fn load(config: Config, limiter: Arc<Limiter>) -> Result<Self> {
+        let store: Store = fs::read_acme_state()?;
+        debug!(
+            accounts = store.accounts.len(),
+            orders = store.orders.len(),
+            "loaded ACME state"
+        );
+        Ok(Self {
+            config,
+            limiter,
+            store: Mutex::new(store),
+            nonces: Mutex::new(Nonces::default()),
+        })
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn new_nonce(&self) -> String {
+        let nonce = random_id();
+        let mut nonces = self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        if nonces.order.len() >= MAX_NONCES
+            && let Some(old) = nonces.order.pop_front()
+        {
+            nonces.issued.remove(&old);
+        }
+        nonces.issued.insert(nonce.clone());
+        nonces.order.push_back(nonce.clone());
+        nonce
+    }
+
+    fn consume_nonce(&self, nonce: &str) -> bool {
+        let mut nonces = self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        nonces.issued.remove(nonce)
+    }
+
+    fn dispatch(
+        self: &Arc<Self>,
+        method: &Method,
+        base: &str,
+        route: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Outcome {
+        let segments: Vec<&str> = route.trim_start_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["directory"]) => Ok(Reply::json(StatusCode::OK, &directory(base))),
+            (&Method::HEAD, ["new-nonce"]) => Ok(Reply::empty(StatusCode::OK)),
+            (&Method::GET, ["new-nonce"]) => Ok(Reply::empty(StatusCode::NO_CONTENT)),
+            (&Method::POST, _) => {
+                let signed = self.authenticate(base, route, content_type, body)?;
+                match segments.as_slice() {
+                    ["new-account"] => self.new_account(base, signed),
+                    ["account", id] => self.account(base, id, signed),
+                    ["account", id, "orders"] => self.account_orders(base, id, signed),
+                    ["new-order"] => self.new_order(base, signed),
+                    ["order", id] => self.order(base, id, signed),
+                    ["order", id, "finalize"] => self.finalize(base, id, signed),
+                    ["authz", id] => self.authorization(base, id, signed),
+                    ["chall", id, kind] => self.challenge(base, id, kind, signed),
+                    ["cert", serial] => self.certificate(serial, signed),
+                    ["key-change"] => self.key_change(base, signed),
+                    ["revoke-cert"] => self.revoke_cert(signed),
+                    _ => Err(Problem::not_found()),
+                }
+            }
+            (_, ["directory" | "new-nonce"]) => Err(Problem::new(
+                "malformed",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed",
+            )),
+            _ => Err(Problem::new(
+                "malformed",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "ACME resources are fetched with POST-as-GET",
+            )),
+        }
+    }
+
+    /// Checks media type, nonce, URL and signature of a POST as RFC 8555 section 6 requires.
+    fn authenticate(
+        &self,
+        base: &str,
+        route: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> std::result::Result<Signed, Problem> {
+        if content_type != Some("application/jose+json") {
+            return Err(Problem::new(
+                "malformed",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "requests must be application/jose+json",
+            ));
+        }
+        let jws = Jws::parse(body).map_err(|e| Problem::malformed(e.plain()))?;
+        if !jose::ALGORITHMS.contains(&jws.alg.as_str()) {
+            return Err(Problem::new(
+                "badSignatureAlgorithm",
+                StatusCode::BAD_REQUEST,
+                format!("algorithm {} is not supported", jws.alg),
+            ));
+        }
+        if !jws.nonce.as_deref().is_some_and(|n| self.consume_nonce(n)) {
+            return Err(Problem::new(
+                "badNonce",
+                StatusCode::BAD_REQUEST,
+                "missing, reused or unknown nonce",
+            ));
+        }
+        if jws.url != format!("{base}{route}") {
+            return Err(Problem::new(
+                "unauthorized",
+                StatusCode::UNAUTHORIZED,
+                "JWS url does not match the request URL",
+            ));
+        }
+        let (key, account) = match &jws.key {
+            KeyRef::Jwk(jwk) => (jwk.clone(), None),
+            KeyRef::Kid(kid) => {
+                let id = kid.strip_prefix(&format!("{base}/account/"));
+                let account = id.and_then(|id| self.store().accounts.get(id).cloned());
+                let (Some(id), Some(account)) = (id, account) else {
+                    return Err(Problem::new(
+                        "accountDoesNotExist",
+                        StatusCode::BAD_REQUEST,
+                        format!("no account {kid}"),
+                    ));
+                };
+                if account.status != Status::Valid {
+                    return Err(Problem::unauthorized("account is deactivated"));
+                }
+                let jwk = Jwk::from_json(&account.jwk).map_err(Problem::internal)?;
+                (jwk, Some((id.to_owned(), account)))
+            }
+        };
+        jws.verify(&key)
+            .map_err(|e| Problem::malformed(e.plain()))?;
+        Ok(Signed { jws, key, account })
+    }
+
+    fn new_account(&self, base: &str, signed: Signed) -> Outcome {
+        if signed.account.is_some() {
+            return Err(Problem::malformed("newAccount must be signed with a jwk"));
+        }
+        let req: NewAccount = signed.payload()?;
+        let thumbprint = signed.key.thumbprint();
+        let mut store = self.store();
+        if let Some((id, account)) = store
+            .accounts
+            .iter()
+            .find(|(_, a)| a.thumbprint == thumbprint)
+        {
+            let url = format!("{base}/account/{id}");
+            return Ok(Reply::json(StatusCode::OK, &account_json(base, id, account)).at(url));
+        }
+        if req.only_return_existing {
+            return Err(Problem::new(
+                "accountDoesNotExist",
+                StatusCode::BAD_REQUEST,
+                "no account exists for this key",
+            ));
+        }
+        check_contacts(&req.contact)?;
+
+        let id = random_id();
+        let account = Account {
+            status: Status::Valid,
+            contact: req.contact,
+            jwk: signed.key.to_json(),
+            thumbprint: thumbprint.clone(),
+            created_at: Utc::now(),
+        };
+        store.accounts.insert(id.clone(), account.clone());
+        store.save()?;
+        drop(store);
+
+        info!(account = %id, %thumbprint, "ACME account created");
+        if let Err(e) = audit::emit("acme-account", &format!("{id} {thumbprint}"), false) {
+            error!("audit failed: {}", e);
+        }
+        let url = format!("{base}/account/{id}");
+        Ok(Reply::json(StatusCode::CREATED, &account_json(base, &id, &account)).at(url))
+    }
+
+    fn account(&self, base: &str, id: &str, signed: Signed) -> Outcome {
+        if signed.account_id()? != id {
+            return Err(Problem::unauthorized(
+                "the account URL does not match the kid",
+            ));
+        }
+        let mut store = self.store();
+        let account = store.accounts.get_mut(id).ok_or_else(Problem::not_found)?;
+        if !signed.jws.is_post_as_get() {
+            let update: AccountUpdate = signed.payload()?;
+            if let Some(contact) = update.contact {
+                check_contacts(&contact)?;
+                account.contact = contact;
+            }
+            match update.status {
+                None | Some(Status::Valid) => {}
+                Some(Status::Deactivated) => {
+                    account.status = Status::Deactivated;
+                    info!(account = %id, "ACME account deactivated");
+                }
+                Some(_) => return Err(Problem::malformed("accounts can only be deactivated")),
+            }
+            let account = account.clone();
+            store.save()?;
+            return Ok(Reply::json(
+                StatusCode::OK,
+                &account_json(base, id, &account),
+            ));
+        }
+        Ok(Reply::json(
+            StatusCode::OK,
+            &account_json(base, id, account),
+        ))
+    }
+
+    fn account_orders(&self, base: &str, id: &str, signed: Signed) -> Outcome {
+        if signed.account_id()? != id {
+            return Err(Problem::unauthorized(
+                "the account URL does not match the kid",
+            ));
+        }
+        let orders: Vec<String> = self
+            .store()
+            .orders
+            .iter()
+            .filter(|(_, o)| o.account == id)
+            .map(|(order, _)| format!("{base}/order/{order}"))
+            .collect();
+        Ok(Reply::json(StatusCode::OK, &json!({ "orders": orders })))
+    }
+
+    fn new_order(&self, base: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?.to_owned();
+        let req: NewOrder = signed.payload()?;
+        if req.not_before.is_some() || req.not_after.is_some() {
+            return Err(Problem::malformed(
+                "notBefore and notAfter are not supported; validity is fixed by the CA",
+            ));
+        }
+        if req.identifiers.is_empty() {
+            return Err(Problem::malformed("an order needs at least one identifier"));
+        }
+        let mut identifiers: Vec<Identifier> = Vec::new();
+        for identifier in req.identifiers {
+            let identifier = self.check_identifier(identifier)?;
+            if !identifiers.contains(&identifier) {
+                identifiers.push(identifier);
+            }
+        }
+
+        let expires = Utc::now() + Duration::days(LIFETIME_DAYS);
+        let mut store = self.store();
+        let mut authorizations = Vec::new();
+        for identifier in &identifiers {
+            let (value, wildcard) = match identifier.value.strip_prefix("*.") {
+                Some(base) => (base.to_owned(), true),
+                None => (identifier.value.clone(), false),
+            };
+            // http-01 cannot prove control over every name below a wildcard
+            let kinds: &[&str] = if wildcard {
+                &["dns-01"]
+            } else {
+                &["http-01", "dns-01"]
+            };
+            let challenges = kinds
+                .iter()
+                .map(|kind| Challenge {
+                    kind: (*kind).to_owned(),
+                    token: jose::b64url(&rand::random::<[u8; 32]>()),
+                    status: Status::Pending,
+                    validated: None,
+                    error: None,
+                })
+                .collect();
+            let id = random_id();
+            store.authorizations.insert(
+                id.clone(),
+                Authorization {
+                    account: account.clone(),
+                    identifier: Identifier {
+                        kind: "dns".into(),
+                        value,
+                    },
+                    wildcard,
+                    status: Status::Pending,
+                    expires,
+                    challenges,
+                },
+            );
+            authorizations.push(id);
+        }
+        let id = random_id();
+        let order = Order {
+            account: account.clone(),
+            status: Status::Pending,
+            expires,
+            identifiers,
+            authorizations,
+            certificate: None,
+            error: None,
+        };
+        store.orders.insert(id.clone(), order.clone());
+        store.save()?;
+        drop(store);
+
+        let names: Vec<&str> = order.identifiers.iter().map(|i| i.value.as_str()).collect();
+        info!(%account, order = %id, identifiers = %names.join(", "), "ACME order created");
+        let url = format!("{base}/order/{id}");
+        Ok(Reply::json(StatusCode::CREATED, &order_json(base, &id, &order)).at(url))
+    }
+
+    /// Normalizes a `dns` identifier and checks it against the syntax rules and domain policy.
+    fn check_identifier(&self, identifier: Identifier) -> std::result::Result<Identifier, Problem> {
+        if identifier.kind != "dns" {
+            return Err(Problem::new(
+                "unsupportedIdentifier",
+                StatusCode::BAD_REQUEST,
+                format!("identifier type {} is not supported", identifier.kind),
+            ));
+        }
+        let value = identifier.value.trim_end_matches('.').to_ascii_lowercase();
+        let name = value.strip_prefix("*.").unwrap_or(&value);
+        let valid = name.len() <= 253
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            });
+        if !valid {
+            return Err(Problem::new(
+                "rejectedIdentifier",
+                StatusCode::BAD_REQUEST,
+                format!("{} is not a valid domain name", identifier.value),
+            ));
+        }
+        let allowed = self.config.domains.is_empty()
+            || self.config.domains.iter().any(|suffix| {
+                let suffix = suffix.trim_matches('.').to_ascii_lowercase();
+                name == suffix || name.ends_with(&format!(".{suffix}"))
+            });
+        if !allowed {
+            return Err(Problem::new(
+                "rejectedIdentifier",
+                StatusCode::BAD_REQUEST,
+                format!("policy forbids issuing for {value}"),
+            ));
+        }
+        Ok(Identifier {
+            kind: "dns".into(),
+            value,
+        })
+    }
+
+    fn order(&self, base: &str, id: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?;
+        let mut store = self.store();
+        store.refresh_order(id);
+        let order = store.orders.get(id).ok_or_else(Problem::not_found)?;
+        if order.account != account {
+            return Err(Problem::unauthorized("order belongs to another account"));
+        }
+        Ok(Reply::json(StatusCode::OK, &order_json(base, id, order)))
+    }
+
+    fn authorization(&self, base: &str, id: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?;
+        let mut store = self.store();
+        let authz = store
+            .authorizations
+            .get_mut(id)
+            .ok_or_else(Problem::not_found)?;
+        if authz.account != account {
+            return Err(Problem::unauthorized(
+                "authorization belongs to another account",
+            ));
+        }
+        if !signed.jws.is_post_as_get() {
+            let update: StatusUpdate = signed.payload()?;
+            if update.status != Some(Status::Deactivated) {
+                return Err(Problem::malformed("authorizations can only be deactivated"));
+            }
+            if !matches!(authz.status, Status::Pending | Status::Valid) {
+                return Err(Problem::malformed(
+                    "only pending or valid authorizations can be deactivated",
+                ));
+            }
+            authz.status = Status::Deactivated;
+            let authz = authz.clone();
+            store.save()?;
+            info!(authz = %id, "ACME authorization deactivated");
+            return Ok(Reply::json(StatusCode::OK, &authz_json(base, id, &authz)));
+        }
+        Ok(Reply::json(StatusCode::OK, &authz_json(base, id, authz)))
+    }
+
+    /// Returns a challenge; a POST with a JSON object payload starts its validation.
+    fn challenge(self: &Arc<Self>, base: &str, id: &str, kind: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?;
+        let mut store = self.store();
+        let authz = store
+            .authorizations
+            .get_mut(id)
+            .ok_or_else(Problem::not_found)?;
+        if authz.account != account {
+            return Err(Problem::unauthorized(
+                "authorization belongs to another account",
+            ));
+        }
+        let pending = authz_status(authz, Utc::now()) == Status::Pending;
+        let challenge = authz
+            .challenges
+            .iter_mut()
+            .find(|c| c.kind == kind)
+            .ok_or_else(Problem::not_found)?;
+        if !signed.jws.is_post_as_get() && pending && challenge.status == Status::Pending {
+            challenge.status = Status::Processing;
+            let body = challenge_json(base, id, challenge);
+            store.save()?;
+            drop(store);
+            debug!(authz = %id, %kind, "validating ACME challenge");
+            tokio::spawn(self.clone().validate(id.to_owned(), kind.to_owned()));
+            return Ok(challenge_reply(base, id, &body));
+        }
+        Ok(challenge_reply(
+            base,
+            id,
+            &challenge_json(base, id, challenge),
+        ))
+    }
+
+    /// Runs one validation attempt and records the outcome on the challenge and authorization.
+    async fn validate(self: Arc<Self>, authz_id: String, kind: String) {
+        let lookup = {
+            let store = self.store();
+            store.authorizations.get(&authz_id).and_then(|authz| {
+                let token = authz
+                    .challenges
+                    .iter()
+                    .find(|c| c.kind == kind)?
+                    .token
+                    .clone();
+                let thumbprint = store.accounts.get(&authz.account)?.thumbprint.clone();
+                Some((authz.identifier.value.clone(), token, thumbprint))
+            })
+        };
+        let Some((domain, token, thumbprint)) = lookup else {
+            return;
+        };
+        let key_authorization = format!("{token}.{thumbprint}");
+        let result = match kind.as_str() {
+            "http-01" => self.check_http(&domain, &token, &key_authorization).await,
+            _ => self.check_dns(&domain, &key_authorization).await,
+        };
+        let server = self.clone();
+        let recorded = tokio::task::spawn_blocking(move || {
+            server.record_validation(&authz_id, &kind, &domain, result)
+        })
+        .await;
+        if let Err(e) = recorded {
+            error!("recording ACME validation result failed: {}", e);
+        }
+    }
+
+    fn record_validation(
+        &self,
+        authz_id: &str,
+        kind: &str,
+        domain: &str,
+        result: std::result::Result<(), Problem>,
+    ) {
+        let mut store = self.store();
+        let Some(authz) = store.authorizations.get_mut(authz_id) else {
+            return;
+        };
+        let Some(challenge) = authz.challenges.iter_mut().find(|c| c.kind == kind) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                challenge.status = Status::Valid;
+                challenge.validated = Some(Utc::now());
+                authz.status = Status::Valid;
+                info!(%domain, %kind, authz = %authz_id, "ACME challenge validated");
+            }
+            Err(problem) => {
+                warn!(%domain, %kind, authz = %authz_id, detail = %problem.detail, "ACME challenge failed");
+                challenge.status = Status::Invalid;
+                challenge.error = Some(problem.to_json());
+                authz.status = Status::Invalid;
+            }
+        }
+        if let Err(problem) = store.save() {
+            error!("saving ACME validation result failed: {}", problem.detail);
+        }
+    }
+
+    /// Fetches `http://<domain>/.well-known/acme-challenge/<token>` (RFC 8555 section 8.3).
+    async fn check_http(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> std::result::Result<(), Problem> {
+        let target = self
+            .config
+            .http_addr
+            .clone()
+            .unwrap_or_else(|| format!("{domain}:80"));
+        let path = format!("/.well-known/acme-challenge/{token}");
+        let connection =
+            |detail: String| Problem::new("connection", StatusCode::BAD_REQUEST, detail);
+        let (status, body) =
+            tokio::time::timeout(VALIDATION_TIMEOUT, http_get(&target, domain, &path))
+                .await
+                .map_err(|_| connection(format!("http://{domain}{path} timed out")))?
+                .map_err(|e| {
+                    connection(format!("fetching http://{domain}{path}: {}", e.plain()))
+                })?;
+        if status != StatusCode::OK {
+            return Err(Problem::new(
+                "incorrectResponse",
+                StatusCode::FORBIDDEN,
+                format!("http://{domain}{path} answered {status}"),
+            ));
+        }
+        if body.trim_end() != key_authorization {
+            return Err(Problem::new(
+                "incorrectResponse",
+                StatusCode::FORBIDDEN,
+                format!("http://{domain}{path} does not hold the key authorization"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Looks for the key authorization digest in the TXT records of `_acme-challenge.<domain>`.
+    async fn check_dns(
+        &self,
+        domain: &str,
+        key_authorization: &str,
+    ) -> std::result::Result<(), Problem> {
+        let dns_error = |detail: String| Problem::new("dns", StatusCode::BAD_REQUEST, detail);
+        let server = match self.config.dns_server {
+            Some(server) => server,
+            None => dns::system_resolver().map_err(|e| dns_error(e.plain()))?,
+        };
+        let name = format!("_acme-challenge.{domain}");
+        let records = dns::lookup_txt(server, &name, VALIDATION_TIMEOUT)
+            .await
+            .map_err(|e| dns_error(format!("TXT lookup of {name}: {}", e.plain())))?;
+        let expected = jose::b64url(&Sha256::digest(key_authorization.as_bytes()));
+        if !records.contains(&expected) {
+            return Err(Problem::new(
+                "incorrectResponse",
+                StatusCode::FORBIDDEN,
+                format!("no TXT record at {name} holds the key authorization digest"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Issues the certificate for a ready order through the same path as the other
+    /// enrollment protocols; an order over quota stays ready for a later retry.
+    fn finalize(&self, base: &str, id: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?;
+        let req: Finalize = signed.payload()?;
+        let mut store = self.store();
+        store.refresh_order(id);
+        let order = store.orders.get(id).ok_or_else(Problem::not_found)?;
+        if order.account != account {
+            return Err(Problem::unauthorized("order belongs to another account"));
+        }
+        if order.status != Status::Ready {
+            return Err(Problem::new(
+                "orderNotReady",
+                StatusCode::FORBIDDEN,
+                format!("order is {}", status_name(order.status)),
+            ));
+        }
+        let bad_csr = |detail: String| Problem::new("badCSR", StatusCode::BAD_REQUEST, detail);
+        let der = jose::b64url_decode(&req.csr).map_err(|e| bad_csr(e.plain()))?;
+        let (cn, mut names) = x509::csr_names(&der).map_err(|e| bad_csr(e.plain()))?;
+        let cn = cn.map(|cn| cn.to_ascii_lowercase());
+        if names.is_empty()
+            && let Some(cn) = &cn
+        {
+            names.push(cn.clone());
+        }
+        let wanted: HashSet<&str> = order.identifiers.iter().map(|i| i.value.as_str()).collect();
+        let requested: HashSet<&str> = names.iter().map(String::as_str).collect();
+        if wanted != requested || cn.as_deref().is_some_and(|cn| !wanted.contains(cn)) {
+            return Err(bad_csr(format!(
+                "CSR names {} do not match the order",
+                names.join(", ")
+            )));
+        }
+        let csr = CertificateSigningRequestParams::from_der(&der.into())
+            .map_err(|e| bad_csr(format!("invalid CSR: {e}")))?;
+        let spki = csr.public_key.subject_public_key_info();
+        if spki == signed.key.spki() {
+            return Err(bad_csr("the CSR must not use the account key".into()));
+        }
+        revocation::ensure_key_allowed(&spki).map_err(|e| bad_csr(e.plain()))?;
+
+        let san: Vec<String> = order.identifiers.iter().map(|i| i.value.clone()).collect();
+        let cn = cn.unwrap_or_else(|| san[0].clone());
+        let requester = Requester {
+            name: &format!("acme {account}"),
+            quota: None,
+        };
+        let result = match issue(
+            &self.limiter,
+            requester,
+            &cn,
+            san.clone(),
+            self.config.days,
+            Profile::Default,
+            KeySource::Csr(&csr),
+        ) {
+            Ok(Err(retry)) => {
+                return Err(Problem {
+                    retry_after: Some(retry),
+                    ..Problem::new(
+                        "rateLimited",
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "certificate quota exceeded",
+                    )
+                });
+            }
+            Ok(Ok(issued)) => Ok(issued),
+            Err(e) => Err(e),
+        };
+        let order = store.orders.get_mut(id).ok_or_else(Problem::not_found)?;
+        let outcome = match result {
+            Ok((issued, _, _)) => {
+                order.status = Status::Valid;
+                order.certificate = Some(issued.serial.clone());
+                info!(%account, order = %id, serial = %issued.serial, %cn, "ACME certificate issued");
+                if let Err(e) = audit::emit(
+                    "acme-issue",
+                    &format!("{id} {} {}", issued.serial, san.join(",")),
+                    false,
+                ) {
+                    error!("audit failed: {}", e);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let problem = Problem::internal(e);
+                order.status = Status::Invalid;
+                order.error = Some(problem.to_json());
+                Err(problem)
+            }
+        };
+        let order = order.clone();
+        store.save()?;
+        outcome?;
+        let url = format!("{base}/order/{id}");
+        Ok(Reply::json(StatusCode::OK, &order_json(base, id, &order)).at(url))
+    }
+
+    /// Certificate chain of an order, leaf first.
+    fn certificate(&self, serial: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?;
+        if !self.store().owns_certificate(account, serial) {
+            return Err(Problem::not_found());
+        }
+        let leaf = fs::read_issued_pem(serial).map_err(Problem::internal)?;
+        let root = fs::read_root_cert().map_err(Problem::internal)?;
+        Ok(Reply {
+            content_type: "application/pem-certificate-chain",
+            body: Bytes::from(format!("{}\n{}", leaf.trim_end(), root)),
+            ..Reply::empty(StatusCode::OK)
+        })
+    }
+
+    /// Account key rollover: the payload is a JWS by the new key naming the account and old key.
+    fn key_change(&self, base: &str, signed: Signed) -> Outcome {
+        let account = signed.account_id()?.to_owned();
+        let inner = Jws::parse(&signed.jws.payload).map_err(|e| Problem::malformed(e.plain()))?;
+        let KeyRef::Jwk(new_key) = &inner.key else {
+            return Err(Problem::malformed(
+                "the inner JWS must carry the new key as jwk",
+            ));
+        };
+        if inner.url != signed.jws.url {
+            return Err(Problem::malformed("inner and outer JWS urls differ"));
+        }
+        if !jose::ALGORITHMS.contains(&inner.alg.as_str()) {
+            return Err(Problem::new(
+                "badSignatureAlgorithm",
+                StatusCode::BAD_REQUEST,
+                format!("algorithm {} is not supported", inner.alg),
+            ));
+        }
+        inner
+            .verify(new_key)
+            .map_err(|e| Problem::malformed(e.plain()))?;
+        let req: KeyChange = inner.json().map_err(|e| Problem::malformed(e.plain()))?;
+        if req.account != format!("{base}/account/{account}") {
+            return Err(Problem::malformed("key change names another account"));
+        }
+        let old = Jwk::from_json(&req.old_key).map_err(|e| Problem::malformed(e.plain()))?;
+        if old != signed.key {
+            return Err(Problem::malformed("oldKey is not the current account key"));
+        }
+
+        let thumbprint = new_key.thumbprint();
+        let mut store = self.store();
+        if let Some((other, _)) = store
+            .accounts
+            .iter()
+            .find(|(_, a)| a.thumbprint == thumbprint)
+        {
+            let mut problem = Problem::new(
+                "malformed",
+                StatusCode::CONFLICT,
+                "the new key already belongs to an account",
+            );
+            problem.location = Some(format!("{base}/account/{other}"));
+            return Err(problem);
+        }
+        let entry = store
+            .accounts
+            .get_mut(&account)
+            .ok_or_else(Problem::not_found)?;
+        entry.jwk = new_key.to_json();
+        entry.thumbprint = thumbprint.clone();
+        let entry = entry.clone();
+        store.save()?;
+        drop(store);
+
+        info!(%account, %thumbprint, "ACME account key changed");
+        if let Err(e) = audit::emit("acme-key-change", &format!("{account} {thumbprint}"), false) {
+            error!("audit failed: {}", e);
+        }
+        Ok(Reply::json(
+            StatusCode::OK,
+            &account_json(base, &account, &entry),
+        ))
+    }
+
+    /// Revokes a certificate for the account that ordered it or for a holder of its key.
+    fn revoke_cert(&self, signed: Signed) -> Outcome {
+        let req: RevokeCert = signed.payload()?;
+        let der =
+            jose::b64url_decode(&req.certificate).map_err(|e| Problem::malformed(e.plain()))?;
+        let (_, cert) = X509Certificate::from_der(&der)
+            .map_err(|e| Problem::malformed(format!("bad certificate: {e}")))?;
+        let serial = x509::format_serial(cert.raw_serial());
+        // a look-alike carrying a real serial must not revoke the certificate behind it
+        let issued = fs::read_issued_pem(&serial)
+            .ok()
+            .and_then(|pem| x509::pem_to_der(&pem, "CERTIFICATE").ok());
+        if issued.as_deref() != Some(der.as_slice()) {
+            return Err(Problem::unauthorized(
+                "the certificate was not issued by this CA",
+            ));
+        }
+        let authorized = match &signed.account {
+            Some((account, _)) => self.store().owns_certificate(account, &serial),
+            None => signed.key.spki() == cert.public_key().raw,
+        };
+        if !authorized {
+            return Err(Problem::unauthorized(
+                "neither the account nor the key may revoke this certificate",
+            ));
+        }
+        let reason = match req.reason.unwrap_or(0) {
+            0 => Reason::Unspecified,
+            1 => Reason::KeyCompromise,
+            3 => Reason::AffiliationChanged,
+            4 => Reason::Superseded,
+            5 => Reason::CessationOfOperation,
+            code => {
+                return Err(Problem::new(
+                    "badRevocationReason",
+                    StatusCode::BAD_REQUEST,
+                    format!("revocation reason {code} is not allowed"),
+                ));
+            }
+        };
+        let revoked = revocation::current()
+            .map_err(Problem::internal)?
+            .into_iter()
+            .any(|e| e.serial == serial && e.reason != Some(Reason::CertificateHold));
+        if revoked {
+            return Err(Problem::new(
+                "alreadyRevoked",
+                StatusCode::BAD_REQUEST,
+                format!("certificate {serial} is already revoked"),
+            ));
+        }
+        let entry =
+            revocation::revoke(&serial, reason, None).map_err(|e| Problem::malformed(e.plain()))?;
+
+        info!(serial = %entry.serial, "certificate revoked via ACME");
+        let record = serde_json::to_string(&entry).unwrap_or_default();
+        if let Err(e) = audit::emit("revoke", &record, false) {
+            error!("audit failed: {}", e);
+        }
+        let crl = crate::cmd::crl::CrlArgs {
+            days: self.config.crl_days,
+            delta: false,
+        };
+        if let Err(e) = crl.run(false) {
+            error!("CRL regeneration failed: {}", e);
+        }
+        Ok(Reply::empty(StatusCode::OK))
+    }
+}
+
+/// Serves one request below `prefix`, the URL path the ACME directory is mounted at.
+pub async fn handle(
+    req: Request<Bytes>,
+    server: Arc<Server>,
+    prefix: &str,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .unwrap_or_default()
+        .to_owned();
+    let base = format!("https://{host}{prefix}");
+    let route = req.uri().path()[prefix.len()..].to_owned();
+    let method = req.method().clone();
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_owned);
+    let body = req.into_body();
+
+    // handlers sign and write the store under a std mutex, so they run off the async workers
+    let handler = server.clone();
+    let request_base = base.clone();
+    let reply = tokio::task::spawn_blocking(move || {
+        handler.dispatch(
+            &method,
+            &request_base,
+            &route,
+            content_type.as_deref(),
+            &body,
+        )
+    })
+    .await
+    .unwrap_or_else(|e| {
+        Err(Problem::internal(Error::Other(format!(
+            "ACME handler: {e}"
+        ))))
+    })
+    .unwrap_or_else(Problem::into_reply);
+    let mut resp = Response::new(Full::new(reply.body));
+    *resp.status_mut() = reply.status;
+    let headers = resp.headers_mut();
+    let mut set = |name: header::HeaderName, value: &str| {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.append(name, value);
+        }
+    };
+    set(
+        header::HeaderName::from_static("replay-nonce"),
+        &server.new_nonce(),
+    );
+    set(header::CACHE_CONTROL, "no-store");
+    set(header::LINK, &format!("<{base}/directory>;rel=\"index\""));
+    if let Some(link) = &reply.link {
+        set(header::LINK, link);
+    }
+    if let Some(location) = &reply.location {
+        set(header::LOCATION, location);
+    }
+    if let Some(RetryAfter(secs)) = reply.retry_after {
+        set(header::RETRY_AFTER, &secs.to_string());
+    }
+    if !reply.content_type.is_empty() {
+        set(header::CONTENT_TYPE, reply.content_type);
+    }
+    Ok(resp)
+}
+
+fn directory(base: &str) -> Value {
+    json!({
+        "newNonce": format!("{base}/new-nonce"),
+        "newAccount": format!("{base}/new-account"),
+        "newOrder": format!("{base}/new-order"),
+        "revokeCert": format!("{base}/revoke-cert"),
+        "keyChange": format!("{base}/key-change"),
+        "meta": { "externalAccountRequired": false },
+    })
+}
+
+fn random_id() -> String {
+    jose::b64url(&rand::random::<[u8; 16]>())
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn status_name(status: Status) -> String {
+    serde_json::to_value(status)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+fn check_contacts(contacts: &[String]) -> std::result::Result<(), Problem> {
+    for contact in contacts {
+        let valid = contact
+            .strip_prefix("mailto:")
+            .is_some_and(|addr| addr.contains('@') && !addr.contains(['?', ',']));
+        if !valid {
+            return Err(Problem::new(
+                "invalidContact",
+                StatusCode::BAD_REQUEST,
+                format!("{contact} is not a mailto: address"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn account_json(base: &str, id: &str, account: &Account) -> Value {
+    json!({
+        "status": account.status,
+        "contact": account.contact,
+        "orders": format!("{base}/account/{id}/orders"),
+        "createdAt": timestamp(account.created_at),
+    })
+}
+
+fn order_json(base: &str, id: &str, order: &Order) -> Value {
+    let mut doc = json!({
+        "status": order.status,
+        "expires": timestamp(order.expires),
+        "identifiers": order.identifiers,
+        "authorizations": order
+            .authorizations
+            .iter()
+            .map(|a| format!("{base}/authz/{a}"))
+            .collect::<Vec<_>>(),
+        "finalize": format!("{base}/order/{id}/finalize"),
+    });
+    if let Some(serial) = &order.certificate {
+        doc["certificate"] = json!(format!("{base}/cert/{serial}"));
+    }
+    if let Some(error) = &order.error {
+        doc["error"] = error.clone();
+    }
+    doc
+}
+
+fn authz_json(base: &str, id: &str, authz: &Authorization) -> Value {
+    let mut doc = json!({
+        "identifier": authz.identifier,
+        "status": authz_status(authz, Utc::now()),
+        "expires": timestamp(authz.expires),
+        "challenges": authz
+            .challenges
+            .iter()
+            .map(|c| challenge_json(base, id, c))
+            .collect::<Vec<_>>(),
+    });
+    if authz.wildcard {
+        doc["wildcard"] = json!(true);
+    }
+    doc
+}
+
+fn challenge_json(base: &str, authz: &str, challenge: &Challenge) -> Value {
+    let mut doc = json!({
+        "type": challenge.kind,
+        "url": format!("{base}/chall/{authz}/{}", challenge.kind),
+        "token": challenge.token,
+        "status": challenge.status,
+    });
+    if let Some(validated) = challenge.validated {
+        doc["validated"] = json!(timestamp(validated));
+    }
+    if let Some(error) = &challenge.error {
+        doc["error"] = error.clone();
+    }
+    doc
+}
+
+fn challenge_reply(base: &str, authz: &str, body: &Value) -> Reply {
+    let mut reply = Reply::json(StatusCode::OK, body);
+    reply.link = Some(format!("<{base}/authz/{authz}>;rel=\"up\""));
+    reply
+}
+
+/// Plain HTTP GET used for http-01; the body is capped so a hostile server cannot exhaust memory.
+async fn http_get(target: &str, host: &str, path: &str) -> Result<(StatusCode, String)> {
+    let stream = tokio::net::TcpStream::connect(target)
+        .await
+        .map_err(Error::from)?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    tokio::spawn(conn);
+    let req = Request::get(path)
+        .header(header::HOST, host)
+        .body(Full::new(Bytes::new()))
+        .map_err(|e| Error::Other(e.to_string()))?;
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    let status = resp.status();
+    let body = Limited::new(resp.into_body(), MAX_CHALLENGE_RESPONSE)
+        .collect()
+        .await
+        .map_err(|e| Error::Other(format!("reading response: {e}")))?
+        .to_bytes();
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::sign_cert;
+    use crate::util::profile::KeyAlgorithm;
+    use rcgen::{CertificateParams, DistinguishedName, KeyPair, SerialNumber};
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _};
+
+    const BASE: &str = "https://ca.test/acme";
+
+    fn server(quota: Option<u32>) -> Arc<Server> {
+        super::super::tests::ca();
+        let config = Config {
+            days: 30,
+            domains: vec![],
+            http_addr: None,
+            dns_server: None,
+            crl_days: 7,
+        };
+        Arc::new(Server::load(config, super::super::tests::limiter(quota)).unwrap())
+    }
+
+    fn ring_key(pkcs8: &[u8]) -> EcdsaKeyPair {
+        EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8,
+            &SystemRandom::new(),
+        )
+        .unwrap()
+    }
+
+    /// Sends `payload` to `route` signed by `key`, naming the account `kid` or else
+    /// embedding the JWK.
+    fn post(
+        server: &Arc<Server>,
+        key: &EcdsaKeyPair,
+        kid: Option<&str>,
+        route: &str,
+        payload: &Value,
+    ) -> Outcome {
+        let point = key.public_key().as_ref();
+        let mut header = json!({
+            "alg": "ES256",
+            "nonce": server.new_nonce(),
+            "url": format!("{BASE}{route}"),
+        });
+        match kid {
+            Some(kid) => header["kid"] = json!(kid),
+            None => {
+                header["jwk"] = json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "x": jose::b64url(&point[1..33]),
+                    "y": jose::b64url(&point[33..]),
+                });
+            }
+        }
+        let protected = jose::b64url(header.to_string().as_bytes());
+        let payload = jose::b64url(payload.to_string().as_bytes());
+        let sig = key
+            .sign(
+                &SystemRandom::new(),
+                format!("{protected}.{payload}").as_bytes(),
+            )
+            .unwrap();
+        let body = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": jose::b64url(sig.as_ref()),
+        });
+        server.dispatch(
+            &Method::POST,
+            BASE,
+            route,
+            Some("application/jose+json"),
+            body.to_string().as_bytes(),
+        )
+    }
+
+    fn revoke(server: &Arc<Server>, key: &EcdsaKeyPair, der: &[u8]) -> Outcome {
+        let payload = json!({ "certificate": jose::b64url(der) });
+        post(server, key, None, "/revoke-cert", &payload)
+    }
+
+    fn is_revoked(serial: &str) -> bool {
+        revocation::current()
+            .unwrap()
+            .iter()
+            .any(|e| e.serial == serial)
+    }
+
+    #[test]
+    fn revocation_needs_the_issued_certificate_and_its_key() {
+        let server = server(None);
+        let (issued, pem, key_pem) = sign_cert::issue(
+            "victim.acme.test",
+            vec!["victim.acme.test".into()],
+            30,
+            Profile::Default,
+            KeySource::Generate(KeyAlgorithm::EcdsaP256),
+        )
+        .unwrap();
+        let der = x509::pem_to_der(&pem, "CERTIFICATE").unwrap();
+        let holder = ring_key(&x509::pem_to_der(&key_pem.unwrap(), "PRIVATE KEY").unwrap());
+
+        // a self-signed look-alike with the victim's serial and the attacker's key
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let attacker = ring_key(pkcs8.as_ref());
+        let forger = KeyPair::from_pem(&x509::der_to_pem(pkcs8.as_ref(), "PRIVATE KEY")).unwrap();
+        let (_, parsed) = X509Certificate::from_der(&der).unwrap();
+        let mut params = CertificateParams::new(vec!["victim.acme.test".to_owned()]).unwrap();
+        params.serial_number = Some(SerialNumber::from_slice(parsed.raw_serial()));
+        let forged = params.self_signed(&forger).unwrap();
+
+        let problem = revoke(&server, &attacker, forged.der()).err().unwrap();
+        assert_eq!(problem.kind, "unauthorized");
+        let problem = revoke(&server, &attacker, &der).err().unwrap();
+        assert_eq!(problem.kind, "unauthorized");
+        assert!(!is_revoked(&issued.serial));
+
+        let Ok(reply) = revoke(&server, &holder, &der) else {
+            panic!("the key holder could not revoke");
+        };
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(is_revoked(&issued.serial));
+        let problem = revoke(&server, &holder, &der).err().unwrap();
+        assert_eq!(problem.kind, "alreadyRevoked");
+    }
+
+    #[test]
+    fn finalize_counts_against_the_account_quota() {
+        let server = server(Some(1));
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let key = ring_key(pkcs8.as_ref());
+        let Ok(reply) = post(&server, &key, None, "/new-account", &json!({})) else {
+            panic!("account not created");
+        };
+        let kid = reply.location.unwrap();
+        let account = kid.rsplit('/').next().unwrap().to_owned();
+
+        // two orders whose authorizations are already done
+        let domain = format!("quota-{:016x}.test", rand::random::<u64>());
+        let identifier = Identifier {
+            kind: "dns".into(),
+            value: domain.clone(),
+        };
+        let orders: Vec<String> = (0..2).map(|_| random_id()).collect();
+        {
+            let mut store = server.store();
+            for id in &orders {
+                let order = Order {
+                    account: account.clone(),
+                    status: Status::Ready,
+                    expires: Utc::now() + Duration::days(1),
+                    identifiers: vec![identifier.clone()],
+                    authorizations: vec![],
+                    certificate: None,
+                    error: None,
+                };
+                store.orders.insert(id.clone(), order);
+            }
+        }
+        let finalize = |id: &str| {
+            let csr_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![domain.clone()]).unwrap();
+            params.distinguished_name = DistinguishedName::new();
+            let csr = params.serialize_request(&csr_key).unwrap();
+            let payload = json!({ "csr": jose::b64url(csr.der()) });
+            post(
+                &server,
+                &key,
+                Some(&kid),
+                &format!("/order/{id}/finalize"),
+                &payload,
+            )
+        };
+
+        if let Err(problem) = finalize(&orders[0]) {
+            panic!("first order not finalized: {problem:?}");
+        }
+        let problem = finalize(&orders[1]).err().unwrap();
+        assert_eq!(problem.kind, "rateLimited");
+        assert!(problem.retry_after.is_some());
+        assert_eq!(server.store().orders[&orders[1]].status, Status::Ready);
+    }
+}
@@ -0,0 +1,221 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep, sleep};
+
+/// Requests of one connection being handled, and how many have finished.
+#[derive(Default)]
+pub struct InFlight {
+    active: AtomicUsize,
+    finished: AtomicUsize,
+    /// Read parked while a request was active, woken to start the idle timeout
+    reader: Mutex<Option<Waker>>,
+}
+
+impl InFlight {
+    /// Counts a request until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> RequestGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        RequestGuard(self.clone())
+    }
+}
+
+pub struct RequestGuard(Arc<InFlight>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.finished.fetch_add(1, Ordering::Relaxed);
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        let reader = self
+            .0
+            .reader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(waker) = reader {
+            waker.wake();
+        }
+    }
+}
+
+/// Closes a connection whose client is too slow to send a request or stays silent too
+/// long between requests.
+///
+/// A new connection has `header` to deliver its first request head. Once a response is
+/// out, the client may stay silent for `idle`; its next bytes start another `header`
+/// period. No deadline applies while a request is being handled.
+pub struct TimedIo<T> {
+    io: T,
+    in_flight: Arc<InFlight>,
+    header: Duration,
+    idle: Duration,
+    reading_head: bool,
+    finished: usize,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<T> TimedIo<T> {
+    pub fn new(io: T, in_flight: Arc<InFlight>, header: Duration, idle: Duration) -> Self {
+        TimedIo {
+            io,
+            in_flight,
+            header,
+            idle,
+            reading_head: true,
+            finished: 0,
+            deadline: Box::pin(sleep(header)),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TimedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.in_flight.active.load(Ordering::Relaxed) > 0 {
+            *this
+                .in_flight
+                .reader
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(cx.waker().clone());
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        }
+        let finished = this.in_flight.finished.load(Ordering::Relaxed);
+        if finished != this.finished {
+            this.finished = finished;
+            this.reading_head = false;
+            this.deadline.as_mut().reset(Instant::now() + this.idle);
+        }
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if !this.reading_head && buf.filled().len() > filled {
+                    this.reading_head = true;
+                    this.deadline.as_mut().reset(Instant::now() + this.header);
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending if this.deadline.as_mut().poll(cx).is_ready() => {
+                let what = if this.reading_head {
+                    "request header"
+                } else {
+                    "idle connection"
+                };
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{what} timed out"),
+                )))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TimedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio::time::timeout;
+
+    const HEADER: Duration = Duration::from_millis(100);
+    const IDLE: Duration = Duration::from_millis(300);
+
+    fn read_error(result: io::Result<usize>) -> String {
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        e.to_string()
+    }
+
+    #[test]
+    fn silent_clients_are_timed_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (_client, server) = duplex(64);
+            let mut io = TimedIo::new(server, Arc::new(InFlight::default()), HEADER, IDLE);
+            let started = Instant::now();
+            let e = read_error(io.read(&mut [0; 16]).await);
+            assert_eq!(e, "request header timed out");
+            assert!(started.elapsed() >= HEADER);
+        });
+    }
+
+    #[test]
+    fn connections_idle_only_between_requests() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut client, server) = duplex(64);
+            let in_flight = Arc::new(InFlight::default());
+            let mut io = TimedIo::new(server, in_flight.clone(), HEADER, IDLE);
+            let mut buf = [0; 16];
+            client.write_all(b"GET").await.unwrap();
+            assert_eq!(io.read(&mut buf).await.unwrap(), 3);
+
+            // no deadline while the request is handled
+            let request = in_flight.start();
+            assert!(timeout(HEADER * 2, io.read(&mut buf)).await.is_err());
+
+            // once it is answered the client gets the idle period, not the header one
+            drop(request);
+            let started = Instant::now();
+            let e = read_error(io.read(&mut buf).await);
+            assert_eq!(e, "idle connection timed out");
+            assert!(started.elapsed() >= IDLE);
+        });
+    }
+
+    #[test]
+    fn next_request_gets_the_header_period() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut client, server) = duplex(64);
+            let in_flight = Arc::new(InFlight::default());
+            let mut io = TimedIo::new(server, in_flight.clone(), HEADER, IDLE);
+            let mut buf = [0; 16];
+            drop(in_flight.start());
+
+            client.write_all(b"GET").await.unwrap();
+            assert_eq!(io.read(&mut buf).await.unwrap(), 3);
+            let started = Instant::now();
+            let e = read_error(io.read(&mut buf).await);
+            assert_eq!(e, "request header timed out");
+            assert!(started.elapsed() < IDLE);
+        });
+    }
+}
@@ -0,0 +1,479 @@
+use crate::cmd::sign_cert::KeySource;
+use crate::error::{Error, Result};
+use crate::util::issued::IssuedCert;
+use crate::util::profile::Profile;
+use crate::util::ratelimit::{Limiter, RetryAfter};
+use crate::util::{audit, cms, fs, password, revocation, x509};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{self, HeaderValue};
+use hyper::http::StatusCode;
+use hyper::{Method, Request, Response};
+use rcgen::CertificateSigningRequestParams;
+use rustls::pki_types::CertificateDer;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use super::{Requester, check_names, issue, reply};
+
+/// Wrong passwords a user may enter in a row before logins are refused for a while.
+const FREE_ATTEMPTS: u32 = 5;
+/// How long the first refusal lasts; every further wrong password doubles it.
+const LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+
+/// Settings of the EST endpoint and the failed logins it has seen.
+pub struct Service {
+    days: u32,
+    profile: Profile,
+    limiter: Arc<Limiter>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+/// Wrong passwords in a row for one user and when the last one was entered.
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Service {
+    pub fn new(days: u32, profile: Profile, limiter: Arc<Limiter>) -> Self {
+        Service {
+            days,
+            profile,
+            limiter,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time left before a password of `user` is checked again, so that guessing cannot
+    /// keep the PBKDF2 workers busy.
+    fn locked_out(&self, user: &str, now: Instant) -> std::result::Result<(), RetryAfter> {
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(f) = failures.get(user).filter(|f| f.count >= FREE_ATTEMPTS) else {
+            return Ok(());
+        };
+        let lockout = LOCKOUT
+            .saturating_mul(1 << (f.count - FREE_ATTEMPTS).min(16))
+            .min(MAX_LOCKOUT);
+        match (f.last + lockout).checked_duration_since(now) {
+            Some(left) if !left.is_zero() => Err(RetryAfter(left.as_secs().max(1))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a wrong password of `user`, or forgets earlier ones after a right one.
+    fn record_login(&self, user: &str, ok: bool, now: Instant) {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        if ok {
+            failures.remove(user);
+        } else {
+            let f = failures.entry(user.to_owned()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            f.count += 1;
+            f.last = now;
+        }
+    }
+}
+
+/// Who an enrollment request was authenticated as.
+enum Client {
+    /// A TLS client certificate issued by this CA
+    Certificate(IssuedCert),
+    /// An HTTP basic-auth user created with `est-user`
+    User(String),
+}
+
+impl Client {
+    fn describe(&self) -> String {
+        match self {
+            Client::Certificate(cert) => format!("certificate {} ({})", cert.serial, cert.cn),
+            Client::User(name) => format!("user {name}"),
+        }
+    }
+
+    /// Name whose issuance quota this client's enrollments count against.
+    fn quota_name(&self) -> String {
+        match self {
+            Client::Certificate(cert) => format!("est certificate {}", cert.cn),
+            Client::User(name) => format!("est user {name}"),
+        }
+    }
+}
+
+/// Serves `/.well-known/est/<operation>` (RFC 7030).
+pub async fn handle(
+    req: Request<Bytes>,
+    operation: &str,
+    peer: Option<&CertificateDer<'static>>,
+    service: &Service,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    match (req.method(), operation) {
+        (&Method::GET, "cacerts") => Ok(cacerts()),
+        // no particular CSR attributes are required (RFC 7030 section 4.5.2)
+        (&Method::GET, "csrattrs") => Ok(reply(StatusCode::NO_CONTENT, "")),
+        (&Method::POST, "simpleenroll") => enroll(req, peer, service, false).await,
+        (&Method::POST, "simplereenroll") => enroll(req, peer, service, true).await,
+        (_, "cacerts" | "csrattrs" | "simpleenroll" | "simplereenroll") => {
+            Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"))
+        }
+        _ => Ok(reply(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+fn cacerts() -> Response<Full<Bytes>> {
+    match fs::read_root_cert().and_then(|pem| x509::pem_to_der(&pem, "CERTIFICATE")) {
+        Ok(root) => pkcs7_reply(&cms::certs_only(&[&root])),
+        Err(e) => {
+            error!("EST cacerts failed: {}", e);
+            reply(StatusCode::INTERNAL_SERVER_ERROR, "error")
+        }
+    }
+}
+
+/// Issues a certificate for a PKCS#10 request. Clients authenticated by certificate may only
+/// enroll names that certificate carries; re-enrollment must come from the certificate being
+/// renewed and keep its subject and subjectAltName.
+async fn enroll(
+    req: Request<Bytes>,
+    peer: Option<&CertificateDer<'static>>,
+    service: &Service,
+    renew: bool,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    let client = match authenticate(&req, peer, service).await {
+        Ok(Ok(Some(client))) => client,
+        Ok(Err(RetryAfter(secs))) => {
+            let mut resp = reply(StatusCode::TOO_MANY_REQUESTS, "too many failed logins");
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            return Ok(resp);
+        }
+        Ok(Ok(None)) => {
+            let mut resp = reply(StatusCode::UNAUTHORIZED, "unauthorized");
+            resp.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"est\""),
+            );
+            return Ok(resp);
+        }
+        Err(e) => {
+            error!("EST authentication failed: {}", e);
+            return Ok(reply(StatusCode::INTERNAL_SERVER_ERROR, "error"));
+        }
+    };
+    let renewed = match (&client, renew) {
+        (Client::Certificate(cert), true) => Some(cert),
+        (Client::User(_), true) => {
+            return Ok(reply(
+                StatusCode::FORBIDDEN,
+                "re-enrollment must be authenticated by the certificate being renewed",
+            ));
+        }
+        (_, false) => None,
+    };
+
+    let body = req.into_body();
+    let (spki, csr, cn, names) = match parse_csr(&body) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(reply(StatusCode::BAD_REQUEST, e.plain())),
+    };
+    if let Err(e) = check_names(&cn, &names) {
+        return Ok(reply(StatusCode::BAD_REQUEST, e.plain()));
+    }
+    if let Client::Certificate(cert) = &client
+        && !names_covered(cert, &cn, &names)
+    {
+        return Ok(reply(
+            StatusCode::FORBIDDEN,
+            "a client certificate may only enroll names it carries itself",
+        ));
+    }
+    if let Some(cert) = renewed {
+        let current: HashSet<&String> = cert.san.iter().collect();
+        let requested: HashSet<&String> = names.iter().collect();
+        if cn != cert.cn || current != requested {
+            return Ok(reply(
+                StatusCode::BAD_REQUEST,
+                "subject and subjectAltName must match the certificate being renewed",
+            ));
+        }
+    }
+    if let Err(e) = revocation::ensure_key_allowed(&spki) {
+        return Ok(reply(StatusCode::BAD_REQUEST, e.plain()));
+    }
+
+    let requester = Requester {
+        name: &client.quota_name(),
+        quota: None,
+    };
+    let key = KeySource::Csr(&csr);
+    let issued = issue(
+        &service.limiter,
+        requester,
+        &cn,
+        names,
+        service.days,
+        service.profile,
+        key,
+    );
+    let (issued, pem, _) = match issued {
+        Ok(Ok(issued)) => issued,
+        Ok(Err(RetryAfter(secs))) => {
+            let mut resp = reply(StatusCode::TOO_MANY_REQUESTS, "certificate quota exceeded");
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            return Ok(resp);
+        }
+        Err(e) => {
+            error!("EST issuance failed: {}", e);
+            return Ok(reply(StatusCode::INTERNAL_SERVER_ERROR, "error"));
+        }
+    };
+    let action = if renew { "est-reenroll" } else { "est-enroll" };
+    info!(client = %client.describe(), serial = %issued.serial, %cn, "certificate enrolled via EST");
+    if let Err(e) = audit::emit(
+        action,
+        &format!("{} {} {}", client.describe(), issued.serial, cn),
+        false,
+    ) {
+        error!("audit failed: {}", e);
+    }
+    match x509::pem_to_der(&pem, "CERTIFICATE") {
+        Ok(cert) => Ok(pkcs7_reply(&cms::certs_only(&[&cert]))),
+        Err(e) => {
+            error!("EST response failed: {}", e);
+            Ok(reply(StatusCode::INTERNAL_SERVER_ERROR, "error"))
+        }
+    }
+}
+
+/// Whether `cn` and `names` are all among the subject CN and subjectAltNames of `cert`, so
+/// that a certificate only ever obtains further certificates for the names it was issued for.
+fn names_covered(cert: &IssuedCert, cn: &str, names: &[String]) -> bool {
+    let held: HashSet<&str> = std::iter::once(cert.cn.as_str())
+        .chain(cert.san.iter().map(String::as_str))
+        .collect();
+    held.contains(cn) && names.iter().all(|name| held.contains(name.as_str()))
+}
+
+/// Accepts an unrevoked client certificate of this CA, else HTTP basic auth unless the user
+/// is locked out after too many wrong passwords.
+async fn authenticate(
+    req: &Request<Bytes>,
+    peer: Option<&CertificateDer<'static>>,
+    service: &Service,
+) -> Result<std::result::Result<Option<Client>, RetryAfter>> {
+    if let Some(peer) = peer {
+        let cert = IssuedCert::from_der(peer)?;
+        if revocation::current()?
+            .iter()
+            .any(|e| e.serial == cert.serial)
+        {
+            warn!(serial = %cert.serial, "EST client certificate is revoked");
+        } else {
+            return Ok(Ok(Some(Client::Certificate(cert))));
+        }
+    }
+    let Some(credentials) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b64| BASE64.decode(b64.trim()).ok())
+        .and_then(|raw| String::from_utf8(raw).ok())
+    else {
+        return Ok(Ok(None));
+    };
+    let Some((name, pass)) = credentials.split_once(':') else {
+        return Ok(Ok(None));
+    };
+    let Some((name, hash)) = fs::read_est_users()?.into_iter().find(|(n, _)| n == name) else {
+        warn!(%name, "unknown EST user");
+        return Ok(Ok(None));
+    };
+    if let Err(retry) = service.locked_out(&name, Instant::now()) {
+        warn!(%name, "EST user locked out after failed logins");
+        return Ok(Err(retry));
+    }
+    let pass = pass.to_owned();
+    // PBKDF2 takes a while; keep it off the connection tasks
+    let ok = tokio::task::spawn_blocking(move || password::verify(&pass, &hash))
+        .await
+        .unwrap_or(false);
+    service.record_login(&name, ok, Instant::now());
+    if !ok {
+        warn!(%name, "wrong EST password");
+        return Ok(Ok(None));
+    }
+    Ok(Ok(Some(Client::User(name))))
+}
+
+/// Decodes an `application/pkcs10` body (base64, PEM or raw DER) and returns the request's
+/// SubjectPublicKeyInfo, parsed form, subject CN and subjectAltNames.
+pub(super) fn parse_csr(
+    body: &[u8],
+) -> Result<(
+    Vec<u8>,
+    CertificateSigningRequestParams,
+    String,
+    Vec<String>,
+)> {
+    let text = String::from_utf8_lossy(body);
+    let der = if body.first() == Some(&0x30) {
+        body.to_vec()
+    } else if text.contains("-----BEGIN") {
+        x509::pem_to_der(&text, "CERTIFICATE REQUEST")?
+    } else {
+        let b64: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        BASE64
+            .decode(b64)
+            .map_err(|e| Error::Other(format!("CSR is not base64: {e}")))?
+    };
+    let (cn, names) = x509::csr_names(&der)?;
+    let csr = CertificateSigningRequestParams::from_der(&der.into())
+        .map_err(|e| Error::Other(format!("invalid CSR: {e}")))?;
+    let cn = cn.or_else(|| names.first().cloned()).ok_or_else(|| {
+        Error::Other("CSR names neither a subject CN nor a subjectAltName".into())
+    })?;
+    let spki = rcgen::PublicKeyData::subject_public_key_info(&csr.public_key);
+    Ok((spki, csr, cn, names))
+}
+
+/// Base64 certs-only PKCS#7 as RFC 7030 section 4.1.3 prescribes.
+fn pkcs7_reply(der: &[u8]) -> Response<Full<Bytes>> {
+    let encoded = BASE64.encode(der);
+    let mut body = String::with_capacity(encoded.len() + encoded.len() / 64 + 1);
+    for line in encoded.as_bytes().chunks(64) {
+        body.push_str(&String::from_utf8_lossy(line));
+        body.push_str("\r\n");
+    }
+    let mut resp = reply(StatusCode::OK, body);
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/pkcs7-mime; smime-type=certs-only"),
+    );
+    resp.headers_mut().insert(
+        header::HeaderName::from_static("content-transfer-encoding"),
+        HeaderValue::from_static("base64"),
+    );
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::sign_cert;
+    use crate::util::profile::KeyAlgorithm;
+    use rcgen::{CertificateParams, DnType, KeyPair};
+
+    fn enroll_as(
+        service: &Service,
+        peer: &CertificateDer<'static>,
+        cn: &str,
+    ) -> Response<Full<Bytes>> {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        let csr = params.serialize_request(&key).unwrap().pem().unwrap();
+        let req = Request::post("/.well-known/est/simpleenroll")
+            .body(Bytes::from(csr))
+            .unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(handle(req, "simpleenroll", Some(peer), service))
+            .unwrap()
+    }
+
+    #[test]
+    fn enrollment_names_stay_in_cert_dir() {
+        super::super::tests::ca();
+        let cn = format!("est-{:016x}.test", rand::random::<u64>());
+        let (_, pem, _) = sign_cert::issue(
+            &cn,
+            vec![],
+            30,
+            Profile::Client,
+            KeySource::Generate(KeyAlgorithm::EcdsaP256),
+        )
+        .unwrap();
+        let peer = CertificateDer::from(x509::pem_to_der(&pem, "CERTIFICATE").unwrap());
+        let service = Service::new(30, Profile::Client, super::super::tests::limiter(None));
+
+        for cn in ["../escape", "a/b", "bad name", ".."] {
+            let resp = enroll_as(&service, &peer, cn);
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{cn:?}");
+        }
+        let resp = enroll_as(&service, &peer, &cn);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn certificates_only_enroll_their_own_names() {
+        super::super::tests::ca();
+        let id = rand::random::<u64>();
+        let (_, pem, _) = sign_cert::issue(
+            &format!("a-{id:016x}.example"),
+            vec![format!("www.a-{id:016x}.example")],
+            30,
+            Profile::Default,
+            KeySource::Generate(KeyAlgorithm::EcdsaP256),
+        )
+        .unwrap();
+        let peer = CertificateDer::from(x509::pem_to_der(&pem, "CERTIFICATE").unwrap());
+        let service = Service::new(30, Profile::Default, super::super::tests::limiter(None));
+
+        let resp = enroll_as(&service, &peer, &format!("b-{id:016x}.example"));
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = enroll_as(&service, &peer, &format!("www.a-{id:016x}.example"));
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn enrollment_counts_against_the_client_quota() {
+        super::super::tests::ca();
+        let cn = format!("est-{:016x}.test", rand::random::<u64>());
+        let (_, pem, _) = sign_cert::issue(
+            &cn,
+            vec![],
+            30,
+            Profile::Client,
+            KeySource::Generate(KeyAlgorithm::EcdsaP256),
+        )
+        .unwrap();
+        let peer = CertificateDer::from(x509::pem_to_der(&pem, "CERTIFICATE").unwrap());
+        let service = Service::new(30, Profile::Client, super::super::tests::limiter(Some(1)));
+
+        let resp = enroll_as(&service, &peer, &cn);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = enroll_as(&service, &peer, &cn);
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[test]
+    fn wrong_passwords_lock_the_user_out() {
+        let service = Service::new(30, Profile::Default, super::super::tests::limiter(None));
+        let start = Instant::now();
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(service.locked_out("alice", start).is_ok());
+            service.record_login("alice", false, start);
+        }
+        assert_eq!(service.locked_out("alice", start), Err(RetryAfter(30)));
+        assert!(service.locked_out("bob", start).is_ok());
+        assert!(service.locked_out("alice", start + LOCKOUT).is_ok());
+
+        // each further failure doubles the wait
+        service.record_login("alice", false, start + LOCKOUT);
+        assert_eq!(
+            service.locked_out("alice", start + LOCKOUT),
+            Err(RetryAfter(60))
+        );
+        service.record_login("alice", true, start + LOCKOUT * 3);
+        assert!(service.locked_out("alice", start + LOCKOUT * 3).is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error::{Error, Result};
+use crate::util::issued::IssuedCert;
+use crate::util::token::{Limits, Permission, Scope};
+use crate::util::{fs, revocation, x509};
+use clap::ValueEnum;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{Identity, load_certs};
+
+/// Whether TLS clients must, may or cannot present a certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ClientAuth {
+    None,
+    Optional,
+    Required,
+}
+
+/// API permissions for the client certificate naming `subject` as CN or subjectAltName
+/// whose key has the given SubjectPublicKeyInfo hash, given as
+/// `subject@spki-sha256=permission,...`. Names alone are not enough: enrollment would let
+/// anyone obtain a certificate for them.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    subject: String,
+    /// Lowercase hex, as [`x509::spki_hash`] writes it
+    spki_sha256: String,
+    permissions: Vec<Permission>,
+}
+
+impl FromStr for ClientIdentity {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let (subject, spki_sha256, permissions) = s
+            .split_once('=')
+            .and_then(|(id, permissions)| {
+                let (subject, spki) = id.rsplit_once('@')?;
+                Some((subject, spki, permissions))
+            })
+            .ok_or_else(|| format!("expected SUBJECT@SPKI-SHA256=PERMISSIONS, got {s:?}"))?;
+        if subject.is_empty() {
+            return Err("empty client certificate subject".into());
+        }
+        if spki_sha256.len() != 64 || !spki_sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "{spki_sha256:?} is not a hex SHA-256 key hash of {subject}"
+            ));
+        }
+        let permissions = permissions
+            .split(',')
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<Permission>, _>>()?;
+        Ok(Self {
+            subject: subject.to_owned(),
+            spki_sha256: spki_sha256.to_ascii_lowercase(),
+            permissions,
+        })
+    }
+}
+
+/// TLS settings and client-certificate identities of the server. The configuration is
+/// rebuilt whenever the complete CRL is reissued, so revocations reach new handshakes
+/// without a restart.
+pub struct Mtls {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    auth: ClientAuth,
+    roots: Arc<RootCertStore>,
+    ca_subject: Vec<u8>,
+    identities: Vec<ClientIdentity>,
+    http2: bool,
+    current: Mutex<(Option<SystemTime>, Arc<ServerConfig>)>,
+}
+
+impl Mtls {
+    /// Trusts `client_ca` (a PEM bundle) for client certificates, or this CA's root.
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        auth: ClientAuth,
+        client_ca: Option<&str>,
+        identities: Vec<ClientIdentity>,
+        http2: bool,
+    ) -> Result<Self> {
+        let root = x509::pem_to_der(&fs::read_root_cert()?, "CERTIFICATE")?;
+        let (_, ca) = X509Certificate::from_der(&root)
+            .map_err(|e| Error::Other(format!("bad CA certificate: {e}")))?;
+        let ca_subject = ca.subject().as_raw().to_vec();
+        let trusted = match client_ca {
+            Some(path) => load_certs(path)?,
+            None => vec![root.into()],
+        };
+        let mut roots = RootCertStore::empty();
+        for cert in trusted {
+            roots
+                .add(cert)
+                .map_err(|e| Error::Other(format!("bad client CA certificate: {e}")))?;
+        }
+        let roots = Arc::new(roots);
+        let modified = fs::crl_modified("crl");
+        let config = server_config(&certs, &key, auth, &roots, http2)?;
+        Ok(Self {
+            certs,
+            key,
+            auth,
+            roots,
+            ca_subject,
+            identities,
+            http2,
+            current: Mutex::new((modified, config)),
+        })
+    }
+
+    /// The configuration for the next handshake.
+    pub fn config(&self) -> Result<Arc<ServerConfig>> {
+        let mut current = self
+            .current
+            .lock()
+            .map_err(|_| Error::Other("TLS configuration lock poisoned".into()))?;
+        let modified = fs::crl_modified("crl");
+        if self.auth != ClientAuth::None && modified != current.0 {
+            *current = (
+                modified,
+                server_config(&self.certs, &self.key, self.auth, &self.roots, self.http2)?,
+            );
+            info!("reloaded CRL for client certificate checks");
+        }
+        Ok(current.1.clone())
+    }
+
+    /// Whether `peer` was issued by this CA rather than another CA of the trust bundle.
+    pub fn issued_here(&self, peer: &CertificateDer<'_>) -> bool {
+        X509Certificate::from_der(peer)
+            .is_ok_and(|(_, cert)| cert.issuer().as_raw() == self.ca_subject.as_slice())
+    }
+
+    /// Maps a verified client certificate to the first identity naming its CN or a SAN and
+    /// its key.
+    ///
+    /// Certificates of this CA are also looked up in the revocation list, which covers
+    /// revocations only published in a delta CRL so far.
+    pub fn identify(&self, peer: &CertificateDer<'_>) -> Result<Option<Identity>> {
+        let cert = IssuedCert::from_der(peer)?;
+        if self.issued_here(peer)
+            && revocation::current()?
+                .iter()
+                .any(|e| e.serial == cert.serial)
+        {
+            warn!(serial = %cert.serial, "client certificate is revoked");
+            return Ok(None);
+        }
+        let names: Vec<&String> = std::iter::once(&cert.cn).chain(&cert.san).collect();
+        Ok(self
+            .identities
+            .iter()
+            .find(|id| {
+                id.spki_sha256 == cert.spki_sha256
+                    && names.iter().any(|n| n.eq_ignore_ascii_case(&id.subject))
+            })
+            .map(|id| Identity {
+                name: id.subject.clone(),
+                permissions: id.permissions.clone(),
+                scope: Scope::default(),
+                limits: Limits::default(),
+            }))
+    }
+}
+
+/// Server settings that verify client certificates against `roots` and the complete CRL.
+fn server_config(
+    certs: &[CertificateDer<'static>],
+    key: &PrivateKeyDer<'static>,
+    auth: ClientAuth,
+    roots: &Arc<RootCertStore>,
+    http2: bool,
+) -> Result<Arc<ServerConfig>> {
+    let verifier = match auth {
+        ClientAuth::None => WebPkiClientVerifier::no_client_auth(),
+        auth => {
+            let mut builder = WebPkiClientVerifier::builder(roots.clone())
+                .only_check_end_entity_revocation()
+                // other CAs of a trust bundle publish no CRL here
+                .allow_unknown_revocation_status();
+            match fs::read_crl("crl")? {
+                Some(crl) => builder = builder.with_crls([CertificateRevocationListDer::from(crl)]),
+                None => {
+                    warn!("no CRL issued yet; client certificates are not checked for revocation")
+                }
+            }
+            if auth == ClientAuth::Optional {
+                builder = builder.allow_unauthenticated();
+            }
+            builder.build().map_err(|e| Error::Other(e.to_string()))?
+        }
+    };
+    let mut config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs.to_vec(), key.clone_key())
+        .map_err(|e| Error::Other(e.to_string()))?;
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::sign_cert::{self, KeySource};
+    use crate::util::profile::{KeyAlgorithm, Profile};
+    use rcgen::{CertificateParams, KeyPair};
+
+    fn client(cn: &str) -> (CertificateDer<'static>, IssuedCert) {
+        let (issued, pem, _) = sign_cert::issue(
+            cn,
+            vec![],
+            30,
+            Profile::Client,
+            KeySource::Generate(KeyAlgorithm::EcdsaP256),
+        )
+        .unwrap();
+        let der = x509::pem_to_der(&pem, "CERTIFICATE").unwrap();
+        (der.into(), issued)
+    }
+
+    #[test]
+    fn identities_are_bound_to_a_key() {
+        super::super::tests::ca();
+        let cn = format!("bot-{:016x}.test", rand::random::<u64>());
+        let (peer, issued) = client(&cn);
+        // anyone may enroll for the same name, but not with the same key
+        let (lookalike, _) = client(&cn);
+
+        let id: ClientIdentity = format!("{cn}@{}=sign,read", issued.spki_sha256.to_uppercase())
+            .parse()
+            .unwrap();
+        assert!(format!("{cn}=sign").parse::<ClientIdentity>().is_err());
+        assert!(format!("{cn}@abcd=sign").parse::<ClientIdentity>().is_err());
+
+        let key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let mtls = Mtls::new(
+            vec![server.der().clone()],
+            PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+            ClientAuth::Optional,
+            None,
+            vec![id],
+            false,
+        )
+        .unwrap();
+        let identity = mtls.identify(&peer).unwrap().unwrap();
+        assert_eq!(identity.name, cn);
+        assert_eq!(identity.permissions, [Permission::Sign, Permission::Read]);
+        assert!(mtls.identify(&lookalike).unwrap().is_none());
+    }
+}
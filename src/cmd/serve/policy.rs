@@ -0,0 +1,58 @@
+use crate::error::{Error, Result};
+use crate::util::ratelimit;
+use serde::Deserialize;
+
+use super::ServeArgs;
+use super::mtls::ClientIdentity;
+
+/// Contents of the `--policy` file, re-read on SIGHUP. Each setting present replaces
+/// the command line flag of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Policy {
+    client_identity: Option<Vec<String>>,
+    rate_limit_ip: Option<u32>,
+    rate_limit_identity: Option<u32>,
+    quota_identity: Option<u32>,
+    quota_domain: Option<u32>,
+    quota_window_hours: Option<u32>,
+}
+
+/// Client certificate identities and limits in effect.
+pub struct Settings {
+    pub identities: Vec<ClientIdentity>,
+    pub limits: ratelimit::Config,
+}
+
+/// Combines the flags with the policy file, if one is configured.
+pub fn load(args: &ServeArgs) -> Result<Settings> {
+    let policy = match &args.policy {
+        Some(path) => {
+            let data = std::fs::read_to_string(path).map_err(Error::from)?;
+            serde_json::from_str::<Policy>(&data)
+                .map_err(|e| Error::Other(format!("bad policy file {path}: {e}")))?
+        }
+        None => Policy::default(),
+    };
+    let identities = match policy.client_identity {
+        Some(list) => list
+            .iter()
+            .map(|s| s.parse())
+            .collect::<std::result::Result<_, String>>()
+            .map_err(|e| Error::Other(format!("bad client-identity in policy: {e}")))?,
+        None => args.client_identity.clone(),
+    };
+    let hours = policy.quota_window_hours.unwrap_or(args.quota_window_hours);
+    Ok(Settings {
+        identities,
+        limits: ratelimit::Config {
+            per_ip: policy.rate_limit_ip.unwrap_or(args.rate_limit_ip),
+            per_identity: policy
+                .rate_limit_identity
+                .unwrap_or(args.rate_limit_identity),
+            identity_quota: policy.quota_identity.or(args.quota_identity),
+            domain_quota: policy.quota_domain.or(args.quota_domain),
+            window: chrono::Duration::hours(hours.into()),
+        },
+    })
+}
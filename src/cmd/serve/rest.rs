@@ -0,0 +1,630 @@
+use crate::util::crl::{Reason, Revocation};
+use crate::util::issued::IssuedCert;
+use crate::util::token::{self, Permission};
+use crate::util::{fs, revocation, x509};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use hyper::header::{self, HeaderValue};
+use hyper::http::StatusCode;
+use hyper::{Request, Response};
+use rustls::pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json as value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{error, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{ApiError, AppState, Identity, authorize, identify, json, percent_decode, tokens};
+
+type Reply = std::result::Result<Response<Full<Bytes>>, ApiError>;
+
+/// What a route does; [`dispatch`] maps each to its handler.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Ca,
+    ListCerts,
+    GetCert,
+    RevokeCert,
+    Crl,
+    Sign,
+    Revoke,
+    ListTokens,
+    CreateToken,
+    RevokeToken,
+    Health,
+    Version,
+    OpenApi,
+}
+
+/// One endpoint of the certificate and token API. Both [`dispatch`] and `/openapi.json`
+/// are built from [`ROUTES`], so the document cannot drift from what is served.
+struct Route {
+    method: &'static str,
+    /// Path with `{name}` segments for parameters
+    path: &'static str,
+    op: Op,
+    summary: &'static str,
+    /// Checked before the handler runs; `None` for public routes
+    permission: Option<Permission>,
+    /// Query parameters as `(name, description)`
+    query: &'static [(&'static str, &'static str)],
+    /// Description of the JSON request body
+    body: Option<&'static str>,
+    /// Success status, content type and description
+    response: (u16, &'static str, &'static str),
+}
+
+const JSON: &str = "application/json";
+
+static ROUTES: &[Route] = &[
+    Route {
+        method: "GET",
+        path: "/ca",
+        op: Op::Ca,
+        summary: "CA certificate and its chain up to the root",
+        permission: None,
+        query: &[],
+        body: None,
+        response: (
+            200,
+            JSON,
+            "PEM `certificate`, `chain`, `subject` and validity",
+        ),
+    },
+    Route {
+        method: "GET",
+        path: "/certs",
+        op: Op::ListCerts,
+        summary: "List issued certificates",
+        permission: Some(Permission::Read),
+        query: &[
+            ("name", "CN or SAN, exact or as a `*.suffix` pattern"),
+            ("status", "`valid`, `expired`, `revoked` or `on-hold`"),
+            ("expires_before", "RFC 3339 time notAfter must precede"),
+            ("offset", "Entries to skip (default 0)"),
+            ("limit", "Page size (default 100, at most 1000)"),
+        ],
+        body: None,
+        response: (
+            200,
+            JSON,
+            "`total` matches and the `items` of this page, oldest first",
+        ),
+    },
+    Route {
+        method: "GET",
+        path: "/certs/{serial}",
+        op: Op::GetCert,
+        summary: "An issued certificate with its status and PEM",
+        permission: Some(Permission::Read),
+        query: &[],
+        body: None,
+        response: (200, JSON, "Certificate details and `revocation`, if any"),
+    },
+    Route {
+        method: "POST",
+        path: "/certs/{serial}/revoke",
+        op: Op::RevokeCert,
+        summary: "Revoke an issued certificate and regenerate the CRL",
+        permission: Some(Permission::Revoke),
+        query: &[],
+        body: Some("Optional `reason` and `invalidity_date`"),
+        response: (200, JSON, "The revocation record"),
+    },
+    Route {
+        method: "GET",
+        path: "/crl",
+        op: Op::Crl,
+        summary: "Latest complete CRL, or the delta CRL",
+        permission: None,
+        query: &[("delta", "`true` for the delta CRL")],
+        body: None,
+        response: (200, "application/pkix-crl", "DER CRL"),
+    },
+    Route {
+        method: "POST",
+        path: "/sign",
+        op: Op::Sign,
+        summary: "Issue a certificate within the caller's scope",
+        permission: Some(Permission::Sign),
+        query: &[],
+        body: Some("`days` and optionally `cn`, `san`, `profile`, `key_algorithm` and a PEM `csr`"),
+        response: (
+            201,
+            JSON,
+            "PEM `certificate`, `chain`, `serial`, validity and any generated `private_key`",
+        ),
+    },
+    Route {
+        method: "POST",
+        path: "/revoke",
+        op: Op::Revoke,
+        summary: "Revoke a certificate with the revoke permission or a holder signature",
+        permission: None,
+        query: &[],
+        body: Some(
+            "`serial`, optional `reason` and `invalidity_date`, or a holder `timestamp` and `signature`",
+        ),
+        response: (200, JSON, "The revocation record"),
+    },
+    Route {
+        method: "GET",
+        path: "/tokens",
+        op: Op::ListTokens,
+        summary: "List API tokens",
+        permission: Some(Permission::Admin),
+        query: &[],
+        body: None,
+        response: (200, JSON, "Tokens without their hashes"),
+    },
+    Route {
+        method: "POST",
+        path: "/tokens",
+        op: Op::CreateToken,
+        summary: "Create an API token",
+        permission: Some(Permission::Admin),
+        query: &[],
+        body: Some(
+            "`name` and optionally `permissions`, `names`, `profiles`, `max_days`, `rate_limit`, `quota` and `valid_days`",
+        ),
+        response: (201, JSON, "The token with its secret, shown only once"),
+    },
+    Route {
+        method: "DELETE",
+        path: "/tokens/{id}",
+        op: Op::RevokeToken,
+        summary: "Revoke an API token",
+        permission: Some(Permission::Admin),
+        query: &[],
+        body: None,
+        response: (200, JSON, "The revoked token"),
+    },
+    Route {
+        method: "GET",
+        path: "/health",
+        op: Op::Health,
+        summary: "Whether the CA can serve requests",
+        permission: None,
+        query: &[],
+        body: None,
+        response: (
+            200,
+            JSON,
+            "`status` ok; 503 when the root certificate is unusable",
+        ),
+    },
+    Route {
+        method: "GET",
+        path: "/version",
+        op: Op::Version,
+        summary: "Server name and version",
+        permission: None,
+        query: &[],
+        body: None,
+        response: (200, JSON, "`name` and `version`"),
+    },
+    Route {
+        method: "GET",
+        path: "/openapi.json",
+        op: Op::OpenApi,
+        summary: "This OpenAPI document",
+        permission: None,
+        query: &[],
+        body: None,
+        response: (200, JSON, "OpenAPI 3.1 document"),
+    },
+];
+
+impl Route {
+    /// Path parameters if `path` fits this route's template.
+    fn matches(&self, path: &str) -> Option<Vec<String>> {
+        let mut params = Vec::new();
+        let mut segments = path.split('/');
+        for template in self.path.split('/') {
+            let segment = segments.next()?;
+            if template.starts_with('{') {
+                if segment.is_empty() {
+                    return None;
+                }
+                params.push(percent_decode(segment));
+            } else if template != segment {
+                return None;
+            }
+        }
+        segments.next().is_none().then_some(params)
+    }
+}
+
+/// Routes an API request, answering 404 for unknown paths and 405 for unsupported methods.
+pub async fn dispatch(
+    req: Request<Bytes>,
+    state: Arc<AppState>,
+    peer: Option<Arc<CertificateDer<'static>>>,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    let path = req.uri().path().to_owned();
+    let mut allowed = Vec::new();
+    let mut found = None;
+    for route in ROUTES {
+        if let Some(params) = route.matches(&path) {
+            if route.method == req.method().as_str() {
+                found = Some((route, params));
+                break;
+            }
+            allowed.push(route.method);
+        }
+    }
+    let Some((route, params)) = found else {
+        if allowed.is_empty() {
+            return Ok(ApiError::new(StatusCode::NOT_FOUND, "not_found", "not found").response());
+        }
+        let mut resp = ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "method not allowed",
+        )
+        .response();
+        if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+            resp.headers_mut().insert(header::ALLOW, allow);
+        }
+        return Ok(resp);
+    };
+
+    let identity = identify(&req, &state, peer.as_deref());
+    if let Some(id) = &identity
+        && let Err(retry) = state.limiter.check_identity(&id.name, id.limits.rate_limit)
+    {
+        warn!(identity = %id.name, "rate limit exceeded");
+        return Ok(ApiError::limited("rate_limited", "too many requests", retry).response());
+    }
+    if let Some(permission) = route.permission
+        && let Err(e) = authorize(identity.as_ref(), permission)
+    {
+        return Ok(e.response());
+    }
+    let query = query_params(req.uri().query().unwrap_or_default());
+    let body = match route.body {
+        Some(_) => req.into_body(),
+        None => Bytes::new(),
+    };
+    // the permission check above guarantees an identity where one is needed
+    let caller = || identity.as_ref().ok_or_else(ApiError::internal);
+    let result = match route.op {
+        Op::Ca => ca(),
+        Op::ListCerts => list_certs(&query),
+        Op::GetCert => get_cert(&params[0]),
+        Op::RevokeCert => caller().and_then(|id| revoke_cert(&params[0], &body, id, &state)),
+        Op::Crl => crl(&query),
+        Op::Sign => caller().and_then(|id| super::sign(&body, id, &state)),
+        Op::Revoke => super::revoke(&body, identity.as_ref(), &state),
+        Op::ListTokens => tokens::list(),
+        Op::CreateToken => caller().and_then(|id| tokens::create(&body, &id.name)),
+        Op::RevokeToken => caller().and_then(|id| tokens::revoke(&params[0], &id.name)),
+        Op::Health => health(),
+        Op::Version => Ok(json(
+            StatusCode::OK,
+            &value!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") }),
+        )),
+        Op::OpenApi => Ok(json(StatusCode::OK, &openapi())),
+    };
+    Ok(result.unwrap_or_else(|e| e.response()))
+}
+
+fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (percent_decode(k), percent_decode(&v.replace('+', " "))))
+        .collect()
+}
+
+/// Canonical form of a serial from the URL.
+fn serial_param(serial: &str) -> std::result::Result<String, ApiError> {
+    x509::parse_serial(serial)
+        .map(|s| x509::format_serial(&s))
+        .map_err(|e| ApiError::invalid(e.plain()))
+}
+
+fn internal(context: &str, e: impl std::fmt::Display) -> ApiError {
+    error!("{context}: {e}");
+    ApiError::internal()
+}
+
+fn ca() -> Reply {
+    let pem = fs::read_root_cert().map_err(|e| internal("reading the root certificate", e))?;
+    let der = x509::pem_to_der(&pem, "CERTIFICATE")
+        .map_err(|e| internal("decoding the root certificate", e))?;
+    let (_, cert) =
+        X509Certificate::from_der(&der).map_err(|e| internal("parsing the root certificate", e))?;
+    let validity = cert.validity();
+    Ok(json(
+        StatusCode::OK,
+        &value!({
+            "subject": cert.subject().to_string(),
+            "serial": x509::format_serial(cert.raw_serial()),
+            "not_before": DateTime::from_timestamp(validity.not_before.timestamp(), 0),
+            "not_after": DateTime::from_timestamp(validity.not_after.timestamp(), 0),
+            "certificate": pem,
+            "chain": [pem],
+        }),
+    ))
+}
+
+/// Status of an issued certificate given its current revocation, if any.
+fn status(cert: &IssuedCert, revocation: Option<&Revocation>, now: DateTime<Utc>) -> &'static str {
+    match revocation {
+        Some(r) if r.reason == Some(Reason::CertificateHold) => "on-hold",
+        Some(_) => "revoked",
+        None if cert.not_after < now => "expired",
+        None => "valid",
+    }
+}
+
+#[derive(Serialize)]
+struct CertSummary<'a> {
+    serial: &'a str,
+    cn: &'a str,
+    san: &'a [String],
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    status: &'static str,
+}
+
+fn list_certs(query: &HashMap<String, String>) -> Reply {
+    let number = |name: &str, default: usize| match query.get(name) {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|_| ApiError::invalid(format!("{name} must be a non-negative integer"))),
+        None => Ok(default),
+    };
+    let offset = number("offset", 0)?;
+    let limit = number("limit", 100)?;
+    if limit == 0 || limit > 1000 {
+        return Err(ApiError::invalid("limit must be between 1 and 1000"));
+    }
+    let status_filter = query.get("status").map(String::as_str);
+    if let Some(s) = status_filter
+        && !["valid", "expired", "revoked", "on-hold"].contains(&s)
+    {
+        return Err(ApiError::invalid(format!("unknown status {s:?}")));
+    }
+    let expires_before = match query.get("expires_before") {
+        Some(t) => Some(
+            DateTime::parse_from_rfc3339(t)
+                .map_err(|_| ApiError::invalid("expires_before must be an RFC 3339 time"))?
+                .with_timezone(&Utc),
+        ),
+        None => None,
+    };
+
+    let issued = fs::read_issued().map_err(|e| internal("reading issued certificates", e))?;
+    let revoked: HashMap<String, Revocation> = revocation::current()
+        .map_err(|e| internal("reading revocations", e))?
+        .into_iter()
+        .map(|r| (r.serial.clone(), r))
+        .collect();
+    let now = Utc::now();
+    let matching: Vec<CertSummary> = issued
+        .iter()
+        .filter(|c| {
+            query.get("name").is_none_or(|pattern| {
+                std::iter::once(&c.cn)
+                    .chain(&c.san)
+                    .any(|n| token::name_matches(pattern, n))
+            })
+        })
+        .filter(|c| expires_before.is_none_or(|t| c.not_after < t))
+        .map(|c| CertSummary {
+            serial: &c.serial,
+            cn: &c.cn,
+            san: &c.san,
+            not_before: c.not_before,
+            not_after: c.not_after,
+            status: status(c, revoked.get(&c.serial), now),
+        })
+        .filter(|c| status_filter.is_none_or(|s| c.status == s))
+        .collect();
+    let total = matching.len();
+    let items: Vec<CertSummary> = matching.into_iter().skip(offset).take(limit).collect();
+    Ok(json(
+        StatusCode::OK,
+        &value!({ "total": total, "offset": offset, "limit": limit, "items": items }),
+    ))
+}
+
+fn get_cert(serial: &str) -> Reply {
+    let serial = serial_param(serial)?;
+    let issued = fs::read_issued().map_err(|e| internal("reading issued certificates", e))?;
+    let Some(cert) = issued.iter().find(|c| c.serial == serial) else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("no certificate with serial {serial}"),
+        ));
+    };
+    let revocation = revocation::current()
+        .map_err(|e| internal("reading revocations", e))?
+        .into_iter()
+        .find(|r| r.serial == serial);
+    let pem = fs::read_issued_pem(&serial).map_err(|e| internal("reading the certificate", e))?;
+    Ok(json(
+        StatusCode::OK,
+        &value!({
+            "serial": cert.serial,
+            "cn": cert.cn,
+            "san": cert.san,
+            "not_before": cert.not_before,
+            "not_after": cert.not_after,
+            "spki_sha256": cert.spki_sha256,
+            "status": status(cert, revocation.as_ref(), Utc::now()),
+            "revocation": revocation,
+            "certificate": pem,
+        }),
+    ))
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RevokeCertRequest {
+    reason: Option<Reason>,
+    invalidity_date: Option<DateTime<Utc>>,
+}
+
+fn revoke_cert(serial: &str, body: &[u8], identity: &Identity, state: &AppState) -> Reply {
+    let serial = serial_param(serial)?;
+    let data: RevokeCertRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        RevokeCertRequest::default()
+    } else {
+        serde_json::from_slice(body).map_err(ApiError::malformed)?
+    };
+    let entry = revocation::revoke(
+        &serial,
+        data.reason.unwrap_or(Reason::Unspecified),
+        data.invalidity_date,
+    )
+    .map_err(|e| {
+        error!("revocation refused: {}", e);
+        ApiError::new(StatusCode::BAD_REQUEST, "revocation_refused", e.plain())
+    })?;
+    Ok(super::revoked(&entry, &identity.name, state))
+}
+
+fn crl(query: &HashMap<String, String>) -> Reply {
+    let name = match query.get("delta").map(String::as_str) {
+        Some("true") => "delta",
+        None | Some("false") => "crl",
+        Some(_) => return Err(ApiError::invalid("delta must be true or false")),
+    };
+    let Some(der) = fs::read_crl(name).map_err(|e| internal("reading the CRL", e))? else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("no {name} CRL has been issued"),
+        ));
+    };
+    let mut resp = super::reply(StatusCode::OK, der);
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/pkix-crl"),
+    );
+    Ok(resp)
+}
+
+/// Healthy while the root certificate is readable and within its validity period.
+fn health() -> Reply {
+    let check = fs::read_root_cert()
+        .and_then(|pem| x509::pem_to_der(&pem, "CERTIFICATE"))
+        .and_then(|der| {
+            let (_, cert) = X509Certificate::from_der(&der)
+                .map_err(|e| crate::error::Error::Other(format!("bad root certificate: {e}")))?;
+            if cert.validity().is_valid() {
+                Ok(())
+            } else {
+                Err(crate::error::Error::Other(
+                    "root certificate is not valid now".into(),
+                ))
+            }
+        });
+    Ok(match check {
+        Ok(()) => json(StatusCode::OK, &value!({ "status": "ok" })),
+        Err(e) => {
+            error!("health check failed: {}", e);
+            json(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &value!({ "status": "unavailable", "message": e.plain() }),
+            )
+        }
+    })
+}
+
+/// OpenAPI 3.1 description of [`ROUTES`].
+fn openapi() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let mut parameters: Vec<Value> = route
+            .path
+            .split('/')
+            .filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                value!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } })
+            })
+            .collect();
+        parameters.extend(route.query.iter().map(|(name, description)| {
+            value!({
+                "name": name,
+                "in": "query",
+                "description": description,
+                "schema": { "type": "string" },
+            })
+        }));
+        let (status, content_type, description) = route.response;
+        let schema = if content_type == JSON {
+            value!({ "type": "object" })
+        } else {
+            value!({ "type": "string", "format": "binary" })
+        };
+        let mut operation = value!({
+            "operationId": format!("{:?}", route.op),
+            "summary": route.summary,
+            "parameters": parameters,
+            "responses": {
+                status.to_string(): {
+                    "description": description,
+                    "content": { content_type: { "schema": schema } },
+                },
+                "default": {
+                    "description": "Error",
+                    "content": { JSON: { "schema": { "$ref": "#/components/schemas/Error" } } },
+                },
+            },
+        });
+        if let Some(body) = route.body {
+            operation["requestBody"] = value!({
+                "description": body,
+                "content": { JSON: { "schema": { "type": "object" } } },
+            });
+        }
+        operation["security"] = match route.permission {
+            Some(permission) => {
+                operation["description"] =
+                    Value::from(format!("Requires the `{}` permission.", permission.name()));
+                value!([{ "bearerToken": [] }, { "clientCertificate": [] }])
+            }
+            None => value!([]),
+        };
+        let entry = paths
+            .entry(route.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        entry[route.method.to_ascii_lowercase()] = operation;
+    }
+    value!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "hypatia-ca API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Certificate, revocation and token management. ACME, EST, SCEP, OCSP and the time-stamping authority follow their own RFCs and are not described here.",
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerToken": { "type": "http", "scheme": "bearer" },
+                "clientCertificate": { "type": "mutualTLS" },
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "error": {
+                            "type": "object",
+                            "properties": {
+                                "code": { "type": "string" },
+                                "message": { "type": "string" },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
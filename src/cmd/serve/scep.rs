@@ -0,0 +1,251 @@
+use crate::cmd::sign_cert::KeySource;
+use crate::util::cms::ContentCipher;
+use crate::util::profile::Profile;
+use crate::util::ratelimit::Limiter;
+use crate::util::scep::{self, PkiMessage, Responder};
+use crate::util::{audit, revocation, x509};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{self, HeaderValue};
+use hyper::http::StatusCode;
+use hyper::{Method, Request, Response};
+use tracing::{error, info, warn};
+
+use super::{Requester, check_names, est, issue, percent_decode, reply};
+
+/// Serves SCEP (RFC 8894) operations named by the `operation` query parameter.
+pub async fn handle(
+    req: Request<Bytes>,
+    responder: &Responder,
+    days: u32,
+    limiter: &Limiter,
+) -> std::result::Result<Response<Full<Bytes>>, hyper::Error> {
+    let query = req.uri().query().unwrap_or_default();
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(&value.replace('+', " ")))
+    };
+    let operation = param("operation").unwrap_or_default();
+    let message = param("message");
+    match (req.method(), operation.as_str()) {
+        (&Method::GET, "GetCACaps") => Ok(typed(scep::CAPABILITIES.into(), "text/plain")),
+        (&Method::GET, "GetCACert") => Ok(typed(
+            responder.ca_certs().into(),
+            "application/x-x509-ca-ra-cert",
+        )),
+        (&Method::GET, "PKIOperation") => {
+            match message.and_then(|m| BASE64.decode(m.replace(' ', "+").trim()).ok()) {
+                Some(der) => Ok(pki_operation(responder, &der, days, limiter)),
+                None => Ok(reply(StatusCode::BAD_REQUEST, "bad message encoding")),
+            }
+        }
+        (&Method::POST, "PKIOperation") => {
+            let der = req.into_body();
+            Ok(pki_operation(responder, &der, days, limiter))
+        }
+        (_, "GetCACaps" | "GetCACert" | "PKIOperation") => {
+            Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"))
+        }
+        _ => Ok(reply(StatusCode::BAD_REQUEST, "unknown SCEP operation")),
+    }
+}
+
+fn typed(body: Bytes, content_type: &'static str) -> Response<Full<Bytes>> {
+    let mut resp = reply(StatusCode::OK, body);
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
+
+/// Answers a pkiMessage with a CertRep; rejected requests still get a signed failure.
+fn pki_operation(
+    responder: &Responder,
+    der: &[u8],
+    days: u32,
+    limiter: &Limiter,
+) -> Response<Full<Bytes>> {
+    let msg = match scep::parse_message(der) {
+        Ok(msg) => msg,
+        Err(e) => return reply(StatusCode::BAD_REQUEST, e.plain()),
+    };
+    let rep = match enroll(responder, &msg, days, limiter) {
+        Ok((cert, cipher)) => responder.success(&msg, &cert, cipher),
+        Err(fail_info) => {
+            warn!(transaction = %msg.transaction_id, fail_info, "SCEP request rejected");
+            responder.failure(&msg, fail_info)
+        }
+    };
+    match rep {
+        Ok(rep) => typed(rep.into(), "application/x-pki-message"),
+        Err(e) => {
+            error!("SCEP response failed: {}", e);
+            reply(StatusCode::INTERNAL_SERVER_ERROR, "error")
+        }
+    }
+}
+
+/// Verifies a PKCSReq, redeems its challenge password and issues the certificate.
+fn enroll(
+    responder: &Responder,
+    msg: &PkiMessage,
+    days: u32,
+    limiter: &Limiter,
+) -> std::result::Result<(Vec<u8>, ContentCipher), u8> {
+    msg.verify()?;
+    if msg.message_type != scep::PKCS_REQ {
+        warn!(message_type = %msg.message_type, "unsupported SCEP message type");
+        return Err(scep::BAD_REQUEST);
+    }
+    let (der, cipher) = responder.decrypt(msg).map_err(|e| {
+        warn!("SCEP envelope: {}", e);
+        scep::BAD_MESSAGE_CHECK
+    })?;
+    let challenge = x509::csr_challenge_password(&der)
+        .ok()
+        .flatten()
+        .ok_or(scep::BAD_REQUEST)?;
+    let (spki, csr, cn, names) = est::parse_csr(&der).map_err(|e| {
+        warn!("SCEP CSR: {}", e);
+        scep::BAD_REQUEST
+    })?;
+    if let Err(e) = check_names(&cn, &names) {
+        warn!("SCEP CSR: {}", e);
+        return Err(scep::BAD_REQUEST);
+    }
+    if let Err(e) = revocation::ensure_key_allowed(&spki) {
+        warn!("SCEP CSR: {}", e);
+        return Err(scep::BAD_REQUEST);
+    }
+    match responder.redeem_challenge(&challenge, &cn) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(transaction = %msg.transaction_id, %cn, "unknown, expired or foreign SCEP challenge");
+            return Err(scep::BAD_REQUEST);
+        }
+        Err(e) => {
+            error!("SCEP challenge check failed: {}", e);
+            return Err(scep::BAD_REQUEST);
+        }
+    }
+
+    let requester = Requester {
+        name: &format!("scep {cn}"),
+        quota: None,
+    };
+    let key = KeySource::Csr(&csr);
+    let (issued, pem, _) = match issue(limiter, requester, &cn, names, days, Profile::Default, key)
+    {
+        Ok(Ok(issued)) => issued,
+        Ok(Err(_)) => return Err(scep::BAD_REQUEST),
+        Err(e) => {
+            error!("SCEP issuance failed: {}", e);
+            return Err(scep::BAD_REQUEST);
+        }
+    };
+    info!(transaction = %msg.transaction_id, serial = %issued.serial, %cn, "certificate enrolled via SCEP");
+    if let Err(e) = audit::emit(
+        "scep-enroll",
+        &format!("{} {} {}", msg.transaction_id, issued.serial, cn),
+        false,
+    ) {
+        error!("audit failed: {}", e);
+    }
+    let cert = x509::pem_to_der(&pem, "CERTIFICATE").map_err(|e| {
+        error!("SCEP response failed: {}", e);
+        scep::BAD_REQUEST
+    })?;
+    Ok((cert, cipher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::cms::{self, Recipient};
+    use crate::util::fs;
+    use rcgen::{Attribute, CertificateParams, DnType, KeyPair};
+
+    const OID_CHALLENGE_PASSWORD: &[u64] = &[1, 2, 840, 113549, 1, 9, 7];
+
+    /// A PKCSReq for `cn` carrying `challenge`, encrypted to the RA and signed by a
+    /// throwaway self-signed certificate.
+    fn pkcs_req(cn: &str, challenge: &str) -> PkiMessage {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        let password =
+            yasna::construct_der(|w| w.write_set(|w| w.next().write_printable_string(challenge)));
+        let csr = params
+            .serialize_request_with_attributes(
+                &key,
+                vec![Attribute {
+                    oid: OID_CHALLENGE_PASSWORD,
+                    values: password,
+                }],
+            )
+            .unwrap();
+        let (ra, _) = fs::read_scep_signer().unwrap().unwrap();
+        let ra = Recipient::from_cert(&x509::pem_to_der(&ra, "CERTIFICATE").unwrap()).unwrap();
+        let content = cms::envelope(csr.der(), &ra, ContentCipher::Aes128).unwrap();
+        let signer = CertificateParams::new(Vec::<String>::new())
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let der = scep::tests::request(&key, signer.der(), &content);
+        scep::parse_message(&der).unwrap()
+    }
+
+    #[test]
+    fn challenges_only_enroll_their_subject() {
+        super::super::tests::ca();
+        let responder = Responder::load(30).unwrap();
+        let limiter = super::super::tests::limiter(None);
+        let cn = format!("scep-{:016x}.test", rand::random::<u64>());
+        let (password, _) = scep::new_challenge(&cn, chrono::Duration::hours(1)).unwrap();
+
+        for name in ["../escape", "a/b", ".."] {
+            let msg = pkcs_req(name, &password);
+            assert_eq!(
+                enroll(&responder, &msg, 30, &limiter).err(),
+                Some(scep::BAD_REQUEST),
+                "{name:?}"
+            );
+        }
+        let msg = pkcs_req(&format!("other-{cn}"), &password);
+        assert_eq!(
+            enroll(&responder, &msg, 30, &limiter).err(),
+            Some(scep::BAD_REQUEST)
+        );
+
+        let msg = pkcs_req(&cn, &password);
+        assert!(enroll(&responder, &msg, 30, &limiter).is_ok());
+        // used up
+        let msg = pkcs_req(&cn, &password);
+        assert_eq!(
+            enroll(&responder, &msg, 30, &limiter).err(),
+            Some(scep::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn enrollment_counts_against_the_subject_quota() {
+        super::super::tests::ca();
+        let responder = Responder::load(30).unwrap();
+        let limiter = super::super::tests::limiter(Some(1));
+        let cn = format!("scep-{:016x}.test", rand::random::<u64>());
+        let hour = chrono::Duration::hours(1);
+        let (first, _) = scep::new_challenge(&cn, hour).unwrap();
+        let (second, _) = scep::new_challenge(&cn, hour).unwrap();
+
+        assert!(enroll(&responder, &pkcs_req(&cn, &first), 30, &limiter).is_ok());
+        let msg = pkcs_req(&cn, &second);
+        assert_eq!(
+            enroll(&responder, &msg, 30, &limiter).err(),
+            Some(scep::BAD_REQUEST)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::util::profile::Profile;
+use crate::util::token::{self, ApiToken, Limits, Permission, Scope};
+use crate::util::{audit, fs};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use hyper::Response;
+use hyper::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{ApiError, json};
+
+/// Body of `POST /tokens`, mirroring the flags of `api-token --create`.
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+    #[serde(default = "default_permissions")]
+    permissions: Vec<Permission>,
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    profiles: Vec<Profile>,
+    max_days: Option<u32>,
+    rate_limit: Option<u32>,
+    quota: Option<u32>,
+    #[serde(default = "default_valid_days")]
+    valid_days: u32,
+}
+
+fn default_permissions() -> Vec<Permission> {
+    vec![Permission::Sign]
+}
+
+fn default_valid_days() -> u32 {
+    90
+}
+
+/// A stored token as listed by the API, without its hash.
+#[derive(Serialize)]
+struct TokenInfo<'a> {
+    id: &'a str,
+    name: &'a str,
+    permissions: &'a [Permission],
+    #[serde(flatten)]
+    scope: &'a Scope,
+    #[serde(flatten)]
+    limits: Limits,
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked: Option<DateTime<Utc>>,
+    active: bool,
+}
+
+impl<'a> From<&'a ApiToken> for TokenInfo<'a> {
+    fn from(t: &'a ApiToken) -> Self {
+        TokenInfo {
+            id: &t.id,
+            name: &t.name,
+            permissions: &t.permissions,
+            scope: &t.scope,
+            limits: t.limits,
+            created: t.created,
+            expires: t.expires,
+            revoked: t.revoked,
+            active: t.is_active(Utc::now()),
+        }
+    }
+}
+
+/// `GET /tokens`
+pub fn list() -> std::result::Result<Response<Full<Bytes>>, ApiError> {
+    let tokens = fs::read_api_tokens().map_err(|e| {
+        error!("API token listing failed: {}", e);
+        ApiError::internal()
+    })?;
+    Ok(json(
+        StatusCode::OK,
+        &tokens.iter().map(TokenInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+/// `POST /tokens`
+pub fn create(body: &[u8], admin: &str) -> std::result::Result<Response<Full<Bytes>>, ApiError> {
+    let data: CreateRequest = serde_json::from_slice(body).map_err(ApiError::malformed)?;
+    let scope = Scope {
+        names: data.names,
+        profiles: data.profiles,
+        max_days: data.max_days,
+    };
+    let limits = Limits {
+        rate_limit: data.rate_limit,
+        quota: data.quota,
+    };
+    let (secret, created) =
+        token::create(&data.name, data.permissions, scope, limits, data.valid_days)
+            .map_err(|e| ApiError::invalid(e.plain()))?;
+    info!(id = %created.id, name = %created.name, %admin, "API token created via API");
+    emit(&format!(
+        "{} created ({}) by {admin}",
+        created.id, created.name
+    ));
+    #[derive(Serialize)]
+    struct Created<'a> {
+        token: &'a str,
+        #[serde(flatten)]
+        info: TokenInfo<'a>,
+    }
+    Ok(json(
+        StatusCode::CREATED,
+        &Created {
+            token: &secret,
+            info: TokenInfo::from(&created),
+        },
+    ))
+}
+
+/// `DELETE /tokens/{id}`
+pub fn revoke(id: &str, admin: &str) -> std::result::Result<Response<Full<Bytes>>, ApiError> {
+    let known = fs::read_api_tokens().is_ok_and(|tokens| tokens.iter().any(|t| t.id == id));
+    if !known {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("no API token {id}"),
+        ));
+    }
+    let revoked = token::revoke(id)
+        .map_err(|e| ApiError::new(StatusCode::CONFLICT, "already_revoked", e.plain()))?;
+    info!(%id, %admin, "API token revoked via API");
+    emit(&format!("{id} revoked by {admin}"));
+    Ok(json(StatusCode::OK, &TokenInfo::from(&revoked)))
+}
+
+fn emit(details: &str) {
+    if let Err(e) = audit::emit("api-token", details, false) {
+        error!("audit failed: {}", e);
+    }
+}
@@ -1,57 +1,119 @@
+use crate::cmd::Runnable;
+use crate::cmd::signature::{SignerArgs, TrustArgs, describe};
 use crate::error::{Error, Result};
-use crate::util::{audit, fs};
+use crate::util::envelope::{Envelope, HashAlg};
+use crate::util::{audit, keystore};
 use clap::Args;
-use crypt_guard::*;
-use crypt_guard::KDF::{
-    Dilithium2, Dilithium3, Dilithium5, Falcon1024, Falcon512, Detached, Signature,
-};
-use crypt_guard::KDF::KeyOperations;
-use crypt_guard::error::CryptError;
-use tracing::{debug, event, info, Level};
-use zeroize::Zeroize;
+use std::io::{Read, Write};
+use tracing::{Level, debug, event, info};
 
+/// Signs or verifies a message given on the command line or piped through stdin; the
+/// armored signature goes to stdout, so no temporary files are needed.
 #[derive(Args, Debug)]
 pub struct SignArgs {
-    /// Message to sign
+    /// Message to sign or verify; read from stdin when omitted
     #[arg(long)]
-    pub message: String,
+    pub message: Option<String>,
 
-    /// Algorithm: falcon512, falcon1024, dilithium2, dilithium3, dilithium5
-    #[arg(long, default_value = "falcon512")]
-    pub algorithm: String,
+    /// Verify --signature over the message instead of signing it
+    #[arg(long, requires = "signature")]
+    pub verify: bool,
+
+    /// Armored signature to verify, or `-` to read it from stdin (needs --message)
+    #[arg(long, requires = "verify")]
+    pub signature: Option<String>,
+
+    #[command(flatten)]
+    pub signer: SignerArgs,
+
+    #[command(flatten)]
+    pub trust: TrustArgs,
 }
 
-impl crate::cmd::Runnable for SignArgs {
-    fn run(&self, cli: &crate::Cli) -> Result<()> {
-        let data = self.message.as_bytes().to_vec();
-        let sig = match self.algorithm.as_str() {
-            "falcon512" => {
-                let (_pk, sk) = FalconKeypair!(512);
-                Signature!(Falcon, sk.to_owned(), 512, data.to_owned(), Detached)
-            }
-            "falcon1024" => {
-                let (_pk, sk) = FalconKeypair!(1024);
-                Signature!(Falcon, sk.to_owned(), 1024, data.to_owned(), Detached)
-            }
-            "dilithium2" => {
-                let (_pk, sk) = DilithiumKeypair!(2);
-                Signature!(Dilithium, sk.to_owned(), 2, data.to_owned(), Detached)
-            }
-            "dilithium3" => {
-                let (_pk, sk) = DilithiumKeypair!(3);
-                Signature!(Dilithium, sk.to_owned(), 3, data.to_owned(), Detached)
+impl SignArgs {
+    fn sign(&self, json: bool) -> Result<()> {
+        if self.message.is_none()
+            && self.signer.passphrase_file.is_none()
+            && std::env::var_os(keystore::PASSPHRASE_ENV).is_none()
+        {
+            return Err(Error::Other(format!(
+                "the message is read from stdin, so the passphrase must come from \
+                 --passphrase-file or ${}",
+                keystore::PASSPHRASE_ENV
+            )));
+        }
+        let (identity, env) = self.signer.envelope(|hash| match &self.message {
+            Some(message) => Ok(hash.digest(message.as_bytes())),
+            None => hash.digest_reader(std::io::stdin().lock()),
+        })?;
+        let mut out = std::io::stdout().lock();
+        out.write_all(env.to_armor()?.as_bytes())
+            .and_then(|()| out.flush())
+            .map_err(Error::from)?;
+
+        info!(key = %identity.name, key_id = %identity.key_id, "message signed");
+        audit::emit("sign-message", &describe(&[identity]), json)?;
+        event!(Level::INFO, "message signed");
+        Ok(())
+    }
+
+    fn verify(&self, json: bool) -> Result<()> {
+        let path = self
+            .signature
+            .as_deref()
+            .ok_or_else(|| Error::Other("--signature is required to verify".into()))?;
+        let sig = if path == "-" {
+            if self.message.is_none() {
+                return Err(Error::Other(
+                    "stdin cannot carry both the message and the signature; pass --message".into(),
+                ));
             }
-            "dilithium5" => {
-                let (_pk, sk) = DilithiumKeypair!(5);
-                Signature!(Dilithium, sk.to_owned(), 5, data.to_owned(), Detached)
+            let mut sig = Vec::new();
+            std::io::stdin()
+                .lock()
+                .read_to_end(&mut sig)
+                .map_err(Error::from)?;
+            sig
+        } else {
+            std::fs::read(path).map_err(Error::from)?
+        };
+        let bundle = Envelope::parse_bundle(&sig)?
+            .ok_or_else(|| Error::Other(format!("{path} is not a signature envelope")))?;
+
+        // envelopes may use different digests, so a piped message is read only once
+        let message = match &self.message {
+            Some(message) => message.as_bytes().to_vec(),
+            None => {
+                let mut data = Vec::new();
+                std::io::stdin()
+                    .lock()
+                    .read_to_end(&mut data)
+                    .map_err(Error::from)?;
+                data
             }
-            _ => return Err(Error::Other("unknown algorithm".into())),
         };
-        debug!("signature generated");
-        fs::write_signature(&sig)?;
-        info!("signature stored");
-        audit::emit("sign", &self.algorithm, cli.json)?;
-        event!(Level::INFO, "message signed");
+        debug!(
+            bytes = message.len(),
+            signatures = bundle.len(),
+            "verifying message"
+        );
+        let signers = self
+            .trust
+            .verify_bundle(&bundle, |hash: HashAlg| Ok(hash.digest(&message)))?;
+
+        info!(signers = %describe(&signers), "message signature verified");
+        audit::emit("sign-message-verify", &describe(&signers), json)?;
+        event!(Level::INFO, "verification complete");
         Ok(())
     }
 }
+
+impl Runnable for SignArgs {
+    fn run(self, json: bool) -> Result<()> {
+        if self.verify {
+            self.verify(json)
+        } else {
+            self.sign(json)
+        }
+    }
+}
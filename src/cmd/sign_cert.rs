@@ -1,7 +1,12 @@
 use crate::error::{Error, Result};
-use crate::util::{audit, fs};
+use crate::util::issued::IssuedCert;
+use crate::util::profile::{KeyAlgorithm, Profile};
+use crate::util::{audit, fs, revocation, x509};
 use clap::Args;
-use rcgen::{CertificateParams, DnType, IsCa, Issuer, KeyPair};
+use rcgen::{
+    CertificateParams, CertificateSigningRequestParams, DnType, IsCa, Issuer, KeyPair,
+    PublicKeyData,
+};
 use time::{Duration, OffsetDateTime};
 use tracing::{Level, debug, event, info};
 use zeroize::Zeroizing;
@@ -19,30 +24,113 @@ pub struct SignCertArgs {
     /// Subject Alternative Names
     #[arg(long)]
     pub san: Vec<String>,
+
+    /// PEM certificate signing request; a new key pair is generated when omitted
+    #[arg(long)]
+    pub csr: Option<String>,
+
+    /// Key usages of the certificate
+    #[arg(long, value_enum, default_value = "default")]
+    pub profile: Profile,
+
+    /// Type of the generated key pair (ignored with --csr)
+    #[arg(long, value_enum, default_value = "ecdsa-p256", conflicts_with = "csr")]
+    pub key_algorithm: KeyAlgorithm,
+}
+
+/// Where the certified public key comes from.
+pub enum KeySource<'a> {
+    Csr(&'a CertificateSigningRequestParams),
+    /// A new key pair, written next to the certificate and returned by [`issue`]
+    Generate(KeyAlgorithm),
+}
+
+/// An issued certificate with its PEM and, for generated keys, the private key PEM.
+pub type Issued = (IssuedCert, String, Option<Zeroizing<String>>);
+
+/// Most days a certificate issued now may be valid for without outliving the root CA.
+pub fn max_days() -> Result<u32> {
+    let root = x509::pem_to_der(&fs::read_root_cert()?, "CERTIFICATE")?;
+    days_left(&root, OffsetDateTime::now_utc())
+}
+
+fn days_left(ca_der: &[u8], now: OffsetDateTime) -> Result<u32> {
+    let ca = IssuedCert::from_der(ca_der)?;
+    let left = (ca.not_after.timestamp() - now.unix_timestamp()) / 86_400;
+    Ok(u32::try_from(left.max(0)).unwrap_or(u32::MAX))
+}
+
+/// Issues a leaf certificate for `cn` and `san` with the key usages of `profile`, valid for
+/// `days`, which must end within the root CA's validity; writes it below the certificate directory and records it as issued. Returns the
+/// certificate and, for generated keys, the private key PEM.
+pub fn issue(
+    cn: &str,
+    san: Vec<String>,
+    days: u32,
+    profile: Profile,
+    key: KeySource<'_>,
+) -> Result<Issued> {
+    fs::check_cert_name(cn)?;
+    let (ca_cert, ca_key) = fs::read_root_ca()?;
+    let ca_key = KeyPair::from_pem(&ca_key).map_err(Error::from)?;
+    let ca = Issuer::from_ca_cert_pem(&ca_cert, ca_key).map_err(Error::from)?;
+
+    let mut params = CertificateParams::new(san).map_err(Error::from)?;
+
+    params.is_ca = IsCa::ExplicitNoCa;
+    profile.apply(&mut params);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, cn.to_owned());
+    let now = OffsetDateTime::now_utc();
+    let max = days_left(&x509::pem_to_der(&ca_cert, "CERTIFICATE")?, now)?;
+    if days > max {
+        return Err(Error::Other(format!(
+            "a certificate valid for {days} days would outlive the root CA; at most {max} days are possible"
+        )));
+    }
+    params.not_before = now;
+    params.not_after = now + Duration::days(days.into());
+    params.serial_number = Some(x509::random_serial());
+
+    debug!("signing certificate");
+    let (cert, key_pem) = match key {
+        KeySource::Csr(csr) => {
+            revocation::ensure_key_allowed(&csr.public_key.subject_public_key_info())?;
+            let cert = params
+                .signed_by(&csr.public_key, &ca)
+                .map_err(Error::from)?;
+            (cert, None)
+        }
+        KeySource::Generate(alg) => {
+            let key = alg.generate()?;
+            let cert = params.signed_by(&key, &ca).map_err(Error::from)?;
+            let key_pem: Zeroizing<String> = Zeroizing::new(key.serialize_pem());
+            (cert, Some(key_pem))
+        }
+    };
+    let cert_pem = cert.pem();
+
+    fs::write_cert(cn, &cert_pem, key_pem.as_deref().map(|k| k.as_str()))?;
+    let issued = IssuedCert::from_der(cert.der())?;
+    fs::record_issued(&issued, &cert_pem)?;
+    Ok((issued, cert_pem, key_pem))
 }
 
 impl crate::cmd::Runnable for SignCertArgs {
     fn run(self, json: bool) -> Result<()> {
-        let (ca_cert, ca_key) = fs::read_root_ca()?;
-        let ca_key = KeyPair::from_pem(&ca_key).map_err(Error::from)?;
-        let ca = Issuer::from_ca_cert_pem(&ca_cert, ca_key).map_err(Error::from)?;
-
-        let mut params = CertificateParams::new(self.san).map_err(Error::from)?;
-      
-        params.is_ca = IsCa::ExplicitNoCa;
-        params
-            .distinguished_name
-            .push(DnType::CommonName, self.cn.to_owned());
-        let now = OffsetDateTime::now_utc();
-        params.not_after = now + Duration::days(self.days.into());
-
-        debug!("signing certificate");
-        let key = KeyPair::generate().map_err(Error::from)?;
-        let cert = params.signed_by(&key, &ca).map_err(Error::from)?;
-        let cert_pem = cert.pem();
-        let key_pem: Zeroizing<String> = Zeroizing::new(key.serialize_pem());
-
-        fs::write_cert(&self.cn, &cert_pem, &key_pem)?;
+        let csr = match &self.csr {
+            Some(path) => {
+                let csr = std::fs::read_to_string(path).map_err(Error::from)?;
+                Some(CertificateSigningRequestParams::from_pem(&csr).map_err(Error::from)?)
+            }
+            None => None,
+        };
+        let key = match &csr {
+            Some(csr) => KeySource::Csr(csr),
+            None => KeySource::Generate(self.key_algorithm),
+        };
+        issue(&self.cn, self.san, self.days, self.profile, key)?;
         audit::emit("sign-cert", &self.cn, json)?;
 
         event!(Level::INFO, cn = %self.cn, "certificate signed");
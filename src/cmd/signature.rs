@@ -1,29 +1,395 @@
 use crate::cmd::Runnable;
 use crate::error::{Error, Result};
-use crate::util::audit;
+use crate::util::envelope::{Envelope, HashAlg};
+use crate::util::keystore::{self, PublicIdentity};
+use crate::util::manifest::{Filter, Manifest};
+use crate::util::pq::Algorithm;
+use crate::util::{audit, fs, pqcert, tsp, x509};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use chrono::{DateTime, Utc};
 use clap::{ArgGroup, Args};
-use crypt_guard::KDF::{
-    Detached, Dilithium2, Dilithium3, Dilithium5, Falcon512, Falcon1024, KeyOperations, Signature,
-};
-use crypt_guard::error::CryptError;
-use crypt_guard::*;
-use std::fs;
-use tracing::{error, event, info};
-use zeroize::Zeroize;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use tracing::{debug, error, event, info, warn};
+
+/// Keystore identity and envelope options used when signing.
+#[derive(Args, Debug)]
+pub struct SignerArgs {
+    /// Keystore key to sign with (see `keygen`)
+    #[arg(long)]
+    pub key: Option<String>,
+
+    /// File holding the keystore passphrase (defaults to $HYPATIA_PASSPHRASE or a prompt)
+    #[arg(long)]
+    pub passphrase_file: Option<String>,
+
+    /// Digest the data is pre-hashed with before signing
+    #[arg(long, value_enum, default_value = "sha3-512")]
+    pub hash: HashAlg,
+
+    /// Free-form comment stored in the signature envelope
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Embed the key's certificate from `certify-key` in the signature envelope
+    #[arg(long)]
+    pub embed_cert: bool,
+
+    /// Countersign the envelope with an RFC 3161 timestamp from this TSA URL
+    #[arg(long)]
+    pub tsa: Option<String>,
+
+    /// CA certificate for an https TSA (defaults to this CA's root)
+    #[arg(long, requires = "tsa")]
+    pub tls_ca: Option<String>,
+}
+
+impl SignerArgs {
+    /// Unlocks the key and signs the digest returned by `digest`.
+    pub fn envelope(
+        &self,
+        digest: impl FnOnce(HashAlg) -> Result<Vec<u8>>,
+    ) -> Result<(PublicIdentity, Envelope)> {
+        let name = self
+            .key
+            .as_deref()
+            .ok_or_else(|| Error::Other("--key is required for signing".into()))?;
+        let cert = if self.embed_cert {
+            let pem = fs::read_identity_cert(name)?.ok_or_else(|| {
+                Error::Other(format!("key {name} has no certificate; run `certify-key`"))
+            })?;
+            Some(x509::pem_to_der(&pem, "CERTIFICATE")?)
+        } else {
+            None
+        };
+        let pass = keystore::passphrase(self.passphrase_file.as_deref())?;
+        let (identity, sk) = keystore::unlock(name, &pass)?;
+        let mut env = Envelope::sign(
+            &identity,
+            &sk,
+            self.hash,
+            digest(self.hash)?,
+            cert,
+            self.comment.clone(),
+        )?;
+        if let Some(url) = &self.tsa {
+            env.timestamp = Some(tsp::fetch(
+                url,
+                &env.timestamp_digest()?,
+                self.tls_ca.as_deref(),
+            )?);
+            info!(tsa = %url, "signature timestamped");
+        }
+        Ok((identity, env))
+    }
+}
+
+/// Which signers are trusted when verifying, and how many must agree.
+#[derive(Args, Debug)]
+pub struct TrustArgs {
+    /// Public key (`.pub` written by `keygen`) to verify against; repeatable
+    #[arg(long)]
+    pub pubkey: Vec<String>,
+
+    /// Treat --pubkey as a raw public key of this algorithm, as written before keystore
+    /// identities existed
+    #[arg(long, value_enum, requires = "pubkey")]
+    pub algorithm: Option<Algorithm>,
+
+    /// Signer certificate issued by `certify-key`; its chain, expiry and revocation
+    /// status are checked before the signature is accepted. Repeatable
+    #[arg(long)]
+    pub cert: Vec<String>,
+
+    /// Trusted root certificate for signer certificates (defaults to this CA's root)
+    #[arg(long)]
+    pub ca: Option<String>,
+
+    /// CRL (PEM or DER) to check signer certificates against
+    #[arg(long)]
+    pub crl: Option<String>,
+
+    /// Directory of trusted `.pub` keys consulted when no key or certificate is given
+    #[arg(long, default_value = fs::TRUSTED_KEYS_DIR)]
+    pub trusted_keys: String,
+
+    /// Number of distinct trusted signers required
+    #[arg(long, default_value = "1")]
+    pub threshold: usize,
+
+    /// Root certificate the TSA of a timestamped signature must chain to (defaults to
+    /// this CA's root)
+    #[arg(long)]
+    pub tsa_ca: Option<String>,
+
+    /// Reject signatures without a valid RFC 3161 timestamp
+    #[arg(long)]
+    pub require_timestamp: bool,
+}
+
+impl TrustArgs {
+    /// Checks a signer certificate's chain, expiry and revocation status at `at`.
+    fn validate_cert(&self, cert: &[u8], at: DateTime<Utc>) -> Result<PublicIdentity> {
+        let root = match &self.ca {
+            Some(path) => std::fs::read_to_string(path).map_err(Error::from)?,
+            None => fs::read_root_cert()?,
+        };
+        let root = x509::pem_to_der(&root, "CERTIFICATE")?;
+        let crl = match &self.crl {
+            Some(path) => Some(x509::read_pem_or_der(path, "X509 CRL")?),
+            None => None,
+        };
+        pqcert::validate(cert, &root, crl.as_deref(), at)
+    }
+
+    /// Certificates named by `--cert`, validated per signature once its time is known.
+    fn explicit_certs(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.cert
+            .iter()
+            .map(|path| {
+                let pem = std::fs::read_to_string(path).map_err(Error::from)?;
+                Ok((path.clone(), x509::pem_to_der(&pem, "CERTIFICATE")?))
+            })
+            .collect()
+    }
+
+    /// Keys named by `--pubkey`.
+    fn explicit_keys(&self) -> Result<Vec<PublicIdentity>> {
+        let mut keys = Vec::new();
+        for path in &self.pubkey {
+            keys.push(match self.algorithm {
+                Some(algorithm) => {
+                    let pk = std::fs::read(path).map_err(Error::from)?;
+                    PublicIdentity {
+                        name: path.clone(),
+                        algorithm,
+                        key_id: keystore::key_id(&pk),
+                        public_key: B64.encode(pk),
+                        classical: None,
+                        kem: None,
+                    }
+                }
+                None => fs::read_public_identity(path)?,
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Keys from the trusted-keys directory, used only when no explicit key was given.
+    fn directory_keys(&self) -> Result<Vec<PublicIdentity>> {
+        if !self.cert.is_empty() || !self.pubkey.is_empty() {
+            return Ok(vec![]);
+        }
+        fs::read_trusted_keys(&self.trusted_keys)
+    }
+
+    /// Verifies the envelope's timestamp, if any, and returns the time it attests.
+    fn timestamp(&self, env: &Envelope) -> Result<Option<DateTime<Utc>>> {
+        if env.timestamp.is_none() {
+            if self.require_timestamp {
+                return Err(Error::Other("signature is not timestamped".into()));
+            }
+            return Ok(None);
+        }
+        let root = match &self.tsa_ca {
+            Some(path) => std::fs::read_to_string(path).map_err(Error::from)?,
+            None => fs::read_root_cert()?,
+        };
+        let info = env.verify_timestamp(&x509::pem_to_der(&root, "CERTIFICATE")?)?;
+        Ok(info.map(|info| info.gen_time))
+    }
+
+    /// Key an envelope is checked against, in order of preference: `--cert` and
+    /// `--pubkey`, a certificate embedded in the envelope, then the trusted-keys
+    /// directory. Certificates are validated at `at`, the time the signature was
+    /// timestamped or now. A `.pk` file beside the signed data is never consulted.
+    fn key_for(
+        &self,
+        env: &Envelope,
+        at: DateTime<Utc>,
+        certs: &[(String, Vec<u8>)],
+        explicit: &[PublicIdentity],
+        directory: &[PublicIdentity],
+    ) -> Result<PublicIdentity> {
+        if self.cert.is_empty()
+            && self.pubkey.is_empty()
+            && let Some(cert) = &env.cert
+        {
+            return self.validate_cert(cert, at);
+        }
+        if let Some(key) = explicit
+            .iter()
+            .chain(directory)
+            .find(|k| k.key_id == env.key_id)
+        {
+            return Ok(key.clone());
+        }
+        let mut rejected = None;
+        for (path, cert) in certs {
+            match self.validate_cert(cert, at) {
+                Ok(key) if key.key_id == env.key_id => return Ok(key),
+                Ok(_) => {}
+                Err(e) => rejected = Some(format!("{path}: {}", e.plain())),
+            }
+        }
+        Err(Error::Other(match rejected {
+            Some(reason) => format!(
+                "signing key {} is not trusted; certificate rejected ({reason})",
+                env.key_id
+            ),
+            None => format!("signing key {} is not trusted", env.key_id),
+        }))
+    }
+
+    /// Verifies every envelope in a bundle against `digest(hash)` of the signed data
+    /// and returns the distinct valid signers. Fails when fewer than `--threshold`
+    /// signers are valid.
+    pub fn verify_bundle(
+        &self,
+        envelopes: &[Envelope],
+        mut digest: impl FnMut(HashAlg) -> Result<Vec<u8>>,
+    ) -> Result<Vec<PublicIdentity>> {
+        let certs = self.explicit_certs()?;
+        let explicit = self.explicit_keys()?;
+        let directory = self.directory_keys()?;
+        let mut digests: HashMap<HashAlg, Vec<u8>> = HashMap::new();
+        let mut valid: Vec<PublicIdentity> = Vec::new();
+        for env in envelopes {
+            let expected = match digests.entry(env.hash_alg) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(digest(env.hash_alg)?),
+            };
+            let checked = if *expected != env.digest {
+                Err(Error::Other("data was modified after signing".into()))
+            } else {
+                self.timestamp(env).and_then(|time| {
+                    let at = time.unwrap_or_else(Utc::now);
+                    let key = self.key_for(env, at, &certs, &explicit, &directory)?;
+                    env.verify(&key)?;
+                    Ok((key, time))
+                })
+            };
+            match checked {
+                Ok((key, time)) => {
+                    info!(
+                        key = %key.name,
+                        key_id = %key.key_id,
+                        algorithm = %env.algorithm_name(),
+                        signed_at = %env.signed_at,
+                        timestamp = time.map(|t| t.to_string()).unwrap_or_default(),
+                        comment = env.comment.as_deref().unwrap_or_default(),
+                        "valid signature"
+                    );
+                    if !valid.iter().any(|k| k.key_id == key.key_id) {
+                        valid.push(key);
+                    }
+                }
+                Err(e) => {
+                    warn!(key_id = %env.key_id, error = %e.plain(), "signature rejected")
+                }
+            }
+        }
+        if valid.len() < self.threshold.max(1) {
+            error!(
+                valid = valid.len(),
+                required = self.threshold,
+                "not enough valid signatures"
+            );
+            return Err(Error::Other(format!(
+                "verification failed: {} of {} required signers valid",
+                valid.len(),
+                self.threshold.max(1)
+            )));
+        }
+        Ok(valid)
+    }
+
+    /// Verifies a raw signature from before envelopes existed over `data`.
+    pub fn verify_raw(&self, sig: &[u8], data: &[u8]) -> Result<PublicIdentity> {
+        let mut keys = self.explicit_keys()?;
+        for (path, cert) in self.explicit_certs()? {
+            match self.validate_cert(&cert, Utc::now()) {
+                Ok(key) => keys.push(key),
+                Err(e) => warn!(%path, error = %e.plain(), "signer certificate rejected"),
+            }
+        }
+        keys.extend(self.directory_keys()?);
+        if keys.is_empty() {
+            return Err(Error::Other(format!(
+                "no trusted keys; pass --pubkey or add .pub files to {}",
+                self.trusted_keys
+            )));
+        }
+        if self.threshold > 1 {
+            return Err(Error::Other(
+                "a raw signature has a single signer; re-sign to use --threshold".into(),
+            ));
+        }
+        for key in keys {
+            if key.algorithm.verify(&key.public_key()?, sig, data) {
+                return Ok(key);
+            }
+        }
+        error!("signature verification failed");
+        Err(Error::Other("verification failed".into()))
+    }
+}
+
+/// Names and key IDs of `signers`, for the audit log.
+pub fn describe(signers: &[PublicIdentity]) -> String {
+    signers
+        .iter()
+        .map(|k| format!("{} {}", k.name, k.key_id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
 #[derive(Args, Debug)]
 #[command(group(ArgGroup::new("mode").required(true).args(["sign", "verify"])))]
+#[command(group(ArgGroup::new("target").required(true).args(["file", "dir"])))]
 pub struct SignatureArgs {
     /// File to sign or verify
     #[arg(long)]
-    pub file: String,
+    pub file: Option<String>,
 
-    /// Algorithm: falcon512, falcon1024, dilithium2, dilithium3, dilithium5
-    #[arg(long, default_value = "falcon512")]
-    pub algorithm: String,
+    /// Directory tree to sign or verify through a signed manifest
+    #[arg(long)]
+    pub dir: Option<String>,
 
-    /// Sign the message
+    /// Manifest of --dir (defaults to `<dir>.manifest`)
+    #[arg(long, requires = "dir")]
+    pub manifest: Option<String>,
+
+    /// Only cover files under --dir matching this glob; repeatable
+    #[arg(long, requires = "dir")]
+    pub include: Vec<String>,
+
+    /// Leave out files under --dir matching this glob; repeatable
+    #[arg(long, requires = "dir")]
+    pub exclude: Vec<String>,
+
+    /// Signature bundle (defaults to `<file>.sig` or `<manifest>.sig`)
     #[arg(long)]
+    pub sig: Option<String>,
+
+    /// Write the signature bundle ASCII-armored instead of binary
+    #[arg(long)]
+    pub armor: bool,
+
+    /// Add the signature to an existing bundle instead of replacing it
+    #[arg(long, requires = "sign")]
+    pub append: bool,
+
+    #[command(flatten)]
+    pub signer: SignerArgs,
+
+    #[command(flatten)]
+    pub trust: TrustArgs,
+
+    /// Sign the message
+    #[arg(long, requires = "key")]
     pub sign: bool,
 
     /// Verify the message
@@ -31,106 +397,179 @@ pub struct SignatureArgs {
     pub verify: bool,
 }
 
-impl Runnable for SignatureArgs {
-    fn run(self, json: bool) -> Result<()> {
-        let data = fs::read(&self.file).map_err(Error::from)?;
-        if self.sign {
-            match self.algorithm.as_str() {
-                "falcon512" => {
-                    let (pk, mut sk) = FalconKeypair!(512);
-                    let sig = Signature!(Falcon, sk.to_owned(), 512, data.to_owned(), Detached);
-                    sk.zeroize();
-                    fs::write(format!("{}.pk", self.file), pk).map_err(Error::from)?;
-                    fs::write(format!("{}.sig", self.file), sig).map_err(Error::from)?;
-                }
-                "falcon1024" => {
-                    let (pk, mut sk) = FalconKeypair!(1024);
-                    let sig = Signature!(Falcon, sk.to_owned(), 1024, data.to_owned(), Detached);
-                    sk.zeroize();
-                    fs::write(format!("{}.pk", self.file), pk).map_err(Error::from)?;
-                    fs::write(format!("{}.sig", self.file), sig).map_err(Error::from)?;
-                }
-                "dilithium2" => {
-                    let (pk, mut sk) = DilithiumKeypair!(2);
-                    let sig = Signature!(Dilithium, sk.to_owned(), 2, data.to_owned(), Detached);
-                    sk.zeroize();
-                    fs::write(format!("{}.pk", self.file), pk).map_err(Error::from)?;
-                    fs::write(format!("{}.sig", self.file), sig).map_err(Error::from)?;
-                }
-                "dilithium3" => {
-                    let (pk, mut sk) = DilithiumKeypair!(3);
-                    let sig = Signature!(Dilithium, sk.to_owned(), 3, data.to_owned(), Detached);
-                    sk.zeroize();
-                    fs::write(format!("{}.pk", self.file), pk).map_err(Error::from)?;
-                    fs::write(format!("{}.sig", self.file), sig).map_err(Error::from)?;
-                }
-                "dilithium5" => {
-                    let (pk, mut sk) = DilithiumKeypair!(5);
-                    let sig = Signature!(Dilithium, sk.to_owned(), 5, data.to_owned(), Detached);
-                    sk.zeroize();
-                    fs::write(format!("{}.pk", self.file), pk).map_err(Error::from)?;
-                    fs::write(format!("{}.sig", self.file), sig).map_err(Error::from)?;
-                }
-                _ => return Err(Error::Other("unknown algorithm".into())),
+impl SignatureArgs {
+    /// The file the signature bundle covers: `--file` or the manifest of `--dir`.
+    fn signed_file(&self) -> String {
+        match (&self.file, &self.dir) {
+            (Some(file), _) => file.clone(),
+            (None, dir) => self.manifest.clone().unwrap_or_else(|| {
+                format!(
+                    "{}.manifest",
+                    dir.as_deref().unwrap_or_default().trim_end_matches('/')
+                )
+            }),
+        }
+    }
+
+    fn sig_path(&self) -> String {
+        self.sig
+            .clone()
+            .unwrap_or_else(|| format!("{}.sig", self.signed_file()))
+    }
+
+    fn filter(&self) -> Filter {
+        Filter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+
+    /// Writes the manifest of `--dir`. When appending, the tree is rebuilt with the
+    /// hash and filters of the existing manifest and must still match it.
+    fn write_manifest(&self, dir: &str) -> Result<()> {
+        let path = self.signed_file();
+        if self.append && Path::new(&self.sig_path()).exists() {
+            let text = std::fs::read_to_string(&path).map_err(Error::from)?;
+            let signed = Manifest::parse(&text)?;
+            let actual = Manifest::build(Path::new(dir), signed.hash, signed.filter.clone())?;
+            if actual.to_text() != text {
+                return Err(Error::Other(format!(
+                    "{dir} no longer matches {path}; verify it before adding signatures"
+                )));
             }
-            info!("signature stored");
-            audit::emit("signature-sign", &self.algorithm, json)?;
-            event!(tracing::Level::INFO, "file signed");
-        } else if self.verify {
-            let sig = fs::read(format!("{}.sig", self.file)).map_err(Error::from)?;
-            let pk = fs::read(format!("{}.pk", self.file)).map_err(Error::from)?;
-            let res = match self.algorithm.as_str() {
-                "falcon512" => Verify!(
-                    Falcon,
-                    pk.to_owned(),
-                    512,
-                    sig.to_owned(),
-                    data.to_owned(),
-                    Detached
-                ),
-                "falcon1024" => Verify!(
-                    Falcon,
-                    pk.to_owned(),
-                    1024,
-                    sig.to_owned(),
-                    data.to_owned(),
-                    Detached
-                ),
-                "dilithium2" => Verify!(
-                    Dilithium,
-                    pk.to_owned(),
-                    2,
-                    sig.to_owned(),
-                    data.to_owned(),
-                    Detached
-                ),
-                "dilithium3" => Verify!(
-                    Dilithium,
-                    pk.to_owned(),
-                    3,
-                    sig.to_owned(),
-                    data.to_owned(),
-                    Detached
-                ),
-                "dilithium5" => Verify!(
-                    Dilithium,
-                    pk.to_owned(),
-                    5,
-                    sig.to_owned(),
-                    data.to_owned(),
-                    Detached
-                ),
-                _ => return Err(Error::Other("unknown algorithm".into())),
-            };
-            if res {
-                info!("signature verified");
-            } else {
-                error!("signature verification failed");
-                return Err(Error::Other("verification failed".into()));
+            debug!(%path, "manifest unchanged");
+            return Ok(());
+        }
+        let manifest = Manifest::build(Path::new(dir), self.signer.hash, self.filter())?;
+        std::fs::write(&path, manifest.to_text()).map_err(Error::from)?;
+        info!(%path, files = manifest.entries.len(), "manifest written");
+        Ok(())
+    }
+
+    /// Compares `--dir` with the signed manifest under the filters the signer recorded;
+    /// the verifier cannot narrow what was committed to.
+    fn check_manifest(&self, dir: &str) -> Result<()> {
+        let text = std::fs::read_to_string(self.signed_file()).map_err(Error::from)?;
+        let signed = Manifest::parse(&text)?;
+        if (!self.include.is_empty() || !self.exclude.is_empty())
+            && (self.include != signed.filter.include || self.exclude != signed.filter.exclude)
+        {
+            return Err(Error::Other(
+                "--include and --exclude differ from the filters recorded in the signed \
+                 manifest; verify without them"
+                    .into(),
+            ));
+        }
+        let keep = signed.filter.matcher()?;
+        let actual = Manifest::build(Path::new(dir), signed.hash, signed.filter.clone())?;
+
+        let diff = signed.diff(&actual, keep);
+        for path in &diff.added {
+            warn!(%path, "added");
+        }
+        for path in &diff.removed {
+            warn!(%path, "removed");
+        }
+        for path in &diff.modified {
+            warn!(%path, "modified");
+        }
+        if !diff.is_empty() {
+            error!("directory does not match the signed manifest");
+            return Err(Error::Other(format!(
+                "signature is valid but {dir} differs from the manifest: \
+                 {} added, {} removed, {} modified",
+                diff.added.len(),
+                diff.removed.len(),
+                diff.modified.len()
+            )));
+        }
+        info!(
+            files = actual.entries.len(),
+            "directory matches the manifest"
+        );
+        Ok(())
+    }
+
+    fn sign(&self, json: bool) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            self.write_manifest(dir)?;
+        }
+        let file = self.signed_file();
+        let (identity, env) = self.signer.envelope(|hash| hash.digest_file(&file))?;
+
+        let mut bundle = Vec::new();
+        let path = self.sig_path();
+        if self.append && Path::new(&path).exists() {
+            let existing = std::fs::read(&path).map_err(Error::from)?;
+            bundle = Envelope::parse_bundle(&existing)?.ok_or_else(|| {
+                Error::Other(format!(
+                    "{path} is a raw signature and cannot be appended to"
+                ))
+            })?;
+            if bundle.iter().any(|e| e.key_id == env.key_id) {
+                return Err(Error::Other(format!(
+                    "key {} already signed this bundle",
+                    identity.name
+                )));
+            }
+            if bundle
+                .iter()
+                .any(|e| e.hash_alg == env.hash_alg && e.digest != env.digest)
+            {
+                return Err(Error::Other(
+                    "existing signatures were made over different file contents".into(),
+                ));
             }
-            audit::emit("signature-verify", &self.algorithm, json)?;
-            event!(tracing::Level::INFO, "verification complete");
+            debug!(count = bundle.len(), "appending to signature bundle");
         }
+        bundle.push(env);
+        std::fs::write(&path, Envelope::encode_bundle(&bundle, self.armor)?)
+            .map_err(Error::from)?;
+
+        info!(
+            key = %identity.name,
+            key_id = %identity.key_id,
+            signatures = bundle.len(),
+            "signature stored"
+        );
+        audit::emit("signature-sign", &describe(&[identity]), json)?;
+        event!(tracing::Level::INFO, "file signed");
         Ok(())
     }
+
+    fn verify(&self, json: bool) -> Result<()> {
+        let file = self.signed_file();
+        if Path::new(&format!("{file}.pk")).exists() {
+            warn!("ignoring untrusted public key stored beside the file");
+        }
+        let sig = std::fs::read(self.sig_path()).map_err(Error::from)?;
+        let signers = match Envelope::parse_bundle(&sig)? {
+            Some(bundle) => self
+                .trust
+                .verify_bundle(&bundle, |hash| hash.digest_file(&file))?,
+            None => {
+                debug!("reading raw signature");
+                // raw signatures cover the whole file, so it has to be loaded
+                let data = std::fs::read(&file).map_err(Error::from)?;
+                vec![self.trust.verify_raw(&sig, &data)?]
+            }
+        };
+        info!(signers = %describe(&signers), "signature verified");
+        if let Some(dir) = &self.dir {
+            self.check_manifest(dir)?;
+        }
+        audit::emit("signature-verify", &describe(&signers), json)?;
+        event!(tracing::Level::INFO, "verification complete");
+        Ok(())
+    }
+}
+
+impl Runnable for SignatureArgs {
+    fn run(self, json: bool) -> Result<()> {
+        if self.sign {
+            self.sign(json)
+        } else {
+            self.verify(json)
+        }
+    }
 }
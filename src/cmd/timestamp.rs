@@ -0,0 +1,113 @@
+use crate::cmd::Runnable;
+use crate::error::{Error, Result};
+use crate::util::envelope::{ARMOR_TAG, Envelope, HashAlg};
+use crate::util::{audit, fs, tsp, x509};
+use clap::{ArgGroup, Args};
+use tracing::{Level, event, info};
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["file", "sig"])))]
+pub struct TimestampArgs {
+    /// File to timestamp; the token is written to --token
+    #[arg(long)]
+    pub file: Option<String>,
+
+    /// Signature bundle whose envelopes are countersigned in place
+    #[arg(long, conflicts_with = "verify")]
+    pub sig: Option<String>,
+
+    /// Time-stamp token of --file (defaults to `<file>.tst`)
+    #[arg(long, requires = "file")]
+    pub token: Option<String>,
+
+    /// TSA URL, e.g. https://ca.example.com:8080/tsa
+    #[arg(long, required_unless_present = "verify")]
+    pub url: Option<String>,
+
+    /// CA certificate for an https TSA (defaults to this CA's root)
+    #[arg(long)]
+    pub tls_ca: Option<String>,
+
+    /// Verify the token of --file instead of requesting one
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Root certificate the TSA must chain to (defaults to this CA's root)
+    #[arg(long, requires = "verify")]
+    pub tsa_ca: Option<String>,
+}
+
+impl TimestampArgs {
+    fn url(&self) -> Result<&str> {
+        self.url
+            .as_deref()
+            .ok_or_else(|| Error::Other("--url is required".into()))
+    }
+
+    fn token_path(&self, file: &str) -> String {
+        self.token.clone().unwrap_or_else(|| format!("{file}.tst"))
+    }
+
+    /// Requests a token over the SHA-512 of `file`.
+    fn stamp_file(&self, file: &str, json: bool) -> Result<()> {
+        let digest = HashAlg::Sha512.digest_file(file)?;
+        let token = tsp::fetch(self.url()?, &digest, self.tls_ca.as_deref())?;
+        let path = self.token_path(file);
+        std::fs::write(&path, &token).map_err(Error::from)?;
+        info!(%file, token = %path, "time-stamp token stored");
+        audit::emit("timestamp", file, json)
+    }
+
+    fn verify_file(&self, file: &str, json: bool) -> Result<()> {
+        let token = std::fs::read(self.token_path(file)).map_err(Error::from)?;
+        let root = match &self.tsa_ca {
+            Some(path) => std::fs::read_to_string(path).map_err(Error::from)?,
+            None => fs::read_root_cert()?,
+        };
+        let digest = HashAlg::Sha512.digest_file(file)?;
+        let stamp = tsp::verify(&token, &digest, &x509::pem_to_der(&root, "CERTIFICATE")?)?;
+        info!(%file, serial = %stamp.serial, time = %stamp.gen_time, "time-stamp token valid");
+        audit::emit(
+            "timestamp-verify",
+            &format!("{file} {} {}", stamp.serial, stamp.gen_time),
+            json,
+        )
+    }
+
+    /// Countersigns every envelope in the bundle that has no timestamp yet.
+    fn stamp_bundle(&self, path: &str, json: bool) -> Result<()> {
+        let data = std::fs::read(path).map_err(Error::from)?;
+        let mut bundle = Envelope::parse_bundle(&data)?.ok_or_else(|| {
+            Error::Other(format!(
+                "{path} is a raw signature; re-sign it to add a timestamp"
+            ))
+        })?;
+        let armor = std::str::from_utf8(&data)
+            .is_ok_and(|text| text.contains(&format!("-----BEGIN {ARMOR_TAG}-----")));
+        let mut stamped = 0;
+        for env in bundle.iter_mut().filter(|env| env.timestamp.is_none()) {
+            env.timestamp = Some(tsp::fetch(
+                self.url()?,
+                &env.timestamp_digest()?,
+                self.tls_ca.as_deref(),
+            )?);
+            stamped += 1;
+        }
+        std::fs::write(path, Envelope::encode_bundle(&bundle, armor)?).map_err(Error::from)?;
+        info!(%path, stamped, signatures = bundle.len(), "signatures timestamped");
+        audit::emit("timestamp", &format!("{path} {stamped}"), json)
+    }
+}
+
+impl Runnable for TimestampArgs {
+    fn run(self, json: bool) -> Result<()> {
+        match (&self.file, &self.sig) {
+            (Some(file), _) if self.verify => self.verify_file(file, json)?,
+            (Some(file), _) => self.stamp_file(file, json)?,
+            (None, Some(sig)) => self.stamp_bundle(sig, json)?,
+            (None, None) => return Err(Error::Other("nothing to timestamp".into())),
+        }
+        event!(Level::INFO, "timestamp complete");
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use crate::cmd::Runnable;
+use crate::error::Result;
+use crate::util::{audit, revocation};
+use clap::Args;
+use tracing::{Level, event, info};
+
+#[derive(Args, Debug)]
+pub struct UnholdArgs {
+    /// Serial number of the held certificate
+    #[arg(long)]
+    pub serial: String,
+
+    /// Days until nextUpdate of the regenerated CRL
+    #[arg(long, default_value = "7")]
+    pub crl_days: u32,
+}
+
+impl Runnable for UnholdArgs {
+    fn run(self, json: bool) -> Result<()> {
+        let entry = revocation::unhold(&self.serial)?;
+        info!(serial = %entry.serial, "certificate released from hold");
+        audit::emit("unhold", &entry.serial, json)?;
+        event!(Level::INFO, "hold removed");
+
+        crate::cmd::crl::CrlArgs {
+            days: self.crl_days,
+            delta: false,
+        }
+        .run(json)
+    }
+}
@@ -13,17 +13,24 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 impl std::error::Error for Error {}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Error {
+    /// The message without terminal colors, e.g. for API responses.
+    pub fn plain(&self) -> String {
         match self {
-            Error::Io(e) => write!(f, "{}", format!("IO error: {e}").red()),
-            Error::Rcgen(e) => write!(f, "{}", format!("rcgen error: {e}").red()),
-            Error::Serde(e) => write!(f, "{}", format!("serde error: {e}").red()),
-            Error::Other(m) => write!(f, "{}", m.red()),
+            Error::Io(e) => format!("IO error: {e}"),
+            Error::Rcgen(e) => format!("rcgen error: {e}"),
+            Error::Serde(e) => format!("serde error: {e}"),
+            Error::Other(m) => m.clone(),
         }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.plain().red())
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::Io(e)
@@ -58,4 +65,4 @@ mod tests {
         }
         assert!(returns_ok().is_ok());
     }
-}
\ No newline at end of file
+}
@@ -25,20 +25,43 @@ pub struct Cli {
 pub enum Commands {
     /// Generate offline root CA
     InitRoot(cmd::init_root::InitRootArgs),
+    /// Create a named, passphrase-protected signing key
+    Keygen(cmd::keygen::KeygenArgs),
+    /// Certify a Falcon or Dilithium signing key with the root CA
+    CertifyKey(cmd::certify_key::CertifyKeyArgs),
     /// Sign or verify messages
-    Signature(cmd::signature::SignatureArgs),
+    Signature(Box<cmd::signature::SignatureArgs>),
+    /// Sign a message from stdin or --message, or verify a piped message
+    SignMessage(Box<cmd::sign::SignArgs>),
+    /// Request or verify RFC 3161 timestamps
+    Timestamp(cmd::timestamp::TimestampArgs),
+    /// Encrypt a file to one or more Kyber recipients
+    Encrypt(cmd::encrypt::EncryptArgs),
+    /// Decrypt a file with a keystore encryption key
+    Decrypt(cmd::decrypt::DecryptArgs),
     /// Sign a certificate with the root CA
     SignCert(cmd::sign_cert::SignCertArgs),
     /// Serve an HTTP API for certificate requests
-    Serve(cmd::serve::ServeArgs),
+    Serve(Box<cmd::serve::ServeArgs>),
+    /// Add or remove an HTTP basic-auth user of the EST endpoints
+    EstUser(cmd::est_user::EstUserArgs),
+    /// Issue a one-time challenge password for SCEP enrollment
+    ScepChallenge(cmd::scep_challenge::ScepChallengeArgs),
+    /// Create, list or revoke scoped tokens of the HTTP API
+    ApiToken(cmd::api_token::ApiTokenArgs),
     /// Revoke a certificate
     Revoke(cmd::revoke::RevokeArgs),
+    /// Release a certificate from certificateHold
+    Unhold(cmd::unhold::UnholdArgs),
+    /// Issue a signed certificate revocation list
+    Crl(cmd::crl::CrlArgs),
 }
 
 fn main() -> Result<()> {
     fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_ansi(true)
+        .with_writer(std::io::stderr)
         .init();
     info!("hypatia-ca started");
     let cli = Cli::parse();
@@ -46,10 +69,21 @@ fn main() -> Result<()> {
     event!(Level::DEBUG, command = ?cli.command, "dispatching command");
     match cli.command {
         Commands::InitRoot(args) => args.run(json)?,
+        Commands::Keygen(args) => args.run(json)?,
+        Commands::CertifyKey(args) => args.run(json)?,
         Commands::Signature(args) => args.run(json)?,
+        Commands::SignMessage(args) => args.run(json)?,
+        Commands::Timestamp(args) => args.run(json)?,
+        Commands::Encrypt(args) => args.run(json)?,
+        Commands::Decrypt(args) => args.run(json)?,
         Commands::SignCert(args) => args.run(json)?,
         Commands::Serve(args) => args.run(json)?,
+        Commands::EstUser(args) => args.run(json)?,
+        Commands::ScepChallenge(args) => args.run(json)?,
+        Commands::ApiToken(args) => args.run(json)?,
         Commands::Revoke(args) => args.run(json)?,
+        Commands::Unhold(args) => args.run(json)?,
+        Commands::Crl(args) => args.run(json)?,
     }
     Ok(())
 }
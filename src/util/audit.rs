@@ -4,10 +4,16 @@ use std::fs::{self, OpenOptions};
 use std::io::Write;
 use tracing::{Level, debug, event};
 
+#[cfg(not(test))]
 const LOG_FILE: &str = "/opt/hypatia-ca/audit.log";
+/// Tests keep their entries out of the real log.
+#[cfg(test)]
+const LOG_FILE: &str = "/tmp/hypatia-ca-test/audit.log";
 
 pub fn emit(action: &str, details: &str, json: bool) -> Result<()> {
-    fs::create_dir_all("/opt/hypatia-ca").map_err(Error::from)?;
+    if let Some(dir) = std::path::Path::new(LOG_FILE).parent() {
+        fs::create_dir_all(dir).map_err(Error::from)?;
+    }
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
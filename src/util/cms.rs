@@ -0,0 +1,382 @@
+use crate::error::{Error, Result};
+use aes::{Aes128, Aes192, Aes256};
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use des::TdesEde3;
+use rand::RngCore;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use yasna::models::ObjectIdentifier;
+use yasna::{ASN1Error, ASN1ErrorKind, BERReader, Tag};
+use zeroize::Zeroizing;
+
+const OID_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+const OID_ENVELOPED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 3];
+const OID_RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+const OID_AES128_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 2];
+const OID_AES192_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 22];
+const OID_AES256_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 42];
+const OID_DES_EDE3_CBC: &[u64] = &[1, 2, 840, 113549, 3, 7];
+
+/// Degenerate "certs-only" CMS SignedData (RFC 5652 without signers), the PKCS#7 form EST
+/// and SCEP use to return certificates.
+pub fn certs_only(certs: &[&[u8]]) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next()
+                .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+            w.next().write_tagged(Tag::context(0), |w| {
+                w.write_sequence(|w| {
+                    w.next().write_u8(1);
+                    w.next().write_set(|_| {});
+                    w.next().write_sequence(|w| {
+                        w.next().write_oid(&ObjectIdentifier::from_slice(OID_DATA));
+                    });
+                    w.next().write_tagged_implicit(Tag::context(0), |w| {
+                        w.write_set_of(|w| {
+                            for cert in certs {
+                                w.next().write_der(cert);
+                            }
+                        })
+                    });
+                    w.next().write_set(|_| {});
+                })
+            });
+        })
+    })
+}
+
+/// Content-encryption algorithms accepted in EnvelopedData, all in CBC mode. Triple DES
+/// is only here because older SCEP clients know nothing else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentCipher {
+    Aes128,
+    Aes192,
+    Aes256,
+    DesEde3,
+}
+
+impl ContentCipher {
+    fn from_oid(oid: &ObjectIdentifier) -> Option<Self> {
+        [Self::Aes128, Self::Aes192, Self::Aes256, Self::DesEde3]
+            .into_iter()
+            .find(|c| oid.components().as_slice() == c.oid())
+    }
+
+    fn oid(self) -> &'static [u64] {
+        match self {
+            Self::Aes128 => OID_AES128_CBC,
+            Self::Aes192 => OID_AES192_CBC,
+            Self::Aes256 => OID_AES256_CBC,
+            Self::DesEde3 => OID_DES_EDE3_CBC,
+        }
+    }
+
+    fn key_len(self) -> usize {
+        match self {
+            Self::Aes128 => 16,
+            Self::Aes192 | Self::DesEde3 => 24,
+            Self::Aes256 => 32,
+        }
+    }
+
+    fn iv_len(self) -> usize {
+        match self {
+            Self::DesEde3 => 8,
+            _ => 16,
+        }
+    }
+
+    fn encrypt(self, key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        fn run<C: BlockEncryptMut + KeyIvInit>(
+            key: &[u8],
+            iv: &[u8],
+            data: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(C::new_from_slices(key, iv)
+                .map_err(|e| Error::Other(format!("bad content key: {e}")))?
+                .encrypt_padded_vec_mut::<Pkcs7>(data))
+        }
+        match self {
+            Self::Aes128 => run::<cbc::Encryptor<Aes128>>(key, iv, data),
+            Self::Aes192 => run::<cbc::Encryptor<Aes192>>(key, iv, data),
+            Self::Aes256 => run::<cbc::Encryptor<Aes256>>(key, iv, data),
+            Self::DesEde3 => run::<cbc::Encryptor<TdesEde3>>(key, iv, data),
+        }
+    }
+
+    fn decrypt(self, key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        fn run<C: BlockDecryptMut + KeyIvInit>(
+            key: &[u8],
+            iv: &[u8],
+            data: &[u8],
+        ) -> Result<Vec<u8>> {
+            C::new_from_slices(key, iv)
+                .map_err(|e| Error::Other(format!("bad content key: {e}")))?
+                .decrypt_padded_vec_mut::<Pkcs7>(data)
+                .map_err(|_| Error::Other("enveloped content does not decrypt".into()))
+        }
+        match self {
+            Self::Aes128 => run::<cbc::Decryptor<Aes128>>(key, iv, data),
+            Self::Aes192 => run::<cbc::Decryptor<Aes192>>(key, iv, data),
+            Self::Aes256 => run::<cbc::Decryptor<Aes256>>(key, iv, data),
+            Self::DesEde3 => run::<cbc::Decryptor<TdesEde3>>(key, iv, data),
+        }
+    }
+}
+
+/// An RSA key-transport recipient of EnvelopedData, named by issuer and serial number.
+pub struct Recipient {
+    /// DER IssuerAndSerialNumber
+    issuer_and_serial: Vec<u8>,
+    key: RsaPublicKey,
+}
+
+impl Recipient {
+    pub fn from_cert(der: &[u8]) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| Error::Other(format!("bad recipient certificate: {e}")))?;
+        let key = RsaPublicKey::from_public_key_der(cert.public_key().raw)
+            .map_err(|_| Error::Other("recipient certificate has no RSA key".into()))?;
+        Ok(Self {
+            issuer_and_serial: yasna::construct_der(|w| {
+                w.write_sequence(|w| {
+                    w.next().write_der(cert.issuer().as_raw());
+                    w.next().write_bigint_bytes(cert.raw_serial(), true);
+                })
+            }),
+            key,
+        })
+    }
+}
+
+/// Encrypts `content` for `recipient` as CMS EnvelopedData with a fresh content key.
+pub fn envelope(content: &[u8], recipient: &Recipient, cipher: ContentCipher) -> Result<Vec<u8>> {
+    let mut key = Zeroizing::new(vec![0u8; cipher.key_len()]);
+    let mut iv = vec![0u8; cipher.iv_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    rand::thread_rng().fill_bytes(&mut iv);
+    let ciphertext = cipher.encrypt(&key, &iv, content)?;
+    let encrypted_key = recipient
+        .key
+        .encrypt(&mut rand::rngs::OsRng, Pkcs1v15Encrypt, &key)
+        .map_err(|e| Error::Other(format!("content key encryption failed: {e}")))?;
+    Ok(yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next()
+                .write_oid(&ObjectIdentifier::from_slice(OID_ENVELOPED_DATA));
+            w.next().write_tagged(Tag::context(0), |w| {
+                w.write_sequence(|w| {
+                    w.next().write_u8(0);
+                    w.next().write_set(|w| {
+                        w.next().write_sequence(|w| {
+                            w.next().write_u8(0);
+                            w.next().write_der(&recipient.issuer_and_serial);
+                            w.next().write_sequence(|w| {
+                                w.next()
+                                    .write_oid(&ObjectIdentifier::from_slice(OID_RSA_ENCRYPTION));
+                                w.next().write_null();
+                            });
+                            w.next().write_bytes(&encrypted_key);
+                        })
+                    });
+                    w.next().write_sequence(|w| {
+                        w.next().write_oid(&ObjectIdentifier::from_slice(OID_DATA));
+                        w.next().write_sequence(|w| {
+                            w.next()
+                                .write_oid(&ObjectIdentifier::from_slice(cipher.oid()));
+                            w.next().write_bytes(&iv);
+                        });
+                        w.next()
+                            .write_tagged_implicit(Tag::context(0), |w| w.write_bytes(&ciphertext));
+                    });
+                })
+            });
+        })
+    }))
+}
+
+/// The error of DER that does not have the expected structure.
+pub fn invalid() -> ASN1Error {
+    ASN1Error::new(ASN1ErrorKind::Invalid)
+}
+
+/// Reads the next element as raw DER if it carries `tag`.
+pub fn read_if_tag(r: BERReader, tag: Tag) -> yasna::ASN1Result<Vec<u8>> {
+    if r.lookahead_tag()? != tag {
+        return Err(invalid());
+    }
+    r.read_der()
+}
+
+/// AlgorithmIdentifier whose parameters are absent or NULL.
+pub fn read_algorithm(r: BERReader) -> yasna::ASN1Result<ObjectIdentifier> {
+    r.read_sequence(|r| {
+        let oid = r.next().read_oid()?;
+        r.read_optional(|r| r.read_null())?;
+        Ok(oid)
+    })
+}
+
+/// Signed attributes as `(type, first value)` pairs.
+pub fn read_attributes(der: &[u8]) -> Result<Vec<(ObjectIdentifier, Vec<u8>)>> {
+    yasna::parse_ber(der, |r| {
+        r.collect_set_of(|r| {
+            r.read_sequence(|r| {
+                let oid = r.next().read_oid()?;
+                let mut values = r.next().collect_set_of(|r| r.read_der())?;
+                Ok((oid, values.pop().ok_or_else(invalid)?))
+            })
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed signed attributes: {e}")))
+}
+
+/// RSA key-transport entries of an EnvelopedData: `(rid, encrypted key)`.
+type KeyTransports = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn read_recipient(r: BERReader) -> yasna::ASN1Result<Option<(Vec<u8>, Vec<u8>)>> {
+    // only KeyTransRecipientInfo is untagged; key agreement and the rest are skipped
+    if r.lookahead_tag()? != yasna::tags::TAG_SEQUENCE {
+        r.read_der()?;
+        return Ok(None);
+    }
+    r.read_sequence(|r| {
+        r.next().read_u8()?;
+        let rid = r.next().read_der()?;
+        let alg = r.next().read_sequence(|r| {
+            let oid = r.next().read_oid()?;
+            r.read_optional(|r| r.read_der())?;
+            Ok(oid)
+        })?;
+        let encrypted_key = r.next().read_bytes()?;
+        Ok((alg == ObjectIdentifier::from_slice(OID_RSA_ENCRYPTION))
+            .then_some((rid, encrypted_key)))
+    })
+}
+
+/// Decrypts a CMS EnvelopedData (DER or BER) addressed to `key` and returns the content
+/// with the cipher it was encrypted with, so a reply can use the same one.
+pub fn decrypt_enveloped(der: &[u8], key: &RsaPrivateKey) -> Result<(Vec<u8>, ContentCipher)> {
+    let (content_type, recipients, alg, iv, ciphertext) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let content_type = r.next().read_oid()?;
+            let (recipients, alg, iv, ciphertext) = r.next().read_tagged(Tag::context(0), |r| {
+                r.read_sequence(|r| {
+                    r.next().read_u8()?;
+                    r.read_optional(|r| read_if_tag(r, Tag::context(0)))?;
+                    let recipients: KeyTransports = r
+                        .next()
+                        .collect_set_of(read_recipient)?
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    let (alg, iv, ciphertext) = r.next().read_sequence(|r| {
+                        r.next().read_oid()?;
+                        let (alg, iv) = r.next().read_sequence(|r| {
+                            let alg = r.next().read_oid()?;
+                            let iv = r.next().read_bytes()?;
+                            Ok((alg, iv))
+                        })?;
+                        let ciphertext = r
+                            .next()
+                            .read_tagged_implicit(Tag::context(0), |r| r.read_bytes())?;
+                        Ok((alg, iv, ciphertext))
+                    })?;
+                    r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+                    Ok((recipients, alg, iv, ciphertext))
+                })
+            })?;
+            Ok((content_type, recipients, alg, iv, ciphertext))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed enveloped data: {e}")))?;
+    if content_type != ObjectIdentifier::from_slice(OID_ENVELOPED_DATA) {
+        return Err(Error::Other("content is not CMS EnvelopedData".into()));
+    }
+    let cipher = ContentCipher::from_oid(&alg)
+        .ok_or_else(|| Error::Other(format!("unsupported content encryption {alg}")))?;
+    if iv.len() != cipher.iv_len() {
+        return Err(Error::Other("bad content encryption IV".into()));
+    }
+    let content_key = recipients
+        .iter()
+        .find_map(|(_, encrypted)| {
+            key.decrypt(Pkcs1v15Encrypt, encrypted)
+                .ok()
+                .map(Zeroizing::new)
+                .filter(|k| k.len() == cipher.key_len())
+        })
+        .ok_or_else(|| Error::Other("enveloped data is not addressed to this key".into()))?;
+    Ok((cipher.decrypt(&content_key, &iv, &ciphertext)?, cipher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, KeyPair};
+
+    #[test]
+    fn certs_only_carries_certificates() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["a.test".into()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let der = certs_only(&[cert.der()]);
+
+        let certs = yasna::parse_der(&der, |r| {
+            r.read_sequence(|r| {
+                assert_eq!(
+                    r.next().read_oid()?,
+                    ObjectIdentifier::from_slice(OID_SIGNED_DATA)
+                );
+                r.next().read_tagged(Tag::context(0), |r| {
+                    r.read_sequence(|r| {
+                        assert_eq!(r.next().read_u8()?, 1);
+                        r.next().read_der()?;
+                        r.next().read_der()?;
+                        let certs = r.next().read_tagged_implicit(Tag::context(0), |r| {
+                            r.collect_set_of(|r| r.read_der())
+                        })?;
+                        r.next().read_der()?;
+                        Ok(certs)
+                    })
+                })
+            })
+        })
+        .unwrap();
+        assert_eq!(certs, vec![cert.der().to_vec()]);
+    }
+
+    #[test]
+    fn envelope_roundtrip() {
+        // small key: generating RSA keys is slow in debug builds
+        let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+        let recipient = Recipient {
+            issuer_and_serial: yasna::construct_der(|w| {
+                w.write_sequence(|w| {
+                    w.next().write_sequence(|_| {});
+                    w.next().write_u8(1);
+                })
+            }),
+            key: key.to_public_key(),
+        };
+        for cipher in [
+            ContentCipher::Aes128,
+            ContentCipher::Aes256,
+            ContentCipher::DesEde3,
+        ] {
+            let der = envelope(b"enveloped content", &recipient, cipher).unwrap();
+            assert_eq!(
+                decrypt_enveloped(&der, &key).unwrap(),
+                (b"enveloped content".to_vec(), cipher)
+            );
+        }
+        let other = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+        let der = envelope(b"x", &recipient, ContentCipher::Aes128).unwrap();
+        assert!(decrypt_enveloped(&der, &other).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::error::{Error, Result};
+use crate::util::x509::{self, CaSigner};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// RFC 5280 CRLReason codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    RemoveFromCrl,
+    PrivilegeWithdrawn,
+    AaCompromise,
+}
+
+impl Reason {
+    pub fn code(self) -> i64 {
+        match self {
+            Reason::Unspecified => 0,
+            Reason::KeyCompromise => 1,
+            Reason::CaCompromise => 2,
+            Reason::AffiliationChanged => 3,
+            Reason::Superseded => 4,
+            Reason::CessationOfOperation => 5,
+            Reason::CertificateHold => 6,
+            Reason::RemoveFromCrl => 8,
+            Reason::PrivilegeWithdrawn => 9,
+            Reason::AaCompromise => 10,
+        }
+    }
+}
+
+/// One entry of the revocation list as stored on disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Revocation {
+    pub serial: String,
+    pub revoked_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invalidity_date: Option<DateTime<Utc>>,
+}
+
+/// Numbering state persisted between CRL runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CrlState {
+    /// Last CRL number issued, complete or delta.
+    pub last_number: u64,
+    /// Number of the last complete CRL.
+    pub base_number: Option<u64>,
+    /// thisUpdate of the last complete CRL.
+    pub base_issued: Option<DateTime<Utc>>,
+}
+
+/// Validity window and numbering of a CRL to be issued.
+pub struct CrlInfo {
+    pub number: u64,
+    pub this_update: DateTime<Utc>,
+    pub next_update: DateTime<Utc>,
+    /// CRL number of the complete CRL this delta CRL is based on.
+    pub delta_base: Option<u64>,
+}
+
+pub fn to_offset(t: DateTime<Utc>) -> Result<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(t.timestamp()).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Builds and signs an X.509 v2 CRL, returning its DER encoding.
+pub fn build(signer: &CaSigner, info: &CrlInfo, entries: &[Revocation]) -> Result<Vec<u8>> {
+    let this_update = to_offset(info.this_update)?;
+    let next_update = to_offset(info.next_update)?;
+    if next_update <= this_update {
+        return Err(Error::Other("nextUpdate must be after thisUpdate".into()));
+    }
+    let mut revoked = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let invalidity = match entry.invalidity_date {
+            Some(d) => Some(to_offset(d)?),
+            None => None,
+        };
+        revoked.push((
+            x509::parse_serial(&entry.serial)?,
+            to_offset(entry.revoked_at)?,
+            entry.reason.filter(|r| *r != Reason::Unspecified),
+            invalidity,
+        ));
+    }
+
+    let tbs = yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_u8(1);
+            signer.write_algorithm(writer.next());
+            writer.next().write_der(&signer.subject_der);
+            x509::write_time(writer.next(), this_update);
+            x509::write_time(writer.next(), next_update);
+            if !revoked.is_empty() {
+                writer.next().write_sequence(|writer| {
+                    for (serial, revoked_at, reason, invalidity) in &revoked {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_bigint_bytes(serial, true);
+                            x509::write_time(writer.next(), *revoked_at);
+                            if reason.is_none() && invalidity.is_none() {
+                                return;
+                            }
+                            writer.next().write_sequence(|writer| {
+                                if let Some(reason) = reason {
+                                    let value =
+                                        yasna::construct_der(|w| w.write_enum(reason.code()));
+                                    x509::write_extension(
+                                        writer.next(),
+                                        &[2, 5, 29, 21],
+                                        false,
+                                        &value,
+                                    );
+                                }
+                                if let Some(invalidity) = invalidity {
+                                    let value = yasna::construct_der(|w| {
+                                        x509::write_generalized_time(w, *invalidity)
+                                    });
+                                    x509::write_extension(
+                                        writer.next(),
+                                        &[2, 5, 29, 24],
+                                        false,
+                                        &value,
+                                    );
+                                }
+                            });
+                        });
+                    }
+                });
+            }
+            writer
+                .next()
+                .write_tagged(yasna::Tag::context(0), |writer| {
+                    writer.write_sequence(|writer| {
+                        x509::write_authority_key_id(writer.next(), &signer.key_id);
+                        let number = yasna::construct_der(|w| w.write_u64(info.number));
+                        x509::write_extension(writer.next(), &[2, 5, 29, 20], false, &number);
+                        if let Some(base) = info.delta_base {
+                            let base = yasna::construct_der(|w| w.write_u64(base));
+                            x509::write_extension(writer.next(), &[2, 5, 29, 27], true, &base);
+                        }
+                    })
+                });
+        })
+    });
+    signer.sign(&tbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::KeyPair;
+    use x509_parser::prelude::*;
+
+    fn signer() -> CaSigner {
+        CaSigner {
+            subject_der: yasna::construct_der(|w| w.write_sequence(|_| {})),
+            key_id: vec![1, 2, 3, 4],
+            key: KeyPair::generate().unwrap(),
+        }
+    }
+
+    #[test]
+    fn delta_crl_carries_entries_and_indicator() {
+        let now = Utc::now();
+        let info = CrlInfo {
+            number: 7,
+            this_update: now,
+            next_update: now + chrono::Duration::days(1),
+            delta_base: Some(5),
+        };
+        let entries = vec![Revocation {
+            serial: "0a1b".into(),
+            revoked_at: now,
+            reason: Some(Reason::KeyCompromise),
+            invalidity_date: None,
+        }];
+        let der = build(&signer(), &info, &entries).unwrap();
+        let (_, crl) = parse_x509_crl(&der).unwrap();
+        assert_eq!(crl.crl_number().map(|n| n.to_string()), Some("7".into()));
+        let revoked: Vec<_> = crl.iter_revoked_certificates().collect();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].raw_serial(), &[0x0a, 0x1b]);
+        assert_eq!(revoked[0].reason_code().map(|(_, r)| r.0), Some(1));
+        assert!(
+            crl.extensions().iter().any(|e| e.critical
+                && e.oid == x509_parser::oid_registry::OID_X509_EXT_DELTA_CRL_INDICATOR)
+        );
+    }
+}
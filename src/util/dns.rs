@@ -0,0 +1,153 @@
+use crate::error::{Error, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::debug;
+
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Builds a recursive DNS query for the TXT records of `name`.
+pub fn txt_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::Other(format!("invalid DNS name {name}")));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// Extracts the TXT records answering query `id`; the character strings of each record
+/// are concatenated. A name that does not exist yields no records.
+pub fn parse_txt_response(id: u16, msg: &[u8]) -> Result<Vec<String>> {
+    let truncated = || Error::Other("truncated DNS response".into());
+    let u16_at = |pos: usize| -> Result<u16> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    // skips a possibly compressed name and returns the offset after it
+    let skip_name = |mut pos: usize| -> Result<usize> {
+        loop {
+            let len = *msg.get(pos).ok_or_else(truncated)?;
+            match len {
+                0 => return Ok(pos + 1),
+                l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+                l => pos += 1 + l as usize,
+            }
+        }
+    };
+
+    if u16_at(0)? != id {
+        return Err(Error::Other("DNS response does not match the query".into()));
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(Error::Other("DNS message is not a response".into()));
+    }
+    if flags & 0x0200 != 0 {
+        return Err(Error::Other("DNS response was truncated".into()));
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(vec![]),
+        rcode => return Err(Error::Other(format!("DNS server answered rcode {rcode}"))),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pos)? + 4;
+    }
+    let mut out = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(pos)?;
+        let (rtype, class) = (u16_at(pos)?, u16_at(pos + 2)?);
+        let len = u16_at(pos + 8)? as usize;
+        let start = pos + 10;
+        let rdata = msg.get(start..start + len).ok_or_else(truncated)?;
+        pos = start + len;
+        if rtype != TYPE_TXT || class != CLASS_IN {
+            continue;
+        }
+        let mut record = Vec::new();
+        let mut i = 0;
+        while i < rdata.len() {
+            let l = rdata[i] as usize;
+            record.extend_from_slice(rdata.get(i + 1..i + 1 + l).ok_or_else(truncated)?);
+            i += 1 + l;
+        }
+        out.push(String::from_utf8_lossy(&record).into_owned());
+    }
+    Ok(out)
+}
+
+/// Looks up the TXT records of `name` at the recursive resolver `server` over UDP.
+pub async fn lookup_txt(server: SocketAddr, name: &str, timeout: Duration) -> Result<Vec<String>> {
+    let id: u16 = rand::random();
+    let query = txt_query(id, name)?;
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(bind)
+        .await
+        .map_err(Error::from)?;
+    socket.connect(server).await.map_err(Error::from)?;
+    socket.send(&query).await.map_err(Error::from)?;
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(timeout, socket.recv(&mut buf))
+        .await
+        .map_err(|_| Error::Other(format!("DNS server {server} did not answer")))?
+        .map_err(Error::from)?;
+    let records = parse_txt_response(id, &buf[..len])?;
+    debug!(%name, %server, count = records.len(), "TXT lookup");
+    Ok(records)
+}
+
+/// First nameserver listed in /etc/resolv.conf.
+pub fn system_resolver() -> Result<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").map_err(Error::from)?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<std::net::IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| Error::Other("no nameserver in /etc/resolv.conf".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_txt_answers() {
+        let query = txt_query(0x1234, "_acme-challenge.example.test").unwrap();
+        let mut msg = query.clone();
+        // response flags, two answers
+        msg[2..4].copy_from_slice(&[0x81, 0x80]);
+        msg[6..8].copy_from_slice(&[0, 2]);
+        // CNAME answer pointing at the question name, skipped
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        // TXT answer split into two character strings
+        msg.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 8]);
+        msg.extend_from_slice(&[3, b'a', b'b', b'c', 3, b'd', b'e', b'f']);
+
+        assert_eq!(parse_txt_response(0x1234, &msg).unwrap(), vec!["abcdef"]);
+        assert!(parse_txt_response(0x4321, &msg).is_err());
+        assert!(parse_txt_response(0x1234, &msg[..msg.len() - 2]).is_err());
+
+        msg[3] = 0x83;
+        assert!(parse_txt_response(0x1234, &msg).unwrap().is_empty());
+    }
+}
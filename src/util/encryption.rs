@@ -0,0 +1,512 @@
+use crate::error::{Error, Result};
+use crate::util::keystore::{KemKey, KemSecret};
+use crate::util::pq::Kem;
+use rand::RngCore;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::hkdf;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use yasna::Tag;
+use yasna::models::ObjectIdentifier;
+use zeroize::Zeroizing;
+
+const VERSION: u8 = 1;
+/// id-alg-AEADChaCha20Poly1305 (RFC 8103).
+const OID_CHACHA20_POLY1305: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 3, 18];
+/// HKDF salt for key-encryption keys, so they are never shared with another protocol.
+const KDF_SALT: &[u8] = b"hypatia-ca encryption v1";
+
+/// Plaintext bytes per sealed chunk, so files of any size are encrypted in constant memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Largest chunk size accepted from a header.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// Largest header accepted, bounding the memory a hostile file can claim.
+const MAX_HEADER: usize = 16 * 1024 * 1024;
+const TAG_LEN: usize = 16;
+const FILE_KEY_LEN: usize = 32;
+
+/// A recipient a file is encrypted to: the `.pub` of a keystore identity or the subject of
+/// an encryption certificate.
+#[derive(Clone, Debug)]
+pub struct Recipient {
+    pub name: String,
+    pub key: KemKey,
+}
+
+/// Per-recipient copy of the file key.
+#[derive(Clone, Debug)]
+struct RecipientInfo {
+    key_id: Vec<u8>,
+    kem: Kem,
+    ciphertext: Vec<u8>,
+    ephemeral: Option<[u8; 32]>,
+    wrapped_key: Vec<u8>,
+}
+
+/// Header of an encrypted file.
+///
+/// ```text
+/// EncryptedFile ::= SEQUENCE {
+///     version     INTEGER (1),
+///     cipher      OBJECT IDENTIFIER,      -- id-alg-AEADChaCha20Poly1305
+///     chunkSize   INTEGER,
+///     recipients  SEQUENCE OF RecipientInfo }
+///
+/// RecipientInfo ::= SEQUENCE {
+///     keyId       OCTET STRING,
+///     kem         OBJECT IDENTIFIER,
+///     ciphertext  OCTET STRING,           -- Kyber encapsulation
+///     ephemeral   [0] IMPLICIT OCTET STRING OPTIONAL,  -- X25519 public key
+///     wrappedKey  OCTET STRING }
+/// ```
+///
+/// The header is followed by the payload: the file split into `chunkSize` pieces, each
+/// sealed with ChaCha20-Poly1305 under the random file key. Chunk nonces hold a counter
+/// and a final-chunk flag, so reordering or truncating the payload is detected, and the
+/// SHA-256 of the header is the associated data of every chunk.
+struct Header {
+    chunk_size: usize,
+    recipients: Vec<RecipientInfo>,
+}
+
+impl Header {
+    fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(VERSION);
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_CHACHA20_POLY1305));
+                w.next().write_u64(self.chunk_size as u64);
+                w.next().write_sequence_of(|w| {
+                    for r in &self.recipients {
+                        w.next().write_sequence(|w| {
+                            w.next().write_bytes(&r.key_id);
+                            w.next()
+                                .write_oid(&ObjectIdentifier::from_slice(r.kem.oid()));
+                            w.next().write_bytes(&r.ciphertext);
+                            if let Some(ephemeral) = &r.ephemeral {
+                                w.next().write_tagged_implicit(Tag::context(0), |w| {
+                                    w.write_bytes(ephemeral)
+                                });
+                            }
+                            w.next().write_bytes(&r.wrapped_key);
+                        });
+                    }
+                });
+            })
+        })
+    }
+
+    fn from_der(der: &[u8]) -> Result<Self> {
+        let (version, cipher, chunk_size, recipients) = yasna::parse_der(der, |r| {
+            r.read_sequence(|r| {
+                let version = r.next().read_u8()?;
+                let cipher = r.next().read_oid()?;
+                let chunk_size = r.next().read_u64()?;
+                let recipients = r.next().collect_sequence_of(|r| {
+                    r.read_sequence(|r| {
+                        let key_id = r.next().read_bytes()?;
+                        let kem = r.next().read_oid()?;
+                        let ciphertext = r.next().read_bytes()?;
+                        let ephemeral = r.read_optional(|r| {
+                            r.read_tagged_implicit(Tag::context(0), |r| r.read_bytes())
+                        })?;
+                        let wrapped_key = r.next().read_bytes()?;
+                        Ok((key_id, kem, ciphertext, ephemeral, wrapped_key))
+                    })
+                })?;
+                Ok((version, cipher, chunk_size, recipients))
+            })
+        })
+        .map_err(|e| Error::Other(format!("bad encrypted file header: {e}")))?;
+        if version != VERSION {
+            return Err(Error::Other(format!(
+                "unsupported encrypted file version {version}"
+            )));
+        }
+        if cipher.components().as_slice() != OID_CHACHA20_POLY1305 {
+            return Err(Error::Other(format!("unsupported cipher {cipher}")));
+        }
+        let chunk_size = usize::try_from(chunk_size)
+            .ok()
+            .filter(|size| (1..=MAX_CHUNK_SIZE).contains(size))
+            .ok_or_else(|| Error::Other(format!("unsupported chunk size {chunk_size}")))?;
+        let recipients = recipients
+            .into_iter()
+            .map(|(key_id, kem, ciphertext, ephemeral, wrapped_key)| {
+                let kem = Kem::from_oid(kem.components())
+                    .ok_or_else(|| Error::Other(format!("unsupported key encapsulation {kem}")))?;
+                let ephemeral = ephemeral
+                    .map(|key| {
+                        key.try_into()
+                            .map_err(|_| Error::Other("bad X25519 ephemeral key".into()))
+                    })
+                    .transpose()?;
+                Ok(RecipientInfo {
+                    key_id,
+                    kem,
+                    ciphertext,
+                    ephemeral,
+                    wrapped_key,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Header {
+            chunk_size,
+            recipients,
+        })
+    }
+}
+
+/// Derives the key that wraps the file key for one recipient from the Kyber shared
+/// secret and, for hybrid keys, the X25519 shared secret. Both ciphertexts and the
+/// recipient's public keys go into the HKDF info, so neither secret can be replayed
+/// under a different encapsulation.
+fn key_encryption_key(
+    kyber_ss: &[u8],
+    x25519_ss: Option<&[u8]>,
+    r: &RecipientInfo,
+    kyber_pk: &[u8],
+    x25519_pk: Option<&[u8; 32]>,
+) -> Result<LessSafeKey> {
+    let mut ikm = Zeroizing::new(kyber_ss.to_vec());
+    ikm.extend_from_slice(x25519_ss.unwrap_or_default());
+    let ephemeral = r.ephemeral.as_ref().map_or(&[][..], |e| &e[..]);
+    let x25519_pk = x25519_pk.map_or(&[][..], |k| &k[..]);
+    let info = [
+        r.kem.name().as_bytes(),
+        &r.ciphertext,
+        ephemeral,
+        kyber_pk,
+        x25519_pk,
+    ];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, KDF_SALT).extract(&ikm);
+    let okm = prk
+        .expand(&info, &CHACHA20_POLY1305)
+        .map_err(|_| Error::Other("key derivation failed".into()))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn key_id_bytes(pk: &[u8]) -> Vec<u8> {
+    Sha256::digest(pk)[..8].to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Nonce of chunk `index`: a big-endian counter followed by the final-chunk flag.
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = u8::from(last);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn payload_key(file_key: &[u8]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, file_key)
+        .map_err(|_| Error::Other("cannot initialise cipher".into()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Reads until `buf` is full or the input ends; returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads the DER header, a single SEQUENCE, off the front of `reader`.
+fn read_header(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let bad = || Error::Other("not an encrypted file".into());
+    let mut prefix = [0u8; 2];
+    if read_full(reader, &mut prefix)? != 2 || prefix[0] != 0x30 {
+        return Err(bad());
+    }
+    let mut der = prefix.to_vec();
+    let len = if prefix[1] < 0x80 {
+        usize::from(prefix[1])
+    } else {
+        let count = usize::from(prefix[1] & 0x7f);
+        if count == 0 || count > 4 {
+            return Err(bad());
+        }
+        let mut len = [0u8; 4];
+        if read_full(reader, &mut len[4 - count..])? != count {
+            return Err(bad());
+        }
+        der.extend_from_slice(&len[4 - count..]);
+        u32::from_be_bytes(len) as usize
+    };
+    if len > MAX_HEADER {
+        return Err(bad());
+    }
+    let start = der.len();
+    der.resize(start + len, 0);
+    if read_full(reader, &mut der[start..])? != len {
+        return Err(bad());
+    }
+    Ok(der)
+}
+
+/// Encrypts everything `reader` yields to `recipients` and writes the header and sealed
+/// chunks to `writer`. Returns the number of plaintext bytes.
+pub fn encrypt(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    recipients: &[Recipient],
+) -> Result<u64> {
+    if recipients.is_empty() {
+        return Err(Error::Other("no recipients".into()));
+    }
+    let mut file_key = Zeroizing::new([0u8; FILE_KEY_LEN]);
+    rand::thread_rng().fill_bytes(file_key.as_mut());
+
+    let mut infos = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let key = &recipient.key;
+        let kyber_pk = key.public_key()?;
+        let x25519_pk = key.x25519()?;
+        let (kyber_ss, ciphertext) = key.algorithm.encapsulate(&kyber_pk)?;
+        let (ephemeral, x25519_ss) = match &x25519_pk {
+            Some(pk) => {
+                let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+                let public = PublicKey::from(&secret);
+                let shared = secret.diffie_hellman(&PublicKey::from(*pk));
+                if !shared.was_contributory() {
+                    return Err(Error::Other(format!(
+                        "X25519 key of {} is invalid",
+                        recipient.name
+                    )));
+                }
+                (
+                    Some(public.to_bytes()),
+                    Some(Zeroizing::new(shared.to_bytes())),
+                )
+            }
+            None => (None, None),
+        };
+        let mut info = RecipientInfo {
+            key_id: key_id_bytes(&kyber_pk),
+            kem: key.algorithm,
+            ciphertext,
+            ephemeral,
+            wrapped_key: file_key.to_vec(),
+        };
+        let kek = key_encryption_key(
+            &kyber_ss,
+            x25519_ss.as_ref().map(|s| &s[..]),
+            &info,
+            &kyber_pk,
+            x25519_pk.as_ref(),
+        )?;
+        kek.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key([0u8; NONCE_LEN]),
+            Aad::from(&info.key_id),
+            &mut info.wrapped_key,
+        )
+        .map_err(|_| Error::Other("cannot wrap file key".into()))?;
+        infos.push(info);
+    }
+    let header = Header {
+        chunk_size: CHUNK_SIZE,
+        recipients: infos,
+    }
+    .to_der();
+    writer.write_all(&header).map_err(Error::from)?;
+
+    let aad = Sha256::digest(&header);
+    let key = payload_key(file_key.as_ref())?;
+    let mut buf = Zeroizing::new(vec![0u8; CHUNK_SIZE + TAG_LEN]);
+    let mut total = 0u64;
+    for index in 0.. {
+        let n = read_full(&mut reader, &mut buf[..CHUNK_SIZE])?;
+        // a short chunk ends the file; a file that fills its last chunk gets an empty one
+        let last = n < CHUNK_SIZE;
+        let tag = key
+            .seal_in_place_separate_tag(chunk_nonce(index, last), Aad::from(&aad), &mut buf[..n])
+            .map_err(|_| Error::Other("encryption failed".into()))?;
+        buf[n..n + TAG_LEN].copy_from_slice(tag.as_ref());
+        writer.write_all(&buf[..n + TAG_LEN]).map_err(Error::from)?;
+        total += n as u64;
+        if last {
+            break;
+        }
+    }
+    writer.flush().map_err(Error::from)?;
+    Ok(total)
+}
+
+/// Decrypts a file written by [`encrypt`] with the recipient key `key`/`secret`. Plaintext
+/// is written as chunks authenticate, so callers must discard the output on error.
+pub fn decrypt(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    key: &KemKey,
+    secret: &KemSecret,
+) -> Result<u64> {
+    let der = read_header(&mut reader)?;
+    let header = Header::from_der(&der)?;
+    let kyber_pk = key.public_key()?;
+    let key_id = key_id_bytes(&kyber_pk);
+    let info = header
+        .recipients
+        .iter()
+        .find(|r| r.key_id == key_id && r.kem == key.algorithm)
+        .ok_or_else(|| {
+            Error::Other(format!(
+                "file is not encrypted to key {} (recipients: {})",
+                hex(&key_id),
+                header
+                    .recipients
+                    .iter()
+                    .map(|r| hex(&r.key_id))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })?;
+
+    let kyber_ss = key.algorithm.decapsulate(&secret.kyber, &info.ciphertext)?;
+    let x25519_pk = key.x25519()?;
+    // a hybrid key only accepts hybrid stanzas, so the X25519 half cannot be stripped
+    let x25519_ss = match (&secret.x25519, &info.ephemeral) {
+        (Some(sk), Some(ephemeral)) => {
+            let shared = StaticSecret::from(**sk).diffie_hellman(&PublicKey::from(*ephemeral));
+            // a low-order ephemeral key would make the X25519 half a known constant
+            if !shared.was_contributory() {
+                return Err(Error::Other(format!(
+                    "X25519 ephemeral key for {} is invalid",
+                    hex(&key_id)
+                )));
+            }
+            Some(Zeroizing::new(shared.to_bytes()))
+        }
+        (None, None) => None,
+        _ => {
+            return Err(Error::Other(format!(
+                "file was encrypted to key {} with a different hybrid mode",
+                hex(&key_id)
+            )));
+        }
+    };
+    let kek = key_encryption_key(
+        &kyber_ss,
+        x25519_ss.as_ref().map(|s| &s[..]),
+        info,
+        &kyber_pk,
+        x25519_pk.as_ref(),
+    )?;
+    let mut wrapped = Zeroizing::new(info.wrapped_key.clone());
+    let file_key = kek
+        .open_in_place(
+            Nonce::assume_unique_for_key([0u8; NONCE_LEN]),
+            Aad::from(&info.key_id),
+            &mut wrapped,
+        )
+        .map_err(|_| Error::Other("cannot unwrap the file key; wrong key?".into()))?;
+    if file_key.len() != FILE_KEY_LEN {
+        return Err(Error::Other("bad file key length".into()));
+    }
+
+    let aad = Sha256::digest(&der);
+    let key = payload_key(file_key)?;
+    let chunk = header.chunk_size + TAG_LEN;
+    let mut buf = Zeroizing::new(vec![0u8; chunk]);
+    let mut total = 0u64;
+    for index in 0.. {
+        let n = read_full(&mut reader, &mut buf)?;
+        let last = n < chunk;
+        let plain = key
+            .open_in_place(chunk_nonce(index, last), Aad::from(&aad), &mut buf[..n])
+            .map_err(|_| Error::Other("decryption failed: file is corrupt or truncated".into()))?;
+        writer.write_all(plain).map_err(Error::from)?;
+        total += plain.len() as u64;
+        if last {
+            break;
+        }
+    }
+    writer.flush().map_err(Error::from)?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as B64;
+
+    fn recipient(kem: Kem, hybrid: bool) -> (Recipient, KemSecret) {
+        let (pk, sk) = kem.keypair().unwrap();
+        let x25519 = hybrid.then(|| StaticSecret::random_from_rng(rand::rngs::OsRng));
+        let key = KemKey {
+            algorithm: kem,
+            key_id: hex(&key_id_bytes(&pk)),
+            public_key: B64.encode(&pk),
+            x25519: x25519
+                .as_ref()
+                .map(|sk| B64.encode(PublicKey::from(sk).as_bytes())),
+        };
+        let secret = KemSecret {
+            kyber: sk,
+            x25519: x25519.map(|sk| Zeroizing::new(sk.to_bytes())),
+        };
+        let recipient = Recipient {
+            name: kem.name().into(),
+            key,
+        };
+        (recipient, secret)
+    }
+
+    #[test]
+    fn encrypt_to_several_recipients() {
+        let (alice, alice_sk) = recipient(Kem::Kyber768, false);
+        let (bob, bob_sk) = recipient(Kem::Kyber1024, true);
+        let (carol, carol_sk) = recipient(Kem::Kyber512, false);
+        let recipients = [alice.clone(), bob.clone()];
+
+        for len in [0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 7] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut sealed = Vec::new();
+            assert_eq!(
+                encrypt(&data[..], &mut sealed, &recipients).unwrap(),
+                len as u64
+            );
+            for (r, sk) in [(&alice, &alice_sk), (&bob, &bob_sk)] {
+                let mut plain = Vec::new();
+                decrypt(&sealed[..], &mut plain, &r.key, sk).unwrap();
+                assert_eq!(plain, data);
+            }
+            assert!(decrypt(&sealed[..], &mut Vec::new(), &carol.key, &carol_sk).is_err());
+        }
+
+        let data = vec![7u8; CHUNK_SIZE + 100];
+        let mut sealed = Vec::new();
+        encrypt(&data[..], &mut sealed, &recipients).unwrap();
+        // dropping the final chunk leaves a full, non-final chunk at the end
+        let truncated = &sealed[..sealed.len() - 100 - TAG_LEN];
+        assert!(decrypt(truncated, &mut Vec::new(), &alice.key, &alice_sk).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered[..], &mut Vec::new(), &alice.key, &alice_sk).is_err());
+
+        // the hybrid recipient refuses a stanza without its X25519 half
+        let pq_only = Recipient {
+            key: KemKey {
+                x25519: None,
+                ..bob.key.clone()
+            },
+            ..bob.clone()
+        };
+        let mut sealed = Vec::new();
+        encrypt(&data[..], &mut sealed, &[pq_only]).unwrap();
+        assert!(decrypt(&sealed[..], &mut Vec::new(), &bob.key, &bob_sk).is_err());
+    }
+}
@@ -0,0 +1,631 @@
+use crate::error::{Error, Result};
+use crate::util::keystore::{Classical, PublicIdentity, SecretKey};
+use crate::util::pq::Algorithm;
+use crate::util::tsp::{self, TstInfo};
+use crate::util::{crl, keystore, x509};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use sha2::{Digest, Sha512};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Sha3_512, Shake256};
+use std::io::Read;
+use yasna::Tag;
+use yasna::models::ObjectIdentifier;
+
+/// PEM label of the ASCII-armored envelope.
+pub const ARMOR_TAG: &str = "HYPATIA SIGNATURE";
+
+const VERSION: u8 = 1;
+/// Prefix of the signed bytes, so an envelope signature is never valid for anything else.
+const CONTEXT: &[u8] = b"hypatia-ca signature envelope v1\0";
+
+const OID_SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+const OID_SHA3_512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 10];
+const OID_SHAKE256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 12];
+
+/// Read size used when hashing, so files of any size are signed in constant memory.
+const BUF_SIZE: usize = 64 * 1024;
+/// Output length taken from SHAKE256.
+const SHAKE_LEN: usize = 64;
+
+/// Digest algorithm applied to the signed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum HashAlg {
+    #[value(name = "sha512")]
+    Sha512,
+    #[value(name = "sha3-512")]
+    Sha3_512,
+    #[value(name = "shake256")]
+    Shake256,
+}
+
+impl HashAlg {
+    fn oid(self) -> &'static [u64] {
+        match self {
+            HashAlg::Sha512 => OID_SHA512,
+            HashAlg::Sha3_512 => OID_SHA3_512,
+            HashAlg::Shake256 => OID_SHAKE256,
+        }
+    }
+
+    fn from_oid(oid: &[u64]) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|h| h.oid() == oid)
+    }
+
+    /// Hashes everything `reader` yields through a fixed-size buffer.
+    pub fn digest_reader(self, reader: impl Read) -> Result<Vec<u8>> {
+        Ok(match self {
+            HashAlg::Sha512 => {
+                let mut h = Sha512::new();
+                feed(reader, |b| Digest::update(&mut h, b))?;
+                h.finalize().to_vec()
+            }
+            HashAlg::Sha3_512 => {
+                let mut h = Sha3_512::new();
+                feed(reader, |b| Digest::update(&mut h, b))?;
+                h.finalize().to_vec()
+            }
+            HashAlg::Shake256 => {
+                let mut h = Shake256::default();
+                feed(reader, |b| Update::update(&mut h, b))?;
+                let mut out = vec![0u8; SHAKE_LEN];
+                XofReader::read(&mut h.finalize_xof(), &mut out);
+                out
+            }
+        })
+    }
+
+    pub fn digest_file(self, path: &str) -> Result<Vec<u8>> {
+        let file = std::fs::File::open(path).map_err(Error::from)?;
+        self.digest_reader(file)
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        // reading from a slice cannot fail
+        self.digest_reader(data).unwrap_or_default()
+    }
+}
+
+fn feed(mut reader: impl Read, mut update: impl FnMut(&[u8])) -> Result<()> {
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => update(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+}
+
+/// A self-describing detached signature.
+///
+/// ```text
+/// Envelope ::= SEQUENCE {
+///     version      INTEGER (1),
+///     algorithm    OBJECT IDENTIFIER,
+///     keyId        OCTET STRING,
+///     signedAt     GeneralizedTime,
+///     hashAlg      OBJECT IDENTIFIER,
+///     digest       OCTET STRING,
+///     certificate  [0] EXPLICIT Certificate OPTIONAL,
+///     comment      [1] EXPLICIT UTF8String OPTIONAL,
+///     classicalAlg [2] EXPLICIT OBJECT IDENTIFIER OPTIONAL,
+///     signature    OCTET STRING,
+///     classicalSig [3] EXPLICIT OCTET STRING OPTIONAL,
+///     timestamp    [4] EXPLICIT TimeStampToken OPTIONAL }
+/// ```
+///
+/// The signature covers a context string followed by the DER of every field before it.
+/// Hybrid envelopes carry a second, classical signature over the same bytes; since
+/// `classicalAlg` is part of them, neither half can be removed without breaking the other.
+/// The optional RFC 3161 timestamp countersigns the SHA-512 of everything before it.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub algorithm: Algorithm,
+    pub key_id: String,
+    pub signed_at: DateTime<Utc>,
+    pub hash_alg: HashAlg,
+    pub digest: Vec<u8>,
+    /// DER signer certificate issued by `certify-key`.
+    pub cert: Option<Vec<u8>>,
+    pub comment: Option<String>,
+    pub signature: Vec<u8>,
+    pub classical: Option<ClassicalSignature>,
+    /// DER TimeStampToken over [`Envelope::timestamp_digest`].
+    pub timestamp: Option<Vec<u8>>,
+}
+
+/// Classical half of a hybrid envelope.
+#[derive(Clone, Debug)]
+pub struct ClassicalSignature {
+    pub algorithm: Classical,
+    pub signature: Vec<u8>,
+}
+
+impl Envelope {
+    /// Signs `digest` with `sk` and returns the finished envelope. Hybrid identities
+    /// sign with both of their keys.
+    pub fn sign(
+        identity: &PublicIdentity,
+        sk: &SecretKey,
+        hash_alg: HashAlg,
+        digest: Vec<u8>,
+        cert: Option<Vec<u8>>,
+        comment: Option<String>,
+    ) -> Result<Self> {
+        let mut env = Envelope {
+            algorithm: identity.algorithm,
+            key_id: identity.key_id.clone(),
+            signed_at: Utc::now(),
+            hash_alg,
+            digest,
+            cert,
+            comment,
+            signature: vec![],
+            classical: identity.classical.as_ref().map(|c| ClassicalSignature {
+                algorithm: c.algorithm,
+                signature: vec![],
+            }),
+            timestamp: None,
+        };
+        let signed = env.signed_bytes()?;
+        env.signature = env.algorithm.sign(&sk.pq, &signed);
+        if let Some(classical) = &mut env.classical {
+            classical.signature = sk
+                .sign_classical(&signed)?
+                .ok_or_else(|| Error::Other(format!("key {} is not unlocked", identity.name)))?;
+        }
+        Ok(env)
+    }
+
+    /// Algorithm name as shown to users, e.g. `falcon512+p384` for a hybrid envelope.
+    pub fn algorithm_name(&self) -> String {
+        match &self.classical {
+            Some(c) => format!("{}+{}", self.algorithm.name(), c.algorithm.name()),
+            None => self.algorithm.name().to_owned(),
+        }
+    }
+
+    /// Checks the signature with `key`, which must match the envelope's algorithm and key ID.
+    /// A hybrid envelope is valid only if both of its signatures verify.
+    pub fn verify(&self, key: &PublicIdentity) -> Result<()> {
+        if key.algorithm != self.algorithm || key.key_id != self.key_id {
+            return Err(Error::Other(format!(
+                "signature was made by {} key {}, not {}",
+                self.algorithm.name(),
+                self.key_id,
+                key.key_id
+            )));
+        }
+        let pk = key.public_key()?;
+        if keystore::key_id(&pk) != self.key_id {
+            return Err(Error::Other(format!("key {} has a wrong key ID", key.name)));
+        }
+        if !self
+            .algorithm
+            .verify(&pk, &self.signature, &self.signed_bytes()?)
+        {
+            return Err(Error::Other("verification failed".into()));
+        }
+        match (&self.classical, &key.classical) {
+            (None, None) => {}
+            (Some(sig), Some(classical)) if sig.algorithm == classical.algorithm => {
+                x509::verify_signature(&classical.spki()?, &self.signed_bytes()?, &sig.signature)
+                    .map_err(|_| Error::Other("classical signature verification failed".into()))?;
+            }
+            _ => {
+                return Err(Error::Other(format!(
+                    "signature is {} but key {} is {}",
+                    self.algorithm_name(),
+                    key.name,
+                    key.algorithm_name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes `reader` with the envelope's algorithm and compares the result.
+    pub fn matches(&self, reader: impl Read) -> Result<bool> {
+        Ok(self.hash_alg.digest_reader(reader)? == self.digest)
+    }
+
+    /// SHA-512 of the signed envelope without its timestamp; the imprint a TSA countersigns.
+    pub fn timestamp_digest(&self) -> Result<Vec<u8>> {
+        Ok(Sha512::digest(self.encode(true, false)?).to_vec())
+    }
+
+    /// Verifies the timestamp, if any, against the TSA root `root_der`.
+    pub fn verify_timestamp(&self, root_der: &[u8]) -> Result<Option<TstInfo>> {
+        self.timestamp
+            .as_ref()
+            .map(|token| tsp::verify(token, &self.timestamp_digest()?, root_der))
+            .transpose()
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = CONTEXT.to_vec();
+        out.extend(self.encode(false, false)?);
+        Ok(out)
+    }
+
+    fn encode(&self, with_signature: bool, with_timestamp: bool) -> Result<Vec<u8>> {
+        let key_id = (0..self.key_id.len())
+            .step_by(2)
+            .map(|i| {
+                self.key_id
+                    .get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| Error::Other(format!("invalid key ID {}", self.key_id)))?;
+        let signed_at = crl::to_offset(self.signed_at)?;
+        Ok(yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(VERSION);
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(self.algorithm.oid()));
+                w.next().write_bytes(&key_id);
+                x509::write_generalized_time(w.next(), signed_at);
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(self.hash_alg.oid()));
+                w.next().write_bytes(&self.digest);
+                if let Some(cert) = &self.cert {
+                    w.next()
+                        .write_tagged(Tag::context(0), |w| w.write_der(cert));
+                }
+                if let Some(comment) = &self.comment {
+                    w.next()
+                        .write_tagged(Tag::context(1), |w| w.write_utf8_string(comment));
+                }
+                if let Some(classical) = &self.classical {
+                    w.next().write_tagged(Tag::context(2), |w| {
+                        w.write_oid(&ObjectIdentifier::from_slice(classical.algorithm.oid()))
+                    });
+                }
+                if with_signature {
+                    w.next().write_bytes(&self.signature);
+                    if let Some(classical) = &self.classical {
+                        w.next()
+                            .write_tagged(Tag::context(3), |w| w.write_bytes(&classical.signature));
+                    }
+                }
+                if with_timestamp && let Some(token) = &self.timestamp {
+                    w.next()
+                        .write_tagged(Tag::context(4), |w| w.write_der(token));
+                }
+            })
+        }))
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>> {
+        self.encode(true, true)
+    }
+
+    pub fn to_armor(&self) -> Result<String> {
+        Ok(x509::der_to_pem(&self.to_der()?, ARMOR_TAG))
+    }
+
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let fields = yasna::parse_der(der, |r| {
+            r.read_sequence(|r| {
+                let version = r.next().read_u8()?;
+                let algorithm = r.next().read_oid()?;
+                let key_id = r.next().read_bytes()?;
+                let signed_at = r.next().read_generalized_time()?;
+                let hash_alg = r.next().read_oid()?;
+                let digest = r.next().read_bytes()?;
+                let cert = r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                let comment =
+                    r.read_optional(|r| r.read_tagged(Tag::context(1), |r| r.read_utf8string()))?;
+                let classical_alg =
+                    r.read_optional(|r| r.read_tagged(Tag::context(2), |r| r.read_oid()))?;
+                let signature = r.next().read_bytes()?;
+                let classical_sig =
+                    r.read_optional(|r| r.read_tagged(Tag::context(3), |r| r.read_bytes()))?;
+                let timestamp =
+                    r.read_optional(|r| r.read_tagged(Tag::context(4), |r| r.read_der()))?;
+                Ok((
+                    version,
+                    algorithm,
+                    key_id,
+                    signed_at,
+                    hash_alg,
+                    digest,
+                    cert,
+                    comment,
+                    signature,
+                    classical_alg,
+                    classical_sig,
+                    timestamp,
+                ))
+            })
+        })
+        .map_err(|e| Error::Other(format!("bad signature envelope: {e}")))?;
+        let (
+            version,
+            algorithm,
+            key_id,
+            signed_at,
+            hash_alg,
+            digest,
+            cert,
+            comment,
+            signature,
+            classical_alg,
+            classical_sig,
+            timestamp,
+        ) = fields;
+        if version != VERSION {
+            return Err(Error::Other(format!(
+                "unsupported signature envelope version {version}"
+            )));
+        }
+        let algorithm = Algorithm::from_oid(algorithm.components())
+            .ok_or_else(|| Error::Other(format!("unknown signature algorithm {algorithm:?}")))?;
+        let hash_alg = HashAlg::from_oid(hash_alg.components())
+            .ok_or_else(|| Error::Other(format!("unknown hash algorithm {hash_alg:?}")))?;
+        let signed_at = DateTime::from_timestamp(signed_at.datetime().unix_timestamp(), 0)
+            .ok_or_else(|| Error::Other("signing time out of range".into()))?;
+        let classical = match (classical_alg, classical_sig) {
+            (None, None) => None,
+            (Some(oid), Some(signature)) => Some(ClassicalSignature {
+                algorithm: Classical::from_oid(oid.components()).ok_or_else(|| {
+                    Error::Other(format!("unknown classical signature algorithm {oid:?}"))
+                })?,
+                signature,
+            }),
+            _ => {
+                return Err(Error::Other(
+                    "bad signature envelope: incomplete classical signature".into(),
+                ));
+            }
+        };
+        Ok(Envelope {
+            algorithm,
+            key_id: key_id.iter().map(|b| format!("{b:02x}")).collect(),
+            signed_at,
+            hash_alg,
+            digest,
+            cert,
+            comment,
+            signature,
+            classical,
+            timestamp,
+        })
+    }
+
+    /// Reads a signature bundle: one or more armored blocks, or concatenated DER
+    /// envelopes. `None` means the bytes are a raw signature in the format written
+    /// before envelopes existed; input that starts like an envelope but does not parse
+    /// is an error rather than a raw signature.
+    pub fn parse_bundle(data: &[u8]) -> Result<Option<Vec<Self>>> {
+        if let Ok(text) = std::str::from_utf8(data)
+            && text.contains(&format!("-----BEGIN {ARMOR_TAG}-----"))
+        {
+            return pem::parse_many(text)
+                .map_err(|e| Error::Other(format!("bad PEM: {e}")))?
+                .into_iter()
+                .filter(|p| p.tag() == ARMOR_TAG)
+                .map(|p| Self::from_der(p.contents()))
+                .collect::<Result<Vec<_>>>()
+                .map(Some);
+        }
+        if !starts_like_envelope(data) {
+            return Ok(None);
+        }
+        split_der(data)
+            .ok_or_else(|| Error::Other("bad signature envelope: truncated bundle".into()))?
+            .into_iter()
+            .map(Self::from_der)
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Encodes a bundle so that [`Envelope::parse_bundle`] reads it back.
+    pub fn encode_bundle(envelopes: &[Self], armor: bool) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for env in envelopes {
+            if armor {
+                out.extend(env.to_armor()?.into_bytes());
+            } else {
+                out.extend(env.to_der()?);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Splits concatenated DER SEQUENCEs; `None` if `data` is not exactly that.
+/// Header and content length of the DER SEQUENCE at the start of `data`.
+fn sequence_header(data: &[u8]) -> Option<(usize, usize)> {
+    if data.first() != Some(&0x30) {
+        return None;
+    }
+    match *data.get(1)? {
+        n @ 0..=0x7f => Some((2, n as usize)),
+        n @ 0x81..=0x84 => {
+            let count = (n & 0x7f) as usize;
+            let bytes = data.get(2..2 + count)?;
+            Some((
+                2 + count,
+                bytes.iter().fold(0usize, |acc, b| acc << 8 | *b as usize),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `data` opens with a SEQUENCE whose first field is the envelope's one-byte
+/// version INTEGER, which a raw signature does not by chance.
+fn starts_like_envelope(data: &[u8]) -> bool {
+    sequence_header(data)
+        .and_then(|(header, _)| data.get(header..header + 2))
+        .is_some_and(|version| version == [0x02, 0x01])
+}
+
+fn split_der(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut parts = Vec::new();
+    while !data.is_empty() {
+        let (header, len) = sequence_header(data)?;
+        let end = header.checked_add(len)?;
+        parts.push(data.get(..end)?);
+        data = &data[end..];
+    }
+    (!parts.is_empty()).then_some(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::keystore::ClassicalKey;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as B64;
+    use rcgen::{KeyPair, PublicKeyData};
+
+    #[test]
+    fn envelope_roundtrip_and_tamper() {
+        let (pk, sk) = Algorithm::Falcon512.keypair();
+        let key = PublicIdentity {
+            name: "test".into(),
+            algorithm: Algorithm::Falcon512,
+            key_id: keystore::key_id(&pk),
+            public_key: B64.encode(&pk),
+            classical: None,
+            kem: None,
+        };
+        let sk = SecretKey {
+            pq: sk,
+            classical: None,
+            kem: None,
+        };
+        let data = b"release artifact";
+        let env = Envelope::sign(
+            &key,
+            &sk,
+            HashAlg::Shake256,
+            HashAlg::Shake256.digest(data),
+            None,
+            Some("v1.0".into()),
+        )
+        .unwrap();
+
+        let armored = env.to_armor().unwrap();
+        let parsed = Envelope::parse_bundle(armored.as_bytes()).unwrap().unwrap();
+        let parsed = parsed[0].clone();
+        assert_eq!(parsed.comment.as_deref(), Some("v1.0"));
+        assert!(parsed.matches(&data[..]).unwrap());
+        assert!(!parsed.matches(&b"other"[..]).unwrap());
+        parsed.verify(&key).unwrap();
+
+        let digest = env.timestamp_digest().unwrap();
+        let stamped = Envelope {
+            timestamp: Some(vec![0x30, 0x00]),
+            ..env.clone()
+        };
+        let stamped = Envelope::from_der(&stamped.to_der().unwrap()).unwrap();
+        assert_eq!(stamped.timestamp, Some(vec![0x30, 0x00]));
+        assert_eq!(stamped.timestamp_digest().unwrap(), digest);
+        stamped.verify(&key).unwrap();
+
+        let mut tampered = Envelope::from_der(&env.to_der().unwrap()).unwrap();
+        tampered.comment = Some("v2.0".into());
+        assert!(tampered.verify(&key).is_err());
+        assert!(
+            Envelope::parse_bundle(b"\x39raw falcon bytes")
+                .unwrap()
+                .is_none()
+        );
+        let der = env.to_der().unwrap();
+        assert!(Envelope::parse_bundle(&der[..der.len() - 1]).is_err());
+        // a version the parser does not know, behind a valid outer header
+        let mut corrupt = der.clone();
+        let (header, _) = sequence_header(&der).unwrap();
+        corrupt[header + 2] = 0x05;
+        assert!(Envelope::parse_bundle(&corrupt).is_err());
+    }
+
+    #[test]
+    fn bundle_holds_several_envelopes() {
+        let (pk, sk) = Algorithm::Dilithium2.keypair();
+        let key = PublicIdentity {
+            name: "test".into(),
+            algorithm: Algorithm::Dilithium2,
+            key_id: keystore::key_id(&pk),
+            public_key: B64.encode(&pk),
+            classical: None,
+            kem: None,
+        };
+        let sk = SecretKey {
+            pq: sk,
+            classical: None,
+            kem: None,
+        };
+        let digest = HashAlg::Sha3_512.digest(b"data");
+        let env = Envelope::sign(&key, &sk, HashAlg::Sha3_512, digest, None, None).unwrap();
+        let long = Envelope {
+            comment: Some("x".repeat(300)),
+            ..env.clone()
+        };
+        for armor in [false, true] {
+            let bundle = Envelope::encode_bundle(&[env.clone(), long.clone()], armor).unwrap();
+            let parsed = Envelope::parse_bundle(&bundle).unwrap().unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[1].comment, long.comment);
+        }
+    }
+
+    #[test]
+    fn hybrid_needs_both_signatures() {
+        let (pk, sk) = Algorithm::Dilithium3.keypair();
+        let classical = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let key = PublicIdentity {
+            name: "test".into(),
+            algorithm: Algorithm::Dilithium3,
+            key_id: keystore::key_id(&pk),
+            public_key: B64.encode(&pk),
+            classical: Some(ClassicalKey {
+                algorithm: Classical::P384,
+                public_key: B64.encode(classical.subject_public_key_info()),
+            }),
+            kem: None,
+        };
+        let sk = SecretKey {
+            pq: sk,
+            classical: Some(classical),
+            kem: None,
+        };
+        let digest = HashAlg::Sha3_512.digest(b"data");
+        let env = Envelope::sign(&key, &sk, HashAlg::Sha3_512, digest, None, None).unwrap();
+        assert_eq!(env.algorithm_name(), "dilithium3+p384");
+        let parsed = Envelope::from_der(&env.to_der().unwrap()).unwrap();
+        parsed.verify(&key).unwrap();
+
+        let mut bad_classical = parsed.clone();
+        if let Some(c) = &mut bad_classical.classical {
+            c.signature[10] ^= 1;
+        }
+        assert!(bad_classical.verify(&key).is_err());
+
+        let mut bad_pq = parsed.clone();
+        bad_pq.signature[0] ^= 1;
+        assert!(bad_pq.verify(&key).is_err());
+
+        // dropping the classical half changes the signed bytes
+        let stripped = Envelope {
+            classical: None,
+            ..parsed.clone()
+        };
+        let pq_only = PublicIdentity {
+            classical: None,
+            ..key.clone()
+        };
+        assert!(stripped.verify(&pq_only).is_err());
+        assert!(stripped.verify(&key).is_err());
+        assert!(parsed.verify(&pq_only).is_err());
+    }
+}
@@ -1,13 +1,53 @@
 use crate::error::{Error, Result};
+use crate::util::crl::{CrlState, Revocation};
+use crate::util::issued::IssuedCert;
+use crate::util::keystore::{PublicIdentity, StoredIdentity};
+use crate::util::ratelimit::LimitState;
+use crate::util::token::ApiToken;
+use crate::util::x509;
+use chrono::{DateTime, Utc};
 use std::fs;
 use std::path::Path;
-use tracing::{debug, error};
+use std::time::SystemTime;
+use tracing::{debug, error, warn};
 use zeroize::Zeroizing;
 
-const ROOT_DIR: &str = "/opt/hypatia-ca/data/root";
+/// Expands to a path below the data directory. Tests get a scratch directory instead, so
+/// they never touch a real CA.
+#[cfg(not(test))]
+macro_rules! data_path {
+    ($path:literal) => {
+        concat!("/opt/hypatia-ca/data", $path)
+    };
+}
+#[cfg(test)]
+macro_rules! data_path {
+    ($path:literal) => {
+        concat!("/tmp/hypatia-ca-test/data", $path)
+    };
+}
+
+const ROOT_DIR: &str = data_path!("/root");
 
-const CRL_FILE: &str = "/opt/hypatia-ca/data/revoked.txt";
-const CERT_DIR: &str = "/opt/hypatia-ca/data/certs";
+const LEGACY_REVOCATION_FILE: &str = data_path!("/revoked.txt");
+const REVOCATION_FILE: &str = data_path!("/revoked.jsonl");
+const REVOCATION_LOCK: &str = data_path!("/revoked.lock");
+const CRL_DIR: &str = data_path!("/crl");
+const CERT_DIR: &str = data_path!("/certs");
+const ISSUED_DIR: &str = data_path!("/issued");
+const ISSUED_FILE: &str = data_path!("/issued.jsonl");
+const BLOCKED_KEYS_FILE: &str = data_path!("/blocked-keys.txt");
+const OCSP_DIR: &str = data_path!("/ocsp");
+const TSA_DIR: &str = data_path!("/tsa");
+const ACME_DIR: &str = data_path!("/acme");
+const EST_USERS_FILE: &str = data_path!("/est-users.txt");
+const API_TOKENS_FILE: &str = data_path!("/api-tokens.json");
+const RATE_LIMITS_FILE: &str = data_path!("/rate-limits.json");
+const SCEP_DIR: &str = data_path!("/scep");
+const SCEP_CHALLENGES_FILE: &str = data_path!("/scep/challenges.txt");
+const SCEP_CHALLENGES_LOCK: &str = data_path!("/scep/challenges.lock");
+const KEYS_DIR: &str = data_path!("/keys");
+pub const TRUSTED_KEYS_DIR: &str = data_path!("/trusted-keys");
 
 pub fn ensure_dirs() -> Result<()> {
     fs::create_dir_all(ROOT_DIR).map_err(Error::from)?;
@@ -44,17 +84,75 @@ pub fn read_root_ca() -> Result<(String, Zeroizing<String>)> {
     Ok((cert, key))
 }
 
-pub fn write_cert(name: &str, cert_pem: &str, key_pem: &str) -> Result<()> {
+pub fn read_root_cert() -> Result<String> {
+    fs::read_to_string(Path::new(ROOT_DIR).join("cert.pem")).map_err(Error::from)
+}
+
+/// Certificate names come from common names, often out of untrusted CSRs, and become
+/// file names below the certificate directory; anything that could leave it is refused.
+pub fn check_cert_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.contains("..")
+        && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control());
+    if !valid {
+        return Err(Error::Other(format!("invalid certificate name {name:?}")));
+    }
+    Ok(())
+}
+
+pub fn write_cert(name: &str, cert_pem: &str, key_pem: Option<&str>) -> Result<()> {
+    check_cert_name(name)?;
     fs::create_dir_all(CERT_DIR).map_err(Error::from)?;
     let cert_path = Path::new(CERT_DIR).join(format!("{name}.pem"));
-    let key_path = Path::new(CERT_DIR).join(format!("{name}.key"));
     debug!("writing certificate to {:?}", cert_path);
     fs::write(cert_path, cert_pem).map_err(Error::from)?;
-    fs::write(key_path, key_pem).map_err(Error::from)
+    if let Some(key_pem) = key_pem {
+        let key_path = Path::new(CERT_DIR).join(format!("{name}.key"));
+        fs::write(key_path, key_pem).map_err(Error::from)?;
+    }
+    Ok(())
 }
 
-pub fn append_revocation(serial: &str) -> Result<()> {
-    if let Some(parent) = Path::new(CRL_FILE).parent() {
+/// Records an issued certificate in the index and keeps a copy under its serial.
+pub fn record_issued(entry: &IssuedCert, cert_pem: &str) -> Result<()> {
+    fs::create_dir_all(ISSUED_DIR).map_err(Error::from)?;
+    fs::write(
+        Path::new(ISSUED_DIR).join(format!("{}.pem", entry.serial)),
+        cert_pem,
+    )
+    .map_err(Error::from)?;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ISSUED_FILE)
+        .map_err(Error::from)?;
+    let line = serde_json::to_string(entry).map_err(Error::from)?;
+    writeln!(file, "{}", line).map_err(Error::from)
+}
+
+pub fn read_issued() -> Result<Vec<IssuedCert>> {
+    let path = Path::new(ISSUED_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = Vec::new();
+    for line in fs::read_to_string(path).map_err(Error::from)?.lines() {
+        if !line.trim().is_empty() {
+            out.push(serde_json::from_str(line).map_err(Error::from)?);
+        }
+    }
+    Ok(out)
+}
+
+pub fn read_issued_pem(serial: &str) -> Result<String> {
+    let path = Path::new(ISSUED_DIR).join(format!("{serial}.pem"));
+    fs::read_to_string(path).map_err(Error::from)
+}
+
+pub fn append_blocked_key(spki_hash: &str) -> Result<()> {
+    if let Some(parent) = Path::new(BLOCKED_KEYS_FILE).parent() {
         fs::create_dir_all(parent).map_err(Error::from)?;
     }
     use std::fs::OpenOptions;
@@ -62,7 +160,542 @@ pub fn append_revocation(serial: &str) -> Result<()> {
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(CRL_FILE)
+        .open(BLOCKED_KEYS_FILE)
         .map_err(Error::from)?;
-    writeln!(file, "{}", serial).map_err(Error::from)
+    writeln!(file, "{}", spki_hash).map_err(Error::from)
+}
+
+pub fn read_blocked_keys() -> Result<Vec<String>> {
+    let path = Path::new(BLOCKED_KEYS_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_to_string(path)
+        .map_err(Error::from)?
+        .lines()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// Loads the delegated OCSP signing certificate and key, if one was issued.
+pub fn read_ocsp_signer() -> Result<Option<(String, Zeroizing<String>)>> {
+    let cert_path = Path::new(OCSP_DIR).join("cert.pem");
+    let key_path = Path::new(OCSP_DIR).join("key.pem");
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+    let cert = fs::read_to_string(cert_path).map_err(Error::from)?;
+    let key = Zeroizing::new(fs::read_to_string(key_path).map_err(Error::from)?);
+    Ok(Some((cert, key)))
+}
+
+pub fn write_ocsp_signer(cert_pem: &str, key_pem: &str) -> Result<()> {
+    fs::create_dir_all(OCSP_DIR).map_err(Error::from)?;
+    let cert_path = Path::new(OCSP_DIR).join("cert.pem");
+    debug!("writing OCSP signer to {:?}", cert_path);
+    fs::write(cert_path, cert_pem).map_err(Error::from)?;
+    let key_path = Path::new(OCSP_DIR).join("key.pem");
+    write_via_temp(&key_path.to_string_lossy(), 0o600, |w| {
+        use std::io::Write;
+        w.write_all(key_pem.as_bytes()).map_err(Error::from)
+    })
+}
+
+/// Loads the time-stamping authority certificate and key, if one was issued.
+pub fn read_tsa_signer() -> Result<Option<(String, Zeroizing<String>)>> {
+    let cert_path = Path::new(TSA_DIR).join("cert.pem");
+    let key_path = Path::new(TSA_DIR).join("key.pem");
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+    let cert = fs::read_to_string(cert_path).map_err(Error::from)?;
+    let key = Zeroizing::new(fs::read_to_string(key_path).map_err(Error::from)?);
+    Ok(Some((cert, key)))
+}
+
+pub fn write_tsa_signer(cert_pem: &str, key_pem: &str) -> Result<()> {
+    fs::create_dir_all(TSA_DIR).map_err(Error::from)?;
+    let cert_path = Path::new(TSA_DIR).join("cert.pem");
+    debug!("writing TSA signer to {:?}", cert_path);
+    fs::write(cert_path, cert_pem).map_err(Error::from)?;
+    let key_path = Path::new(TSA_DIR).join("key.pem");
+    write_via_temp(&key_path.to_string_lossy(), 0o600, |w| {
+        use std::io::Write;
+        w.write_all(key_pem.as_bytes()).map_err(Error::from)
+    })
+}
+
+/// Last serial number used in a time-stamp token, 0 if none was issued yet.
+pub fn read_tsa_serial() -> Result<u64> {
+    let path = Path::new(TSA_DIR).join("serial");
+    if !path.exists() {
+        return Ok(0);
+    }
+    fs::read_to_string(&path)
+        .map_err(Error::from)?
+        .trim()
+        .parse()
+        .map_err(|e| Error::Other(format!("corrupt TSA serial in {path:?}: {e}")))
+}
+
+/// Persists the serial through a rename, so a crash never leaves a lower value behind.
+pub fn write_tsa_serial(serial: u64) -> Result<()> {
+    fs::create_dir_all(TSA_DIR).map_err(Error::from)?;
+    write_via_temp(&format!("{TSA_DIR}/serial"), 0o644, |w| {
+        use std::io::Write;
+        writeln!(w, "{serial}").map_err(Error::from)
+    })
+}
+
+/// Loads the ACME account, order and authorization store, empty before first use.
+pub fn read_acme_state<T: serde::de::DeserializeOwned + Default>() -> Result<T> {
+    let path = Path::new(ACME_DIR).join("state.json");
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let data = fs::read_to_string(path).map_err(Error::from)?;
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+/// Replaces the ACME store through a rename, so a crash never leaves it half written.
+pub fn write_acme_state<T: serde::Serialize>(state: &T) -> Result<()> {
+    fs::create_dir_all(ACME_DIR).map_err(Error::from)?;
+    write_via_temp(&format!("{ACME_DIR}/state.json"), 0o600, |w| {
+        serde_json::to_writer_pretty(w, state).map_err(Error::from)
+    })
+}
+
+/// EST basic-auth users as `(name, password hash)` pairs, one `name:hash` line each.
+pub fn read_est_users() -> Result<Vec<(String, String)>> {
+    let path = Path::new(EST_USERS_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = Vec::new();
+    for line in fs::read_to_string(path).map_err(Error::from)?.lines() {
+        match line.split_once(':') {
+            Some((name, hash)) => out.push((name.to_owned(), hash.to_owned())),
+            None if line.trim().is_empty() => {}
+            None => warn!("skipping malformed EST user entry"),
+        }
+    }
+    Ok(out)
+}
+
+pub fn write_est_users(users: &[(String, String)]) -> Result<()> {
+    write_via_temp(EST_USERS_FILE, 0o600, |w| {
+        use std::io::Write;
+        for (name, hash) in users {
+            writeln!(w, "{name}:{hash}").map_err(Error::from)?;
+        }
+        Ok(())
+    })
+}
+
+/// API tokens, including revoked and expired ones; empty before the first is created.
+pub fn read_api_tokens() -> Result<Vec<ApiToken>> {
+    let path = Path::new(API_TOKENS_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data = fs::read_to_string(path).map_err(Error::from)?;
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+pub fn write_api_tokens(tokens: &[ApiToken]) -> Result<()> {
+    write_via_temp(API_TOKENS_FILE, 0o600, |w| {
+        serde_json::to_writer_pretty(w, tokens).map_err(Error::from)
+    })
+}
+
+/// Rate limit buckets and quota usage of the server, empty before first use.
+pub fn read_rate_limits() -> Result<LimitState> {
+    let path = Path::new(RATE_LIMITS_FILE);
+    if !path.exists() {
+        return Ok(LimitState::default());
+    }
+    let data = fs::read_to_string(path).map_err(Error::from)?;
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+pub fn write_rate_limits(state: &LimitState) -> Result<()> {
+    write_via_temp(RATE_LIMITS_FILE, 0o600, |w| {
+        serde_json::to_writer(w, state).map_err(Error::from)
+    })
+}
+
+/// Loads the SCEP registration authority certificate and key, if one was issued.
+pub fn read_scep_signer() -> Result<Option<(String, Zeroizing<String>)>> {
+    let cert_path = Path::new(SCEP_DIR).join("cert.pem");
+    let key_path = Path::new(SCEP_DIR).join("key.pem");
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+    let cert = fs::read_to_string(cert_path).map_err(Error::from)?;
+    let key = Zeroizing::new(fs::read_to_string(key_path).map_err(Error::from)?);
+    Ok(Some((cert, key)))
+}
+
+pub fn write_scep_signer(cert_pem: &str, key_pem: &str) -> Result<()> {
+    fs::create_dir_all(SCEP_DIR).map_err(Error::from)?;
+    let cert_path = Path::new(SCEP_DIR).join("cert.pem");
+    debug!("writing SCEP signer to {:?}", cert_path);
+    fs::write(cert_path, cert_pem).map_err(Error::from)?;
+    let key_path = Path::new(SCEP_DIR).join("key.pem");
+    write_via_temp(&key_path.to_string_lossy(), 0o600, |w| {
+        use std::io::Write;
+        w.write_all(key_pem.as_bytes()).map_err(Error::from)
+    })
+}
+
+/// An outstanding SCEP challenge password as `(SHA-256 hex, expiry, subject CN)`.
+pub type ScepChallenge = (String, DateTime<Utc>, String);
+
+/// Runs `update` on the outstanding SCEP challenges and stores any change. `scep-challenge`
+/// and the server both rewrite the file, so the whole read-modify-write holds an exclusive
+/// lock.
+pub fn update_scep_challenges<T>(update: impl FnOnce(&mut Vec<ScepChallenge>) -> T) -> Result<T> {
+    fs::create_dir_all(SCEP_DIR).map_err(Error::from)?;
+    // the challenge file is replaced by renaming, so the lock needs a file of its own
+    let _lock = lock_file(SCEP_CHALLENGES_LOCK)?;
+    let mut challenges = read_scep_challenges()?;
+    let before = challenges.clone();
+    let out = update(&mut challenges);
+    if challenges != before {
+        write_scep_challenges(&challenges)?;
+    }
+    Ok(out)
+}
+
+fn read_scep_challenges() -> Result<Vec<ScepChallenge>> {
+    let path = Path::new(SCEP_CHALLENGES_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = Vec::new();
+    for line in fs::read_to_string(path).map_err(Error::from)?.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let entry = (|| {
+            let hash = fields.next()?;
+            let expiry = DateTime::parse_from_rfc3339(fields.next()?).ok()?;
+            let cn = fields.next()?;
+            Some((hash.to_owned(), expiry.with_timezone(&Utc), cn.to_owned()))
+        })();
+        match entry {
+            Some(entry) => out.push(entry),
+            None if line.trim().is_empty() => {}
+            None => warn!("skipping malformed SCEP challenge entry"),
+        }
+    }
+    Ok(out)
+}
+
+fn write_scep_challenges(challenges: &[ScepChallenge]) -> Result<()> {
+    write_via_temp(SCEP_CHALLENGES_FILE, 0o600, |w| {
+        use std::io::Write;
+        for (hash, expiry, cn) in challenges {
+            writeln!(w, "{hash} {} {cn}", expiry.to_rfc3339()).map_err(Error::from)?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes `path` through a temporary file beside it (created with `mode`) and renames it
+/// into place only once `write` succeeds, so a failed run never leaves partial output
+/// behind.
+pub fn write_via_temp<T>(
+    path: &str,
+    mode: u32,
+    write: impl FnOnce(&mut std::io::BufWriter<fs::File>) -> Result<T>,
+) -> Result<T> {
+    write_through_temp(path, mode, true, write)
+}
+
+/// Like [`write_via_temp`] for user-named output, which is only replaced with `force`.
+pub fn write_output<T>(
+    path: &str,
+    mode: u32,
+    force: bool,
+    write: impl FnOnce(&mut std::io::BufWriter<fs::File>) -> Result<T>,
+) -> Result<T> {
+    if !force && Path::new(path).exists() {
+        return Err(Error::Other(format!(
+            "{path} already exists; use --force to overwrite"
+        )));
+    }
+    write_through_temp(path, mode, force, write)
+}
+
+fn write_through_temp<T>(
+    path: &str,
+    mode: u32,
+    replace: bool,
+    write: impl FnOnce(&mut std::io::BufWriter<fs::File>) -> Result<T>,
+) -> Result<T> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // unique and created exclusively, so concurrent writers never share or follow one
+    let tmp = format!("{path}.{:016x}.tmp", rand::random::<u64>());
+    let file = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(mode)
+        .open(&tmp)
+        .map_err(Error::from)?;
+    let mut writer = std::io::BufWriter::new(file);
+    let result = write(&mut writer).and_then(|value| {
+        writer
+            .into_inner()
+            .map_err(|e| Error::from(e.into_error()))?;
+        if replace {
+            fs::rename(&tmp, path).map_err(Error::from)?;
+        } else {
+            // a link fails if `path` appeared in the meantime, unlike a rename
+            fs::hard_link(&tmp, path).map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    Error::Other(format!("{path} already exists; use --force to overwrite"))
+                }
+                _ => Error::from(e),
+            })?;
+            let _ = fs::remove_file(&tmp);
+        }
+        Ok(value)
+    });
+    if result.is_err() {
+        debug!("removing partial output {tmp}");
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Opens `path` and takes an exclusive lock on it, which lasts until the file is dropped.
+/// Separate opens exclude each other, within this process as well as across processes.
+fn lock_file(path: &str) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(Error::from)?;
+    lock.lock().map_err(Error::from)?;
+    Ok(lock)
+}
+
+/// Locks the revocation list and the CRL state until the returned file is dropped.
+/// Revocations check the list before appending to it and every CRL takes the next number
+/// from the state, so neither may interleave with another request or command.
+pub fn lock_revocations() -> Result<fs::File> {
+    if let Some(parent) = Path::new(REVOCATION_LOCK).parent() {
+        fs::create_dir_all(parent).map_err(Error::from)?;
+    }
+    lock_file(REVOCATION_LOCK)
+}
+
+pub fn append_revocation(entry: &Revocation) -> Result<()> {
+    if let Some(parent) = Path::new(REVOCATION_FILE).parent() {
+        fs::create_dir_all(parent).map_err(Error::from)?;
+    }
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(REVOCATION_FILE)
+        .map_err(Error::from)?;
+    let line = serde_json::to_string(entry).map_err(Error::from)?;
+    writeln!(file, "{}", line).map_err(Error::from)
+}
+
+/// Loads all revocations, including bare serials from the legacy `revoked.txt`.
+pub fn read_revocations() -> Result<Vec<Revocation>> {
+    let mut out = Vec::new();
+    let legacy = Path::new(LEGACY_REVOCATION_FILE);
+    if legacy.exists() {
+        let revoked_at = fs::metadata(legacy)
+            .and_then(|m| m.modified())
+            .map_err(Error::from)?
+            .into();
+        for line in fs::read_to_string(legacy).map_err(Error::from)?.lines() {
+            let serial = line.trim();
+            if serial.is_empty() {
+                continue;
+            }
+            match x509::parse_serial(serial) {
+                Ok(bytes) => out.push(Revocation {
+                    serial: x509::format_serial(&bytes),
+                    revoked_at,
+                    reason: None,
+                    invalidity_date: None,
+                }),
+                Err(_) => warn!(%serial, "skipping malformed legacy revocation"),
+            }
+        }
+    }
+    let path = Path::new(REVOCATION_FILE);
+    if path.exists() {
+        for line in fs::read_to_string(path).map_err(Error::from)?.lines() {
+            if !line.trim().is_empty() {
+                out.push(serde_json::from_str(line).map_err(Error::from)?);
+            }
+        }
+    }
+    debug!(count = out.len(), "loaded revocations");
+    Ok(out)
+}
+
+pub fn read_crl_state() -> Result<CrlState> {
+    let path = Path::new(CRL_DIR).join("state.json");
+    if !path.exists() {
+        return Ok(CrlState::default());
+    }
+    let data = fs::read_to_string(path).map_err(Error::from)?;
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+pub fn write_crl_state(state: &CrlState) -> Result<()> {
+    fs::create_dir_all(CRL_DIR).map_err(Error::from)?;
+    let data = serde_json::to_string_pretty(state).map_err(Error::from)?;
+    fs::write(Path::new(CRL_DIR).join("state.json"), data).map_err(Error::from)
+}
+
+/// Writes `<name>.der` and `<name>.pem` below the CRL directory.
+pub fn write_crl(name: &str, der: &[u8], pem: &str) -> Result<()> {
+    fs::create_dir_all(CRL_DIR).map_err(Error::from)?;
+    let der_path = Path::new(CRL_DIR).join(format!("{name}.der"));
+    debug!("writing CRL to {:?}", der_path);
+    fs::write(der_path, der).map_err(Error::from)?;
+    fs::write(Path::new(CRL_DIR).join(format!("{name}.pem")), pem).map_err(Error::from)
+}
+
+/// Reads `<name>.der` from the CRL directory, if that CRL was issued.
+pub fn read_crl(name: &str) -> Result<Option<Vec<u8>>> {
+    let path = Path::new(CRL_DIR).join(format!("{name}.der"));
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read(path).map(Some).map_err(Error::from)
+}
+
+/// Modification time of `<name>.der`, to notice a reissued CRL cheaply.
+pub fn crl_modified(name: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(CRL_DIR).join(format!("{name}.der")))
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// Stores a signing identity as `<name>.key` (sealed, mode 0600) and `<name>.pub`.
+pub fn write_identity(identity: &StoredIdentity, force: bool) -> Result<()> {
+    fs::create_dir_all(KEYS_DIR).map_err(Error::from)?;
+    let name = &identity.public.name;
+    let key_path = Path::new(KEYS_DIR).join(format!("{name}.key"));
+    if !force && key_path.exists() {
+        return Err(Error::Other(format!(
+            "key {name} already exists; use --force to overwrite"
+        )));
+    }
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    debug!("writing signing key to {:?}", key_path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(key_path)
+        .map_err(Error::from)?;
+    let data = serde_json::to_string_pretty(identity).map_err(Error::from)?;
+    file.write_all(data.as_bytes()).map_err(Error::from)?;
+    let data = serde_json::to_string_pretty(&identity.public).map_err(Error::from)?;
+    fs::write(public_identity_path(name), data).map_err(Error::from)
+}
+
+pub fn public_identity_path(name: &str) -> std::path::PathBuf {
+    Path::new(KEYS_DIR).join(format!("{name}.pub"))
+}
+
+pub fn read_identity(name: &str) -> Result<StoredIdentity> {
+    let path = Path::new(KEYS_DIR).join(format!("{name}.key"));
+    if !path.exists() {
+        return Err(Error::Other(format!("no key named {name} in the keystore")));
+    }
+    let data = Zeroizing::new(fs::read_to_string(path).map_err(Error::from)?);
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+/// Stores the certificate the CA issued for a keystore key as `<name>.crt`.
+pub fn write_identity_cert(name: &str, cert_pem: &str) -> Result<()> {
+    fs::create_dir_all(KEYS_DIR).map_err(Error::from)?;
+    fs::write(Path::new(KEYS_DIR).join(format!("{name}.crt")), cert_pem).map_err(Error::from)
+}
+
+pub fn read_identity_cert(name: &str) -> Result<Option<String>> {
+    let path = Path::new(KEYS_DIR).join(format!("{name}.crt"));
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read_to_string(path).map(Some).map_err(Error::from)
+}
+
+pub fn read_public_identity(path: &str) -> Result<PublicIdentity> {
+    let data = fs::read_to_string(path).map_err(Error::from)?;
+    serde_json::from_str(&data).map_err(Error::from)
+}
+
+/// Loads every `*.pub` identity from the trusted-keys directory.
+pub fn read_trusted_keys(dir: &str) -> Result<Vec<PublicIdentity>> {
+    let path = Path::new(dir);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = Vec::new();
+    for entry in fs::read_dir(path).map_err(Error::from)? {
+        let path = entry.map_err(Error::from)?.path();
+        if path.extension().is_some_and(|e| e == "pub") {
+            match read_public_identity(&path.to_string_lossy()) {
+                Ok(id) => out.push(id),
+                Err(_) => warn!(?path, "skipping unreadable trusted key"),
+            }
+        }
+    }
+    debug!(count = out.len(), "loaded trusted keys");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cert_names_stay_in_cert_dir() {
+        assert!(check_cert_name("app.example.com").is_ok());
+        assert!(check_cert_name("*.example.com").is_ok());
+        assert!(check_cert_name("Build Signer").is_ok());
+        for name in ["", "../../etc/x", "/abs/path", "a\\b", "..", "a\0b", "a\nb"] {
+            assert!(check_cert_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn output_is_only_replaced_with_force() {
+        use std::io::Write;
+        let dir = std::env::temp_dir().join(format!("hypatia-out-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plain.txt").to_string_lossy().into_owned();
+        let put = |data: &'static [u8], force| {
+            write_output(&path, 0o600, force, |w| {
+                w.write_all(data).map_err(Error::from)
+            })
+        };
+        put(b"one", false).unwrap();
+        assert!(put(b"two", false).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"one");
+        put(b"three", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"three");
+        // no temporary files left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,67 @@
+use crate::error::{Error, Result};
+use crate::util::{fs, x509};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Index entry for a certificate issued by this CA.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IssuedCert {
+    pub serial: String,
+    pub cn: String,
+    #[serde(default)]
+    pub san: Vec<String>,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    /// SHA-256 of the SubjectPublicKeyInfo; empty for entries recorded before it was tracked.
+    #[serde(default)]
+    pub spki_sha256: String,
+}
+
+impl IssuedCert {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| Error::Other(format!("bad certificate: {e}")))?;
+        let cn = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let san = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) => Some(s.to_string()),
+                    GeneralName::IPAddress(ip) => x509::ip_to_string(ip),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Ok(Self {
+            serial: x509::format_serial(cert.raw_serial()),
+            cn,
+            san,
+            not_before: timestamp(cert.validity().not_before.timestamp())?,
+            not_after: timestamp(cert.validity().not_after.timestamp())?,
+            spki_sha256: x509::spki_hash(cert.public_key().raw),
+        })
+    }
+
+    /// SPKI hash of the certificate, falling back to the stored PEM for older entries.
+    pub fn spki_hash(&self) -> Result<String> {
+        if !self.spki_sha256.is_empty() {
+            return Ok(self.spki_sha256.clone());
+        }
+        let pem = fs::read_issued_pem(&self.serial)?;
+        Ok(Self::from_der(&x509::pem_to_der(&pem, "CERTIFICATE")?)?.spki_sha256)
+    }
+}
+
+fn timestamp(secs: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0).ok_or_else(|| Error::Other("timestamp out of range".into()))
+}
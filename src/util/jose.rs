@@ -0,0 +1,381 @@
+use crate::error::{Error, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use yasna::models::ObjectIdentifier;
+
+/// JWS algorithms accepted from ACME clients.
+pub const ALGORITHMS: &[&str] = &["ES256", "ES384", "RS256", "EdDSA"];
+
+const OID_EC_PUBLIC_KEY: &[u64] = &[1, 2, 840, 10045, 2, 1];
+const OID_P256: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+const OID_P384: &[u64] = &[1, 3, 132, 0, 34];
+const OID_RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+const OID_ED25519: &[u64] = &[1, 3, 101, 112];
+
+pub fn b64url(data: &[u8]) -> String {
+    BASE64URL.encode(data)
+}
+
+pub fn b64url_decode(data: &str) -> Result<Vec<u8>> {
+    BASE64URL
+        .decode(data)
+        .map_err(|e| Error::Other(format!("invalid base64url: {e}")))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Curve {
+    P256,
+    P384,
+}
+
+impl Curve {
+    fn name(self) -> &'static str {
+        match self {
+            Curve::P256 => "P-256",
+            Curve::P384 => "P-384",
+        }
+    }
+
+    fn coordinate_len(self) -> usize {
+        match self {
+            Curve::P256 => 32,
+            Curve::P384 => 48,
+        }
+    }
+}
+
+/// Public key in JSON Web Key form (RFC 7517), limited to the key types behind [`ALGORITHMS`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Jwk {
+    Ec {
+        curve: Curve,
+        x: Vec<u8>,
+        y: Vec<u8>,
+    },
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    Ed25519 {
+        x: Vec<u8>,
+    },
+}
+
+impl Jwk {
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let member = |name: &str| -> Result<Vec<u8>> {
+            let s = value
+                .get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::Other(format!("JWK lacks \"{name}\"")))?;
+            b64url_decode(s)
+        };
+        let crv = value.get("crv").and_then(Value::as_str);
+        let jwk = match (value.get("kty").and_then(Value::as_str), crv) {
+            (Some("EC"), Some(crv)) => {
+                let curve = match crv {
+                    "P-256" => Curve::P256,
+                    "P-384" => Curve::P384,
+                    _ => return Err(Error::Other(format!("unsupported JWK curve {crv}"))),
+                };
+                let (x, y) = (member("x")?, member("y")?);
+                if x.len() != curve.coordinate_len() || y.len() != curve.coordinate_len() {
+                    return Err(Error::Other(format!("bad {crv} JWK coordinates")));
+                }
+                Jwk::Ec { curve, x, y }
+            }
+            (Some("RSA"), _) => {
+                let trim = |v: Vec<u8>| {
+                    let start = v.iter().position(|b| *b != 0).unwrap_or(v.len());
+                    v[start..].to_vec()
+                };
+                let (n, e) = (trim(member("n")?), trim(member("e")?));
+                if n.len() < 256 || e.is_empty() {
+                    return Err(Error::Other("RSA JWK must be at least 2048 bits".into()));
+                }
+                Jwk::Rsa { n, e }
+            }
+            (Some("OKP"), Some("Ed25519")) => {
+                let x = member("x")?;
+                if x.len() != 32 {
+                    return Err(Error::Other("bad Ed25519 JWK".into()));
+                }
+                Jwk::Ed25519 { x }
+            }
+            (kty, _) => {
+                return Err(Error::Other(format!(
+                    "unsupported JWK type {}",
+                    kty.unwrap_or("(none)")
+                )));
+            }
+        };
+        Ok(jwk)
+    }
+
+    /// Required members only, which is also the form the thumbprint is computed over.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Jwk::Ec { curve, x, y } => {
+                json!({"crv": curve.name(), "kty": "EC", "x": b64url(x), "y": b64url(y)})
+            }
+            Jwk::Rsa { n, e } => json!({"e": b64url(e), "kty": "RSA", "n": b64url(n)}),
+            Jwk::Ed25519 { x } => json!({"crv": "Ed25519", "kty": "OKP", "x": b64url(x)}),
+        }
+    }
+
+    /// RFC 7638 thumbprint: base64url SHA-256 of the required members in lexicographic order.
+    pub fn thumbprint(&self) -> String {
+        let canonical = match self {
+            Jwk::Ec { curve, x, y } => format!(
+                r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+                curve.name(),
+                b64url(x),
+                b64url(y)
+            ),
+            Jwk::Rsa { n, e } => {
+                format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, b64url(e), b64url(n))
+            }
+            Jwk::Ed25519 { x } => format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, b64url(x)),
+        };
+        b64url(&Sha256::digest(canonical.as_bytes()))
+    }
+
+    /// DER SubjectPublicKeyInfo of the key, for comparison with certificates and CSRs.
+    pub fn spki(&self) -> Vec<u8> {
+        yasna::construct_der(|w| {
+            w.write_sequence(|w| match self {
+                Jwk::Ec { curve, x, y } => {
+                    let oid = match curve {
+                        Curve::P256 => OID_P256,
+                        Curve::P384 => OID_P384,
+                    };
+                    w.next().write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_EC_PUBLIC_KEY));
+                        w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+                    });
+                    let point = [&[4u8][..], x, y].concat();
+                    w.next().write_bitvec_bytes(&point, point.len() * 8);
+                }
+                Jwk::Rsa { n, e } => {
+                    w.next().write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_RSA_ENCRYPTION));
+                        w.next().write_null();
+                    });
+                    let key = yasna::construct_der(|w| {
+                        w.write_sequence(|w| {
+                            w.next().write_bigint_bytes(n, true);
+                            w.next().write_bigint_bytes(e, true);
+                        })
+                    });
+                    w.next().write_bitvec_bytes(&key, key.len() * 8);
+                }
+                Jwk::Ed25519 { x } => {
+                    w.next().write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_ED25519));
+                    });
+                    w.next().write_bitvec_bytes(x, x.len() * 8);
+                }
+            })
+        })
+    }
+
+    /// Verifies a JWS signature made with `alg`, which must match the key type.
+    pub fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Result<()> {
+        let ok = match (alg, self) {
+            (
+                "ES256",
+                Jwk::Ec {
+                    curve: Curve::P256,
+                    x,
+                    y,
+                },
+            ) => {
+                let point = [&[4u8][..], x, y].concat();
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            (
+                "ES384",
+                Jwk::Ec {
+                    curve: Curve::P384,
+                    x,
+                    y,
+                },
+            ) => {
+                let point = [&[4u8][..], x, y].concat();
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            ("RS256", Jwk::Rsa { n, e }) => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+            ("EdDSA", Jwk::Ed25519 { x }) => UnparsedPublicKey::new(&signature::ED25519, x)
+                .verify(message, sig)
+                .is_ok(),
+            _ => {
+                return Err(Error::Other(format!(
+                    "algorithm {alg} does not match the JWK"
+                )));
+            }
+        };
+        if !ok {
+            return Err(Error::Other("JWS signature is invalid".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Key a JWS is signed with: either embedded or the URL of an existing account.
+#[derive(Clone, Debug)]
+pub enum KeyRef {
+    Jwk(Jwk),
+    Kid(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Flattened {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct Protected {
+    alg: String,
+    nonce: Option<String>,
+    url: Option<String>,
+    jwk: Option<Value>,
+    kid: Option<String>,
+}
+
+/// A JWS in flattened JSON serialization with the protected header of RFC 8555 section 6.2.
+#[derive(Clone, Debug)]
+pub struct Jws {
+    pub alg: String,
+    pub nonce: Option<String>,
+    pub url: String,
+    pub key: KeyRef,
+    pub payload: Vec<u8>,
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+impl Jws {
+    /// Parses the request body; the signature is not checked until [`Jws::verify`].
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let jws: Flattened = serde_json::from_slice(body)
+            .map_err(|e| Error::Other(format!("request is not a flattened JWS: {e}")))?;
+        let header: Protected = serde_json::from_slice(&b64url_decode(&jws.protected)?)
+            .map_err(|e| Error::Other(format!("bad JWS protected header: {e}")))?;
+        let url = header
+            .url
+            .ok_or_else(|| Error::Other("JWS protected header lacks \"url\"".into()))?;
+        let key = match (header.jwk, header.kid) {
+            (Some(jwk), None) => KeyRef::Jwk(Jwk::from_json(&jwk)?),
+            (None, Some(kid)) => KeyRef::Kid(kid),
+            _ => {
+                return Err(Error::Other(
+                    "JWS must carry exactly one of \"jwk\" and \"kid\"".into(),
+                ));
+            }
+        };
+        Ok(Self {
+            alg: header.alg,
+            nonce: header.nonce,
+            url,
+            key,
+            payload: b64url_decode(&jws.payload)?,
+            signing_input: format!("{}.{}", jws.protected, jws.payload),
+            signature: b64url_decode(&jws.signature)?,
+        })
+    }
+
+    pub fn verify(&self, key: &Jwk) -> Result<()> {
+        key.verify(&self.alg, self.signing_input.as_bytes(), &self.signature)
+    }
+
+    /// POST-as-GET requests carry an empty payload.
+    pub fn is_post_as_get(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.payload)
+            .map_err(|e| Error::Other(format!("bad JWS payload: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+
+    #[test]
+    fn rfc7638_thumbprint() {
+        let jwk = Jwk::from_json(&json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        }))
+        .unwrap();
+        assert_eq!(
+            jwk.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn verifies_es256_jws() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = key.public_key().as_ref();
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": b64url(&point[1..33]),
+            "y": b64url(&point[33..]),
+        });
+        let protected = b64url(
+            json!({"alg": "ES256", "jwk": jwk, "nonce": "n1", "url": "https://ca/acme/new-account"})
+                .to_string()
+                .as_bytes(),
+        );
+        let payload = b64url(br#"{"termsOfServiceAgreed":true}"#);
+        let sig = key
+            .sign(&rng, format!("{protected}.{payload}").as_bytes())
+            .unwrap();
+        let body =
+            json!({"protected": protected, "payload": payload, "signature": b64url(sig.as_ref())});
+
+        let jws = Jws::parse(body.to_string().as_bytes()).unwrap();
+        let KeyRef::Jwk(jwk) = &jws.key else {
+            panic!("expected an embedded JWK");
+        };
+        jws.verify(jwk).unwrap();
+        assert_eq!(jws.url, "https://ca/acme/new-account");
+        assert!(!jws.is_post_as_get());
+        assert_eq!(jwk.spki().len(), 91);
+
+        let mut forged = jws.clone();
+        forged.payload = b"{}".to_vec();
+        forged.signing_input = format!("{protected}.{}", b64url(b"{}"));
+        assert!(forged.verify(jwk).is_err());
+        assert!(jwk.verify("RS256", b"m", sig.as_ref()).is_err());
+    }
+}
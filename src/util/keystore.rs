@@ -0,0 +1,494 @@
+use crate::error::{Error, Result};
+use crate::util::fs;
+use crate::util::pq::{Algorithm, Kem};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use rand::RngCore;
+use rcgen::{KeyPair, PublicKeyData, SignatureAlgorithm, SigningKey};
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, Write};
+use std::num::NonZeroU32;
+use zeroize::Zeroizing;
+
+/// Environment variable consulted for the keystore passphrase.
+pub const PASSPHRASE_ENV: &str = "HYPATIA_PASSPHRASE";
+
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Classical signature algorithm paired with the post-quantum key of a hybrid identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Classical {
+    Ed25519,
+    P384,
+}
+
+impl Classical {
+    pub fn name(self) -> &'static str {
+        match self {
+            Classical::Ed25519 => "ed25519",
+            Classical::P384 => "p384",
+        }
+    }
+
+    /// Signature algorithm OID (Ed25519 or ecdsa-with-SHA384).
+    pub fn oid(self) -> &'static [u64] {
+        match self {
+            Classical::Ed25519 => &[1, 3, 101, 112],
+            Classical::P384 => &[1, 2, 840, 10045, 4, 3, 3],
+        }
+    }
+
+    pub fn from_oid(oid: &[u64]) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|alg| alg.oid() == oid)
+    }
+
+    fn signature_algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            Classical::Ed25519 => &rcgen::PKCS_ED25519,
+            Classical::P384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+        }
+    }
+}
+
+/// Classical half of a hybrid identity.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClassicalKey {
+    pub algorithm: Classical,
+    /// Base64 DER SubjectPublicKeyInfo.
+    pub public_key: String,
+}
+
+impl ClassicalKey {
+    pub fn spki(&self) -> Result<Vec<u8>> {
+        B64.decode(&self.public_key)
+            .map_err(|e| Error::Other(format!("bad classical public key: {e}")))
+    }
+}
+
+/// Encryption key of an identity: a Kyber key, optionally paired with an X25519 key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KemKey {
+    pub algorithm: Kem,
+    pub key_id: String,
+    /// Base64 Kyber public key.
+    pub public_key: String,
+    /// Base64 X25519 public key of a hybrid encryption key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x25519: Option<String>,
+}
+
+impl KemKey {
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        B64.decode(&self.public_key)
+            .map_err(|e| Error::Other(format!("bad encryption key: {e}")))
+    }
+
+    pub fn x25519(&self) -> Result<Option<[u8; 32]>> {
+        self.x25519
+            .as_ref()
+            .map(|key| {
+                B64.decode(key)
+                    .ok()
+                    .and_then(|key| key.try_into().ok())
+                    .ok_or_else(|| Error::Other("bad X25519 public key".into()))
+            })
+            .transpose()
+    }
+
+    /// Algorithm name as shown to users, e.g. `kyber768+x25519` for a hybrid key.
+    pub fn algorithm_name(&self) -> String {
+        match self.x25519 {
+            Some(_) => format!("{}+x25519", self.algorithm.name()),
+            None => self.algorithm.name().to_owned(),
+        }
+    }
+}
+
+/// Public half of a signing identity, as exported to verifiers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PublicIdentity {
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub key_id: String,
+    /// Base64 public key.
+    pub public_key: String,
+    /// Present on hybrid identities, whose signatures must verify with both keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classical: Option<ClassicalKey>,
+    /// Present on identities that can receive encrypted files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kem: Option<KemKey>,
+}
+
+impl PublicIdentity {
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        B64.decode(&self.public_key)
+            .map_err(|e| Error::Other(format!("bad public key for {}: {e}", self.name)))
+    }
+
+    /// Algorithm name as shown to users, e.g. `dilithium3+ed25519` for a hybrid key.
+    pub fn algorithm_name(&self) -> String {
+        match &self.classical {
+            Some(c) => format!("{}+{}", self.algorithm.name(), c.algorithm.name()),
+            None => self.algorithm.name().to_owned(),
+        }
+    }
+}
+
+/// Secret key encrypted with ChaCha20-Poly1305 under a PBKDF2-SHA256 derived key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedKey {
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Keystore entry holding a named, passphrase-protected signing key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredIdentity {
+    #[serde(flatten)]
+    pub public: PublicIdentity,
+    pub created: DateTime<Utc>,
+    pub secret_key: SealedKey,
+    /// PKCS#8 PEM of the classical key, as written by rcgen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classical_key: Option<SealedKey>,
+    /// Kyber secret key, followed by the X25519 secret of a hybrid encryption key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kem_key: Option<SealedKey>,
+}
+
+/// Unlocked secret keys of an identity.
+pub struct SecretKey {
+    pub pq: Zeroizing<Vec<u8>>,
+    pub classical: Option<KeyPair>,
+    pub kem: Option<KemSecret>,
+}
+
+/// Unlocked encryption key of an identity.
+pub struct KemSecret {
+    pub kyber: Zeroizing<Vec<u8>>,
+    pub x25519: Option<Zeroizing<[u8; 32]>>,
+}
+
+impl SecretKey {
+    /// Signs `data` with the classical key, if there is one.
+    pub fn sign_classical(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.classical
+            .as_ref()
+            .map(|key| key.sign(data).map_err(Error::from))
+            .transpose()
+    }
+}
+
+/// Short identifier of a public key: the first 8 bytes of its SHA-256, in hex.
+pub fn key_id(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Keystore names become file names, so only a conservative alphabet is allowed.
+pub fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(Error::Other(format!("invalid key name {name:?}")));
+    }
+    Ok(())
+}
+
+/// Reads the passphrase from `file`, then `$HYPATIA_PASSPHRASE`, then stdin.
+pub fn passphrase(file: Option<&str>) -> Result<Zeroizing<String>> {
+    let mut pass = if let Some(path) = file {
+        Zeroizing::new(std::fs::read_to_string(path).map_err(Error::from)?)
+    } else if let Ok(pass) = std::env::var(PASSPHRASE_ENV) {
+        Zeroizing::new(pass)
+    } else {
+        eprint!("passphrase: ");
+        std::io::stderr().flush().map_err(Error::from)?;
+        let mut line = Zeroizing::new(String::new());
+        std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(Error::from)?;
+        line
+    };
+    let len = pass.trim_end_matches(['\r', '\n']).len();
+    pass.truncate(len);
+    if pass.is_empty() {
+        return Err(Error::Other("empty passphrase".into()));
+    }
+    Ok(pass)
+}
+
+fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| Error::Other("invalid PBKDF2 iteration count".into()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        key.as_mut(),
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key.as_ref())
+        .map_err(|_| Error::Other("cannot initialise cipher".into()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Additional data binding the sealed classical key to its identity, so it cannot be
+/// swapped with the post-quantum secret.
+fn classical_aad(name: &str) -> String {
+    format!("{name}:classical")
+}
+
+fn kem_aad(name: &str) -> String {
+    format!("{name}:kem")
+}
+
+fn seal(name: &str, secret: &[u8], passphrase: &str, iterations: u32) -> Result<SealedKey> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let key = derive(passphrase, &salt, iterations)?;
+    let mut sealed = secret.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(name.as_bytes()),
+        &mut sealed,
+    )
+    .map_err(|_| Error::Other("cannot seal secret key".into()))?;
+    Ok(SealedKey {
+        iterations,
+        salt: B64.encode(salt),
+        nonce: B64.encode(nonce),
+        ciphertext: B64.encode(sealed),
+    })
+}
+
+/// Generates a new identity with its secret keys sealed under `passphrase`. With
+/// `classical`, an Ed25519 or P-384 key is generated alongside for hybrid signatures;
+/// with `kem`, a Kyber encryption key (plus an X25519 key if `x25519` is set).
+pub fn generate(
+    name: &str,
+    algorithm: Algorithm,
+    classical: Option<Classical>,
+    kem: Option<Kem>,
+    x25519: bool,
+    passphrase: &str,
+) -> Result<StoredIdentity> {
+    check_name(name)?;
+    let (pk, sk) = algorithm.keypair();
+    let (classical, classical_key) = match classical {
+        Some(alg) => {
+            let key = KeyPair::generate_for(alg.signature_algorithm()).map_err(Error::from)?;
+            let pem = Zeroizing::new(key.serialize_pem());
+            let public = ClassicalKey {
+                algorithm: alg,
+                public_key: B64.encode(key.subject_public_key_info()),
+            };
+            let sealed = seal(
+                &classical_aad(name),
+                pem.as_bytes(),
+                passphrase,
+                PBKDF2_ITERATIONS,
+            )?;
+            (Some(public), Some(sealed))
+        }
+        None => (None, None),
+    };
+    let (kem, kem_key) = match kem {
+        Some(alg) => {
+            let (pk, sk) = alg.keypair()?;
+            let mut secret = Zeroizing::new(sk.to_vec());
+            let x25519 = x25519.then(|| {
+                let key = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+                secret.extend_from_slice(key.as_bytes());
+                B64.encode(x25519_dalek::PublicKey::from(&key).as_bytes())
+            });
+            let public = KemKey {
+                algorithm: alg,
+                key_id: key_id(&pk),
+                public_key: B64.encode(&pk),
+                x25519,
+            };
+            let sealed = seal(&kem_aad(name), &secret, passphrase, PBKDF2_ITERATIONS)?;
+            (Some(public), Some(sealed))
+        }
+        None => (None, None),
+    };
+    Ok(StoredIdentity {
+        public: PublicIdentity {
+            name: name.to_owned(),
+            algorithm,
+            key_id: key_id(&pk),
+            public_key: B64.encode(&pk),
+            classical,
+            kem,
+        },
+        created: Utc::now(),
+        secret_key: seal(name, &sk, passphrase, PBKDF2_ITERATIONS)?,
+        classical_key,
+        kem_key,
+    })
+}
+
+fn open(sealed: &SealedKey, aad: &str, passphrase: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let decode = |s: &str| {
+        B64.decode(s)
+            .map_err(|e| Error::Other(format!("corrupt keystore entry: {e}")))
+    };
+    let salt = decode(&sealed.salt)?;
+    let nonce: [u8; NONCE_LEN] = decode(&sealed.nonce)?
+        .try_into()
+        .map_err(|_| Error::Other("corrupt keystore entry: bad nonce".into()))?;
+    let mut buf = Zeroizing::new(decode(&sealed.ciphertext)?);
+
+    let key = derive(passphrase, &salt, sealed.iterations)?;
+    Ok(key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad.as_bytes()),
+            &mut buf,
+        )
+        .ok()
+        .map(|plain| Zeroizing::new(plain.to_vec())))
+}
+
+impl StoredIdentity {
+    /// Decrypts the secret keys; fails on a wrong passphrase.
+    pub fn unseal(&self, passphrase: &str) -> Result<SecretKey> {
+        let name = &self.public.name;
+        let wrong = || Error::Other(format!("wrong passphrase for key {name}"));
+        let pq = open(&self.secret_key, name, passphrase)?.ok_or_else(wrong)?;
+        let classical = match (&self.classical_key, &self.public.classical) {
+            (Some(sealed), Some(public)) => {
+                let pem = open(sealed, &classical_aad(name), passphrase)?.ok_or_else(wrong)?;
+                let pem = std::str::from_utf8(&pem)
+                    .map_err(|_| Error::Other(format!("corrupt classical key for {name}")))?;
+                let key =
+                    KeyPair::from_pem_and_sign_algo(pem, public.algorithm.signature_algorithm())
+                        .map_err(Error::from)?;
+                if B64.encode(key.subject_public_key_info()) != public.public_key {
+                    return Err(Error::Other(format!(
+                        "classical key of {name} does not match its public key"
+                    )));
+                }
+                Some(key)
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::Other(format!(
+                    "keystore entry {name} has an incomplete classical key"
+                )));
+            }
+        };
+        let kem = match (&self.kem_key, &self.public.kem) {
+            (Some(sealed), Some(public)) => {
+                let mut kyber = open(sealed, &kem_aad(name), passphrase)?.ok_or_else(wrong)?;
+                let x25519 = match public.x25519()? {
+                    Some(expected) => {
+                        let split = kyber.len().checked_sub(32).ok_or_else(|| {
+                            Error::Other(format!("corrupt encryption key for {name}"))
+                        })?;
+                        let mut secret = Zeroizing::new([0u8; 32]);
+                        secret.copy_from_slice(&kyber[split..]);
+                        kyber.truncate(split);
+                        let derived = x25519_dalek::PublicKey::from(
+                            &x25519_dalek::StaticSecret::from(*secret),
+                        );
+                        if derived.as_bytes() != &expected {
+                            return Err(Error::Other(format!(
+                                "X25519 key of {name} does not match its public key"
+                            )));
+                        }
+                        Some(secret)
+                    }
+                    None => None,
+                };
+                Some(KemSecret { kyber, x25519 })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::Other(format!(
+                    "keystore entry {name} has an incomplete encryption key"
+                )));
+            }
+        };
+        Ok(SecretKey { pq, classical, kem })
+    }
+}
+
+/// Loads `name` from the keystore and unlocks it.
+pub fn unlock(name: &str, passphrase: &str) -> Result<(PublicIdentity, SecretKey)> {
+    check_name(name)?;
+    let stored = fs::read_identity(name)?;
+    let sk = stored.unseal(passphrase)?;
+    Ok((stored.public, sk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip_and_wrong_passphrase() {
+        let id = StoredIdentity {
+            public: PublicIdentity {
+                name: "test".into(),
+                algorithm: Algorithm::Dilithium3,
+                key_id: key_id(b"pk"),
+                public_key: B64.encode(b"pk"),
+                classical: None,
+                kem: None,
+            },
+            created: Utc::now(),
+            secret_key: seal("test", b"secret", "hunter2", 1).unwrap(),
+            classical_key: None,
+            kem_key: None,
+        };
+        assert_eq!(id.unseal("hunter2").unwrap().pq.as_slice(), b"secret");
+        assert!(id.unseal("hunter3").is_err());
+        assert!(check_name("../etc").is_err());
+
+        let key = KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let pem = key.serialize_pem();
+        let mut hybrid = StoredIdentity {
+            public: PublicIdentity {
+                classical: Some(ClassicalKey {
+                    algorithm: Classical::Ed25519,
+                    public_key: B64.encode(key.subject_public_key_info()),
+                }),
+                ..id.public.clone()
+            },
+            classical_key: Some(
+                seal(&classical_aad("test"), pem.as_bytes(), "hunter2", 1).unwrap(),
+            ),
+            ..id
+        };
+        let sk = hybrid.unseal("hunter2").unwrap();
+        assert!(sk.sign_classical(b"data").unwrap().is_some());
+
+        // a classical key sealed under the identity's own associated data is refused
+        hybrid.classical_key = Some(seal("test", pem.as_bytes(), "hunter2", 1).unwrap());
+        assert!(hybrid.unseal("hunter2").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::{Error, Result};
+use crate::util::envelope::HashAlg;
+use clap::ValueEnum;
+use glob::Pattern;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+const HEADER: &str = "hypatia-ca manifest v1";
+
+/// Include and exclude globs, matched against `/`-separated relative paths.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    fn compile(globs: &[String]) -> Result<Vec<Pattern>> {
+        globs
+            .iter()
+            .map(|g| Pattern::new(g).map_err(|e| Error::Other(format!("bad glob {g:?}: {e}"))))
+            .collect()
+    }
+
+    /// A file is kept if it matches some include glob (or none are given) and no exclude glob.
+    pub fn matcher(&self) -> Result<impl Fn(&str) -> bool + use<>> {
+        let include = Self::compile(&self.include)?;
+        let exclude = Self::compile(&self.exclude)?;
+        Ok(move |path: &str| {
+            (include.is_empty() || include.iter().any(|p| p.matches(path)))
+                && !exclude.iter().any(|p| p.matches(path))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub size: u64,
+    pub digest: Vec<u8>,
+}
+
+/// Canonical listing of a directory tree: relative paths in byte order with their
+/// sizes and digests, plus the filters the listing was made with.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub hash: HashAlg,
+    pub filter: Filter,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// Differences between a signed manifest and the tree on disk.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+fn hash_name(hash: HashAlg) -> String {
+    hash.to_possible_value()
+        .map(|v| v.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+impl Manifest {
+    /// Walks `root` and hashes every regular file accepted by `filter`.
+    /// Symbolic links are not followed and are left out.
+    pub fn build(root: &Path, hash: HashAlg, filter: Filter) -> Result<Self> {
+        let keep = filter.matcher()?;
+        let mut entries = BTreeMap::new();
+        for item in WalkDir::new(root).follow_links(false) {
+            let item = item.map_err(|e| Error::Other(format!("cannot walk {root:?}: {e}")))?;
+            if !item.file_type().is_file() {
+                if item.file_type().is_symlink() {
+                    warn!(path = ?item.path(), "skipping symbolic link");
+                }
+                continue;
+            }
+            let rel = item
+                .path()
+                .strip_prefix(root)
+                .map_err(|e| Error::Other(e.to_string()))?;
+            let rel = rel
+                .to_str()
+                .filter(|p| !p.contains('\n'))
+                .ok_or_else(|| Error::Other(format!("unsupported file name {rel:?}")))?
+                .replace(std::path::MAIN_SEPARATOR, "/");
+            if !keep(&rel) {
+                continue;
+            }
+            let size = item
+                .metadata()
+                .map_err(|e| Error::Other(e.to_string()))?
+                .len();
+            let digest = hash.digest_file(&item.path().to_string_lossy())?;
+            entries.insert(rel, Entry { size, digest });
+        }
+        debug!(files = entries.len(), "manifest built");
+        Ok(Self {
+            hash,
+            filter,
+            entries,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{HEADER}\nhash {}\n", hash_name(self.hash));
+        for glob in &self.filter.include {
+            out.push_str(&format!("include {glob}\n"));
+        }
+        for glob in &self.filter.exclude {
+            out.push_str(&format!("exclude {glob}\n"));
+        }
+        out.push('\n');
+        for (path, entry) in &self.entries {
+            let digest: String = entry.digest.iter().map(|b| format!("{b:02x}")).collect();
+            out.push_str(&format!("{digest} {} {path}\n", entry.size));
+        }
+        out
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let bad = |line: &str| Error::Other(format!("bad manifest line: {line:?}"));
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(Error::Other("not a hypatia-ca manifest".into()));
+        }
+        let mut hash = None;
+        let mut filter = Filter::default();
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(' ') {
+                Some(("hash", name)) => hash = HashAlg::from_str(name, false).ok(),
+                Some(("include", glob)) => filter.include.push(glob.to_owned()),
+                Some(("exclude", glob)) => filter.exclude.push(glob.to_owned()),
+                _ => return Err(bad(line)),
+            }
+        }
+        let hash = hash.ok_or_else(|| Error::Other("manifest names no hash".into()))?;
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            let mut parts = line.splitn(3, ' ');
+            let (Some(digest), Some(size), Some(path)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(bad(line));
+            };
+            let digest = (0..digest.len())
+                .step_by(2)
+                .map(|i| {
+                    digest
+                        .get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| bad(line))?;
+            let size = size.parse().map_err(|_| bad(line))?;
+            entries.insert(path.to_owned(), Entry { size, digest });
+        }
+        Ok(Self {
+            hash,
+            filter,
+            entries,
+        })
+    }
+
+    /// Compares this (signed) manifest with `actual`, considering only paths kept by `keep`.
+    pub fn diff(&self, actual: &Manifest, keep: impl Fn(&str) -> bool) -> Diff {
+        let mut diff = Diff::default();
+        for (path, entry) in self.entries.iter().filter(|(p, _)| keep(p)) {
+            match actual.entries.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(found) if found != entry => diff.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        for path in actual.entries.keys().filter(|p| keep(p)) {
+            if !self.entries.contains_key(path) {
+                diff.added.push(path.clone());
+            }
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip_and_diff() {
+        let entry = |b: u8| Entry {
+            size: 1,
+            digest: vec![b; 4],
+        };
+        let signed = Manifest {
+            hash: HashAlg::Sha3_512,
+            filter: Filter {
+                include: vec![],
+                exclude: vec!["*.log".into()],
+            },
+            entries: BTreeMap::from([
+                ("a.txt".to_owned(), entry(1)),
+                ("dir/b name.txt".to_owned(), entry(2)),
+                ("gone".to_owned(), entry(3)),
+            ]),
+        };
+        let parsed = Manifest::parse(&signed.to_text()).unwrap();
+        assert_eq!(parsed.entries, signed.entries);
+        assert_eq!(parsed.filter.exclude, vec!["*.log".to_owned()]);
+        assert_eq!(parsed.hash, HashAlg::Sha3_512);
+
+        let mut actual = signed.clone();
+        actual.entries.remove("gone");
+        actual.entries.insert("a.txt".into(), entry(9));
+        actual.entries.insert("new".into(), entry(4));
+        let keep = parsed.filter.matcher().unwrap();
+        let diff = parsed.diff(&actual, keep);
+        assert_eq!(diff.added, vec!["new"]);
+        assert_eq!(diff.removed, vec!["gone"]);
+        assert_eq!(diff.modified, vec!["a.txt"]);
+    }
+}
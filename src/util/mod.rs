@@ -1,2 +1,22 @@
 pub mod audit;
-pub mod fs;
\ No newline at end of file
+pub mod cms;
+pub mod crl;
+pub mod dns;
+pub mod encryption;
+pub mod envelope;
+pub mod fs;
+pub mod issued;
+pub mod jose;
+pub mod keystore;
+pub mod manifest;
+pub mod ocsp;
+pub mod password;
+pub mod pq;
+pub mod pqcert;
+pub mod profile;
+pub mod ratelimit;
+pub mod revocation;
+pub mod scep;
+pub mod token;
+pub mod tsp;
+pub mod x509;
@@ -0,0 +1,363 @@
+use crate::error::{Error, Result};
+use crate::util::crl::Reason;
+use crate::util::issued::IssuedCert;
+use crate::util::{fs, x509};
+use chrono::{DateTime, Duration, Utc};
+use rcgen::{
+    CertificateParams, CustomExtension, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose, SigningKey,
+};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tracing::{debug, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use yasna::Tag;
+use yasna::models::ObjectIdentifier;
+use zeroize::Zeroizing;
+
+const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_OCSP_BASIC: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+const OID_OCSP_NONCE: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 2];
+const OID_OCSP_NOCHECK: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 5];
+
+/// OCSPResponseStatus values from RFC 6960.
+pub const MALFORMED_REQUEST: u8 = 1;
+pub const INTERNAL_ERROR: u8 = 2;
+
+/// A CertID taken from a request, kept in its original encoding so it can be echoed back.
+pub struct CertId {
+    raw: Vec<u8>,
+    hash_alg: ObjectIdentifier,
+    name_hash: Vec<u8>,
+    key_hash: Vec<u8>,
+    serial: Vec<u8>,
+}
+
+pub struct OcspRequest {
+    pub certs: Vec<CertId>,
+    /// Contents of the nonce extension, echoed verbatim in the response.
+    pub nonce: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub enum CertStatus {
+    Good,
+    Revoked {
+        at: DateTime<Utc>,
+        reason: Option<Reason>,
+    },
+    Unknown,
+}
+
+pub fn parse_request(der: &[u8]) -> Result<OcspRequest> {
+    let (raw_ids, nonce) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let tbs = r.next().read_sequence(|r| {
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_u8()))?;
+                r.read_optional(|r| r.read_tagged(Tag::context(1), |r| r.read_der()))?;
+                let ids = r.next().collect_sequence_of(|r| {
+                    r.read_sequence(|r| {
+                        let id = r.next().read_der()?;
+                        r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                        Ok(id)
+                    })
+                })?;
+                let nonce = r
+                    .read_optional(|r| {
+                        r.read_tagged(Tag::context(2), |r| {
+                            let mut nonce = None;
+                            r.read_sequence_of(|r| {
+                                r.read_sequence(|r| {
+                                    let oid = r.next().read_oid()?;
+                                    r.read_default(false, |r| r.read_bool())?;
+                                    let value = r.next().read_bytes()?;
+                                    if oid == ObjectIdentifier::from_slice(OID_OCSP_NONCE) {
+                                        nonce = Some(value);
+                                    }
+                                    Ok(())
+                                })
+                            })?;
+                            Ok(nonce)
+                        })
+                    })?
+                    .flatten();
+                Ok((ids, nonce))
+            })?;
+            r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+            Ok(tbs)
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed OCSP request: {e}")))?;
+
+    let mut certs = Vec::with_capacity(raw_ids.len());
+    for raw in raw_ids {
+        let (hash_alg, name_hash, key_hash, serial) = yasna::parse_ber(&raw, |r| {
+            r.read_sequence(|r| {
+                let alg = r.next().read_sequence(|r| {
+                    let oid = r.next().read_oid()?;
+                    r.read_optional(|r| r.read_null())?;
+                    Ok(oid)
+                })?;
+                let name_hash = r.next().read_bytes()?;
+                let key_hash = r.next().read_bytes()?;
+                let (serial, _) = r.next().read_bigint_bytes()?;
+                Ok((alg, name_hash, key_hash, serial))
+            })
+        })
+        .map_err(|e| Error::Other(format!("malformed CertID: {e}")))?;
+        certs.push(CertId {
+            raw,
+            hash_alg,
+            name_hash,
+            key_hash,
+            serial,
+        });
+    }
+    if certs.is_empty() {
+        return Err(Error::Other("OCSP request without certificates".into()));
+    }
+    Ok(OcspRequest { certs, nonce })
+}
+
+/// An OCSPResponse carrying only an error status.
+pub fn error_response(status: u8) -> Vec<u8> {
+    yasna::construct_der(|w| w.write_sequence(|w| w.next().write_enum(status.into())))
+}
+
+/// Signs OCSP responses on behalf of the CA with a delegated responder certificate.
+pub struct Responder {
+    key: KeyPair,
+    cert_der: Vec<u8>,
+    issuer_name: Vec<u8>,
+    issuer_key: Vec<u8>,
+    validity: Duration,
+}
+
+impl Responder {
+    /// Loads the delegated OCSP signer, issuing a fresh one when missing or about to expire.
+    pub fn load(validity_hours: u32, signer_days: u32) -> Result<Self> {
+        let (ca_pem, ca_key) = fs::read_root_ca()?;
+        let ca_der = x509::pem_to_der(&ca_pem, "CERTIFICATE")?;
+        let (_, ca) = X509Certificate::from_der(&ca_der)
+            .map_err(|e| Error::Other(format!("bad CA certificate: {e}")))?;
+        let issuer_name = ca.tbs_certificate.subject.as_raw().to_vec();
+        let issuer_key = ca.public_key().subject_public_key.data.to_vec();
+
+        let (cert_pem, key_pem) = match fs::read_ocsp_signer()? {
+            Some((cert, key)) if !expires_soon(&cert)? => (cert, key),
+            _ => issue_signer(&ca_pem, &ca_key, signer_days)?,
+        };
+        let key = KeyPair::from_pem(&key_pem).map_err(Error::from)?;
+        Ok(Self {
+            key,
+            cert_der: x509::pem_to_der(&cert_pem, "CERTIFICATE")?,
+            issuer_name,
+            issuer_key,
+            validity: Duration::hours(validity_hours.into()),
+        })
+    }
+
+    /// A freshly loaded signer if this one expires soon, so a long-running server replaces
+    /// it before responses would carry an expired certificate.
+    pub fn renew(&self, validity_hours: u32, signer_days: u32) -> Result<Option<Self>> {
+        if !der_expires_soon(&self.cert_der)? {
+            return Ok(None);
+        }
+        Self::load(validity_hours, signer_days).map(Some)
+    }
+
+    fn matches_issuer(&self, id: &CertId) -> bool {
+        let (name, key) = if id.hash_alg == ObjectIdentifier::from_slice(OID_SHA1) {
+            (
+                Sha1::digest(&self.issuer_name).to_vec(),
+                Sha1::digest(&self.issuer_key).to_vec(),
+            )
+        } else if id.hash_alg == ObjectIdentifier::from_slice(OID_SHA256) {
+            (
+                Sha256::digest(&self.issuer_name).to_vec(),
+                Sha256::digest(&self.issuer_key).to_vec(),
+            )
+        } else {
+            return false;
+        };
+        name == id.name_hash && key == id.key_hash
+    }
+
+    /// Builds a signed, successful OCSPResponse. `lookup` maps a canonical serial to its status.
+    pub fn respond(
+        &self,
+        req: &OcspRequest,
+        lookup: impl Fn(&str) -> CertStatus,
+    ) -> Result<Vec<u8>> {
+        let now = Utc::now();
+        let this_update = crate::util::crl::to_offset(now)?;
+        let next_update = crate::util::crl::to_offset(now + self.validity)?;
+        let responder_key = Sha1::digest(self.key.public_key_raw()).to_vec();
+
+        let mut statuses = Vec::with_capacity(req.certs.len());
+        for id in &req.certs {
+            let status = if self.matches_issuer(id) {
+                lookup(&x509::format_serial(&id.serial))
+            } else {
+                CertStatus::Unknown
+            };
+            let revoked = match &status {
+                CertStatus::Revoked { at, .. } => Some(crate::util::crl::to_offset(*at)?),
+                _ => None,
+            };
+            statuses.push((id, status, revoked));
+        }
+
+        let tbs = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_tagged(Tag::context(2), |w| w.write_bytes(&responder_key));
+                x509::write_generalized_time(w.next(), this_update);
+                w.next().write_sequence(|w| {
+                    for (id, status, revoked) in &statuses {
+                        w.next().write_sequence(|w| {
+                            w.next().write_der(&id.raw);
+                            match (status, revoked) {
+                                (CertStatus::Revoked { reason, .. }, Some(at)) => {
+                                    w.next().write_tagged_implicit(Tag::context(1), |w| {
+                                        w.write_sequence(|w| {
+                                            x509::write_generalized_time(w.next(), *at);
+                                            if let Some(reason) = reason {
+                                                w.next().write_tagged(Tag::context(0), |w| {
+                                                    w.write_enum(reason.code())
+                                                });
+                                            }
+                                        })
+                                    });
+                                }
+                                (CertStatus::Good, _) => w
+                                    .next()
+                                    .write_tagged_implicit(Tag::context(0), |w| w.write_null()),
+                                _ => w
+                                    .next()
+                                    .write_tagged_implicit(Tag::context(2), |w| w.write_null()),
+                            }
+                            x509::write_generalized_time(w.next(), this_update);
+                            w.next().write_tagged(Tag::context(0), |w| {
+                                x509::write_generalized_time(w, next_update)
+                            });
+                        });
+                    }
+                });
+                if let Some(nonce) = &req.nonce {
+                    w.next().write_tagged(Tag::context(1), |w| {
+                        w.write_sequence(|w| {
+                            x509::write_extension(w.next(), OID_OCSP_NONCE, false, nonce)
+                        })
+                    });
+                }
+            })
+        });
+        let sig = self.key.sign(&tbs).map_err(Error::from)?;
+        let basic = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_der(&tbs);
+                x509::write_signature_algorithm(&self.key, w.next());
+                w.next().write_bitvec_bytes(&sig, sig.len() * 8);
+                w.next().write_tagged(Tag::context(0), |w| {
+                    w.write_sequence(|w| w.next().write_der(&self.cert_der))
+                });
+            })
+        });
+        debug!(count = statuses.len(), "OCSP response signed");
+        Ok(yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_enum(0);
+                w.next().write_tagged(Tag::context(0), |w| {
+                    w.write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_OCSP_BASIC));
+                        w.next().write_bytes(&basic);
+                    })
+                });
+            })
+        }))
+    }
+}
+
+/// Whether a delegated signer certificate expires within a day.
+pub fn expires_soon(cert_pem: &str) -> Result<bool> {
+    der_expires_soon(&x509::pem_to_der(cert_pem, "CERTIFICATE")?)
+}
+
+/// [`expires_soon`] for a DER certificate.
+pub fn der_expires_soon(cert_der: &[u8]) -> Result<bool> {
+    let entry = IssuedCert::from_der(cert_der)?;
+    Ok(entry.not_after - Utc::now() < Duration::days(1))
+}
+
+fn issue_signer(ca_pem: &str, ca_key: &str, days: u32) -> Result<(String, Zeroizing<String>)> {
+    let ca_key = KeyPair::from_pem(ca_key).map_err(Error::from)?;
+    let ca = Issuer::from_ca_cert_pem(ca_pem, ca_key).map_err(Error::from)?;
+
+    let mut params = CertificateParams::new(vec![]).map_err(Error::from)?;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Hypatia-CA OCSP Responder");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::OcspSigning];
+    params.custom_extensions = vec![CustomExtension::from_oid_content(
+        OID_OCSP_NOCHECK,
+        vec![0x05, 0x00],
+    )];
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(days.into());
+    params.serial_number = Some(x509::random_serial());
+
+    let key = KeyPair::generate().map_err(Error::from)?;
+    let cert = params.signed_by(&key, &ca).map_err(Error::from)?;
+    let cert_pem = cert.pem();
+    let key_pem = Zeroizing::new(key.serialize_pem());
+    fs::write_ocsp_signer(&cert_pem, &key_pem)?;
+    fs::record_issued(&IssuedCert::from_der(cert.der())?, &cert_pem)?;
+    info!("issued delegated OCSP signing certificate");
+    Ok((cert_pem, key_pem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_with_nonce() {
+        // Built by hand: one SHA-1 CertID for serial 0x0a1b plus a nonce extension
+        let cert_id = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_sequence(|w| {
+                    w.next().write_oid(&ObjectIdentifier::from_slice(OID_SHA1));
+                    w.next().write_null();
+                });
+                w.next().write_bytes(&[1; 20]);
+                w.next().write_bytes(&[2; 20]);
+                w.next().write_bigint_bytes(&[0x0a, 0x1b], true);
+            })
+        });
+        let nonce = yasna::construct_der(|w| w.write_bytes(b"nonce"));
+        let req = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_sequence(|w| {
+                    w.next().write_sequence(|w| {
+                        w.next().write_sequence(|w| w.next().write_der(&cert_id))
+                    });
+                    w.next().write_tagged(Tag::context(2), |w| {
+                        w.write_sequence(|w| {
+                            x509::write_extension(w.next(), OID_OCSP_NONCE, false, &nonce)
+                        })
+                    });
+                })
+            })
+        });
+        let parsed = parse_request(&req).unwrap();
+        assert_eq!(parsed.certs.len(), 1);
+        assert_eq!(x509::format_serial(&parsed.certs[0].serial), "0a1b");
+        assert_eq!(parsed.nonce, Some(nonce));
+    }
+}
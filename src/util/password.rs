@@ -0,0 +1,72 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use ring::pbkdf2;
+use std::num::NonZeroU32;
+
+const SCHEME: &str = "pbkdf2-sha256";
+/// Lower than the keystore's count: these hashes are checked on every authenticated request.
+const ITERATIONS: u32 = 100_000;
+
+/// Hashes a password as `pbkdf2-sha256$<iterations>$<salt>$<hash>` with a random salt.
+pub fn hash(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let mut out = [0u8; 32];
+    let iterations = NonZeroU32::new(ITERATIONS).expect("non-zero iteration count");
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut out,
+    );
+    format!(
+        "{SCHEME}${ITERATIONS}${}${}",
+        BASE64.encode(salt),
+        BASE64.encode(out)
+    )
+}
+
+/// Checks `password` against a [`hash`] in constant time; malformed hashes never match.
+pub fn verify(password: &str, encoded: &str) -> bool {
+    let mut parts = encoded.split('$');
+    let (Some(SCHEME), Some(iterations), Some(salt), Some(expected), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(expected)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        BASE64.decode(salt),
+        BASE64.decode(expected),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &expected,
+    )
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_and_verify() {
+        let encoded = hash("correct horse");
+        assert!(encoded.starts_with("pbkdf2-sha256$100000$"));
+        assert!(verify("correct horse", &encoded));
+        assert!(!verify("battery staple", &encoded));
+        assert_ne!(encoded, hash("correct horse"));
+        assert!(!verify("correct horse", "plain"));
+        assert!(!verify("correct horse", &format!("{encoded}$extra")));
+    }
+}
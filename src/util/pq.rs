@@ -0,0 +1,162 @@
+use clap::ValueEnum;
+use crypt_guard::KDF::{
+    Detached, Dilithium2, Dilithium3, Dilithium5, Falcon512, Falcon1024, KeyOperations, Signature,
+};
+use crypt_guard::error::CryptError;
+use crypt_guard::*;
+use crypt_guard::{KeyControKyber512, KeyControKyber768, KeyControKyber1024, KyberKeyFunctions};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+
+/// Post-quantum signature algorithms provided by `crypt_guard`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Falcon512,
+    Falcon1024,
+    Dilithium2,
+    Dilithium3,
+    Dilithium5,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Falcon512 => "falcon512",
+            Algorithm::Falcon1024 => "falcon1024",
+            Algorithm::Dilithium2 => "dilithium2",
+            Algorithm::Dilithium3 => "dilithium3",
+            Algorithm::Dilithium5 => "dilithium5",
+        }
+    }
+
+    /// SubjectPublicKeyInfo algorithm OID, as assigned by the Open Quantum Safe project
+    /// for the round 3 Dilithium and Falcon parameter sets.
+    pub fn oid(self) -> &'static [u64] {
+        match self {
+            Algorithm::Falcon512 => &[1, 3, 9999, 3, 11],
+            Algorithm::Falcon1024 => &[1, 3, 9999, 3, 14],
+            Algorithm::Dilithium2 => &[1, 3, 6, 1, 4, 1, 2, 267, 7, 4, 4],
+            Algorithm::Dilithium3 => &[1, 3, 6, 1, 4, 1, 2, 267, 7, 6, 5],
+            Algorithm::Dilithium5 => &[1, 3, 6, 1, 4, 1, 2, 267, 7, 8, 7],
+        }
+    }
+
+    /// Looks up an algorithm by the components of its OID.
+    pub fn from_oid(oid: &[u64]) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|alg| alg.oid() == oid)
+    }
+
+    /// Generates a `(public, secret)` key pair.
+    pub fn keypair(self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        let (pk, sk) = match self {
+            Algorithm::Falcon512 => FalconKeypair!(512),
+            Algorithm::Falcon1024 => FalconKeypair!(1024),
+            Algorithm::Dilithium2 => DilithiumKeypair!(2),
+            Algorithm::Dilithium3 => DilithiumKeypair!(3),
+            Algorithm::Dilithium5 => DilithiumKeypair!(5),
+        };
+        (pk, Zeroizing::new(sk))
+    }
+
+    /// Detached signature over `data`.
+    pub fn sign(self, sk: &[u8], data: &[u8]) -> Vec<u8> {
+        let (sk, data) = (sk.to_vec(), data.to_vec());
+        match self {
+            Algorithm::Falcon512 => Signature!(Falcon, sk, 512, data, Detached),
+            Algorithm::Falcon1024 => Signature!(Falcon, sk, 1024, data, Detached),
+            Algorithm::Dilithium2 => Signature!(Dilithium, sk, 2, data, Detached),
+            Algorithm::Dilithium3 => Signature!(Dilithium, sk, 3, data, Detached),
+            Algorithm::Dilithium5 => Signature!(Dilithium, sk, 5, data, Detached),
+        }
+    }
+
+    pub fn verify(self, pk: &[u8], sig: &[u8], data: &[u8]) -> bool {
+        let (pk, sig, data) = (pk.to_vec(), sig.to_vec(), data.to_vec());
+        match self {
+            Algorithm::Falcon512 => Verify!(Falcon, pk, 512, sig, data, Detached),
+            Algorithm::Falcon1024 => Verify!(Falcon, pk, 1024, sig, data, Detached),
+            Algorithm::Dilithium2 => Verify!(Dilithium, pk, 2, sig, data, Detached),
+            Algorithm::Dilithium3 => Verify!(Dilithium, pk, 3, sig, data, Detached),
+            Algorithm::Dilithium5 => Verify!(Dilithium, pk, 5, sig, data, Detached),
+        }
+    }
+}
+
+/// Post-quantum key-encapsulation mechanisms provided by `crypt_guard`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Kem {
+    Kyber512,
+    Kyber768,
+    Kyber1024,
+}
+
+impl Kem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kem::Kyber512 => "kyber512",
+            Kem::Kyber768 => "kyber768",
+            Kem::Kyber1024 => "kyber1024",
+        }
+    }
+
+    /// SubjectPublicKeyInfo algorithm OID, as assigned by the Open Quantum Safe project
+    /// for the round 3 Kyber parameter sets.
+    pub fn oid(self) -> &'static [u64] {
+        match self {
+            Kem::Kyber512 => &[1, 3, 6, 1, 4, 1, 22554, 5, 6, 1],
+            Kem::Kyber768 => &[1, 3, 6, 1, 4, 1, 22554, 5, 6, 2],
+            Kem::Kyber1024 => &[1, 3, 6, 1, 4, 1, 22554, 5, 6, 3],
+        }
+    }
+
+    pub fn from_oid(oid: &[u64]) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|kem| kem.oid() == oid)
+    }
+
+    /// Generates a `(public, secret)` key pair.
+    pub fn keypair(self) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>)> {
+        let (pk, sk) = match self {
+            Kem::Kyber512 => KeyControKyber512::keypair(),
+            Kem::Kyber768 => KeyControKyber768::keypair(),
+            Kem::Kyber1024 => KeyControKyber1024::keypair(),
+        }
+        .map_err(|e| self.error("key generation", e))?;
+        Ok((pk, Zeroizing::new(sk)))
+    }
+
+    /// Encapsulates a fresh shared secret to `pk`; returns `(shared secret, ciphertext)`.
+    pub fn encapsulate(self, pk: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>)> {
+        let (ss, ct) = match self {
+            Kem::Kyber512 => KeyControKyber512::encap(pk),
+            Kem::Kyber768 => KeyControKyber768::encap(pk),
+            Kem::Kyber1024 => KeyControKyber1024::encap(pk),
+        }
+        .map_err(|e| self.error("encapsulation", e))?;
+        Ok((Zeroizing::new(ss), ct))
+    }
+
+    /// Recovers the shared secret from `ct` with the secret key `sk`.
+    pub fn decapsulate(self, sk: &[u8], ct: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            Kem::Kyber512 => KeyControKyber512::decap(sk, ct),
+            Kem::Kyber768 => KeyControKyber768::decap(sk, ct),
+            Kem::Kyber1024 => KeyControKyber1024::decap(sk, ct),
+        }
+        .map(Zeroizing::new)
+        .map_err(|e| self.error("decapsulation", e))
+    }
+
+    fn error(self, operation: &str, e: CryptError) -> Error {
+        Error::Other(format!("{} {operation} failed: {e:?}", self.name()))
+    }
+}
@@ -0,0 +1,390 @@
+use crate::error::{Error, Result};
+use crate::util::crl::Reason;
+use crate::util::keystore::{self, KemKey, PublicIdentity};
+use crate::util::pq::{Algorithm, Kem};
+use crate::util::x509::{self, CaSigner};
+use crate::util::{crl, revocation};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use chrono::{DateTime, Duration, Utc};
+use sha1::{Digest, Sha1};
+use tracing::debug;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::revocation_list::CertificateRevocationList;
+use yasna::Tag;
+use yasna::models::ObjectIdentifier;
+
+const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const OID_BASIC_CONSTRAINTS: &[u64] = &[2, 5, 29, 19];
+const OID_KEY_USAGE: &[u64] = &[2, 5, 29, 15];
+const OID_EXT_KEY_USAGE: &[u64] = &[2, 5, 29, 37];
+const OID_SUBJECT_KEY_ID: &[u64] = &[2, 5, 29, 14];
+const OID_CODE_SIGNING: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 3];
+
+/// Key bound to a subject by [`issue`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertifiedKey {
+    /// Falcon or Dilithium key, certified for code signing.
+    Signing(Algorithm),
+    /// Kyber key, certified for key encipherment.
+    Encryption(Kem),
+}
+
+impl CertifiedKey {
+    pub fn name(self) -> &'static str {
+        match self {
+            CertifiedKey::Signing(alg) => alg.name(),
+            CertifiedKey::Encryption(kem) => kem.name(),
+        }
+    }
+
+    pub fn oid(self) -> &'static [u64] {
+        match self {
+            CertifiedKey::Signing(alg) => alg.oid(),
+            CertifiedKey::Encryption(kem) => kem.oid(),
+        }
+    }
+}
+
+/// DER SubjectPublicKeyInfo for a Falcon, Dilithium or Kyber public key.
+pub fn spki(key: CertifiedKey, public_key: &[u8]) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next()
+                .write_sequence(|w| w.next().write_oid(&ObjectIdentifier::from_slice(key.oid())));
+            w.next()
+                .write_bitvec_bytes(public_key, public_key.len() * 8);
+        })
+    })
+}
+
+/// Issues an end-entity certificate binding a Falcon, Dilithium or Kyber public key to
+/// `cn`.
+///
+/// rcgen can only encode the key types it can sign with, so the TBSCertificate is
+/// assembled here and signed by the root CA.
+pub fn issue(
+    signer: &CaSigner,
+    cn: &str,
+    key: CertifiedKey,
+    public_key: &[u8],
+    days: u32,
+) -> Result<Vec<u8>> {
+    let now = Utc::now();
+    let not_before = crl::to_offset(now)?;
+    let not_after = crl::to_offset(now + Duration::days(days.into()))?;
+    let serial = x509::random_serial();
+    let key_id = Sha1::digest(public_key).to_vec();
+
+    let tbs = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_tagged(Tag::context(0), |w| w.write_u8(2));
+            w.next().write_bigint_bytes(serial.as_ref(), true);
+            signer.write_algorithm(w.next());
+            w.next().write_der(&signer.subject_der);
+            w.next().write_sequence(|w| {
+                x509::write_time(w.next(), not_before);
+                x509::write_time(w.next(), not_after);
+            });
+            w.next().write_sequence(|w| {
+                w.next().write_set(|w| {
+                    w.next().write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_COMMON_NAME));
+                        w.next().write_utf8_string(cn);
+                    })
+                })
+            });
+            w.next().write_der(&spki(key, public_key));
+            w.next().write_tagged(Tag::context(3), |w| {
+                w.write_sequence(|w| {
+                    x509::write_extension(w.next(), OID_BASIC_CONSTRAINTS, true, &[0x30, 0x00]);
+                    // digitalSignature for signing keys, keyEncipherment for Kyber keys
+                    let usage = yasna::construct_der(|w| match key {
+                        CertifiedKey::Signing(_) => w.write_bitvec_bytes(&[0x80], 1),
+                        CertifiedKey::Encryption(_) => w.write_bitvec_bytes(&[0x20], 3),
+                    });
+                    x509::write_extension(w.next(), OID_KEY_USAGE, true, &usage);
+                    if let CertifiedKey::Signing(_) = key {
+                        let eku = yasna::construct_der(|w| {
+                            w.write_sequence(|w| {
+                                w.next()
+                                    .write_oid(&ObjectIdentifier::from_slice(OID_CODE_SIGNING))
+                            })
+                        });
+                        x509::write_extension(w.next(), OID_EXT_KEY_USAGE, false, &eku);
+                    }
+                    let ski = yasna::construct_der(|w| w.write_bytes(&key_id));
+                    x509::write_extension(w.next(), OID_SUBJECT_KEY_ID, false, &ski);
+                    x509::write_authority_key_id(w.next(), &signer.key_id);
+                })
+            });
+        })
+    });
+    debug!(%cn, algorithm = key.name(), "signing PQ key certificate");
+    signer.sign(&tbs)
+}
+
+/// Validates a signer certificate against `root_der` and returns the key it certifies.
+///
+/// The certificate must be signed by the root, allow code signing, be within its validity period at `at`
+/// (now, or the time a signature was timestamped) and must not have been revoked by
+/// then, neither in the local revocation records nor in `crl_der`.
+pub fn validate(
+    cert_der: &[u8],
+    root_der: &[u8],
+    crl_der: Option<&[u8]>,
+    at: DateTime<Utc>,
+) -> Result<PublicIdentity> {
+    let cert = check(cert_der, root_der, crl_der, at, "signer")?;
+    let code_signing = cert
+        .extended_key_usage()
+        .ok()
+        .flatten()
+        .is_some_and(|eku| eku.value.code_signing);
+    if !code_signing {
+        return Err(Error::Other(
+            "signer certificate does not allow code signing".into(),
+        ));
+    }
+    let (oid, public_key) = subject_key(&cert);
+    let algorithm = Algorithm::from_oid(&oid).ok_or_else(|| {
+        Error::Other(format!(
+            "certificate key {} is not a Falcon or Dilithium key",
+            cert.public_key().algorithm.algorithm
+        ))
+    })?;
+    Ok(PublicIdentity {
+        name: common_name(&cert),
+        algorithm,
+        key_id: keystore::key_id(&public_key),
+        public_key: B64.encode(public_key),
+        classical: None,
+        kem: None,
+    })
+}
+
+/// Validates a recipient certificate like [`validate`] and returns its subject name and
+/// the Kyber key it certifies for key encipherment.
+pub fn validate_recipient(
+    cert_der: &[u8],
+    root_der: &[u8],
+    crl_der: Option<&[u8]>,
+    at: DateTime<Utc>,
+) -> Result<(String, KemKey)> {
+    let cert = check(cert_der, root_der, crl_der, at, "recipient")?;
+    let (oid, public_key) = subject_key(&cert);
+    let algorithm = Kem::from_oid(&oid).ok_or_else(|| {
+        Error::Other(format!(
+            "certificate key {} is not a Kyber key",
+            cert.public_key().algorithm.algorithm
+        ))
+    })?;
+    let encipherment = cert
+        .key_usage()
+        .ok()
+        .flatten()
+        .is_some_and(|usage| usage.value.key_encipherment());
+    if !encipherment {
+        return Err(Error::Other(
+            "recipient certificate does not allow key encipherment".into(),
+        ));
+    }
+    let key = KemKey {
+        algorithm,
+        key_id: keystore::key_id(&public_key),
+        public_key: B64.encode(public_key),
+        x25519: None,
+    };
+    Ok((common_name(&cert), key))
+}
+
+/// Checks the chain, validity period and revocation status shared by both kinds of
+/// certificate; `role` names the certificate in errors.
+fn check<'a>(
+    cert_der: &'a [u8],
+    root_der: &[u8],
+    crl_der: Option<&[u8]>,
+    at: DateTime<Utc>,
+    role: &str,
+) -> Result<X509Certificate<'a>> {
+    let (_, root) = X509Certificate::from_der(root_der)
+        .map_err(|e| Error::Other(format!("bad root certificate: {e}")))?;
+    let (_, cert) = X509Certificate::from_der(cert_der)
+        .map_err(|e| Error::Other(format!("bad {role} certificate: {e}")))?;
+
+    if cert.issuer().as_raw() != root.subject().as_raw() {
+        return Err(Error::Other(format!(
+            "{role} certificate was not issued by the trusted root"
+        )));
+    }
+    x509::verify_signature(
+        root.public_key().raw,
+        cert.tbs_certificate.as_ref(),
+        &cert.signature_value.data,
+    )
+    .map_err(|_| Error::Other(format!("{role} certificate has an invalid CA signature")))?;
+
+    let validity = cert.validity();
+    if at.timestamp() < validity.not_before.timestamp()
+        || at.timestamp() > validity.not_after.timestamp()
+    {
+        return Err(Error::Other(format!(
+            "{role} certificate is not valid at {at} (valid {} to {})",
+            validity.not_before, validity.not_after
+        )));
+    }
+
+    let serial = x509::format_serial(cert.raw_serial());
+    if revocation::effective(&serial, at)?.is_some() {
+        return Err(Error::Other(format!(
+            "{role} certificate {serial} is revoked"
+        )));
+    }
+    if let Some(crl_der) = crl_der {
+        let (_, list) = CertificateRevocationList::from_der(crl_der)
+            .map_err(|e| Error::Other(format!("bad CRL: {e}")))?;
+        if list.issuer().as_raw() != root.subject().as_raw() {
+            return Err(Error::Other(
+                "CRL was not issued by the trusted root".into(),
+            ));
+        }
+        x509::verify_signature(
+            root.public_key().raw,
+            list.tbs_cert_list.as_ref(),
+            &list.signature_value.data,
+        )
+        .map_err(|_| Error::Other("CRL has an invalid signature".into()))?;
+        if list.iter_revoked_certificates().any(|r| {
+            x509::format_serial(r.raw_serial()) == serial
+                && (r.revocation_date.timestamp() <= at.timestamp()
+                    || r.reason_code()
+                        .is_some_and(|(_, code)| i64::from(code.0) == Reason::KeyCompromise.code()))
+        }) {
+            return Err(Error::Other(format!(
+                "{role} certificate {serial} is listed on the CRL"
+            )));
+        }
+    }
+    debug!(%serial, role, "certificate validated");
+    Ok(cert)
+}
+
+/// SubjectPublicKeyInfo algorithm OID components and key bytes.
+fn subject_key(cert: &X509Certificate) -> (Vec<u64>, Vec<u8>) {
+    let spki = cert.public_key();
+    let oid = spki
+        .algorithm
+        .algorithm
+        .iter()
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    (oid, spki.subject_public_key.data.to_vec())
+}
+
+fn common_name(cert: &X509Certificate) -> String {
+    cert.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{KeyPair, PublicKeyData};
+
+    #[test]
+    fn issued_certificate_carries_pq_key() {
+        let signer = CaSigner {
+            subject_der: yasna::construct_der(|w| w.write_sequence(|_| {})),
+            key_id: vec![1, 2, 3, 4],
+            key: KeyPair::generate().unwrap(),
+        };
+        let der = issue(
+            &signer,
+            "release",
+            CertifiedKey::Signing(Algorithm::Dilithium3),
+            b"pq-public-key",
+            30,
+        )
+        .unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let oid: Vec<u64> = cert
+            .public_key()
+            .algorithm
+            .algorithm
+            .iter()
+            .unwrap()
+            .collect();
+        assert_eq!(Algorithm::from_oid(&oid), Some(Algorithm::Dilithium3));
+        assert_eq!(
+            cert.public_key().subject_public_key.data.as_ref(),
+            b"pq-public-key"
+        );
+        x509::verify_signature(
+            &signer.key.subject_public_key_info(),
+            cert.tbs_certificate.as_ref(),
+            &cert.signature_value.data,
+        )
+        .unwrap();
+        assert!(cert.key_usage().unwrap().unwrap().value.digital_signature());
+
+        let der = issue(
+            &signer,
+            "backup",
+            CertifiedKey::Encryption(Kem::Kyber768),
+            b"kem-public-key",
+            30,
+        )
+        .unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let (oid, public_key) = subject_key(&cert);
+        assert_eq!(Kem::from_oid(&oid), Some(Kem::Kyber768));
+        assert_eq!(public_key, b"kem-public-key");
+        let usage = cert.key_usage().unwrap().unwrap().value;
+        assert!(usage.key_encipherment() && !usage.digital_signature());
+        assert!(cert.extended_key_usage().unwrap().is_none());
+    }
+
+    #[test]
+    fn signer_certificate_needs_code_signing() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let root = params.self_signed(&key).unwrap();
+        let (_, parsed) = X509Certificate::from_der(root.der()).unwrap();
+        let signer = CaSigner {
+            subject_der: parsed.subject().as_raw().to_vec(),
+            key_id: vec![1, 2, 3, 4],
+            key,
+        };
+        let now = Utc::now();
+
+        let der = issue(
+            &signer,
+            "release",
+            CertifiedKey::Signing(Algorithm::Falcon512),
+            b"pq-public-key",
+            30,
+        )
+        .unwrap();
+        let identity = validate(&der, root.der(), None, now).unwrap();
+        assert_eq!(identity.name, "release");
+        assert_eq!(identity.algorithm, Algorithm::Falcon512);
+
+        // same root, but only certified for key encipherment
+        let der = issue(
+            &signer,
+            "release",
+            CertifiedKey::Encryption(Kem::Kyber768),
+            b"kem-public-key",
+            30,
+        )
+        .unwrap();
+        let err = validate(&der, root.der(), None, now).unwrap_err();
+        assert!(err.plain().contains("code signing"));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use rcgen::{CertificateParams, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose};
+use rsa::RsaPrivateKey;
+use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+use serde::{Deserialize, Serialize};
+
+/// What a leaf certificate is for, which decides its key usage extensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// No key usage extensions, as issued before profiles existed
+    #[default]
+    Default,
+    /// TLS server: digitalSignature and keyEncipherment, serverAuth
+    Server,
+    /// TLS client: digitalSignature, clientAuth
+    Client,
+}
+
+impl Profile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Default => "default",
+            Profile::Server => "server",
+            Profile::Client => "client",
+        }
+    }
+
+    /// Sets the profile's key usages on `params`.
+    pub fn apply(self, params: &mut CertificateParams) {
+        match self {
+            Profile::Default => {}
+            Profile::Server => {
+                params.key_usages = vec![
+                    KeyUsagePurpose::DigitalSignature,
+                    KeyUsagePurpose::KeyEncipherment,
+                ];
+                params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            }
+            Profile::Client => {
+                params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+                params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            }
+        }
+    }
+}
+
+/// Key pair type generated for certificates requested without a CSR.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+    Rsa2048,
+    Rsa3072,
+}
+
+impl KeyAlgorithm {
+    pub fn generate(self) -> Result<KeyPair> {
+        let alg = match self {
+            KeyAlgorithm::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyAlgorithm::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            KeyAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+            KeyAlgorithm::Rsa2048 => return generate_rsa(2048),
+            KeyAlgorithm::Rsa3072 => return generate_rsa(3072),
+        };
+        KeyPair::generate_for(alg).map_err(Error::from)
+    }
+}
+
+/// rcgen cannot generate RSA keys, so they come from the rsa crate as PKCS#8.
+fn generate_rsa(bits: usize) -> Result<KeyPair> {
+    let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, bits)
+        .map_err(|e| Error::Other(format!("RSA key generation failed: {e}")))?;
+    let pem = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| Error::Other(format!("RSA key encoding failed: {e}")))?;
+    KeyPair::from_pem(&pem).map_err(Error::from)
+}
@@ -0,0 +1,321 @@
+use crate::error::{Error, Result};
+use crate::util::fs;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+/// Server-wide limits of the `serve` API. Rates are requests per minute with a burst of
+/// the same size; `0` disables a rate.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub per_ip: u32,
+    pub per_identity: u32,
+    /// Certificates per identity per window, unless its token sets its own
+    pub identity_quota: Option<u32>,
+    /// Certificates per registered domain per window
+    pub domain_quota: Option<u32>,
+    pub window: Duration,
+}
+
+/// Quota entries recorded by [`Limiter::reserve`], to be handed back with
+/// [`Limiter::release`] if the certificate is not issued after all.
+#[derive(Debug)]
+pub struct Reservation {
+    keys: Vec<String>,
+    at: DateTime<Utc>,
+}
+
+/// Seconds a client should wait before retrying a denied request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryAfter(pub u64);
+
+/// Remaining requests of one client, refilled continuously.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bucket {
+    tokens: f64,
+    updated: DateTime<Utc>,
+}
+
+/// Limiter state kept in `rate-limits.json` so a restart neither refills buckets nor
+/// resets quotas.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LimitState {
+    #[serde(default)]
+    pub buckets: HashMap<String, Bucket>,
+    /// Issuance times per `identity:` or `domain:` key within the quota window
+    #[serde(default)]
+    pub issuances: HashMap<String, Vec<DateTime<Utc>>>,
+}
+
+impl LimitState {
+    /// Takes one request from the bucket of `key`, which holds `per_minute` requests.
+    fn take(
+        &mut self,
+        key: &str,
+        per_minute: u32,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<(), RetryAfter> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = f64::from(per_minute);
+        let bucket = self.buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).num_milliseconds().max(0) as f64 / 1000.0;
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(RetryAfter(
+                ((1.0 - bucket.tokens) * 60.0 / capacity).ceil() as u64
+            ))
+        }
+    }
+
+    /// Records an issuance for every key unless one of them already used up its limit
+    /// within `window`.
+    fn reserve(
+        &mut self,
+        keys: &[(String, u32)],
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<(), RetryAfter> {
+        for (key, limit) in keys {
+            if *limit == 0 {
+                // a zero quota allows nothing, whatever was recorded
+                return Err(RetryAfter(window.num_seconds().max(1) as u64));
+            }
+            let Some(times) = self.issuances.get_mut(key) else {
+                continue;
+            };
+            times.retain(|t| *t > now - window);
+            if times.len() >= *limit as usize
+                && let Some(oldest) = times.iter().min()
+            {
+                let wait = (*oldest + window - now).num_seconds().max(1);
+                return Err(RetryAfter(wait as u64));
+            }
+        }
+        for (key, _) in keys {
+            self.issuances.entry(key.clone()).or_default().push(now);
+        }
+        Ok(())
+    }
+
+    /// Forgets the issuance recorded for each of `keys` at `at`.
+    fn release(&mut self, keys: &[String], at: DateTime<Utc>) {
+        for key in keys {
+            let Some(times) = self.issuances.get_mut(key) else {
+                continue;
+            };
+            if let Some(i) = times.iter().position(|t| *t == at) {
+                times.remove(i);
+            }
+            if times.is_empty() {
+                self.issuances.remove(key);
+            }
+        }
+    }
+
+    /// Drops buckets that have refilled completely, which a minute without requests
+    /// guarantees, and issuances older than `window`.
+    fn prune(&mut self, window: Duration, now: DateTime<Utc>) {
+        self.buckets
+            .retain(|_, b| now - b.updated < Duration::minutes(1));
+        self.issuances.retain(|_, times| {
+            times.retain(|t| *t > now - window);
+            !times.is_empty()
+        });
+    }
+}
+
+/// Token-bucket rate limits per client IP and identity, and issuance quotas per identity
+/// and registered domain.
+pub struct Limiter {
+    config: RwLock<Config>,
+    state: Mutex<LimitState>,
+    dirty: AtomicBool,
+}
+
+impl Limiter {
+    pub fn load(config: Config) -> Result<Self> {
+        Ok(Limiter {
+            config: RwLock::new(config),
+            state: Mutex::new(fs::read_rate_limits()?),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Applies new limits; buckets and recorded issuances carry over.
+    pub fn reconfigure(&self, config: Config) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    fn config(&self) -> Config {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn check_ip(&self, ip: std::net::IpAddr) -> std::result::Result<(), RetryAfter> {
+        self.take(&format!("ip:{ip}"), self.config().per_ip)
+    }
+
+    /// Rate-limits an identity to `per_minute`, or the server default when `None`.
+    pub fn check_identity(
+        &self,
+        identity: &str,
+        per_minute: Option<u32>,
+    ) -> std::result::Result<(), RetryAfter> {
+        self.take(
+            &format!("identity:{identity}"),
+            per_minute.unwrap_or(self.config().per_identity),
+        )
+    }
+
+    fn take(&self, key: &str, per_minute: u32) -> std::result::Result<(), RetryAfter> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.dirty.store(true, Ordering::Relaxed);
+        state.take(key, per_minute, Utc::now())
+    }
+
+    /// Counts a certificate for `names` against the quotas of `identity` (`quota`, or the
+    /// server default when `None`) and of each registered domain, refusing it when any is
+    /// used up. The reservation is saved before the certificate is issued.
+    pub fn reserve(
+        &self,
+        identity: &str,
+        quota: Option<u32>,
+        names: &[&str],
+    ) -> Result<std::result::Result<Reservation, RetryAfter>> {
+        let config = self.config();
+        let mut keys = Vec::new();
+        if let Some(limit) = quota.or(config.identity_quota) {
+            keys.push((format!("identity:{identity}"), limit));
+        }
+        if let Some(limit) = config.domain_quota {
+            let mut domains: Vec<String> =
+                names.iter().filter_map(|n| registered_domain(n)).collect();
+            domains.sort();
+            domains.dedup();
+            keys.extend(domains.into_iter().map(|d| (format!("domain:{d}"), limit)));
+        }
+        let now = Utc::now();
+        let reservation = Reservation {
+            keys: keys.iter().map(|(key, _)| key.clone()).collect(),
+            at: now,
+        };
+        if keys.is_empty() {
+            return Ok(Ok(reservation));
+        }
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| Error::Other("rate limit state lock poisoned".into()))?;
+        if let Err(retry) = state.reserve(&keys, config.window, now) {
+            return Ok(Err(retry));
+        }
+        state.prune(config.window, now);
+        fs::write_rate_limits(&state)?;
+        Ok(Ok(reservation))
+    }
+
+    /// Gives back a reservation whose certificate was not issued.
+    pub fn release(&self, reservation: Reservation) -> Result<()> {
+        if reservation.keys.is_empty() {
+            return Ok(());
+        }
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| Error::Other("rate limit state lock poisoned".into()))?;
+        state.release(&reservation.keys, reservation.at);
+        fs::write_rate_limits(&state)
+    }
+
+    /// Writes bucket levels changed since the last save.
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| Error::Other("rate limit state lock poisoned".into()))?;
+        state.prune(self.config().window, Utc::now());
+        fs::write_rate_limits(&state)
+    }
+}
+
+/// The registered domain of a DNS name, approximated by its last two labels since no
+/// public suffix list is bundled. IP addresses have none.
+pub fn registered_domain(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if name.parse::<std::net::IpAddr>().is_ok() || name.contains('@') {
+        return None;
+    }
+    let labels: Vec<&str> = name.rsplit('.').take(2).collect();
+    (labels.len() == 2 && labels.iter().all(|l| !l.is_empty() && *l != "*"))
+        .then(|| format!("{}.{}", labels[1], labels[0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_and_quotas_expire() {
+        let mut state = LimitState::default();
+        let now = Utc::now();
+        assert!(state.take("ip:a", 2, now).is_ok());
+        assert!(state.take("ip:a", 2, now).is_ok());
+        assert_eq!(state.take("ip:a", 2, now), Err(RetryAfter(30)));
+        assert!(state.take("ip:b", 2, now).is_ok());
+        assert!(state.take("ip:a", 2, now + Duration::seconds(30)).is_ok());
+
+        let window = Duration::hours(1);
+        let keys = [("domain:example.com".to_owned(), 2)];
+        assert!(state.reserve(&keys, window, now).is_ok());
+        assert!(
+            state
+                .reserve(&keys, window, now + Duration::minutes(10))
+                .is_ok()
+        );
+        assert_eq!(
+            state.reserve(&keys, window, now + Duration::minutes(20)),
+            Err(RetryAfter(2400))
+        );
+        state.release(&[keys[0].0.clone()], now + Duration::minutes(10));
+        assert!(
+            state
+                .reserve(&keys, window, now + Duration::minutes(20))
+                .is_ok()
+        );
+        assert!(
+            state
+                .reserve(&keys, window, now + Duration::minutes(61))
+                .is_ok()
+        );
+        let none = [("identity:blocked".to_owned(), 0)];
+        assert_eq!(state.reserve(&none, window, now), Err(RetryAfter(3600)));
+        assert!(!state.issuances.contains_key("identity:blocked"));
+
+        assert_eq!(
+            registered_domain("a.b.Example.COM."),
+            Some("example.com".into())
+        );
+        assert_eq!(
+            registered_domain("*.example.com"),
+            Some("example.com".into())
+        );
+        assert_eq!(registered_domain("localhost"), None);
+        assert_eq!(registered_domain("192.0.2.1"), None);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::error::{Error, Result};
+use crate::util::crl::{Reason, Revocation};
+use crate::util::{fs, x509};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use tracing::{debug, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Keeps only the latest record per serial, in order of first appearance.
+pub fn latest(entries: Vec<Revocation>) -> Vec<Revocation> {
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut out: Vec<Revocation> = Vec::new();
+    for entry in entries {
+        match index.get(&entry.serial) {
+            Some(&i) => out[i] = entry,
+            None => {
+                index.insert(entry.serial.clone(), out.len());
+                out.push(entry);
+            }
+        }
+    }
+    out
+}
+
+/// Certificates that are currently revoked or on hold.
+pub fn current() -> Result<Vec<Revocation>> {
+    let mut entries = latest(fs::read_revocations()?);
+    entries.retain(|e| e.reason != Some(Reason::RemoveFromCrl));
+    Ok(entries)
+}
+
+/// The revocation of `serial` that applies to something signed at `at`: one that took
+/// effect by then (counting from the invalidity date, if earlier), or a key compromise,
+/// which also voids earlier signatures.
+pub fn effective(serial: &str, at: DateTime<Utc>) -> Result<Option<Revocation>> {
+    Ok(current()?.into_iter().find(|e| {
+        let since = e
+            .invalidity_date
+            .map_or(e.revoked_at, |d| d.min(e.revoked_at));
+        e.serial == serial && (since <= at || e.reason == Some(Reason::KeyCompromise))
+    }))
+}
+
+/// Revokes an issued certificate after checking that it exists and is not already revoked.
+///
+/// A certificate on hold may be revoked again with a permanent reason.
+pub fn revoke(
+    serial: &str,
+    reason: Reason,
+    invalidity_date: Option<DateTime<Utc>>,
+) -> Result<Revocation> {
+    let _lock = fs::lock_revocations()?;
+    record(serial, reason, invalidity_date)
+}
+
+/// [`revoke`] for callers already holding the revocation lock.
+fn record(
+    serial: &str,
+    reason: Reason,
+    invalidity_date: Option<DateTime<Utc>>,
+) -> Result<Revocation> {
+    let serial = x509::format_serial(&x509::parse_serial(serial)?);
+    if reason == Reason::RemoveFromCrl {
+        return Err(Error::Other(
+            "remove-from-crl is not a revocation reason; use `unhold`".into(),
+        ));
+    }
+    let Some(issued) = fs::read_issued()?.into_iter().find(|c| c.serial == serial) else {
+        return Err(Error::Other(format!(
+            "no certificate with serial {serial} was issued by this CA"
+        )));
+    };
+    if let Some(existing) = current()?.into_iter().find(|e| e.serial == serial) {
+        let on_hold = existing.reason == Some(Reason::CertificateHold);
+        if !on_hold || reason == Reason::CertificateHold {
+            return Err(Error::Other(format!(
+                "certificate {serial} is already revoked"
+            )));
+        }
+    }
+    if invalidity_date.is_some_and(|d| d > Utc::now()) {
+        return Err(Error::Other("invalidity date lies in the future".into()));
+    }
+    let entry = Revocation {
+        serial,
+        revoked_at: Utc::now(),
+        reason: Some(reason),
+        invalidity_date,
+    };
+    fs::append_revocation(&entry)?;
+    debug!(serial = %entry.serial, ?reason, "revocation recorded");
+    if reason == Reason::KeyCompromise {
+        block_key(&issued.spki_hash()?)?;
+    }
+    Ok(entry)
+}
+
+/// Revokes every issued certificate carrying the key identified by `spki_hash`
+/// with reason keyCompromise and blocks the key from future issuance.
+pub fn revoke_key(
+    spki_hash: &str,
+    invalidity_date: Option<DateTime<Utc>>,
+) -> Result<Vec<Revocation>> {
+    let _lock = fs::lock_revocations()?;
+    block_key(spki_hash)?;
+    let current = current()?;
+    let mut revoked = Vec::new();
+    for cert in fs::read_issued()? {
+        if cert.spki_hash()? != spki_hash {
+            continue;
+        }
+        let held = current.iter().find(|e| e.serial == cert.serial);
+        if held.is_some_and(|e| e.reason != Some(Reason::CertificateHold)) {
+            debug!(serial = %cert.serial, "already revoked, skipping");
+            continue;
+        }
+        revoked.push(record(
+            &cert.serial,
+            Reason::KeyCompromise,
+            invalidity_date,
+        )?);
+    }
+    Ok(revoked)
+}
+
+fn block_key(spki_hash: &str) -> Result<()> {
+    if !fs::read_blocked_keys()?.iter().any(|k| k == spki_hash) {
+        fs::append_blocked_key(spki_hash)?;
+        info!(%spki_hash, "key blocked");
+    }
+    Ok(())
+}
+
+/// Refuses keys that were reported as compromised.
+pub fn ensure_key_allowed(spki_der: &[u8]) -> Result<()> {
+    let hash = x509::spki_hash(spki_der);
+    if fs::read_blocked_keys()?.contains(&hash) {
+        return Err(Error::Other(format!(
+            "key {hash} is blocked after a key compromise"
+        )));
+    }
+    Ok(())
+}
+
+/// Message a certificate holder signs with the certificate's private key to
+/// request its revocation, one field per line.
+pub fn self_service_message(serial: &str, reason: Reason, timestamp: &str) -> String {
+    let reason = serde_json::to_value(reason)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_owned))
+        .unwrap_or_default();
+    format!("hypatia-ca-revoke\n{serial}\n{reason}\n{timestamp}\n")
+}
+
+/// Revokes a certificate on request of its holder, proven by `signature` over
+/// [`self_service_message`]. The timestamp must be within five minutes of now.
+pub fn revoke_self_service(
+    serial: &str,
+    reason: Reason,
+    timestamp: &str,
+    signature: &[u8],
+) -> Result<Revocation> {
+    match reason {
+        Reason::Unspecified
+        | Reason::KeyCompromise
+        | Reason::AffiliationChanged
+        | Reason::Superseded
+        | Reason::CessationOfOperation => {}
+        _ => {
+            return Err(Error::Other(format!(
+                "reason {reason:?} is reserved for the CA"
+            )));
+        }
+    }
+    let signed_at = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|e| Error::Other(format!("bad timestamp: {e}")))?;
+    if (Utc::now() - signed_at.with_timezone(&Utc)).abs() > Duration::minutes(5) {
+        return Err(Error::Other("revocation request is stale".into()));
+    }
+    let canonical = x509::format_serial(&x509::parse_serial(serial)?);
+    let pem = fs::read_issued_pem(&canonical)?;
+    let cert = x509::pem_to_der(&pem, "CERTIFICATE")?;
+    let (_, cert) = X509Certificate::from_der(&cert)
+        .map_err(|e| Error::Other(format!("bad certificate: {e}")))?;
+    let msg = self_service_message(serial, reason, timestamp);
+    x509::verify_signature(cert.public_key().raw, msg.as_bytes(), signature)?;
+    revoke(serial, reason, None)
+}
+
+/// Releases a certificate from `certificateHold`.
+pub fn unhold(serial: &str) -> Result<Revocation> {
+    let serial = x509::format_serial(&x509::parse_serial(serial)?);
+    let _lock = fs::lock_revocations()?;
+    match current()?.into_iter().find(|e| e.serial == serial) {
+        Some(e) if e.reason == Some(Reason::CertificateHold) => {}
+        Some(_) => {
+            return Err(Error::Other(format!(
+                "certificate {serial} is permanently revoked"
+            )));
+        }
+        None => {
+            return Err(Error::Other(format!("certificate {serial} is not on hold")));
+        }
+    }
+    let entry = Revocation {
+        serial,
+        revoked_at: Utc::now(),
+        reason: Some(Reason::RemoveFromCrl),
+        invalidity_date: None,
+    };
+    fs::append_revocation(&entry)?;
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(serial: &str, reason: Reason) -> Revocation {
+        Revocation {
+            serial: serial.into(),
+            revoked_at: Utc::now(),
+            reason: Some(reason),
+            invalidity_date: None,
+        }
+    }
+
+    #[test]
+    fn latest_keeps_last_record_per_serial() {
+        let entries = latest(vec![
+            entry("01", Reason::CertificateHold),
+            entry("02", Reason::KeyCompromise),
+            entry("01", Reason::RemoveFromCrl),
+        ]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].serial, "01");
+        assert_eq!(entries[0].reason, Some(Reason::RemoveFromCrl));
+        assert_eq!(entries[1].serial, "02");
+    }
+}
@@ -0,0 +1,541 @@
+use crate::error::{Error, Result};
+use crate::util::cms::{
+    self, ContentCipher, Recipient, invalid, read_algorithm, read_attributes, read_if_tag,
+};
+use crate::util::issued::IssuedCert;
+use crate::util::{fs, ocsp, x509};
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use rcgen::{CertificateParams, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose, SigningKey};
+use rsa::RsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use yasna::Tag;
+use yasna::models::ObjectIdentifier;
+use zeroize::Zeroizing;
+
+const OID_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+const OID_CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+const OID_MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_MESSAGE_TYPE: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 2];
+const OID_PKI_STATUS: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 3];
+const OID_FAIL_INFO: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 4];
+const OID_SENDER_NONCE: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 5];
+const OID_RECIPIENT_NONCE: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 6];
+const OID_TRANSACTION_ID: &[u64] = &[2, 16, 840, 1, 113733, 1, 9, 7];
+
+/// GetCACaps answer: SHA-256 is the only digest accepted in requests.
+pub const CAPABILITIES: &str = "POSTPKIOperation\nSHA-256\nAES\nDES3\nSCEPStandard\n";
+
+/// messageType values (RFC 8894 section 3.2.1.2).
+pub const CERT_REP: &str = "3";
+pub const PKCS_REQ: &str = "19";
+
+/// pkiStatus values.
+const SUCCESS: &str = "0";
+const FAILURE: &str = "2";
+
+/// failInfo values (RFC 8894 section 3.2.1.4).
+pub const BAD_ALG: u8 = 0;
+pub const BAD_MESSAGE_CHECK: u8 = 1;
+pub const BAD_REQUEST: u8 = 2;
+
+/// A SCEP pkiMessage as sent by a client; [`PkiMessage::verify`] checks its signature.
+pub struct PkiMessage {
+    pub message_type: String,
+    pub transaction_id: String,
+    pub sender_nonce: Vec<u8>,
+    /// Requester certificate, usually self-signed; replies are encrypted to its key.
+    pub signer_cert: Vec<u8>,
+    /// The pkcsPKIEnvelope.
+    pub content: Vec<u8>,
+    digest_alg: ObjectIdentifier,
+    /// signedAttrs re-tagged as the SET OF that was signed.
+    signed_attrs: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// A PrintableString attribute value; some clients send UTF8String instead.
+fn read_text(der: &[u8]) -> Option<String> {
+    yasna::parse_ber(der, |r| r.read_printable_string())
+        .or_else(|_| yasna::parse_ber(der, |r| r.read_utf8string()))
+        .ok()
+}
+
+/// Parses a pkiMessage (CMS SignedData) without checking its signature yet, so even a
+/// request that fails verification can be answered with its transaction ID.
+pub fn parse_message(der: &[u8]) -> Result<PkiMessage> {
+    let (content_type, parsed) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let content_type = r.next().read_oid()?;
+            let parsed = r.next().read_tagged(Tag::context(0), |r| {
+                r.read_sequence(|r| {
+                    r.next().read_u8()?;
+                    r.next().read_der()?;
+                    let (e_type, content) = r.next().read_sequence(|r| {
+                        let e_type = r.next().read_oid()?;
+                        let content = r.next().read_tagged(Tag::context(0), |r| r.read_bytes())?;
+                        Ok((e_type, content))
+                    })?;
+                    if e_type != ObjectIdentifier::from_slice(OID_DATA) {
+                        return Err(invalid());
+                    }
+                    let certs = r
+                        .read_optional(|r| {
+                            r.read_tagged_implicit(Tag::context(0), |r| {
+                                r.collect_set_of(|r| r.read_der())
+                            })
+                        })?
+                        .unwrap_or_default();
+                    r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+                    let mut signers = r.next().collect_set_of(|r| {
+                        r.read_sequence(|r| {
+                            r.next().read_u8()?;
+                            let (issuer, serial) = r.next().read_sequence(|r| {
+                                let issuer = r.next().read_der()?;
+                                let (serial, _) = r.next().read_bigint_bytes()?;
+                                Ok((issuer, serial))
+                            })?;
+                            let digest_alg = read_algorithm(r.next())?;
+                            let mut signed_attrs = r
+                                .read_optional(|r| read_if_tag(r, Tag::context(0)))?
+                                .ok_or_else(invalid)?;
+                            signed_attrs[0] = 0x31;
+                            r.next().read_der()?;
+                            let signature = r.next().read_bytes()?;
+                            r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+                            Ok((issuer, serial, digest_alg, signed_attrs, signature))
+                        })
+                    })?;
+                    let signer = signers.pop().ok_or_else(invalid)?;
+                    Ok((content, certs, signer))
+                })
+            })?;
+            Ok((content_type, parsed))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed SCEP message: {e}")))?;
+    if content_type != ObjectIdentifier::from_slice(OID_SIGNED_DATA) {
+        return Err(Error::Other("SCEP message is not CMS SignedData".into()));
+    }
+    let (content, certs, (issuer, serial, digest_alg, signed_attrs, signature)) = parsed;
+    let signer_cert = certs
+        .into_iter()
+        .find(|der| {
+            X509Certificate::from_der(der).is_ok_and(|(_, c)| {
+                c.issuer().as_raw() == issuer.as_slice()
+                    && x509::format_serial(c.raw_serial()) == x509::format_serial(&serial)
+            })
+        })
+        .ok_or_else(|| Error::Other("SCEP message lacks the signer certificate".into()))?;
+
+    let attrs = read_attributes(&signed_attrs)?;
+    let attr = |oid: &[u64]| {
+        attrs
+            .iter()
+            .find(|(o, _)| *o == ObjectIdentifier::from_slice(oid))
+            .map(|(_, v)| v.as_slice())
+    };
+    let message_type = attr(OID_MESSAGE_TYPE)
+        .and_then(read_text)
+        .ok_or_else(|| Error::Other("SCEP message has no message type".into()))?;
+    let transaction_id = attr(OID_TRANSACTION_ID)
+        .and_then(read_text)
+        .ok_or_else(|| Error::Other("SCEP message has no transaction ID".into()))?;
+    let sender_nonce = attr(OID_SENDER_NONCE)
+        .and_then(|der| yasna::parse_ber(der, |r| r.read_bytes()).ok())
+        .ok_or_else(|| Error::Other("SCEP message has no sender nonce".into()))?;
+    Ok(PkiMessage {
+        message_type,
+        transaction_id,
+        sender_nonce,
+        signer_cert,
+        content,
+        digest_alg,
+        signed_attrs,
+        signature,
+    })
+}
+
+impl PkiMessage {
+    /// Checks the content digest and the signature with the signer certificate's key.
+    /// The error is the failInfo to answer with.
+    pub fn verify(&self) -> std::result::Result<(), u8> {
+        if self.digest_alg != ObjectIdentifier::from_slice(OID_SHA256) {
+            debug!(alg = %self.digest_alg, "unsupported SCEP digest algorithm");
+            return Err(BAD_ALG);
+        }
+        let attrs = read_attributes(&self.signed_attrs).map_err(|_| BAD_MESSAGE_CHECK)?;
+        let attr = |oid: &[u64]| {
+            attrs
+                .iter()
+                .find(|(o, _)| *o == ObjectIdentifier::from_slice(oid))
+                .map(|(_, v)| v.as_slice())
+        };
+        let data_type =
+            yasna::construct_der(|w| w.write_oid(&ObjectIdentifier::from_slice(OID_DATA)));
+        let digest = yasna::construct_der(|w| w.write_bytes(&Sha256::digest(&self.content)));
+        if attr(OID_CONTENT_TYPE) != Some(data_type.as_slice())
+            || attr(OID_MESSAGE_DIGEST) != Some(digest.as_slice())
+        {
+            return Err(BAD_MESSAGE_CHECK);
+        }
+        let (_, cert) =
+            X509Certificate::from_der(&self.signer_cert).map_err(|_| BAD_MESSAGE_CHECK)?;
+        x509::verify_signature(cert.public_key().raw, &self.signed_attrs, &self.signature)
+            .map_err(|_| BAD_MESSAGE_CHECK)
+    }
+}
+
+/// The SCEP registration authority: an RSA certificate issued by the CA that clients
+/// encrypt requests to and that signs the replies.
+pub struct Responder {
+    key: KeyPair,
+    decrypt_key: RsaPrivateKey,
+    cert_der: Vec<u8>,
+    issuer_name: Vec<u8>,
+    cert_serial: Vec<u8>,
+    ca_der: Vec<u8>,
+}
+
+impl Responder {
+    /// Loads the RA signer, issuing a fresh one when missing or about to expire.
+    pub fn load(signer_days: u32) -> Result<Self> {
+        let (ca_pem, ca_key) = fs::read_root_ca()?;
+        let (cert_pem, key_pem) = match fs::read_scep_signer()? {
+            Some((cert, key)) if !ocsp::expires_soon(&cert)? => (cert, key),
+            _ => issue_signer(&ca_pem, &ca_key, signer_days)?,
+        };
+        Self::new(
+            &key_pem,
+            x509::pem_to_der(&cert_pem, "CERTIFICATE")?,
+            x509::pem_to_der(&ca_pem, "CERTIFICATE")?,
+        )
+    }
+
+    /// A freshly loaded signer if this one expires soon. Clients fetch the new certificate
+    /// with the next GetCACert.
+    pub fn renew(&self, signer_days: u32) -> Result<Option<Self>> {
+        if !ocsp::der_expires_soon(&self.cert_der)? {
+            return Ok(None);
+        }
+        Self::load(signer_days).map(Some)
+    }
+
+    fn new(key_pem: &str, cert_der: Vec<u8>, ca_der: Vec<u8>) -> Result<Self> {
+        let decrypt_key = RsaPrivateKey::from_pkcs8_pem(key_pem)
+            .map_err(|e| Error::Other(format!("bad SCEP signer key: {e}")))?;
+        let key = KeyPair::from_pem(key_pem).map_err(Error::from)?;
+        let (_, cert) = X509Certificate::from_der(&cert_der)
+            .map_err(|e| Error::Other(format!("bad SCEP signer certificate: {e}")))?;
+        let issuer_name = cert.issuer().as_raw().to_vec();
+        let cert_serial = cert.raw_serial().to_vec();
+        Ok(Self {
+            key,
+            decrypt_key,
+            cert_der,
+            issuer_name,
+            cert_serial,
+            ca_der,
+        })
+    }
+
+    /// GetCACert answer: the RA and CA certificates as certs-only PKCS#7.
+    pub fn ca_certs(&self) -> Vec<u8> {
+        cms::certs_only(&[&self.cert_der, &self.ca_der])
+    }
+
+    /// Decrypts the pkcsPKIEnvelope of a request.
+    pub fn decrypt(&self, msg: &PkiMessage) -> Result<(Vec<u8>, ContentCipher)> {
+        cms::decrypt_enveloped(&msg.content, &self.decrypt_key)
+    }
+
+    /// CertRep carrying `cert_der`, encrypted to the requester with the request's cipher.
+    pub fn success(
+        &self,
+        msg: &PkiMessage,
+        cert_der: &[u8],
+        cipher: ContentCipher,
+    ) -> Result<Vec<u8>> {
+        let recipient = Recipient::from_cert(&msg.signer_cert)?;
+        let envelope = cms::envelope(&cms::certs_only(&[cert_der]), &recipient, cipher)?;
+        self.cert_rep(msg, SUCCESS, None, Some(&envelope))
+    }
+
+    pub fn failure(&self, msg: &PkiMessage, fail_info: u8) -> Result<Vec<u8>> {
+        self.cert_rep(msg, FAILURE, Some(fail_info), None)
+    }
+
+    fn cert_rep(
+        &self,
+        msg: &PkiMessage,
+        status: &str,
+        fail_info: Option<u8>,
+        content: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let mut sender_nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut sender_nonce);
+        let text_attr = |w: yasna::DERWriter, oid: &[u64], value: &str| {
+            w.write_sequence(|w| {
+                w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+                w.next()
+                    .write_set(|w| w.next().write_printable_string(value));
+            })
+        };
+        let bytes_attr = |w: yasna::DERWriter, oid: &[u64], value: &[u8]| {
+            w.write_sequence(|w| {
+                w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+                w.next().write_set(|w| w.next().write_bytes(value));
+            })
+        };
+        let attrs = yasna::construct_der(|w| {
+            w.write_set_of(|w| {
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_CONTENT_TYPE));
+                    w.next()
+                        .write_set(|w| w.next().write_oid(&ObjectIdentifier::from_slice(OID_DATA)));
+                });
+                bytes_attr(
+                    w.next(),
+                    OID_MESSAGE_DIGEST,
+                    &Sha256::digest(content.unwrap_or_default()),
+                );
+                text_attr(w.next(), OID_MESSAGE_TYPE, CERT_REP);
+                text_attr(w.next(), OID_TRANSACTION_ID, &msg.transaction_id);
+                text_attr(w.next(), OID_PKI_STATUS, status);
+                if let Some(fail_info) = fail_info {
+                    text_attr(w.next(), OID_FAIL_INFO, &fail_info.to_string());
+                }
+                bytes_attr(w.next(), OID_SENDER_NONCE, &sender_nonce);
+                bytes_attr(w.next(), OID_RECIPIENT_NONCE, &msg.sender_nonce);
+            })
+        });
+        let sig = self.key.sign(&attrs).map_err(Error::from)?;
+        // signedAttrs are signed as a SET OF but sent as [0] IMPLICIT
+        let mut signed_attrs = attrs;
+        signed_attrs[0] = 0xa0;
+
+        let sha256 = |w: yasna::DERWriter| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_SHA256))
+            })
+        };
+        let signed_data = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(1);
+                w.next().write_set(|w| sha256(w.next()));
+                w.next().write_sequence(|w| {
+                    w.next().write_oid(&ObjectIdentifier::from_slice(OID_DATA));
+                    if let Some(content) = content {
+                        w.next()
+                            .write_tagged(Tag::context(0), |w| w.write_bytes(content));
+                    }
+                });
+                w.next().write_tagged_implicit(Tag::context(0), |w| {
+                    w.write_set_of(|w| w.next().write_der(&self.cert_der))
+                });
+                w.next().write_set(|w| {
+                    w.next().write_sequence(|w| {
+                        w.next().write_u8(1);
+                        w.next().write_sequence(|w| {
+                            w.next().write_der(&self.issuer_name);
+                            w.next().write_bigint_bytes(&self.cert_serial, true);
+                        });
+                        sha256(w.next());
+                        w.next().write_der(&signed_attrs);
+                        x509::write_signature_algorithm(&self.key, w.next());
+                        w.next().write_bytes(&sig);
+                    })
+                });
+            })
+        });
+        Ok(yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_der(&signed_data));
+            })
+        }))
+    }
+
+    /// Consumes a challenge password issued by `scep-challenge` for `cn`; false when it is
+    /// unknown, expired, already used or issued for another subject.
+    pub fn redeem_challenge(&self, password: &str, cn: &str) -> Result<bool> {
+        let now = Utc::now();
+        let hash = challenge_hash(password);
+        let redeems =
+            |(h, expiry, subject): &fs::ScepChallenge| *h == hash && subject == cn && *expiry > now;
+        fs::update_scep_challenges(|challenges| {
+            let found = challenges.iter().any(redeems);
+            challenges.retain(|c| !redeems(c) && c.1 > now);
+            found
+        })
+    }
+}
+
+fn challenge_hash(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Creates a random one-time challenge password for enrolling `cn`, valid for `validity`, and
+/// stores its hash; expired challenges are dropped on the way.
+pub fn new_challenge(cn: &str, validity: Duration) -> Result<(Zeroizing<String>, DateTime<Utc>)> {
+    fs::check_cert_name(cn)?;
+    let mut raw = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut raw);
+    let password = Zeroizing::new(raw.iter().map(|b| format!("{b:02x}")).collect::<String>());
+    let now = Utc::now();
+    let expiry = now + validity;
+    let hash = challenge_hash(&password);
+    fs::update_scep_challenges(|challenges| {
+        challenges.retain(|(_, expiry, _)| *expiry > now);
+        challenges.push((hash, expiry, cn.to_owned()));
+    })?;
+    Ok((password, expiry))
+}
+
+fn issue_signer(ca_pem: &str, ca_key: &str, days: u32) -> Result<(String, Zeroizing<String>)> {
+    let ca_key = KeyPair::from_pem(ca_key).map_err(Error::from)?;
+    let ca = Issuer::from_ca_cert_pem(ca_pem, ca_key).map_err(Error::from)?;
+
+    let mut params = CertificateParams::new(vec![]).map_err(Error::from)?;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Hypatia-CA SCEP RA");
+    // clients encrypt requests to this key, so it has to be RSA
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(days.into());
+    params.serial_number = Some(x509::random_serial());
+
+    let rsa_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)
+        .map_err(|e| Error::Other(format!("RSA key generation failed: {e}")))?;
+    let key_pem = rsa_key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| Error::Other(format!("RSA key encoding failed: {e}")))?;
+    let key = KeyPair::from_pem(&key_pem).map_err(Error::from)?;
+    let cert = params.signed_by(&key, &ca).map_err(Error::from)?;
+    let cert_pem = cert.pem();
+    fs::write_scep_signer(&cert_pem, &key_pem)?;
+    fs::record_issued(&IssuedCert::from_der(cert.der())?, &cert_pem)?;
+    info!("issued SCEP registration authority certificate");
+    Ok((cert_pem, key_pem))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A PKCSReq signed with an ECDSA key, as built by a client.
+    pub(crate) fn request(key: &KeyPair, cert_der: &[u8], content: &[u8]) -> Vec<u8> {
+        let (_, cert) = X509Certificate::from_der(cert_der).unwrap();
+        let attrs = yasna::construct_der(|w| {
+            w.write_set_of(|w| {
+                for (oid, value) in [
+                    (
+                        OID_CONTENT_TYPE,
+                        yasna::construct_der(|w| {
+                            w.write_oid(&ObjectIdentifier::from_slice(OID_DATA))
+                        }),
+                    ),
+                    (
+                        OID_MESSAGE_DIGEST,
+                        yasna::construct_der(|w| w.write_bytes(&Sha256::digest(content))),
+                    ),
+                    (
+                        OID_MESSAGE_TYPE,
+                        yasna::construct_der(|w| w.write_printable_string(PKCS_REQ)),
+                    ),
+                    (
+                        OID_TRANSACTION_ID,
+                        yasna::construct_der(|w| w.write_printable_string("tx1")),
+                    ),
+                    (
+                        OID_SENDER_NONCE,
+                        yasna::construct_der(|w| w.write_bytes(b"nonce")),
+                    ),
+                ] {
+                    w.next().write_sequence(|w| {
+                        w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+                        w.next().write_set(|w| w.next().write_der(&value));
+                    });
+                }
+            })
+        });
+        let sig = key.sign(&attrs).unwrap();
+        let mut signed_attrs = attrs;
+        signed_attrs[0] = 0xa0;
+        yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+                w.next().write_tagged(Tag::context(0), |w| {
+                    w.write_sequence(|w| {
+                        w.next().write_u8(1);
+                        w.next().write_set(|_| {});
+                        w.next().write_sequence(|w| {
+                            w.next().write_oid(&ObjectIdentifier::from_slice(OID_DATA));
+                            w.next()
+                                .write_tagged(Tag::context(0), |w| w.write_bytes(content));
+                        });
+                        w.next().write_tagged_implicit(Tag::context(0), |w| {
+                            w.write_set_of(|w| w.next().write_der(cert_der))
+                        });
+                        w.next().write_set(|w| {
+                            w.next().write_sequence(|w| {
+                                w.next().write_u8(1);
+                                w.next().write_sequence(|w| {
+                                    w.next().write_der(cert.issuer().as_raw());
+                                    w.next().write_bigint_bytes(cert.raw_serial(), true);
+                                });
+                                w.next().write_sequence(|w| {
+                                    w.next()
+                                        .write_oid(&ObjectIdentifier::from_slice(OID_SHA256))
+                                });
+                                w.next().write_der(&signed_attrs);
+                                x509::write_signature_algorithm(key, w.next());
+                                w.next().write_bytes(&sig);
+                            })
+                        });
+                    })
+                });
+            })
+        })
+    }
+
+    #[test]
+    fn parses_and_verifies_request() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.serial_number = Some(x509::random_serial());
+        let cert = params.self_signed(&key).unwrap();
+        let der = request(&key, cert.der(), b"envelope");
+
+        let msg = parse_message(&der).unwrap();
+        assert_eq!(msg.message_type, PKCS_REQ);
+        assert_eq!(msg.transaction_id, "tx1");
+        assert_eq!(msg.sender_nonce, b"nonce");
+        assert_eq!(msg.content, b"envelope");
+        assert_eq!(msg.signer_cert, cert.der().to_vec());
+        assert_eq!(msg.verify(), Ok(()));
+
+        let mut tampered = msg;
+        tampered.content = b"other".to_vec();
+        assert_eq!(tampered.verify(), Err(BAD_MESSAGE_CHECK));
+    }
+}
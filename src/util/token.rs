@@ -0,0 +1,296 @@
+use crate::error::{Error, Result};
+use crate::util::fs;
+use crate::util::profile::Profile;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Prefix of every API token, so leaked tokens are easy to search for.
+const PREFIX: &str = "hca_";
+
+/// Serializes read-modify-write cycles of the token store within the server.
+static STORE: Mutex<()> = Mutex::new(());
+
+/// An API operation a token or client certificate may be granted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// Issue certificates within the token's scope
+    Sign,
+    /// List and inspect issued certificates
+    Read,
+    /// Revoke any certificate
+    Revoke,
+    /// Create, list and revoke API tokens
+    Admin,
+}
+
+impl Permission {
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Sign => "sign",
+            Permission::Read => "read",
+            Permission::Revoke => "revoke",
+            Permission::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        <Permission as ValueEnum>::from_str(s, false)
+    }
+}
+
+/// Limits on the certificates a token may request; empty lists allow anything.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    /// Exact names or `*.suffix` patterns the CN and every SAN must match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_days: Option<u32>,
+}
+
+impl Scope {
+    /// Refuses a certificate for `names` with `profile` and a lifetime of `days` outside
+    /// this scope.
+    pub fn check(&self, names: &[&str], profile: Profile, days: u32) -> Result<()> {
+        if let Some(name) = names.iter().find(|name| {
+            !self.names.is_empty() && !self.names.iter().any(|p| name_matches(p, name))
+        }) {
+            return Err(Error::Other(format!(
+                "name {name} is outside the token scope"
+            )));
+        }
+        if !self.profiles.is_empty() && !self.profiles.contains(&profile) {
+            return Err(Error::Other(format!(
+                "profile {} is outside the token scope",
+                profile.name()
+            )));
+        }
+        if let Some(max) = self.max_days.filter(|max| days > *max) {
+            return Err(Error::Other(format!(
+                "{days} days exceed the token limit of {max}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Per-token overrides of the server's rate limit and issuance quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Requests per minute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
+    /// Certificates per quota window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u32>,
+}
+
+/// Matches `name` against an exact name or a `*.suffix` pattern, which covers names
+/// any number of labels below the suffix but not the suffix itself.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') => {
+            name.len() > suffix.len() && name.ends_with(suffix)
+        }
+        _ => name == pattern,
+    }
+}
+
+/// A stored API token. Only the SHA-256 hash of its secret is kept; the secret is
+/// random, so a slow password hash would add nothing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub hash: String,
+    pub permissions: Vec<Permission>,
+    #[serde(flatten)]
+    pub scope: Scope,
+    #[serde(flatten)]
+    pub limits: Limits,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    /// Creates a token valid until `expires`; the returned `hca_<id>_<secret>` string is
+    /// the only copy of the secret.
+    pub fn generate(
+        name: &str,
+        permissions: Vec<Permission>,
+        scope: Scope,
+        limits: Limits,
+        expires: DateTime<Utc>,
+    ) -> (Zeroizing<String>, ApiToken) {
+        let id = hex(&rand::random::<[u8; 8]>());
+        let secret = Zeroizing::new(hex(&rand::random::<[u8; 32]>()));
+        let token = ApiToken {
+            hash: hex(&Sha256::digest(secret.as_bytes())),
+            id: id.clone(),
+            name: name.to_owned(),
+            permissions,
+            scope,
+            limits,
+            created: Utc::now(),
+            expires,
+            revoked: None,
+        };
+        (Zeroizing::new(format!("{PREFIX}{id}_{}", *secret)), token)
+    }
+
+    /// Whether the token is neither revoked nor expired at `now`.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked.is_none() && now < self.expires
+    }
+}
+
+/// Generates a token valid for `days` and adds it to the store.
+pub fn create(
+    name: &str,
+    permissions: Vec<Permission>,
+    scope: Scope,
+    limits: Limits,
+    days: u32,
+) -> Result<(Zeroizing<String>, ApiToken)> {
+    if name.is_empty() || name.contains(char::is_control) {
+        return Err(Error::Other(format!("invalid token name {name:?}")));
+    }
+    if permissions.is_empty() {
+        return Err(Error::Other("a token needs at least one permission".into()));
+    }
+    if days == 0 {
+        return Err(Error::Other(
+            "a token must be valid for at least one day".into(),
+        ));
+    }
+    let expires = Utc::now()
+        .checked_add_signed(chrono::Duration::days(days.into()))
+        .ok_or_else(|| Error::Other(format!("a token cannot be valid for {days} days")))?;
+    let (secret, token) = ApiToken::generate(name, permissions, scope, limits, expires);
+    let _guard = STORE
+        .lock()
+        .map_err(|_| Error::Other("token store lock poisoned".into()))?;
+    let mut tokens = fs::read_api_tokens()?;
+    tokens.push(token.clone());
+    fs::write_api_tokens(&tokens)?;
+    Ok((secret, token))
+}
+
+/// Marks token `id` revoked; it stays listed so audits can still name it.
+pub fn revoke(id: &str) -> Result<ApiToken> {
+    let _guard = STORE
+        .lock()
+        .map_err(|_| Error::Other("token store lock poisoned".into()))?;
+    let mut tokens = fs::read_api_tokens()?;
+    let token = tokens
+        .iter_mut()
+        .find(|t| t.id == id)
+        .ok_or_else(|| Error::Other(format!("no API token {id}")))?;
+    if token.revoked.is_some() {
+        return Err(Error::Other(format!("API token {id} is already revoked")));
+    }
+    token.revoked = Some(Utc::now());
+    let token = token.clone();
+    fs::write_api_tokens(&tokens)?;
+    Ok(token)
+}
+
+/// Finds the active token for a presented `hca_<id>_<secret>` string. The id only selects
+/// the entry; the secret's hash is compared in constant time.
+pub fn authenticate<'a>(
+    presented: &str,
+    tokens: &'a [ApiToken],
+    now: DateTime<Utc>,
+) -> Option<&'a ApiToken> {
+    let (id, secret) = presented.strip_prefix(PREFIX)?.split_once('_')?;
+    let token = tokens.iter().find(|t| t.id == id)?;
+    let hash = hex(&Sha256::digest(secret.as_bytes()));
+    (constant_time_eq(hash.as_bytes(), token.hash.as_bytes()) && token.is_active(now))
+        .then_some(token)
+}
+
+/// Compares two byte strings without stopping at the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && std::hint::black_box(a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_and_scopes_tokens() {
+        let scope = Scope {
+            names: vec!["*.apps.example".into(), "api.example".into()],
+            profiles: vec![Profile::Server],
+            max_days: Some(30),
+        };
+        let now = Utc::now();
+        let (secret, token) = ApiToken::generate(
+            "deploy",
+            vec![Permission::Sign],
+            scope,
+            Limits::default(),
+            now + chrono::Duration::days(1),
+        );
+        let tokens = vec![token];
+        assert!(authenticate(&secret, &tokens, now).is_some());
+        assert!(authenticate(&secret, &tokens, now + chrono::Duration::days(2)).is_none());
+        let forged = format!("{}x", &secret[..secret.len() - 1]);
+        assert!(authenticate(&forged, &tokens, now).is_none());
+
+        let scope = &tokens[0].scope;
+        assert!(
+            scope
+                .check(&["web.apps.example", "API.example"], Profile::Server, 30)
+                .is_ok()
+        );
+        assert!(scope.check(&["apps.example"], Profile::Server, 30).is_err());
+        assert!(
+            scope
+                .check(&["web.apps.example"], Profile::Client, 30)
+                .is_err()
+        );
+        assert!(
+            scope
+                .check(&["web.apps.example"], Profile::Server, 31)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_unrepresentable_lifetimes() {
+        let create = |days| {
+            create(
+                "forever",
+                vec![Permission::Read],
+                Scope::default(),
+                Limits::default(),
+                days,
+            )
+        };
+        assert!(create(0).is_err());
+        assert!(create(u32::MAX).is_err());
+    }
+}
@@ -0,0 +1,819 @@
+use crate::error::{Error, Result};
+use crate::util::cms::{invalid, read_algorithm, read_attributes, read_if_tag};
+use crate::util::issued::IssuedCert;
+use crate::util::{crl, fs, ocsp, revocation, x509};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::Request;
+use hyper::http::StatusCode;
+use hyper_util::rt::TokioIo;
+use rcgen::{
+    CertificateParams, CustomExtension, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose, SigningKey,
+};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use yasna::models::ObjectIdentifier;
+use yasna::tags::TAG_INTEGER;
+use yasna::Tag;
+use zeroize::Zeroizing;
+
+const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+const OID_TST_INFO: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 1, 4];
+const OID_CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+const OID_MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+const OID_SIGNING_CERT_V2: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 2, 47];
+const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_SHA384: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 2];
+const OID_SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+const OID_SHA3_256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 8];
+const OID_SHA3_512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 10];
+const OID_EXT_KEY_USAGE: &[u64] = &[2, 5, 29, 37];
+const OID_TIME_STAMPING: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 8];
+
+/// Message imprint algorithms the TSA accepts, with their digest lengths.
+const IMPRINT_ALGS: &[(&[u64], usize)] = &[
+    (OID_SHA256, 32),
+    (OID_SHA384, 48),
+    (OID_SHA512, 64),
+    (OID_SHA3_256, 32),
+    (OID_SHA3_512, 64),
+];
+
+/// TSA policy used unless `serve --tsa-policy` names another (anyPolicy).
+pub const DEFAULT_POLICY: &str = "2.5.29.32.0";
+
+/// PKIFailureInfo bits from RFC 3161.
+const BAD_ALG: usize = 0;
+const BAD_DATA_FORMAT: usize = 5;
+const UNACCEPTED_POLICY: usize = 15;
+const UNACCEPTED_EXTENSION: usize = 16;
+const SYSTEM_FAILURE: usize = 25;
+
+/// A TimeStampReq from a client.
+pub struct TimeStampReq {
+    /// messageImprint in its original encoding, echoed in the TSTInfo.
+    imprint: Vec<u8>,
+    pub hash_alg: ObjectIdentifier,
+    pub digest: Vec<u8>,
+    pub policy: Option<ObjectIdentifier>,
+    /// DER INTEGER, echoed verbatim.
+    pub nonce: Option<Vec<u8>>,
+    pub cert_req: bool,
+    pub extensions: bool,
+}
+
+/// The fields of a TSTInfo that verifiers care about.
+#[derive(Debug)]
+pub struct TstInfo {
+    pub policy: ObjectIdentifier,
+    pub hash_alg: ObjectIdentifier,
+    pub digest: Vec<u8>,
+    pub serial: String,
+    pub gen_time: DateTime<Utc>,
+    nonce: Option<Vec<u8>>,
+}
+
+/// Parses a dotted OID such as `1.3.6.1.4.1.99999.1`.
+pub fn parse_oid(oid: &str) -> Result<ObjectIdentifier> {
+    let components = oid
+        .split('.')
+        .map(|c| c.parse::<u64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| Error::Other(format!("invalid OID {oid:?}")))?;
+    if components.len() < 2 {
+        return Err(Error::Other(format!("invalid OID {oid:?}")));
+    }
+    Ok(ObjectIdentifier::new(components))
+}
+
+fn read_imprint(der: &[u8]) -> yasna::ASN1Result<(ObjectIdentifier, Vec<u8>)> {
+    yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let alg = read_algorithm(r.next())?;
+            let digest = r.next().read_bytes()?;
+            Ok((alg, digest))
+        })
+    })
+}
+
+pub fn parse_request(der: &[u8]) -> Result<TimeStampReq> {
+    let (imprint, policy, nonce, cert_req, extensions) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            if r.next().read_u8()? != 1 {
+                return Err(invalid());
+            }
+            let imprint = r.next().read_der()?;
+            let policy = r.read_optional(|r| r.read_oid())?;
+            let nonce = r.read_optional(|r| read_if_tag(r, TAG_INTEGER))?;
+            let cert_req = r.read_default(false, |r| r.read_bool())?;
+            let extensions = r.read_optional(|r| read_if_tag(r, Tag::context(0)))?;
+            Ok((imprint, policy, nonce, cert_req, extensions.is_some()))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed time-stamp request: {e}")))?;
+    let (hash_alg, digest) = read_imprint(&imprint)
+        .map_err(|e| Error::Other(format!("malformed message imprint: {e}")))?;
+    Ok(TimeStampReq {
+        imprint,
+        hash_alg,
+        digest,
+        policy,
+        nonce,
+        cert_req,
+        extensions,
+    })
+}
+
+/// DER TimeStampReq for a SHA-512 `digest`, asking for the TSA certificate.
+pub fn request(digest: &[u8], nonce: u64) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_u8(1);
+            w.next().write_sequence(|w| {
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_SHA512))
+                });
+                w.next().write_bytes(digest);
+            });
+            w.next().write_u64(nonce);
+            w.next().write_bool(true);
+        })
+    })
+}
+
+/// A TimeStampResp carrying only a rejection with one PKIFailureInfo bit set.
+fn rejection(fail_info: usize) -> Vec<u8> {
+    let mut bits = vec![0u8; fail_info / 8 + 1];
+    bits[fail_info / 8] = 0x80 >> (fail_info % 8);
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_sequence(|w| {
+                w.next().write_u8(2);
+                w.next().write_bitvec_bytes(&bits, fail_info + 1);
+            })
+        })
+    })
+}
+
+/// Extracts the token from a TimeStampResp and checks that it answers `nonce`.
+pub fn parse_response(der: &[u8], nonce: u64) -> Result<Vec<u8>> {
+    let (status, fail_info, token) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let (status, fail_info) = r.next().read_sequence(|r| {
+                let status = r.next().read_u8()?;
+                r.read_optional(|r| r.read_sequence_of(|r| r.read_utf8string().map(|_| ())))?;
+                let fail_info = r.read_optional(|r| r.read_bitvec_bytes())?;
+                Ok((status, fail_info))
+            })?;
+            let token = r.read_optional(|r| r.read_der())?;
+            Ok((status, fail_info, token))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed time-stamp response: {e}")))?;
+    let token = match (status, token) {
+        (0 | 1, Some(token)) => token,
+        _ => {
+            let bit = fail_info
+                .and_then(|(bytes, len)| (0..len).find(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0));
+            return Err(Error::Other(format!(
+                "time-stamp request rejected (status {status}, failure bit {bit:?})"
+            )));
+        }
+    };
+    let info = parse_tst_info(&read_token(&token)?.tst_info)?;
+    let expected = yasna::construct_der(|w| w.write_u64(nonce));
+    if info.nonce.as_deref() != Some(expected.as_slice()) {
+        return Err(Error::Other(
+            "time-stamp response does not echo our nonce".into(),
+        ));
+    }
+    Ok(token)
+}
+
+/// Issues RFC 3161 time-stamp tokens with a TSA certificate issued by the CA.
+pub struct Authority {
+    key: KeyPair,
+    cert_der: Vec<u8>,
+    issuer_name: Vec<u8>,
+    cert_serial: Vec<u8>,
+    policy: ObjectIdentifier,
+    /// Last serial handed out; persisted before a token carrying the next one is issued.
+    /// Shared with the authority that renews this one.
+    serial: Arc<Mutex<u64>>,
+}
+
+impl Authority {
+    /// Loads the TSA signer, issuing a fresh one when missing or about to expire.
+    pub fn load(signer_days: u32, policy: &str) -> Result<Self> {
+        let policy = parse_oid(policy)?;
+        let (key, cert_der) = load_signer(signer_days)?;
+        Self::new(key, cert_der, policy, fs::read_tsa_serial()?)
+    }
+
+    /// A freshly loaded signer if this one expires soon. Tokens keep their numbering, since
+    /// requests in flight may still be served by this authority.
+    pub fn renew(&self, signer_days: u32) -> Result<Option<Self>> {
+        if !ocsp::der_expires_soon(&self.cert_der)? {
+            return Ok(None);
+        }
+        let (key, cert_der) = load_signer(signer_days)?;
+        let mut renewed = Self::new(key, cert_der, self.policy.clone(), 0)?;
+        renewed.serial = self.serial.clone();
+        Ok(Some(renewed))
+    }
+
+    fn new(key: KeyPair, cert_der: Vec<u8>, policy: ObjectIdentifier, serial: u64) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(&cert_der)
+            .map_err(|e| Error::Other(format!("bad TSA certificate: {e}")))?;
+        let issuer_name = cert.issuer().as_raw().to_vec();
+        let cert_serial = cert.raw_serial().to_vec();
+        Ok(Self {
+            key,
+            cert_der,
+            issuer_name,
+            cert_serial,
+            policy,
+            serial: Arc::new(Mutex::new(serial)),
+        })
+    }
+
+    fn next_serial(&self) -> Result<u64> {
+        let mut last = self
+            .serial
+            .lock()
+            .map_err(|_| Error::Other("TSA serial lock poisoned".into()))?;
+        let next = *last + 1;
+        fs::write_tsa_serial(next)?;
+        *last = next;
+        Ok(next)
+    }
+
+    /// Answers a DER TimeStampReq with a granted or rejected TimeStampResp.
+    pub fn respond(&self, der: &[u8]) -> Vec<u8> {
+        let req = match parse_request(der) {
+            Ok(req) => req,
+            Err(e) => {
+                debug!("{}", e);
+                return rejection(BAD_DATA_FORMAT);
+            }
+        };
+        let Some(&(_, len)) = IMPRINT_ALGS
+            .iter()
+            .find(|(oid, _)| req.hash_alg.components().as_slice() == *oid)
+        else {
+            return rejection(BAD_ALG);
+        };
+        if req.digest.len() != len {
+            return rejection(BAD_DATA_FORMAT);
+        }
+        if req.policy.as_ref().is_some_and(|p| *p != self.policy) {
+            return rejection(UNACCEPTED_POLICY);
+        }
+        if req.extensions {
+            return rejection(UNACCEPTED_EXTENSION);
+        }
+        match self
+            .next_serial()
+            .and_then(|serial| self.issue(&req, serial, Utc::now()))
+        {
+            Ok(token) => yasna::construct_der(|w| {
+                w.write_sequence(|w| {
+                    w.next().write_sequence(|w| w.next().write_u8(0));
+                    w.next().write_der(&token);
+                })
+            }),
+            Err(e) => {
+                error!("time-stamping failed: {}", e);
+                rejection(SYSTEM_FAILURE)
+            }
+        }
+    }
+
+    /// Builds the TimeStampToken: a CMS SignedData over a TSTInfo.
+    fn issue(&self, req: &TimeStampReq, serial: u64, now: DateTime<Utc>) -> Result<Vec<u8>> {
+        let gen_time = crl::to_offset(now)?;
+        let tst_info = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(1);
+                w.next().write_oid(&self.policy);
+                w.next().write_der(&req.imprint);
+                w.next().write_u64(serial);
+                x509::write_generalized_time(w.next(), gen_time);
+                w.next().write_sequence(|w| w.next().write_u8(1));
+                if let Some(nonce) = &req.nonce {
+                    w.next().write_der(nonce);
+                }
+            })
+        });
+        let attrs = yasna::construct_der(|w| {
+            w.write_set_of(|w| {
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_CONTENT_TYPE));
+                    w.next().write_set(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_TST_INFO))
+                    });
+                });
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_MESSAGE_DIGEST));
+                    w.next()
+                        .write_set(|w| w.next().write_bytes(&Sha256::digest(&tst_info)));
+                });
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_SIGNING_CERT_V2));
+                    w.next().write_set(|w| {
+                        w.next().write_sequence(|w| {
+                            w.next().write_sequence(|w| {
+                                w.next().write_sequence(|w| {
+                                    w.next().write_bytes(&Sha256::digest(&self.cert_der))
+                                })
+                            })
+                        })
+                    });
+                });
+            })
+        });
+        let sig = self.key.sign(&attrs).map_err(Error::from)?;
+        // signedAttrs are signed as a SET OF but sent as [0] IMPLICIT
+        let mut signed_attrs = attrs;
+        signed_attrs[0] = 0xa0;
+
+        let sha256 = |w: yasna::DERWriter| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_SHA256))
+            })
+        };
+        let signed_data = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(3);
+                w.next().write_set(|w| sha256(w.next()));
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_TST_INFO));
+                    w.next()
+                        .write_tagged(Tag::context(0), |w| w.write_bytes(&tst_info));
+                });
+                if req.cert_req {
+                    w.next().write_tagged_implicit(Tag::context(0), |w| {
+                        w.write_set_of(|w| w.next().write_der(&self.cert_der))
+                    });
+                }
+                w.next().write_set(|w| {
+                    w.next().write_sequence(|w| {
+                        w.next().write_u8(1);
+                        w.next().write_sequence(|w| {
+                            w.next().write_der(&self.issuer_name);
+                            w.next().write_bigint_bytes(&self.cert_serial, true);
+                        });
+                        sha256(w.next());
+                        w.next().write_der(&signed_attrs);
+                        x509::write_signature_algorithm(&self.key, w.next());
+                        w.next().write_bytes(&sig);
+                    })
+                });
+            })
+        });
+        info!(serial, "time-stamp token issued");
+        Ok(yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_der(&signed_data));
+            })
+        }))
+    }
+}
+
+fn load_signer(signer_days: u32) -> Result<(KeyPair, Vec<u8>)> {
+    let (cert_pem, key_pem) = match fs::read_tsa_signer()? {
+        Some((cert, key)) if !ocsp::expires_soon(&cert)? => (cert, key),
+        _ => {
+            let (ca_pem, ca_key) = fs::read_root_ca()?;
+            issue_signer(&ca_pem, &ca_key, signer_days)?
+        }
+    };
+    let key = KeyPair::from_pem(&key_pem).map_err(Error::from)?;
+    Ok((key, x509::pem_to_der(&cert_pem, "CERTIFICATE")?))
+}
+
+fn issue_signer(ca_pem: &str, ca_key: &str, days: u32) -> Result<(String, Zeroizing<String>)> {
+    let ca_key = KeyPair::from_pem(ca_key).map_err(Error::from)?;
+    let ca = Issuer::from_ca_cert_pem(ca_pem, ca_key).map_err(Error::from)?;
+    let key = KeyPair::generate().map_err(Error::from)?;
+    let cert = signer_params(days)?
+        .signed_by(&key, &ca)
+        .map_err(Error::from)?;
+    let cert_pem = cert.pem();
+    let key_pem = Zeroizing::new(key.serialize_pem());
+    fs::write_tsa_signer(&cert_pem, &key_pem)?;
+    fs::record_issued(&IssuedCert::from_der(cert.der())?, &cert_pem)?;
+    info!("issued time-stamping authority certificate");
+    Ok((cert_pem, key_pem))
+}
+
+/// TSA certificate profile; RFC 3161 requires the timeStamping EKU to be its only,
+/// critical purpose.
+fn signer_params(days: u32) -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(vec![]).map_err(Error::from)?;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Hypatia-CA Time Stamping Authority");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    let eku = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next()
+                .write_oid(&ObjectIdentifier::from_slice(OID_TIME_STAMPING))
+        })
+    });
+    let mut eku = CustomExtension::from_oid_content(OID_EXT_KEY_USAGE, eku);
+    eku.set_criticality(true);
+    params.custom_extensions = vec![eku];
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(days.into());
+    params.serial_number = Some(x509::random_serial());
+    Ok(params)
+}
+
+struct SignerInfo {
+    issuer: Vec<u8>,
+    serial: Vec<u8>,
+    digest_alg: ObjectIdentifier,
+    /// signedAttrs re-tagged as the SET OF that was signed.
+    signed_attrs: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+struct Token {
+    tst_info: Vec<u8>,
+    certs: Vec<Vec<u8>>,
+    signer: SignerInfo,
+}
+
+fn read_token(der: &[u8]) -> Result<Token> {
+    let (content_type, token) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            let content_type = r.next().read_oid()?;
+            let token = r.next().read_tagged(Tag::context(0), |r| {
+                r.read_sequence(|r| {
+                    r.next().read_u8()?;
+                    r.next().read_der()?;
+                    let (e_type, tst_info) = r.next().read_sequence(|r| {
+                        let e_type = r.next().read_oid()?;
+                        let tst_info = r.next().read_tagged(Tag::context(0), |r| r.read_bytes())?;
+                        Ok((e_type, tst_info))
+                    })?;
+                    if e_type != ObjectIdentifier::from_slice(OID_TST_INFO) {
+                        return Err(invalid());
+                    }
+                    let certs = r
+                        .read_optional(|r| {
+                            r.read_tagged_implicit(Tag::context(0), |r| {
+                                r.collect_set_of(|r| r.read_der())
+                            })
+                        })?
+                        .unwrap_or_default();
+                    r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+                    let mut signers = r.next().collect_set_of(|r| {
+                        r.read_sequence(|r| {
+                            r.next().read_u8()?;
+                            let (issuer, serial) = r.next().read_sequence(|r| {
+                                let issuer = r.next().read_der()?;
+                                let (serial, _) = r.next().read_bigint_bytes()?;
+                                Ok((issuer, serial))
+                            })?;
+                            let digest_alg = read_algorithm(r.next())?;
+                            let mut signed_attrs = r
+                                .read_optional(|r| read_if_tag(r, Tag::context(0)))?
+                                .ok_or_else(invalid)?;
+                            signed_attrs[0] = 0x31;
+                            r.next().read_der()?;
+                            let signature = r.next().read_bytes()?;
+                            r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+                            Ok(SignerInfo {
+                                issuer,
+                                serial,
+                                digest_alg,
+                                signed_attrs,
+                                signature,
+                            })
+                        })
+                    })?;
+                    let signer = signers.pop().ok_or_else(invalid)?;
+                    Ok(Token {
+                        tst_info,
+                        certs,
+                        signer,
+                    })
+                })
+            })?;
+            Ok((content_type, token))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed time-stamp token: {e}")))?;
+    if content_type != ObjectIdentifier::from_slice(OID_SIGNED_DATA) {
+        return Err(Error::Other(
+            "time-stamp token is not CMS SignedData".into(),
+        ));
+    }
+    Ok(token)
+}
+
+fn parse_tst_info(der: &[u8]) -> Result<TstInfo> {
+    let (policy, imprint, serial, gen_time, nonce) = yasna::parse_ber(der, |r| {
+        r.read_sequence(|r| {
+            r.next().read_u8()?;
+            let policy = r.next().read_oid()?;
+            let imprint = r.next().read_der()?;
+            let (serial, _) = r.next().read_bigint_bytes()?;
+            let gen_time = r.next().read_generalized_time()?;
+            r.read_optional(|r| r.read_sequence(|r| r.read_optional(|r| r.read_der())))?;
+            r.read_default(false, |r| r.read_bool())?;
+            let nonce = r.read_optional(|r| read_if_tag(r, TAG_INTEGER))?;
+            r.read_optional(|r| read_if_tag(r, Tag::context(0)))?;
+            r.read_optional(|r| read_if_tag(r, Tag::context(1)))?;
+            Ok((policy, imprint, serial, gen_time, nonce))
+        })
+    })
+    .map_err(|e| Error::Other(format!("malformed TSTInfo: {e}")))?;
+    let (hash_alg, digest) = read_imprint(&imprint)
+        .map_err(|e| Error::Other(format!("malformed message imprint: {e}")))?;
+    let gen_time = gen_time.datetime();
+    let gen_time = DateTime::from_timestamp(gen_time.unix_timestamp(), gen_time.nanosecond())
+        .ok_or_else(|| Error::Other("time-stamp out of range".into()))?;
+    Ok(TstInfo {
+        policy,
+        hash_alg,
+        digest,
+        serial: x509::format_serial(&serial),
+        gen_time,
+        nonce,
+    })
+}
+
+fn cms_digest(alg: &ObjectIdentifier, data: &[u8]) -> Result<Vec<u8>> {
+    let alg = alg.components().as_slice();
+    Ok(if alg == OID_SHA256 {
+        Sha256::digest(data).to_vec()
+    } else if alg == OID_SHA384 {
+        Sha384::digest(data).to_vec()
+    } else if alg == OID_SHA512 {
+        Sha512::digest(data).to_vec()
+    } else {
+        return Err(Error::Other(format!(
+            "unsupported digest algorithm {alg:?}"
+        )));
+    })
+}
+
+/// Verifies a time-stamp token over the SHA-512 `digest` and returns its TSTInfo.
+///
+/// The TSA certificate must be included in the token, be issued by `root_der`, carry
+/// the critical timeStamping EKU and be valid and unrevoked at the time it attests.
+pub fn verify(token: &[u8], digest: &[u8], root_der: &[u8]) -> Result<TstInfo> {
+    let token = read_token(token)?;
+    let info = parse_tst_info(&token.tst_info)?;
+    if info.hash_alg != ObjectIdentifier::from_slice(OID_SHA512) || info.digest != digest {
+        return Err(Error::Other(
+            "time-stamp token is for different data".into(),
+        ));
+    }
+
+    let signer = &token.signer;
+    let cert_der = token
+        .certs
+        .iter()
+        .find(|der| {
+            X509Certificate::from_der(der).is_ok_and(|(_, c)| {
+                c.issuer().as_raw() == signer.issuer.as_slice()
+                    && x509::format_serial(c.raw_serial()) == x509::format_serial(&signer.serial)
+            })
+        })
+        .ok_or_else(|| Error::Other("time-stamp token lacks the TSA certificate".into()))?;
+    let (_, cert) = X509Certificate::from_der(cert_der)
+        .map_err(|e| Error::Other(format!("bad TSA certificate: {e}")))?;
+    let (_, root) = X509Certificate::from_der(root_der)
+        .map_err(|e| Error::Other(format!("bad root certificate: {e}")))?;
+    if cert.issuer().as_raw() != root.subject().as_raw() {
+        return Err(Error::Other(
+            "TSA certificate was not issued by the trusted root".into(),
+        ));
+    }
+    x509::verify_signature(
+        root.public_key().raw,
+        cert.tbs_certificate.as_ref(),
+        &cert.signature_value.data,
+    )
+    .map_err(|_| Error::Other("TSA certificate has an invalid CA signature".into()))?;
+    let eku = cert
+        .extended_key_usage()
+        .map_err(|e| Error::Other(format!("bad TSA certificate: {e}")))?;
+    if !eku.is_some_and(|eku| eku.critical && eku.value.time_stamping) {
+        return Err(Error::Other(
+            "TSA certificate lacks the critical timeStamping usage".into(),
+        ));
+    }
+    let at = info.gen_time.timestamp();
+    let validity = cert.validity();
+    if at < validity.not_before.timestamp() || at > validity.not_after.timestamp() {
+        return Err(Error::Other(format!(
+            "TSA certificate was not valid at {}",
+            info.gen_time
+        )));
+    }
+    let serial = x509::format_serial(cert.raw_serial());
+    if revocation::effective(&serial, info.gen_time)?.is_some() {
+        return Err(Error::Other(format!("TSA certificate {serial} is revoked")));
+    }
+
+    let attrs = read_attributes(&signer.signed_attrs)?;
+    let attr = |oid: &[u64]| {
+        attrs
+            .iter()
+            .find(|(o, _)| *o == ObjectIdentifier::from_slice(oid))
+            .map(|(_, v)| v.as_slice())
+    };
+    let tst_type =
+        yasna::construct_der(|w| w.write_oid(&ObjectIdentifier::from_slice(OID_TST_INFO)));
+    if attr(OID_CONTENT_TYPE) != Some(tst_type.as_slice()) {
+        return Err(Error::Other(
+            "time-stamp token has a wrong content type".into(),
+        ));
+    }
+    let tst_digest = cms_digest(&signer.digest_alg, &token.tst_info)?;
+    let message_digest = yasna::construct_der(|w| w.write_bytes(&tst_digest));
+    if attr(OID_MESSAGE_DIGEST) != Some(message_digest.as_slice()) {
+        return Err(Error::Other("time-stamp token digest mismatch".into()));
+    }
+    if let Some(ess) = attr(OID_SIGNING_CERT_V2) {
+        let (alg, hash) = yasna::parse_ber(ess, |r| {
+            r.read_sequence(|r| {
+                let first = r.next().read_sequence(|r| {
+                    r.next().read_sequence(|r| {
+                        let alg = r.read_optional(read_algorithm)?;
+                        let hash = r.next().read_bytes()?;
+                        r.read_optional(|r| r.read_der())?;
+                        Ok((alg, hash))
+                    })
+                })?;
+                r.read_optional(|r| r.read_der())?;
+                Ok(first)
+            })
+        })
+        .map_err(|e| Error::Other(format!("malformed signing certificate attribute: {e}")))?;
+        let expected = cms_digest(
+            &alg.unwrap_or_else(|| ObjectIdentifier::from_slice(OID_SHA256)),
+            cert_der,
+        )?;
+        if hash != expected {
+            return Err(Error::Other(
+                "time-stamp token names a different TSA certificate".into(),
+            ));
+        }
+    }
+    x509::verify_signature(
+        cert.public_key().raw,
+        &signer.signed_attrs,
+        &signer.signature,
+    )
+    .map_err(|_| Error::Other("time-stamp token has an invalid signature".into()))?;
+    debug!(serial = %info.serial, gen_time = %info.gen_time, "time-stamp token verified");
+    Ok(info)
+}
+
+/// Requests a token for the SHA-512 `digest` from the TSA at `url` (http or https).
+///
+/// HTTPS servers are checked against `tls_ca`, or this CA's root when not given.
+pub fn fetch(url: &str, digest: &[u8], tls_ca: Option<&str>) -> Result<Vec<u8>> {
+    let nonce: u64 = rand::random();
+    let body = request(digest, nonce);
+    let uri: hyper::Uri = url
+        .parse()
+        .map_err(|e| Error::Other(format!("invalid TSA URL {url}: {e}")))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::Other(format!("TSA URL {url} has no host")))?
+        .to_owned();
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(Error::Other(format!("unsupported TSA URL {url}"))),
+    };
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let req = Request::post(uri.path_and_query().map_or("/", |p| p.as_str()))
+        .header(
+            hyper::header::HOST,
+            uri.authority().map_or(host.as_str(), |a| a.as_str()),
+        )
+        .header(hyper::header::CONTENT_TYPE, "application/timestamp-query")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|e| Error::Other(e.to_string()))?;
+    let tls = if https {
+        let root = match tls_ca {
+            Some(path) => std::fs::read_to_string(path).map_err(Error::from)?,
+            None => fs::read_root_cert()?,
+        };
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(x509::pem_to_der(&root, "CERTIFICATE")?.into())
+            .map_err(|e| Error::Other(format!("bad TLS trust anchor: {e}")))?;
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Some(tokio_rustls::TlsConnector::from(Arc::new(config)))
+    } else {
+        None
+    };
+
+    let rt = tokio::runtime::Runtime::new().map_err(|e| Error::Other(e.to_string()))?;
+    let resp = rt.block_on(async move {
+        let stream = tokio::net::TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(Error::from)?;
+        match tls {
+            Some(connector) => {
+                let name = ServerName::try_from(host)
+                    .map_err(|e| Error::Other(format!("invalid TLS server name: {e}")))?;
+                let stream = connector
+                    .connect(name, stream)
+                    .await
+                    .map_err(|e| Error::Other(format!("TLS error: {e}")))?;
+                post(stream, req).await
+            }
+            None => post(stream, req).await,
+        }
+    })?;
+    debug!(%url, "time-stamp response received");
+    parse_response(&resp, nonce)
+}
+
+async fn post<S>(io: S, req: Request<Full<Bytes>>) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(io))
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    tokio::spawn(conn);
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    if resp.status() != StatusCode::OK {
+        return Err(Error::Other(format!("TSA answered {}", resp.status())));
+    }
+    let body = resp
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    Ok(body.to_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertifiedIssuer};
+
+    #[test]
+    fn token_roundtrip() {
+        let mut ca = CertificateParams::new(vec![]).unwrap();
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca.distinguished_name.push(DnType::CommonName, "Test Root");
+        let ca = CertifiedIssuer::self_signed(ca, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = signer_params(1).unwrap().signed_by(&key, &ca).unwrap();
+        let tsa = Authority::new(
+            key,
+            cert.der().to_vec(),
+            parse_oid(DEFAULT_POLICY).unwrap(),
+            41,
+        )
+        .unwrap();
+
+        let digest = Sha512::digest(b"signed artifact").to_vec();
+        let req = parse_request(&request(&digest, 7)).unwrap();
+        assert!(req.cert_req);
+        let token = tsa.issue(&req, 42, Utc::now()).unwrap();
+        let info = verify(&token, &digest, ca.der()).unwrap();
+        assert_eq!(info.serial, "2a");
+        assert_eq!(info.policy, parse_oid(DEFAULT_POLICY).unwrap());
+        assert!(verify(&token, &Sha512::digest(b"other"), ca.der()).is_err());
+
+        let resp = rejection(BAD_ALG);
+        assert!(parse_response(&resp, 7).is_err());
+    }
+}
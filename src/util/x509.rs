@@ -0,0 +1,284 @@
+use crate::error::{Error, Result};
+use crate::util::fs;
+use rcgen::{KeyPair, SerialNumber, SigningKey};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::debug;
+use x509_parser::certification_request::X509CertificationRequest;
+use x509_parser::cri_attributes::ParsedCriAttribute;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::{
+    OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_PKCS1_RSAENCRYPTION,
+    OID_SIG_ED25519,
+};
+use x509_parser::prelude::{FromDer, SubjectPublicKeyInfo, X509Certificate};
+use yasna::models::{GeneralizedTime, ObjectIdentifier, UTCTime};
+use yasna::{DERWriter, Tag};
+
+/// The CA certificate and key used to sign CRLs and other CA-issued structures.
+pub struct CaSigner {
+    pub subject_der: Vec<u8>,
+    pub key_id: Vec<u8>,
+    pub key: KeyPair,
+}
+
+impl CaSigner {
+    pub fn load() -> Result<Self> {
+        let (cert_pem, key_pem) = fs::read_root_ca()?;
+        let key = KeyPair::from_pem(&key_pem).map_err(Error::from)?;
+        let cert_der = pem_to_der(&cert_pem, "CERTIFICATE")?;
+        let (_, cert) = X509Certificate::from_der(&cert_der)
+            .map_err(|e| Error::Other(format!("bad CA certificate: {e}")))?;
+        let subject_der = cert.tbs_certificate.subject.as_raw().to_vec();
+        let key_id = cert
+            .iter_extensions()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) => Some(id.0.to_vec()),
+                _ => None,
+            })
+            .ok_or_else(|| Error::Other("CA certificate lacks a subject key identifier".into()))?;
+        debug!("loaded CA signer");
+        Ok(Self {
+            subject_der,
+            key_id,
+            key,
+        })
+    }
+
+    /// Wraps `tbs` into `SEQUENCE { tbs, signatureAlgorithm, signature }`.
+    pub fn sign(&self, tbs: &[u8]) -> Result<Vec<u8>> {
+        let sig = self.key.sign(tbs).map_err(Error::from)?;
+        Ok(yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_der(tbs);
+                self.write_algorithm(writer.next());
+                writer.next().write_bitvec_bytes(&sig, sig.len() * 8);
+            })
+        }))
+    }
+
+    pub fn write_algorithm(&self, writer: DERWriter) {
+        write_signature_algorithm(&self.key, writer)
+    }
+}
+
+/// Writes the AlgorithmIdentifier matching the signature produced by `key`.
+pub fn write_signature_algorithm(key: &KeyPair, writer: DERWriter) {
+    let alg = key.algorithm();
+    let (oid, null_params): (&[u64], bool) = if alg == &rcgen::PKCS_ECDSA_P384_SHA384 {
+        (&[1, 2, 840, 10045, 4, 3, 3], false)
+    } else if alg == &rcgen::PKCS_ED25519 {
+        (&[1, 3, 101, 112], false)
+    } else if alg == &rcgen::PKCS_RSA_SHA256 {
+        (&[1, 2, 840, 113549, 1, 1, 11], true)
+    } else {
+        (&[1, 2, 840, 10045, 4, 3, 2], false)
+    };
+    writer.write_sequence(|writer| {
+        writer.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        if null_params {
+            writer.next().write_null();
+        }
+    });
+}
+
+/// Writes `Extension ::= SEQUENCE { extnID, critical, extnValue }`.
+pub fn write_extension(writer: DERWriter, oid: &[u64], critical: bool, value: &[u8]) {
+    writer.write_sequence(|writer| {
+        writer.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        if critical {
+            writer.next().write_bool(true);
+        }
+        writer.next().write_bytes(value);
+    });
+}
+
+/// Writes the authorityKeyIdentifier extension for `key_id`.
+pub fn write_authority_key_id(writer: DERWriter, key_id: &[u8]) {
+    let value = yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer
+                .next()
+                .write_tagged_implicit(Tag::context(0), |writer| writer.write_bytes(key_id));
+        })
+    });
+    write_extension(writer, &[2, 5, 29, 35], false, &value);
+}
+
+/// Writes an RFC 5280 `Time`, using UTCTime before 2050 and GeneralizedTime after.
+pub fn write_time(writer: DERWriter, t: OffsetDateTime) {
+    let t = t.replace_nanosecond(0).unwrap_or(t);
+    if t.year() < 2050 {
+        writer.write_utctime(&UTCTime::from_datetime(t));
+    } else {
+        writer.write_generalized_time(&GeneralizedTime::from_datetime(t));
+    }
+}
+
+pub fn write_generalized_time(writer: DERWriter, t: OffsetDateTime) {
+    let t = t.replace_nanosecond(0).unwrap_or(t);
+    writer.write_generalized_time(&GeneralizedTime::from_datetime(t));
+}
+
+/// Text form of an iPAddress general name.
+pub fn ip_to_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => Some(std::net::Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string()),
+        16 => {
+            let bytes: [u8; 16] = ip.try_into().ok()?;
+            Some(std::net::Ipv6Addr::from(bytes).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Common name and subjectAltName entries requested by a DER CSR: DNS names (lowercased)
+/// and IP addresses. Other name types are refused, since issuance cannot carry them over.
+pub fn csr_names(der: &[u8]) -> Result<(Option<String>, Vec<String>)> {
+    let (_, csr) = X509CertificationRequest::from_der(der)
+        .map_err(|e| Error::Other(format!("invalid CSR: {e}")))?;
+    let cn = csr
+        .certification_request_info
+        .subject
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_owned);
+    let mut names = Vec::new();
+    for ext in csr.requested_extensions().into_iter().flatten() {
+        if let ParsedExtension::SubjectAlternativeName(san) = ext {
+            for name in &san.general_names {
+                let name = match name {
+                    GeneralName::DNSName(dns) => Some(dns.to_ascii_lowercase()),
+                    GeneralName::IPAddress(ip) => ip_to_string(ip),
+                    _ => None,
+                };
+                names.push(name.ok_or_else(|| {
+                    Error::Other("CSR requests an unsupported subjectAltName".into())
+                })?);
+            }
+        }
+    }
+    Ok((cn, names))
+}
+
+/// The PKCS#9 challengePassword attribute of a DER CSR, as SCEP clients send it.
+pub fn csr_challenge_password(der: &[u8]) -> Result<Option<String>> {
+    let (_, csr) = X509CertificationRequest::from_der(der)
+        .map_err(|e| Error::Other(format!("invalid CSR: {e}")))?;
+    Ok(csr
+        .certification_request_info
+        .iter_attributes()
+        .find_map(|attr| match attr.parsed_attribute() {
+            ParsedCriAttribute::ChallengePassword(password) => Some(password.0.clone()),
+            _ => None,
+        }))
+}
+
+/// Parses a hex serial such as `0a:1b:2c` or `0A1B2C`.
+pub fn parse_serial(serial: &str) -> Result<Vec<u8>> {
+    let hex: String = serial.chars().filter(|c| *c != ':').collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(Error::Other(format!("invalid serial: {serial}")));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| Error::Other(format!("invalid serial: {serial}")))
+        })
+        .collect()
+}
+
+/// Canonical lowercase hex form of a serial, without leading zero bytes.
+pub fn format_serial(serial: &[u8]) -> String {
+    let start = serial
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(serial.len().saturating_sub(1));
+    serial[start..].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A random positive 128-bit serial number.
+///
+/// rcgen otherwise derives the serial from the public key, so two certificates
+/// issued for the same key would collide.
+pub fn random_serial() -> SerialNumber {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[0] &= 0x7f;
+    SerialNumber::from(bytes.to_vec())
+}
+
+/// Hex SHA-256 of a DER SubjectPublicKeyInfo, used to identify keys.
+pub fn spki_hash(spki_der: &[u8]) -> String {
+    Sha256::digest(spki_der)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Verifies `sig` over `msg` with the key from a DER SubjectPublicKeyInfo.
+///
+/// ECDSA signatures are expected in their ASN.1 form, as produced by `openssl dgst -sign`.
+pub fn verify_signature(spki_der: &[u8], msg: &[u8], sig: &[u8]) -> Result<()> {
+    let (_, spki) = SubjectPublicKeyInfo::from_der(spki_der)
+        .map_err(|e| Error::Other(format!("bad public key: {e}")))?;
+    let alg_oid = &spki.algorithm.algorithm;
+    let curve = spki
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|p| p.as_oid().ok());
+    let alg: &dyn ring::signature::VerificationAlgorithm = if *alg_oid == OID_SIG_ED25519 {
+        &ring::signature::ED25519
+    } else if *alg_oid == OID_PKCS1_RSAENCRYPTION {
+        &ring::signature::RSA_PKCS1_2048_8192_SHA256
+    } else if *alg_oid == OID_KEY_TYPE_EC_PUBLIC_KEY && curve == Some(OID_EC_P256) {
+        &ring::signature::ECDSA_P256_SHA256_ASN1
+    } else if *alg_oid == OID_KEY_TYPE_EC_PUBLIC_KEY && curve == Some(OID_NIST_EC_P384) {
+        &ring::signature::ECDSA_P384_SHA384_ASN1
+    } else {
+        return Err(Error::Other(format!("unsupported key algorithm {alg_oid}")));
+    };
+    ring::signature::UnparsedPublicKey::new(alg, &spki.subject_public_key.data)
+        .verify(msg, sig)
+        .map_err(|_| Error::Other("signature verification failed".into()))
+}
+
+pub fn pem_to_der(input: &str, tag: &str) -> Result<Vec<u8>> {
+    pem::parse_many(input)
+        .map_err(|e| Error::Other(format!("bad PEM: {e}")))?
+        .into_iter()
+        .find(|p| p.tag() == tag)
+        .map(|p| p.into_contents())
+        .ok_or_else(|| Error::Other(format!("no {tag} block found")))
+}
+
+pub fn der_to_pem(der: &[u8], tag: &str) -> String {
+    pem::encode(&pem::Pem::new(tag, der.to_vec()))
+}
+
+/// Reads `path` as DER, or as PEM holding a `tag` block.
+pub fn read_pem_or_der(path: &str, tag: &str) -> Result<Vec<u8>> {
+    let data = std::fs::read(path).map_err(Error::from)?;
+    match std::str::from_utf8(&data) {
+        Ok(pem) if pem.contains("-----BEGIN") => pem_to_der(pem, tag),
+        _ => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_roundtrip() {
+        let bytes = parse_serial("00:0A:ff").unwrap();
+        assert_eq!(bytes, vec![0x00, 0x0a, 0xff]);
+        assert_eq!(format_serial(&bytes), "0aff");
+        assert!(parse_serial("abc").is_err());
+        assert!(parse_serial("aéb").is_err());
+        assert!(parse_serial("+1").is_err());
+    }
+}